@@ -0,0 +1,62 @@
+/*
+ * C API for embedding the WASI runtime.
+ *
+ * Unless noted otherwise, functions returning int return 0 on success and
+ * -1 on failure, in which case enarx_wasi_last_error() describes what went
+ * wrong. Passing a NULL builder or module fails likewise, and so does a
+ * panic in the runtime, rather than unwinding into the caller.
+ *
+ * The static library is built with the ffi feature:
+ *
+ *     cargo rustc --release --features ffi --crate-type staticlib
+ */
+
+#ifndef ENARX_WASI_H
+#define ENARX_WASI_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct enarx_wasi_builder enarx_wasi_builder;
+typedef struct enarx_wasi_module enarx_wasi_module;
+
+/* Message of the last error on this thread, or NULL. Valid until the next
+ * failing call on this thread. */
+const char *enarx_wasi_last_error(void);
+
+/* Configuration of a module's WASI environment. */
+enarx_wasi_builder *enarx_wasi_builder_new(void);
+void enarx_wasi_builder_free(enarx_wasi_builder *builder);
+int enarx_wasi_builder_arg(enarx_wasi_builder *builder, const char *arg);
+int enarx_wasi_builder_env(enarx_wasi_builder *builder, const char *key, const char *value);
+/* Makes the host directory host_path visible to the guest as guest_path,
+ * and lets the guest use the filesystem syscalls. */
+int enarx_wasi_builder_map_dir(enarx_wasi_builder *builder, const char *guest_path,
+                               const char *host_path, bool readonly);
+int enarx_wasi_builder_inherit_stdio(enarx_wasi_builder *builder, bool inherit);
+/* Write the guest's stdout or stderr to a duplicate of fd, e.g. a pipe's,
+ * which the caller keeps. */
+int enarx_wasi_builder_capture_stdout(enarx_wasi_builder *builder, int fd);
+int enarx_wasi_builder_capture_stderr(enarx_wasi_builder *builder, int fd);
+
+/* Compile and instantiate the module wasm[0..len]. Consumes the builder,
+ * which must not be used or freed afterwards, even on failure. Returns NULL
+ * on failure. */
+enarx_wasi_module *enarx_wasi_instantiate(enarx_wasi_builder *builder, const uint8_t *wasm,
+                                          size_t len);
+
+/* Run the module's _start function. Returns 0 on success, the code the
+ * module passed to proc_exit if nonzero, or -1 on failure. */
+int enarx_wasi_module_run(enarx_wasi_module *module);
+void enarx_wasi_module_free(enarx_wasi_module *module);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,102 @@
+use std::io;
+use wasmtime_environ::{MemoryPlan, MemoryStyle, WASM_PAGE_SIZE};
+
+/// How the host backs the guest's linear memory.
+///
+/// On Linux, the options are applied to the whole region the memory can
+/// grow into when the instance is created, if it can't move: pages are
+/// locked as they're faulted in, and allocated on the NUMA node whenever
+/// they are. Elsewhere, and for memories which move when they grow, they're
+/// applied to the memory as it is when the instance is created, and to
+/// whatever it has grown by at the guest's next syscall.
+#[derive(Debug, Default)]
+pub(crate) struct Backing {
+    /// Ask for the memory to be backed by transparent huge pages.
+    pub(crate) huge_pages: bool,
+    /// Lock the memory into RAM, so it's never swapped out to storage the
+    /// host can read.
+    pub(crate) lock: bool,
+    /// Allocate the memory on this NUMA node only.
+    pub(crate) numa_node: Option<u32>,
+    /// The memory the options have been applied to so far.
+    base: usize,
+    applied: usize,
+}
+
+impl Backing {
+    /// Applies the options to the `len` bytes of memory at `base`, and to
+    /// the rest of the `reserved` bytes it can grow into without moving,
+    /// if that's known.
+    pub(crate) unsafe fn reserve(
+        &mut self,
+        base: *mut u8,
+        len: usize,
+        reserved: Option<usize>,
+    ) -> io::Result<()> {
+        // Elsewhere, locking it all would commit it all up front.
+        if cfg!(target_os = "linux") {
+            if let Some(reserved) = reserved.filter(|&reserved| reserved >= len) {
+                return self.apply(base, reserved);
+            }
+        }
+        self.apply(base, len)
+    }
+
+    /// Applies the options to the `len` bytes of memory at `base` that they
+    /// haven't been applied to yet.
+    pub(crate) unsafe fn apply(&mut self, base: *mut u8, len: usize) -> io::Result<()> {
+        if !self.huge_pages && !self.lock && self.numa_node.is_none() {
+            return Ok(());
+        }
+        if base as usize != self.base {
+            // the memory moved when it grew
+            self.base = base as usize;
+            self.applied = 0;
+        }
+        if len <= self.applied {
+            return Ok(());
+        }
+        let start = base.add(self.applied);
+        let grown = len - self.applied;
+        #[cfg(target_os = "linux")]
+        {
+            if self.huge_pages
+                && libc::madvise(start as *mut libc::c_void, grown, libc::MADV_HUGEPAGE) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(node) = self.numa_node {
+                super::numa::bind_memory(node, start, grown)?;
+            }
+            // Pages are locked as they're faulted in, rather than all at
+            // once, which would commit a whole reservation up front.
+            if self.lock && libc::syscall(libc::SYS_mlock2, start, grown, libc::MLOCK_ONFAULT) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            if self.lock && libc::mlock(start as *const libc::c_void, grown) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.applied = len;
+        Ok(())
+    }
+}
+
+/// The bytes the memory `plan` describes can grow into without moving, if
+/// it never moves.
+pub(crate) fn reservation(plan: &MemoryPlan) -> Option<usize> {
+    match plan.style {
+        MemoryStyle::Static { bound } => {
+            let pages = plan
+                .memory
+                .maximum
+                .map_or(bound, |maximum| maximum.min(bound));
+            Some(pages as usize * WASM_PAGE_SIZE as usize)
+        }
+        MemoryStyle::Dynamic => None,
+    }
+}
@@ -0,0 +1,261 @@
+//! Runs a WASI command module, like the wasmtime CLI does, on top of this
+//! crate's syscall layer.
+
+use std::env;
+use std::fs::{self, File};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+#[cfg(target_os = "linux")]
+use wasmtime_wasi::PrivilegeDrop;
+use wasmtime_wasi::{
+    parse_map_dir, Console, OptLevel, PreopenRights, RunError, TerminalFilter, WasiInstanceBuilder,
+};
+
+const USAGE: &str = "\
+usage: enarx-wasi [OPTIONS] MODULE [ARGS...]
+
+options:
+    --dir DIR              make the host directory DIR visible to the guest,
+                           with the filesystem syscalls to use it
+    --mapdir GUEST::HOST   make the host directory HOST visible as GUEST,
+                           likewise
+    --umask MASK           create files and directories in the preopens
+                           with the octal umask MASK, e.g. 177
+    --max-file-size BYTES  fail the module's writes past BYTES in a file
+    --max-path-len BYTES   fail the module's paths longer than BYTES
+    --max-path-depth N     fail the module's paths more than N directories
+                           deep
+    --env KEY=VALUE        set an environment variable
+    --arg ARG              add an argument, before the ARGS
+    --stdin-file FILE      read the guest's stdin from FILE
+    --strict               only export the syscalls the module imports
+    --step                 stop at each syscall, for commands on stdin
+    -g                     register DWARF for the module's code with
+                           gdb and lldb, and don't optimize it
+    --minimal              only export the clocks, random_get, fd_read,
+                           fd_write and proc_exit, for pure computations
+    --trace FILE           write the module's syscalls to FILE, for diffing
+                           against a golden trace
+    --core-dumps DIR       write a core dump into DIR if the module traps
+    --profile FILE         print the time spent per syscall when the module
+                           exits, and write it to FILE as folded stacks
+    --drbg INTERVAL        serve random_get from an SP 800-90A DRBG,
+                           reseeded every INTERVAL requests
+    --terminal-filter strip|escape
+                           strip or escape control sequences in the
+                           guest's stdout and stderr, if terminals
+    --user UID:GID         drop all capabilities and switch to UID and GID
+                           before running the module
+    --unshare-net          run the module in a network namespace of its own
+    -V, --version          print the version
+    -h, --help             print this message";
+
+struct Options {
+    module: PathBuf,
+    builder: WasiInstanceBuilder,
+    stdin_file: Option<PathBuf>,
+    profile: Option<PathBuf>,
+}
+
+/// Parses `value`, given for the option `name`, as a number.
+fn number<T: FromStr>(value: String, name: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid {} {:?}, expected a number", name, value))
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut builder = WasiInstanceBuilder::new();
+    let mut extra_args = Vec::new();
+    let mut stdin_file = None;
+    let mut profile = None;
+    let mut preopens = Vec::new();
+    let mut umask = None;
+    #[cfg(target_os = "linux")]
+    let mut privilege_drop = None;
+
+    let module = loop {
+        let arg = args.next().ok_or("no module given")?;
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "-V" | "--version" => {
+                println!("enarx-wasi {}", env!("CARGO_PKG_VERSION"));
+                process::exit(0);
+            }
+            "--dir" => {
+                let dir = value("--dir")?;
+                preopens.push((dir.clone(), PathBuf::from(dir)));
+            }
+            "--mapdir" => preopens.push(parse_map_dir(&value("--mapdir")?)?),
+            "--umask" => {
+                let mask = value("--umask")?;
+                umask = Some(
+                    u32::from_str_radix(&mask, 8)
+                        .map_err(|_| format!("invalid --umask {:?}, expected octal", mask))?,
+                );
+            }
+            "--max-file-size" => {
+                builder =
+                    builder.max_file_size(number(value("--max-file-size")?, "--max-file-size")?)
+            }
+            "--max-path-len" => {
+                builder = builder.max_path_len(number(value("--max-path-len")?, "--max-path-len")?)
+            }
+            "--max-path-depth" => {
+                builder =
+                    builder.max_path_depth(number(value("--max-path-depth")?, "--max-path-depth")?)
+            }
+            "--env" => {
+                let env = value("--env")?;
+                let mut parts = env.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if !key.is_empty() => {
+                        builder = builder.env(key, value);
+                    }
+                    _ => return Err(format!("invalid --env {:?}, expected KEY=VALUE", env)),
+                }
+            }
+            "--arg" => extra_args.push(value("--arg")?),
+            "--stdin-file" => stdin_file = Some(PathBuf::from(value("--stdin-file")?)),
+            "--strict" => builder = builder.only_imported(true),
+            "--step" => builder = builder.debugger(Console::stdio()),
+            "-g" => {
+                builder = builder.debug_info(true).opt_level(OptLevel::None);
+            }
+            "--minimal" => builder = builder.minimal(true),
+            "--trace" => builder = builder.trace(value("--trace")?),
+            "--core-dumps" => builder = builder.core_dumps(value("--core-dumps")?),
+            "--profile" => {
+                profile = Some(PathBuf::from(value("--profile")?));
+                builder = builder.profile(true);
+            }
+            "--drbg" => {
+                let interval = value("--drbg")?;
+                let interval = interval
+                    .parse()
+                    .map_err(|_| format!("invalid --drbg {:?}, expected a number", interval))?;
+                builder = builder.drbg(interval);
+            }
+            "--terminal-filter" => {
+                let filter = match value("--terminal-filter")?.as_str() {
+                    "strip" => TerminalFilter::Strip,
+                    "escape" => TerminalFilter::Escape,
+                    filter => {
+                        return Err(format!(
+                            "invalid --terminal-filter {:?}, expected strip or escape",
+                            filter
+                        ))
+                    }
+                };
+                builder = builder.terminal_filter(filter);
+            }
+            #[cfg(target_os = "linux")]
+            "--user" => {
+                let user = value("--user")?;
+                let ids: Vec<_> = user.splitn(2, ':').map(str::parse).collect();
+                match ids.as_slice() {
+                    [Ok(uid), Ok(gid)] => {
+                        privilege_drop = Some(
+                            privilege_drop
+                                .unwrap_or_else(PrivilegeDrop::new)
+                                .user(*uid, *gid),
+                        );
+                    }
+                    _ => return Err(format!("invalid --user {:?}, expected UID:GID", user)),
+                }
+            }
+            #[cfg(target_os = "linux")]
+            "--unshare-net" => {
+                privilege_drop = Some(
+                    privilege_drop
+                        .unwrap_or_else(PrivilegeDrop::new)
+                        .unshare_network(),
+                );
+            }
+            option if option.starts_with('-') => {
+                return Err(format!("unknown option {}", option));
+            }
+            module => break PathBuf::from(module),
+        }
+    };
+
+    let name = module.file_name().map_or_else(
+        || module.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    builder = builder.arg(&name).args(&extra_args).args(args);
+    builder = builder.host_filesystem(!preopens.is_empty());
+    for (guest, host) in preopens {
+        builder = match umask {
+            Some(umask) => {
+                builder.preopen_with_rights(&guest, host, PreopenRights::full().umask(umask))
+            }
+            None => builder.preopen(&guest, host),
+        };
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(privilege_drop) = privilege_drop {
+            builder = builder.privilege_drop(privilege_drop);
+        }
+    }
+    Ok(Options {
+        module,
+        builder,
+        stdin_file,
+        profile,
+    })
+}
+
+/// Runs the module, returning the code it passed to `proc_exit`, if any.
+fn run() -> Result<Option<u32>, String> {
+    let options = parse_args()?;
+    let wasm = fs::read(&options.module)
+        .map_err(|err| format!("couldn't read {}: {}", options.module.display(), err))?;
+
+    // The guest inherits our stdio, so redirecting ours redirects its.
+    if let Some(path) = options.stdin_file {
+        let file = File::open(&path)
+            .map_err(|err| format!("couldn't open {}: {}", path.display(), err))?;
+        if unsafe { libc::dup2(file.as_raw_fd(), 0) } < 0 {
+            return Err(format!(
+                "couldn't redirect stdin: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+
+    let mut module = options
+        .builder
+        .instantiate_module(&wasm)
+        .map_err(|err| err.to_string())?;
+    let result = match module.run() {
+        Ok(()) => Ok(None),
+        Err(RunError::Exit(code)) => Ok(Some(code)),
+        Err(err) => Err(err.to_string()),
+    };
+    if let (Some(path), Some(profile)) = (options.profile, module.profile()) {
+        eprint!("{}", profile.report());
+        fs::write(&path, profile.folded())
+            .map_err(|err| format!("couldn't write {}: {}", path.display(), err))?;
+    }
+    result
+}
+
+fn main() {
+    match run() {
+        Ok(None) => {}
+        Ok(Some(code)) => process::exit(code as i32),
+        Err(err) => {
+            eprintln!("enarx-wasi: {}", err);
+            process::exit(1);
+        }
+    }
+}
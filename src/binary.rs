@@ -0,0 +1,152 @@
+//! Just enough of the wasm binary format to rewrite individual sections of
+//! a module before handing it to the JIT.
+
+pub(crate) const IMPORT_SECTION: u8 = 2;
+pub(crate) const TABLE_SECTION: u8 = 4;
+pub(crate) const MEMORY_SECTION: u8 = 5;
+
+/// Returns `wasm` with the payload of every section for which `rewrite`
+/// returns `Some` replaced by it.
+pub(crate) fn rewrite_sections(
+    wasm: &[u8],
+    mut rewrite: impl FnMut(u8, &[u8]) -> Result<Option<Vec<u8>>, String>,
+) -> Result<Vec<u8>, String> {
+    if wasm.len() < 8 {
+        return Err("module is truncated".to_owned());
+    }
+    let mut out = wasm[..8].to_vec();
+    let mut pos = 8;
+    while pos < wasm.len() {
+        let id = wasm[pos];
+        pos += 1;
+        let size = read_u32(wasm, &mut pos)? as usize;
+        let payload = wasm
+            .get(pos..pos + size)
+            .ok_or_else(|| format!("section {} is truncated", id))?;
+        pos += size;
+
+        out.push(id);
+        match rewrite(id, payload)? {
+            Some(payload) => {
+                write_u32(&mut out, payload.len() as u32);
+                out.extend_from_slice(&payload);
+            }
+            None => {
+                write_u32(&mut out, size as u32);
+                out.extend_from_slice(payload);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Reads a LEB128-encoded `u32` at `*pos`, advancing past it.
+pub(crate) fn read_u32(bytes: &[u8], pos: &mut usize) -> Result<u32, String> {
+    let mut result = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes
+            .get(*pos)
+            .ok_or_else(|| "unexpected end of module".to_owned())?;
+        *pos += 1;
+        result |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err("invalid LEB128 integer".to_owned())
+}
+
+pub(crate) fn write_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Reads `len` bytes at `*pos`, advancing past them.
+pub(crate) fn read_bytes<'a>(
+    bytes: &'a [u8],
+    pos: &mut usize,
+    len: usize,
+) -> Result<&'a [u8], String> {
+    let read = bytes
+        .get(*pos..*pos + len)
+        .ok_or_else(|| "unexpected end of module".to_owned())?;
+    *pos += len;
+    Ok(read)
+}
+
+/// Reads a length-prefixed name at `*pos`, advancing past it.
+pub(crate) fn read_name<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8], String> {
+    let len = read_u32(bytes, pos)? as usize;
+    read_bytes(bytes, pos, len)
+}
+
+pub(crate) fn write_name(out: &mut Vec<u8>, name: &[u8]) {
+    write_u32(out, name.len() as u32);
+    out.extend_from_slice(name);
+}
+
+/// Returns `wasm` with the module name of every import renamed according
+/// to `rename`, which returns `None` for names to keep.
+pub(crate) fn rename_import_modules(
+    wasm: &[u8],
+    rename: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<u8>, String> {
+    rewrite_sections(wasm, |id, payload| {
+        if id != IMPORT_SECTION {
+            return Ok(None);
+        }
+        let mut pos = 0;
+        let count = read_u32(payload, &mut pos)?;
+        let mut out = Vec::with_capacity(payload.len());
+        write_u32(&mut out, count);
+        for _ in 0..count {
+            let module = read_name(payload, &mut pos)?;
+            let renamed = std::str::from_utf8(module)
+                .ok()
+                .and_then(|module| rename(module));
+            write_name(&mut out, renamed.as_ref().map_or(module, |m| m.as_bytes()));
+
+            // the field name and import description are copied verbatim
+            let start = pos;
+            read_name(payload, &mut pos)?;
+            let kind = read_bytes(payload, &mut pos, 1)?[0];
+            match kind {
+                // function: type index
+                0 => {
+                    read_u32(payload, &mut pos)?;
+                }
+                // table: element type and limits
+                1 => {
+                    read_bytes(payload, &mut pos, 1)?;
+                    skip_limits(payload, &mut pos)?;
+                }
+                // memory: limits
+                2 => skip_limits(payload, &mut pos)?,
+                // global: value type and mutability
+                3 => {
+                    read_bytes(payload, &mut pos, 2)?;
+                }
+                kind => return Err(format!("unknown import kind {}", kind)),
+            }
+            out.extend_from_slice(&payload[start..pos]);
+        }
+        Ok(Some(out))
+    })
+}
+
+/// Skips the limits of a table or memory at `*pos`.
+pub(crate) fn skip_limits(bytes: &[u8], pos: &mut usize) -> Result<(), String> {
+    let flags = read_u32(bytes, pos)?;
+    read_u32(bytes, pos)?;
+    if flags & 1 != 0 {
+        read_u32(bytes, pos)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,1686 @@
+use super::backing::reservation;
+use super::binary::rename_import_modules;
+use super::capability::{Capabilities, Capability};
+#[cfg(target_os = "linux")]
+use super::cgroup::Cgroup;
+use super::control::Control;
+use super::debugger::Debugger;
+use super::drbg::Drbg;
+use super::env::EnvPolicy;
+use super::fileid::FileIds;
+use super::fs::FsPolicy;
+use super::hostfd::HostFds;
+use super::imports::{check_imports, function_imports, ImportError};
+use super::instantiate::{instantiate, HostFunction, Interface, WasiAbi, MINIMAL_SYSCALLS};
+use super::interrupt::{instrument, Interrupt, INTERRUPT_EXPORT};
+use super::keep::{
+    available_cpus, Backend, FEATURE_FD_LIMIT, FEATURE_HOSTCALL_TIMEOUT, FEATURE_READONLY_PREOPENS,
+};
+use super::limits::Limits;
+use super::module::{CompileOptions, OptLevel, RunError, WasiModule};
+#[cfg(target_os = "linux")]
+use super::numa::bind_thread;
+use super::preopen::{normalize_guest_path, Preopen, PreopenDir, PreopenRights, FIRST_PREOPEN_FD};
+#[cfg(target_os = "linux")]
+use super::privileges::PrivilegeDrop;
+use super::profile::Profiler;
+use super::readahead::ReadAhead;
+use super::resolve::PathLimits;
+use super::scope::GlobalExports;
+use super::secret::{zeroize_string, Secret, SecretFiles, SECRETS_GUEST_PATH};
+use super::state::{
+    shared_state_of, Context, SharedState, WasiContext, WasiState, DEFAULT_MEMORY_EXPORT,
+};
+use super::terminal::{Terminal, TerminalFilter};
+use super::trace::Tracer;
+use super::tz::{verify_zone, ZONEINFO_GUEST_PATH};
+use super::watchdog::Watchdog;
+use super::writeback::WriteBack;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+use target_lexicon::Triple;
+use wasi_common::{WasiCtx, WasiCtxBuilder};
+use wasmtime_jit::{Context as JitContext, Features};
+use wasmtime_runtime::{Export, InstanceHandle, InstantiationError};
+
+/// Builder for an instance implementing the "wasi" interface.
+///
+/// ```ignore
+/// let instance = WasiInstanceBuilder::new()
+///     .arg("hello.wasm")
+///     .env("RUST_BACKTRACE", "1")
+///     .preopen("/data", PathBuf::from("/var/lib/keep/data"))
+///     .host_filesystem(true)
+///     .instantiate(global_exports)?;
+/// ```
+pub struct WasiInstanceBuilder {
+    prefix: String,
+    memory: String,
+    huge_pages: bool,
+    lock_memory: bool,
+    args: Vec<Arg>,
+    argv0: Option<String>,
+    vars: HashMap<String, String>,
+    env: Vec<(String, String)>,
+    env_policy: Option<EnvPolicy>,
+    secret_env: Vec<(String, Secret)>,
+    secrets: HashMap<String, Secret>,
+    secret_files: HashMap<String, Secret>,
+    preopens: Vec<Preopen>,
+    write_back: Vec<(String, usize)>,
+    timezone: Option<(String, PathBuf)>,
+    inherit_stdio: bool,
+    terminal_filter: Option<TerminalFilter>,
+    captures: Vec<(u32, Box<dyn Write>)>,
+    context: Option<Context>,
+    host_functions: Vec<HostFunction>,
+    abi: WasiAbi,
+    only_imported: bool,
+    minimal: bool,
+    compile: CompileOptions,
+    hostcall_timeout: Option<Duration>,
+    limits: Limits,
+    max_fds: Option<u32>,
+    path_limits: PathLimits,
+    max_file_size: Option<u64>,
+    host_filesystem: bool,
+    protect_fds: bool,
+    virtualize_file_ids: bool,
+    read_ahead: usize,
+    core_dumps: Option<PathBuf>,
+    profile: bool,
+    trace: Option<PathBuf>,
+    debugger: Option<Box<dyn Debugger>>,
+    drbg_reseed_interval: Option<u64>,
+    max_cpus: Option<u32>,
+    control: Option<Control>,
+    linked: Vec<Linked>,
+    backend: Backend,
+    attestation: bool,
+    capabilities: Capabilities,
+    #[cfg(target_os = "linux")]
+    cgroup: Option<Cgroup>,
+    #[cfg(target_os = "linux")]
+    numa_node: Option<u32>,
+    #[cfg(target_os = "linux")]
+    privilege_drop: Option<PrivilegeDrop>,
+    #[cfg(target_os = "linux")]
+    landlock: bool,
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    seccomp: bool,
+}
+
+impl WasiInstanceBuilder {
+    /// Start from an empty configuration which inherits the host's stdio.
+    pub fn new() -> Self {
+        Self {
+            prefix: String::new(),
+            memory: DEFAULT_MEMORY_EXPORT.to_owned(),
+            huge_pages: false,
+            lock_memory: false,
+            args: Vec::new(),
+            argv0: None,
+            vars: HashMap::new(),
+            env: Vec::new(),
+            env_policy: None,
+            secret_env: Vec::new(),
+            secrets: HashMap::new(),
+            secret_files: HashMap::new(),
+            preopens: Vec::new(),
+            write_back: Vec::new(),
+            timezone: None,
+            inherit_stdio: true,
+            terminal_filter: None,
+            captures: Vec::new(),
+            context: None,
+            host_functions: Vec::new(),
+            abi: WasiAbi::Unstable,
+            only_imported: false,
+            minimal: false,
+            compile: CompileOptions::default(),
+            hostcall_timeout: None,
+            limits: Limits::default(),
+            max_fds: None,
+            path_limits: PathLimits::default(),
+            max_file_size: None,
+            host_filesystem: false,
+            protect_fds: false,
+            virtualize_file_ids: false,
+            read_ahead: 0,
+            core_dumps: None,
+            profile: false,
+            trace: None,
+            debugger: None,
+            drbg_reseed_interval: None,
+            max_cpus: None,
+            control: None,
+            linked: Vec::new(),
+            backend: Backend::Nil,
+            attestation: false,
+            capabilities: Capabilities::default(),
+            #[cfg(target_os = "linux")]
+            cgroup: None,
+            #[cfg(target_os = "linux")]
+            numa_node: None,
+            #[cfg(target_os = "linux")]
+            privilege_drop: None,
+            #[cfg(target_os = "linux")]
+            landlock: false,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            seccomp: false,
+        }
+    }
+
+    /// Prepend `prefix` to the name of every exported syscall.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_owned();
+        self
+    }
+
+    /// Resolve guest pointers against the linear memory exported as `name`.
+    pub fn memory(mut self, name: &str) -> Self {
+        self.memory = name.to_owned();
+        self
+    }
+
+    /// Ask for the guest's linear memory to be backed by transparent huge
+    /// pages, which reduces TLB pressure for large heaps. Only supported on
+    /// Linux, and only a hint there.
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /// Lock the guest's linear memory into RAM, so it's never swapped out
+    /// to storage the host can read.
+    ///
+    /// On Linux, all the memory can grow into is locked at instantiation,
+    /// page by page as it's faulted in, unless it can move when it grows.
+    /// Elsewhere, and for memories which can move, memory the guest grows
+    /// is locked at its next syscall.
+    ///
+    /// NB: this is bounded by `RLIMIT_MEMLOCK`, which has to cover the
+    /// memory's maximum size, see `max_memory_pages`. Past it,
+    /// instantiation fails, or the guest's syscalls fail with
+    /// `__WASI_ENOMEM` once it has grown its memory beyond it.
+    pub fn lock_memory(mut self, lock: bool) -> Self {
+        self.lock_memory = lock;
+        self
+    }
+
+    /// Add an argument.
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(Arg::Literal(arg.to_owned()));
+        self
+    }
+
+    /// Add several arguments.
+    pub fn args<S: AsRef<str>>(mut self, args: impl IntoIterator<Item = S>) -> Self {
+        self.args.extend(
+            args.into_iter()
+                .map(|arg| Arg::Literal(arg.as_ref().to_owned())),
+        );
+        self
+    }
+
+    /// Add an argument in which every `{name}` is replaced by the value of
+    /// the variable `name` at instantiation. `{{` and `}}` stand for
+    /// literal braces.
+    pub fn arg_template(mut self, template: &str) -> Self {
+        self.args.push(Arg::Template(template.to_owned()));
+        self
+    }
+
+    /// Make `name` the guest's `argv[0]`, whatever the first argument
+    /// added is, for programs which behave differently depending on the
+    /// name they're invoked as.
+    pub fn argv0(mut self, name: &str) -> Self {
+        self.argv0 = Some(name.to_owned());
+        self
+    }
+
+    /// Define the variable `name` for `arg_template`, e.g. the keep's
+    /// instance id or the port it listens on.
+    pub fn var(mut self, name: &str, value: &str) -> Self {
+        self.vars.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Add an environment variable.
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Add several environment variables.
+    pub fn envs<K: AsRef<str>, V: AsRef<str>>(
+        mut self,
+        envs: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.env.extend(
+            envs.into_iter()
+                .map(|(k, v)| (k.as_ref().to_owned(), v.as_ref().to_owned())),
+        );
+        self
+    }
+
+    /// Assemble the environment according to `policy`, which can pass host
+    /// variables through, instead of taking just the ones set with `env`.
+    pub fn env_policy(mut self, policy: EnvPolicy) -> Self {
+        self.env_policy = Some(policy);
+        self
+    }
+
+    /// Make `dir` visible to the guest as `guest_path`, which takes
+    /// `host_filesystem`.
+    pub fn preopen(mut self, guest_path: &str, dir: impl PreopenDir + 'static) -> Self {
+        self.preopens.push(Preopen::new(guest_path, dir));
+        self
+    }
+
+    /// Make the host directory `host_path` visible to the guest as
+    /// `guest_path`, so the guest sees the same path whatever the host
+    /// layout is.
+    pub fn map_dir(self, guest_path: &str, host_path: impl AsRef<Path>) -> Self {
+        self.preopen(guest_path, host_path.as_ref().to_path_buf())
+    }
+
+    /// Make `dir` visible to the guest as `guest_path`, without allowing the
+    /// guest to create, modify or remove anything in it.
+    pub fn preopen_readonly(self, guest_path: &str, dir: impl PreopenDir + 'static) -> Self {
+        self.preopen_with_rights(guest_path, dir, PreopenRights::read_only())
+    }
+
+    /// Make `dir` visible to the guest as `guest_path`, granting it only
+    /// `rights` there.
+    pub fn preopen_with_rights(
+        mut self,
+        guest_path: &str,
+        dir: impl PreopenDir + 'static,
+        rights: PreopenRights,
+    ) -> Self {
+        let mut preopen = Preopen::new(guest_path, dir);
+        preopen.rights = rights;
+        self.preopens.push(preopen);
+        self
+    }
+
+    /// Whether the guest may use the filesystem syscalls on its preopens:
+    /// the `path_*` ones, `fd_readdir`, `fd_seek`, `fd_pread`, the
+    /// fdstats and filestats and the like, and `enarx_tmpfile`. They fail
+    /// with `__WASI_ENOSYS` unless this is turned on, which leaves the
+    /// guest with reading and writing the fds it's handed. Instantiating
+    /// with preopens, or a `timezone`, fails unless it's turned on.
+    ///
+    /// NB: this exposes whatever the preopens hold to the guest, within
+    /// the rights each is preopened with.
+    pub fn host_filesystem(mut self, allow: bool) -> Self {
+        self.host_filesystem = allow;
+        self
+    }
+
+    /// Buffer up to `bytes` of the guest's writes to each file beneath the
+    /// preopen at `guest_path`, instead of writing them to the host file
+    /// right away. They're written when the buffer fills up, and on
+    /// `fd_sync`, `fd_datasync`, `fd_close` and `proc_exit`, or when the
+    /// instance is dropped.
+    ///
+    /// NB: this trades durability for throughput. Writes still buffered
+    /// are lost if the process dies, and other fds and the host don't see
+    /// them until they're written.
+    pub fn write_back(mut self, guest_path: &str, bytes: usize) -> Self {
+        self.write_back.push((guest_path.to_owned(), bytes));
+        self
+    }
+
+    /// Set the guest's time zone to `tz`, e.g. `Europe/Berlin`, with the
+    /// host's time zone database at `zoneinfo` made visible read-only at
+    /// `/usr/share/zoneinfo`, and `TZ` set accordingly.
+    ///
+    /// The zone's entry is checked to be a TZif file at instantiation. The
+    /// guest reads it through the filesystem syscalls, which
+    /// `host_filesystem` has to allow.
+    pub fn timezone(mut self, tz: &str, zoneinfo: impl AsRef<Path>) -> Self {
+        self.timezone = Some((tz.to_owned(), zoneinfo.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Set the environment variable `key` to `secret`, which must be UTF-8.
+    /// Unlike `env`, it isn't subject to the environment policy.
+    ///
+    /// The secret stays a `Secret` until the `WasiCtx` is assembled, and
+    /// the copies made for that are zeroed, but the `WasiCtx` keeps its own
+    /// copy of the environment, which isn't zeroed when it's dropped.
+    /// Secrets read with `enarx_secret_get`, or as a `secret_file`, don't
+    /// have that problem.
+    pub fn secret_env(mut self, key: &str, secret: Secret) -> Self {
+        self.secret_env.push((key.to_owned(), secret));
+        self
+    }
+
+    /// Make `secret` available to the guest as `name`, for it to read with
+    /// the `enarx_secret_get` hostcall, which takes `Capability::Secrets`:
+    /// grant that too.
+    pub fn secret(mut self, name: &str, secret: Secret) -> Self {
+        self.secrets.insert(name.to_owned(), secret);
+        self
+    }
+
+    /// Make `secret` available to the guest as the file `name` in
+    /// `/run/secrets`, a directory which only exists in the guest's view,
+    /// preopened after the others. The guest can open the file for reading,
+    /// read it, seek in it and stat it, and nothing else; it's served from
+    /// the `Secret`, and never written anywhere.
+    pub fn secret_file(mut self, name: &str, secret: Secret) -> Self {
+        self.secret_files.insert(name.to_owned(), secret);
+        self
+    }
+
+    /// Whether the guest's stdio is the host's, or `/dev/null`.
+    pub fn inherit_stdio(mut self, inherit: bool) -> Self {
+        self.inherit_stdio = inherit;
+        self
+    }
+
+    /// Filter the escape sequences and other control characters out of the
+    /// guest's stdout and stderr as `filter` says, for those which are the
+    /// host's and a terminal, so the guest can't take it over.
+    pub fn terminal_filter(mut self, filter: TerminalFilter) -> Self {
+        self.terminal_filter = Some(filter);
+        self
+    }
+
+    /// Write the guest's stdout to `writer`, e.g. a buffer or a pipe,
+    /// instead of its fd 1.
+    pub fn capture_stdout(mut self, writer: impl Write + 'static) -> Self {
+        self.captures.push((1, Box::new(writer)));
+        self
+    }
+
+    /// Write the guest's stderr to `writer`, instead of its fd 2.
+    pub fn capture_stderr(mut self, writer: impl Write + 'static) -> Self {
+        self.captures.push((2, Box::new(writer)));
+        self
+    }
+
+    /// Use a ready-made `WasiCtx` instead of assembling one from the
+    /// arguments, environment, preopens, secret files and stdio configured
+    /// here, which are then ignored.
+    pub fn wasi_ctx(self, ctx: WasiCtx) -> Self {
+        self.host_state(ctx)
+    }
+
+    /// Like `wasi_ctx`, but for an embedder-defined state type wrapping the
+    /// `WasiCtx`. It can be retrieved afterwards with `wasi_context`.
+    pub fn host_state<T: WasiContext>(mut self, state: T) -> Self {
+        self.context = Some(Context::new(state));
+        self
+    }
+
+    /// Export `f` from the instance alongside the WASI syscalls, under the
+    /// same prefix.
+    pub fn host_function(mut self, f: HostFunction) -> Self {
+        self.host_functions.push(f);
+        self
+    }
+
+    /// Cap every linear memory defined by the module `instantiate_module`
+    /// instantiates at `pages` 64KiB pages. `memory.grow` beyond that fails
+    /// inside the guest.
+    pub fn max_memory_pages(mut self, pages: u32) -> Self {
+        self.limits.memory_pages = Some(pages);
+        self
+    }
+
+    /// Cap every table defined by the module `instantiate_module`
+    /// instantiates at `elements` elements.
+    pub fn max_table_elements(mut self, elements: u32) -> Self {
+        self.limits.table_elements = Some(elements);
+        self
+    }
+
+    /// Allow the guest to hold at most `max` fds, stdio and preopens
+    /// included. Opening more fails with `__WASI_EMFILE`.
+    pub fn max_fds(mut self, max: u32) -> Self {
+        self.max_fds = Some(max);
+        self
+    }
+
+    /// Refuse paths longer than `len` bytes with `__WASI_ENAMETOOLONG`,
+    /// symlink targets included.
+    pub fn max_path_len(mut self, len: usize) -> Self {
+        self.path_limits.max_len = Some(len);
+        self
+    }
+
+    /// Refuse paths reaching more than `depth` directories deep beneath
+    /// the fd they're relative to with `__WASI_ENAMETOOLONG`, and symlinks
+    /// whose target has more than `depth` components.
+    pub fn max_path_depth(mut self, depth: usize) -> Self {
+        self.path_limits.max_depth = Some(depth);
+        self
+    }
+
+    /// Allow the guest to make files at most `bytes` large. Writing,
+    /// allocating or truncating past that fails with `__WASI_EFBIG`. Only
+    /// files opened beneath a preopen are limited, not stdio, even if it's
+    /// redirected to a file.
+    ///
+    /// NB: `fd_write`s to files are no longer write-back buffered then, to
+    /// know where they land.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Whether stdio and the preopens are protected from the guest's
+    /// `fd_close` and `fd_renumber`, which then fail with
+    /// `__WASI_ENOTSUP`. They aren't unless this is turned on, as some
+    /// guests close or replace their stdio on purpose.
+    pub fn protect_fds(mut self, protect: bool) -> Self {
+        self.protect_fds = protect;
+        self
+    }
+
+    /// Whether the guest sees synthetic device and inode numbers in place
+    /// of the host's, in filestats and directory entries. They're stable
+    /// within the instance, and hide the host's layout. It doesn't unless
+    /// this is turned on.
+    pub fn virtualize_file_ids(mut self, virtualize: bool) -> Self {
+        self.virtualize_file_ids = virtualize;
+        self
+    }
+
+    /// Read `bytes` ahead on regular files the guest reads from, so many
+    /// small sequential `fd_read`s don't each take a host read. The guest
+    /// can adjust it per fd with `fd_advise`: sequential access reads
+    /// further ahead, random access not at all.
+    ///
+    /// NB: data already read ahead doesn't reflect writes made to the file
+    /// through other fds, or by the host.
+    pub fn read_ahead(mut self, bytes: usize) -> Self {
+        self.read_ahead = bytes;
+        self
+    }
+
+    /// Write a `CoreDump` into a new file in `dir` whenever the module
+    /// `instantiate_module` instantiates traps, for post-mortem analysis.
+    ///
+    /// Core dumps hold the guest's memory, secrets included, so they're only
+    /// readable by their owner. With Landlock, `dir` is the one place
+    /// outside the preopens the runtime may still create files in.
+    pub fn core_dumps(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.core_dumps = Some(dir.into());
+        self
+    }
+
+    /// Whether to profile the guest: how much time each syscall takes, by
+    /// the export it's made from, and how much the exports spend in the
+    /// guest in between. `WasiModule::profile` reports it, also as folded
+    /// stacks for flamegraph tools.
+    pub fn profile(mut self, profile: bool) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Write the guest's syscalls to the file `path` as they're made, in a
+    /// canonical form meant for diffing against the trace of an earlier
+    /// run, e.g. with the goldens under `tests/golden`.
+    ///
+    /// This exposes the guest to the host, so it's refused for any backend
+    /// but `Backend::Nil`.
+    pub fn trace(mut self, path: impl Into<PathBuf>) -> Self {
+        self.trace = Some(path.into());
+        self
+    }
+
+    /// Stop the guest at each of its syscalls and let `debugger` decide
+    /// whether to make it, e.g. a `Console` an operator steps through them
+    /// with.
+    ///
+    /// This exposes the guest to the debugger, so it's refused for any
+    /// backend but `Backend::Nil`.
+    pub fn debugger(mut self, debugger: impl Debugger + 'static) -> Self {
+        self.debugger = Some(Box::new(debugger));
+        self
+    }
+
+    /// Serve `random_get` from an SP 800-90A HMAC_DRBG, seeded from the
+    /// hardware entropy source and reseeded every `reseed_interval`
+    /// requests, for deployments with compliance requirements on the
+    /// guest's randomness. At most 2^48 requests are allowed between
+    /// reseeds.
+    ///
+    /// Instantiation fails if the DRBG's known answer test does, and once
+    /// the entropy source fails a health test, `random_get` only fails with
+    /// `__WASI_EIO`.
+    pub fn drbg(mut self, reseed_interval: u64) -> Self {
+        self.drbg_reseed_interval = Some(reseed_interval);
+        self
+    }
+
+    /// Tell the guest it may use at most `max` CPUs, e.g. to size its
+    /// worker pool for its share of the host. `enarx_cpu_count` reports the
+    /// least of this, the CPUs the instantiating thread may run on, and
+    /// what the cgroup's CPU quota amounts to.
+    pub fn max_cpus(mut self, max: u32) -> Self {
+        self.max_cpus = Some(max);
+        self
+    }
+
+    /// Let the configuration be changed through `control` while the guest
+    /// runs.
+    pub fn control(mut self, control: Control) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Confine the threads servicing the guest to `cgroup`.
+    #[cfg(target_os = "linux")]
+    pub fn cgroup(mut self, cgroup: Cgroup) -> Self {
+        self.cgroup = Some(cgroup);
+        self
+    }
+
+    /// Place the guest on NUMA node `node`: allocate its linear memory
+    /// there, and run the threads servicing it on the node's CPUs.
+    ///
+    /// Like joining a cgroup, this pins the instantiating thread, and the
+    /// threads it spawns from then on.
+    #[cfg(target_os = "linux")]
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.numa_node = Some(node);
+        self
+    }
+
+    /// Once `instantiate_module` has instantiated the module, and before
+    /// any guest code runs, give up the authority `drop` describes.
+    ///
+    /// That happens on the thread's first instantiation only; later ones
+    /// fail if `drop` asks for more than was given up then.
+    #[cfg(target_os = "linux")]
+    pub fn privilege_drop(mut self, drop: PrivilegeDrop) -> Self {
+        self.privilege_drop = Some(drop);
+        self
+    }
+
+    /// Once `instantiate_module` has instantiated the module, deny the
+    /// instantiating thread any access to the host filesystem outside the
+    /// preopens, as their rights allow, with Landlock. Even a bug in path
+    /// resolution then can't reach other host paths.
+    ///
+    /// Like `seccomp`, this stays on the thread, and the threads it spawns,
+    /// and only the thread's first instantiation confines it. Kernels
+    /// without Landlock leave the filesystem as it is, with a warning.
+    #[cfg(target_os = "linux")]
+    pub fn landlock(mut self, enable: bool) -> Self {
+        self.landlock = enable;
+        self
+    }
+
+    /// Once `instantiate_module` has instantiated the module, restrict the
+    /// instantiating thread to the host syscalls the runtime needs from
+    /// then on: any other fails with `ENOSYS`, and those to run or trace
+    /// other programs kill the process. A bug in the runtime then can't be
+    /// turned into arbitrary host syscalls.
+    ///
+    /// The filter can't be lifted: it stays on the thread, and the threads
+    /// it spawns, after the module is gone.
+    ///
+    /// It replaces the `SIGURG` handler, like `hostcall_timeout`, so a
+    /// timeout can still be set later through `Control`.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn seccomp(mut self, enable: bool) -> Self {
+        self.seccomp = enable;
+        self
+    }
+
+    /// Fail any syscall still running after `timeout` with
+    /// `__WASI_ETIMEDOUT`, e.g. a `fd_read` blocked on a dead socket,
+    /// instead of letting it hang the guest forever.
+    ///
+    /// NB: the syscall is interrupted with `SIGURG`, whose handler is
+    /// replaced process-wide, and for good, by a no-op one: one the
+    /// embedder installed is no longer called.
+    pub fn hostcall_timeout(mut self, timeout: Duration) -> Self {
+        self.hostcall_timeout = Some(timeout);
+        self
+    }
+
+    /// The kind of keep the guest runs in, as reported by `enarx_info`.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Whether `enarx_info` tells the guest its keep can be attested.
+    pub fn attestation(mut self, attestation: bool) -> Self {
+        self.attestation = attestation;
+        self
+    }
+
+    /// Grant the guest `capability`, which it may drop later on.
+    pub fn grant(mut self, capability: Capability) -> Self {
+        self.capabilities.grant(capability);
+        self
+    }
+
+    /// Which WASI ABI `instantiate` exports the syscalls with.
+    pub fn abi(mut self, abi: WasiAbi) -> Self {
+        self.abi = abi;
+        self
+    }
+
+    /// Whether `instantiate_for` should only export the functions the
+    /// module imports. This shrinks the attack surface, and speeds up
+    /// instantiation of minimal modules.
+    pub fn only_imported(mut self, only_imported: bool) -> Self {
+        self.only_imported = only_imported;
+        self
+    }
+
+    /// Export nothing but the clocks, `random_get`, `fd_read`, `fd_write`
+    /// and `proc_exit`, and no Enarx hostcalls, for keeps doing pure
+    /// computations. Everything else is missing from the export table
+    /// altogether, so a module importing it fails to instantiate.
+    ///
+    /// There are no preopens in minimal mode, and `fd_read` and `fd_write`
+    /// fail with `__WASI_EBADF` on any fd but stdio's, even one a
+    /// ready-made `WasiCtx` has. Host functions are still exported.
+    pub fn minimal(mut self, minimal: bool) -> Self {
+        self.minimal = minimal;
+        self
+    }
+
+    /// How much `instantiate_module` optimizes the module's code, trading
+    /// compile time for run time.
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.compile.opt_level = opt_level;
+        self
+    }
+
+    /// Whether `instantiate_module` makes the module's floating point
+    /// arithmetic deterministic by canonicalizing every NaN it produces.
+    pub fn canonicalize_nans(mut self, canonicalize: bool) -> Self {
+        self.compile.canonicalize_nans = canonicalize;
+        self
+    }
+
+    /// The wasm proposals `instantiate_module` accepts modules using.
+    pub fn wasm_features(mut self, features: Features) -> Self {
+        self.compile.features = features;
+        self
+    }
+
+    /// Generate code for `target`, with the baseline feature set of its
+    /// architecture, instead of for whatever the host CPU supports.
+    ///
+    /// `target` must share the host's architecture, since the code is run
+    /// in this process: compiling ahead of time for another machine, e.g.
+    /// on an x86_64 box for an aarch64 keep, isn't supported. The pinned
+    /// wasmtime-jit can't serialize what it compiles, and the pinned
+    /// Cranelift has no working aarch64 backend.
+    pub fn target(mut self, target: Triple) -> Self {
+        self.compile.target = Some(target);
+        self
+    }
+
+    /// Set the Cranelift ISA flag `name`, e.g. `has_avx2`, for the code
+    /// `instantiate_module` generates.
+    pub fn isa_flag(mut self, name: &str, value: &str) -> Self {
+        self.compile
+            .isa_flags
+            .push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Whether `instantiate_module` emits DWARF for the module's code, from
+    /// the module's own debug info, and registers it through the GDB JIT
+    /// interface, so gdb or lldb attached to the process can break and
+    /// step through the guest's source. Best paired with `OptLevel::None`.
+    ///
+    /// This exposes the guest to whoever can attach a debugger, so it's
+    /// refused for any backend but `Backend::Nil`.
+    pub fn debug_info(mut self, debug_info: bool) -> Self {
+        self.compile.debug_info = debug_info;
+        self
+    }
+
+    /// Create the instance.
+    pub fn instantiate(
+        mut self,
+        global_exports: GlobalExports,
+    ) -> Result<InstanceHandle, InstantiationError> {
+        let state = Rc::new(RefCell::new(self.state()?));
+        let only = self.minimal_exports();
+        instantiate(
+            &self.prefix,
+            global_exports,
+            Interface::Wasi(self.abi),
+            state,
+            &self.host_functions,
+            only.as_ref(),
+        )
+    }
+
+    /// Create one instance per WASI ABI, and one exporting the Enarx
+    /// hostcalls, all sharing the same state, and return each along with
+    /// the namespace it should be registered under.
+    ///
+    /// This lets modules built by older and newer toolchains link alike,
+    /// and even modules importing from both namespaces at once.
+    pub fn instantiate_namespaces(
+        self,
+        global_exports: GlobalExports,
+    ) -> Result<Vec<(&'static str, InstanceHandle)>, InstantiationError> {
+        let interfaces = WasiAbi::ALL
+            .iter()
+            .map(|&abi| Interface::Wasi(abi))
+            .chain(Some(Interface::Enarx))
+            .map(|interface| (interface, None))
+            .collect();
+        self.instantiate_interfaces(global_exports, interfaces)
+    }
+
+    /// Like `instantiate_namespaces`, but only for the namespaces the
+    /// module `wasm` actually imports from, so callers don't need to know
+    /// which toolchain it was built with.
+    ///
+    /// With `only_imported`, the instances also export nothing but the
+    /// functions the module imports.
+    pub fn instantiate_for(
+        self,
+        wasm: &[u8],
+        global_exports: GlobalExports,
+    ) -> Result<Vec<(&'static str, InstanceHandle)>, InstantiationError> {
+        self.instantiate_for_all(&[wasm], global_exports)
+    }
+
+    /// Like `instantiate_for`, for the imports of all of `modules`.
+    fn instantiate_for_all(
+        self,
+        modules: &[&[u8]],
+        global_exports: GlobalExports,
+    ) -> Result<Vec<(&'static str, InstanceHandle)>, InstantiationError> {
+        let minimal = self.minimal_exports();
+        let mut imported: Vec<(Interface, HashSet<String>)> = Vec::new();
+        for wasm in modules {
+            let imports = function_imports(wasm)
+                .map_err(|err| InstantiationError::Resource(err.to_string()))?;
+            for import in imports {
+                let interface = match Interface::from_namespace(&import.module) {
+                    Some(interface) => interface,
+                    None => continue,
+                };
+                if let Some(ref minimal) = minimal {
+                    if interface == Interface::Enarx || !minimal.contains(&import.field) {
+                        return Err(InstantiationError::Resource(format!(
+                            "the module imports {}.{}, which isn't exported in minimal mode",
+                            import.module, import.field
+                        )));
+                    }
+                }
+                match imported.iter_mut().find(|(i, _)| *i == interface) {
+                    Some((_, names)) => {
+                        names.insert(import.field);
+                    }
+                    None => imported.push((interface, Some(import.field).into_iter().collect())),
+                }
+            }
+        }
+        let only_imported = self.only_imported;
+        let interfaces = imported
+            .into_iter()
+            .map(|(interface, names)| (interface, if only_imported { Some(names) } else { None }))
+            .collect();
+        self.instantiate_interfaces(global_exports, interfaces)
+    }
+
+    /// Compile the module `wasm` and instantiate it, linked against
+    /// instances for the WASI namespaces it imports from.
+    ///
+    /// The module is taken as bytes, e.g. as received over an attested
+    /// channel, so it never needs to be read from the host filesystem.
+    ///
+    /// The module and its WASI instances get exports of their own, which
+    /// no other module can resolve.
+    pub fn instantiate_module(mut self, wasm: &[u8]) -> Result<WasiModule, RunError> {
+        let linked = std::mem::replace(&mut self.linked, Vec::new());
+        let names: Vec<&str> = linked.iter().map(|module| module.name.as_str()).collect();
+        self.check_imports(wasm, &names)?;
+        for module in &linked {
+            module
+                .wasi
+                .as_ref()
+                .unwrap_or(&self)
+                .check_imports(&module.wasm, &names)?;
+        }
+
+        let limits = self.limits;
+        #[cfg(target_os = "linux")]
+        let privilege_drop = self.privilege_drop.take();
+        #[cfg(target_os = "linux")]
+        let landlock = self.landlock;
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        let seccomp = self.seccomp;
+        let wasm = limits.apply(wasm).map_err(RunError::Limits)?;
+        if self.compile.debug_info && self.backend != Backend::Nil {
+            let msg = format!(
+                "debug info can't be registered in a {:?} keep",
+                self.backend
+            );
+            return Err(RunError::Instantiation(InstantiationError::Resource(msg)));
+        }
+        // The checks would throw the DWARF's code offsets off, so a module
+        // compiled with debug info isn't instrumented, and `proc_exit`
+        // returns to it.
+        let instrumented = !self.compile.debug_info;
+        let wasm = if instrumented {
+            instrument(&wasm).map_err(RunError::Jit)?.into()
+        } else {
+            wasm
+        };
+        let interrupt = Interrupt::default();
+        let mut context = self.compile.context()?;
+
+        // WASI instances of the main module, and of the linked modules which
+        // share its WASI context.
+        let shared: Vec<&[u8]> = linked
+            .iter()
+            .filter(|module| module.wasi.is_none())
+            .map(|module| module.wasm.as_slice())
+            .chain(Some(&*wasm))
+            .collect();
+        let state = self.instantiate_wasi(&shared, &mut context, "")?;
+        let mut states: Vec<SharedState> = state.iter().cloned().collect();
+
+        for Linked { name, wasm, wasi } in linked {
+            let mut wasm = limits.apply(&wasm).map_err(RunError::Limits)?.into_owned();
+            if instrumented {
+                wasm =
+                    instrument(&wasm).map_err(|err| RunError::Jit(format!("{}: {}", name, err)))?;
+            }
+            let linked_state = match wasi {
+                Some(wasi) => {
+                    // Give the module WASI instances of its own, under
+                    // namespaces only it imports from.
+                    let suffix = format!("@{}", name);
+                    let state = wasi.instantiate_wasi(&[wasm.as_slice()], &mut context, &suffix)?;
+                    wasm = rename_import_modules(&wasm, |module| {
+                        Interface::from_namespace(module).map(|_| format!("{}{}", module, suffix))
+                    })
+                    .map_err(RunError::Jit)?;
+                    state
+                }
+                None => None,
+            };
+            let mut instance = context
+                .instantiate_module(None, &wasm)
+                .map_err(|err| RunError::Jit(format!("{}: {}", name, err)))?;
+            if let Some(state) = linked_state {
+                bind_memory(&state, &mut instance)?;
+                states.push(state);
+            }
+            register_interrupt(&interrupt, &mut instance);
+            context.name_instance(name, instance);
+        }
+
+        let mut instance = context
+            .instantiate_module(None, &wasm)
+            .map_err(|err| RunError::Jit(err.to_string()))?;
+        if let Some(ref state) = state {
+            bind_memory(state, &mut instance)?;
+        }
+        register_interrupt(&interrupt, &mut instance);
+        for state in &states {
+            state.borrow_mut().interrupt = interrupt.clone();
+        }
+        if let Some(ref control) = self.control {
+            control.attach(&interrupt);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(drop) = privilege_drop {
+                drop.apply().map_err(|err| {
+                    RunError::Instantiation(InstantiationError::Resource(format!(
+                        "couldn't drop privileges: {}",
+                        err
+                    )))
+                })?;
+            }
+            confine_to_preopens(&states, landlock, self.core_dumps.as_ref())?;
+        }
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        {
+            if seccomp {
+                // Spawn the watchdog's thread now, rather than under the
+                // filter for a hostcall timeout set later by `Control`.
+                super::watchdog::start();
+                super::seccomp::install().map_err(|err| {
+                    RunError::Instantiation(InstantiationError::Resource(format!(
+                        "couldn't install seccomp filter: {}",
+                        err
+                    )))
+                })?;
+            }
+        }
+        let mut module = WasiModule::new(context, instance, state, interrupt);
+        module.set_core_dumps(self.core_dumps);
+        Ok(module)
+    }
+
+    /// Instantiate the module `wasm` before the one passed to
+    /// `instantiate_module`, registered under `name` so the modules
+    /// instantiated after it can import its exports from that module name.
+    ///
+    /// Linked modules share the WASI context of the main module, and
+    /// should import its memory rather than define one of their own if
+    /// they pass pointers to the syscalls.
+    pub fn link_module(mut self, name: &str, wasm: &[u8]) -> Self {
+        self.linked.push(Linked {
+            name: name.to_owned(),
+            wasm: wasm.to_vec(),
+            wasi: None,
+        });
+        self
+    }
+
+    /// Like `link_module`, but the module's WASI imports resolve to
+    /// instances of its own, configured by `wasi`: its own context,
+    /// filesystem policy and host functions. A trusted helper module can so
+    /// be given more capabilities than the main module.
+    ///
+    /// Only the configuration of the WASI instances is taken from `wasi`,
+    /// how modules are compiled and limited is up to the main builder.
+    pub fn link_module_with(mut self, name: &str, wasm: &[u8], wasi: WasiInstanceBuilder) -> Self {
+        self.linked.push(Linked {
+            name: name.to_owned(),
+            wasm: wasm.to_vec(),
+            wasi: Some(wasi),
+        });
+        self
+    }
+
+    /// Checks the imports of `wasm` against the WASI instances this
+    /// builder creates, letting through those from the `linked` modules,
+    /// whose exports are only known once they're instantiated.
+    fn check_imports(&self, wasm: &[u8], linked: &[&str]) -> Result<(), RunError> {
+        let host_functions: Vec<_> = self.host_functions.iter().map(HostFunction::name).collect();
+        match check_imports(wasm, &self.prefix, &host_functions) {
+            Err(ImportError::Mismatch(mismatches)) => {
+                let mismatches: Vec<_> = mismatches
+                    .into_iter()
+                    .filter(|m| !linked.contains(&m.import.module.as_str()))
+                    .collect();
+                if mismatches.is_empty() {
+                    Ok(())
+                } else {
+                    Err(ImportError::Mismatch(mismatches).into())
+                }
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Creates the WASI instances `modules` import from, registering them
+    /// in `context` under their namespace followed by `suffix`. Returns
+    /// their state, if any were created.
+    fn instantiate_wasi(
+        self,
+        modules: &[&[u8]],
+        context: &mut JitContext,
+        suffix: &str,
+    ) -> Result<Option<SharedState>, RunError> {
+        let mut state = None;
+        for (namespace, mut instance) in
+            self.instantiate_for_all(modules, context.get_global_exports())?
+        {
+            state = shared_state_of(instance.host_state());
+            context.name_instance(format!("{}{}", namespace, suffix), instance);
+        }
+        Ok(state)
+    }
+
+    /// Instantiate the reactor module `wasm` like `instantiate_module`, and
+    /// initialize it so its exports are ready to be invoked.
+    pub fn instantiate_reactor(self, wasm: &[u8]) -> Result<WasiModule, RunError> {
+        let mut module = self.instantiate_module(wasm)?;
+        module.initialize()?;
+        Ok(module)
+    }
+
+    /// Instantiate the module `wasm` like `instantiate_module`, and run its
+    /// `_start` function.
+    pub fn run(self, wasm: &[u8]) -> Result<(), RunError> {
+        self.instantiate_module(wasm)?.run()
+    }
+
+    fn instantiate_interfaces(
+        mut self,
+        global_exports: GlobalExports,
+        interfaces: Vec<(Interface, Option<HashSet<String>>)>,
+    ) -> Result<Vec<(&'static str, InstanceHandle)>, InstantiationError> {
+        let state = Rc::new(RefCell::new(self.state()?));
+        let minimal = self.minimal_exports();
+        interfaces
+            .into_iter()
+            .filter(|&(interface, _)| minimal.is_none() || interface != Interface::Enarx)
+            .map(|(interface, only)| {
+                let only = match (only, &minimal) {
+                    (Some(only), Some(minimal)) => {
+                        Some(only.intersection(minimal).cloned().collect())
+                    }
+                    (None, Some(minimal)) => Some(minimal.clone()),
+                    (only, None) => only,
+                };
+                let instance = instantiate(
+                    &self.prefix,
+                    global_exports.clone(),
+                    interface,
+                    state.clone(),
+                    &self.host_functions,
+                    only.as_ref(),
+                )?;
+                Ok((interface.namespace(), instance))
+            })
+            .collect()
+    }
+
+    /// In minimal mode, the (prefixed) names of the functions WASI instances
+    /// export.
+    fn minimal_exports(&self) -> Option<HashSet<String>> {
+        if !self.minimal {
+            return None;
+        }
+        let syscalls = MINIMAL_SYSCALLS.iter().map(|&name| name.to_owned());
+        let host_functions = self.host_functions.iter().map(|f| f.name().to_owned());
+        Some(
+            syscalls
+                .chain(host_functions)
+                .map(|name| format!("{}{}", self.prefix, name))
+                .collect(),
+        )
+    }
+
+    /// Assemble just the WASI state, e.g. to reset a pooled module with.
+    ///
+    /// Unlike instantiating, this leaves the thread's cgroup and NUMA node
+    /// alone, and sets up no tracer, watchdog or DRBG: the module keeps
+    /// those from when it was instantiated.
+    pub(crate) fn into_state(mut self) -> Result<WasiState, InstantiationError> {
+        self.wasi_state()
+    }
+
+    /// The WASI state of a new instance, with the host set up for it.
+    fn state(&mut self) -> Result<WasiState, InstantiationError> {
+        self.setup_thread()?;
+        let mut state = self.wasi_state()?;
+        self.setup_host(&mut state)?;
+        Ok(state)
+    }
+
+    /// Joins the cgroup, and binds to the NUMA node, the instance is to
+    /// run in, once per instance.
+    fn setup_thread(&self) -> Result<(), InstantiationError> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref cgroup) = self.cgroup {
+                cgroup.join().map_err(|err| {
+                    InstantiationError::Resource(format!("couldn't join cgroup: {}", err))
+                })?;
+            }
+            if let Some(node) = self.numa_node {
+                bind_thread(node).map_err(|err| {
+                    InstantiationError::Resource(format!(
+                        "couldn't bind to NUMA node {}: {}",
+                        node, err
+                    ))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of CPUs the guest is told it has.
+    fn cpus(&self) -> u32 {
+        // the affinity reflects the cpuset and NUMA node joined, if any
+        let mut cpus = available_cpus();
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(limit) = self.cgroup.as_ref().and_then(Cgroup::cpu_limit) {
+                cpus = cpus.min(limit);
+            }
+        }
+        if let Some(max) = self.max_cpus {
+            cpus = cpus.min(max);
+        }
+        cpus
+    }
+
+    /// Sets up the tracer, watchdog and DRBG of `state`, once per instance:
+    /// the tracer's file is truncated, and the DRBG seeded, on creation.
+    fn setup_host(&mut self, state: &mut WasiState) -> Result<(), InstantiationError> {
+        state.watchdog = self.hostcall_timeout.map(Watchdog::new);
+        if let Some(ref path) = self.trace {
+            if self.backend != Backend::Nil {
+                return Err(InstantiationError::Resource(format!(
+                    "syscalls can't be traced in a {:?} keep",
+                    self.backend
+                )));
+            }
+            let tracer = Tracer::create(path).map_err(|err| {
+                InstantiationError::Resource(format!(
+                    "couldn't create the trace {}: {}",
+                    path.display(),
+                    err
+                ))
+            })?;
+            state.tracer = Some(tracer);
+        }
+        if let Some(reseed_interval) = self.drbg_reseed_interval {
+            let drbg = Drbg::new(reseed_interval).map_err(InstantiationError::Resource)?;
+            state.drbg = Some(drbg);
+        }
+        Ok(())
+    }
+
+    /// The WASI state, without touching the host beyond opening the
+    /// preopens and stdio.
+    fn wasi_state(&mut self) -> Result<WasiState, InstantiationError> {
+        if self.minimal
+            && (!self.preopens.is_empty()
+                || self.timezone.is_some()
+                || !self.secret_files.is_empty())
+        {
+            return Err(InstantiationError::Resource(
+                "there are no preopens in minimal mode".to_owned(),
+            ));
+        }
+        if let Some(name) = self
+            .secret_files
+            .keys()
+            .find(|name| name.is_empty() || name.contains('/') || *name == "." || *name == "..")
+        {
+            return Err(InstantiationError::Resource(format!(
+                "{:?} can't name a secret file",
+                name
+            )));
+        }
+        if !self.host_filesystem && (!self.preopens.is_empty() || self.timezone.is_some()) {
+            return Err(InstantiationError::Resource(
+                "preopens can't be used without host_filesystem".to_owned(),
+            ));
+        }
+        let cpus = self.cpus();
+
+        let mut features = 0;
+        if self.hostcall_timeout.is_some() {
+            features |= FEATURE_HOSTCALL_TIMEOUT;
+        }
+        if self.max_fds.is_some() {
+            features |= FEATURE_FD_LIMIT;
+        }
+        if self.timezone.is_some()
+            || self
+                .preopens
+                .iter()
+                .any(|preopen| preopen.rights.is_readonly())
+        {
+            features |= FEATURE_READONLY_PREOPENS;
+        }
+
+        // A ready-made `WasiCtx`'s stdio could be anything.
+        let inherited = self.context.is_none() && self.inherit_stdio;
+        let mut state = match self.context.take() {
+            Some(ctx) => {
+                let mut state = WasiState::new(ctx, &self.memory);
+                // Any preopens of a ready-made `WasiCtx` are unknown to us.
+                state.fs.set_fd_count(FIRST_PREOPEN_FD);
+                state
+            }
+            None => self.wasi_ctx_state()?,
+        };
+        state.fs.limit_fds(self.max_fds);
+        state.fs.limit_file_size(self.max_file_size);
+        state.fs.allow_host_fs(self.host_filesystem);
+        state.fs.allow_only_stdio(self.minimal);
+        state.path_limits = self.path_limits;
+        if self.protect_fds {
+            state.fs.protect_initial_fds();
+        }
+        if self.virtualize_file_ids {
+            state.file_ids = Some(FileIds::default());
+        }
+        state.read_ahead = ReadAhead::new(self.read_ahead);
+        if let (true, Some(filter)) = (inherited, self.terminal_filter) {
+            for &fd in &[1, 2] {
+                if unsafe { libc::isatty(fd as i32) } == 1 {
+                    state.stdio.set_terminal(fd, Terminal::new(filter));
+                }
+            }
+        }
+        for (fd, writer) in self.captures.drain(..) {
+            state.stdio.capture(fd, writer);
+        }
+        if self.profile {
+            state.profiler = Some(Profiler::default());
+        }
+        if self.debugger.is_some() && self.backend != Backend::Nil {
+            return Err(InstantiationError::Resource(format!(
+                "a debugger can't be attached in a {:?} keep",
+                self.backend
+            )));
+        }
+        state.debugger = self.debugger.take();
+        state.info.backend = self.backend;
+        state.info.attestation = self.attestation;
+        state.info.features = features;
+        state.info.cpus = cpus.max(1);
+        state.control = self.control.clone();
+        state.backing.huge_pages = self.huge_pages;
+        state.backing.lock = self.lock_memory;
+        #[cfg(target_os = "linux")]
+        {
+            state.backing.numa_node = self.numa_node;
+        }
+        state.capabilities = self.capabilities;
+        state.secrets = std::mem::replace(&mut self.secrets, HashMap::new());
+        Ok(state)
+    }
+
+    /// State with a `WasiCtx` assembled from the configuration.
+    fn wasi_ctx_state(&mut self) -> Result<WasiState, InstantiationError> {
+        let mut env = match self.env_policy {
+            Some(ref policy) => policy
+                .apply(std::env::vars(), &self.env)
+                .map_err(InstantiationError::Resource)?,
+            None => self.env.clone(),
+        };
+        for (key, secret) in &self.secret_env {
+            if std::str::from_utf8(secret.bytes()).is_err() {
+                return Err(InstantiationError::Resource(format!(
+                    "secret environment variable {} isn't UTF-8",
+                    key
+                )));
+            }
+            env.retain(|(k, _)| k != key);
+        }
+        let timezone = self.timezone.is_some();
+        if let Some((tz, zoneinfo)) = self.timezone.take() {
+            verify_zone(&zoneinfo, &tz).map_err(InstantiationError::Resource)?;
+            let mut preopen = Preopen::new(ZONEINFO_GUEST_PATH, zoneinfo);
+            preopen.rights = PreopenRights::read_only();
+            self.preopens.push(preopen);
+            env.retain(|(key, _)| key != "TZ");
+            env.push(("TZ".to_owned(), tz));
+        }
+        let mut args = self
+            .args
+            .iter()
+            .map(|arg| match arg {
+                Arg::Literal(arg) => Ok(arg.clone()),
+                Arg::Template(template) => expand(template, &self.vars),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(InstantiationError::Resource)?;
+        if let Some(ref argv0) = self.argv0 {
+            match args.first_mut() {
+                Some(first) => *first = argv0.clone(),
+                None => args.push(argv0.clone()),
+            }
+        }
+        // The secrets are only copied out of their `Secret`s here, as the
+        // `WasiCtxBuilder` takes them, and the copies are zeroed right after.
+        let mut secret_env: Vec<(String, String)> = self
+            .secret_env
+            .iter()
+            .filter(|(key, _)| !(timezone && key == "TZ"))
+            .map(|(key, secret)| {
+                // checked to be UTF-8 above
+                let value = String::from_utf8_lossy(secret.bytes()).into_owned();
+                (key.clone(), value)
+            })
+            .collect();
+        let inherit_stdio = self.inherit_stdio;
+        let wasi_ctx_builder = WasiCtxBuilder::new()
+            .and_then(|ctx| {
+                if inherit_stdio {
+                    ctx.inherit_stdio()
+                } else {
+                    Ok(ctx)
+                }
+            })
+            .and_then(|ctx| ctx.args(args.iter()))
+            .and_then(|ctx| ctx.envs(env.iter().chain(secret_env.iter())));
+        for (_, value) in &mut secret_env {
+            zeroize_string(value);
+        }
+        let mut wasi_ctx_builder = wasi_ctx_builder.map_err(|err| {
+            InstantiationError::Resource(format!("couldn't assemble WASI context object: {}", err))
+        })?;
+
+        // The secret files' preopen comes after all the others.
+        let secrets_fd = FIRST_PREOPEN_FD + self.preopens.len() as u32;
+        let has_secret_files = !self.secret_files.is_empty();
+        let mut fs = FsPolicy::default();
+        fs.set_fd_count(secrets_fd + has_secret_files as u32);
+        let mut restricted = Vec::new();
+        let mut write_back_sizes = HashMap::new();
+        for (guest_path, bytes) in &self.write_back {
+            let guest_path =
+                normalize_guest_path(guest_path).map_err(InstantiationError::Resource)?;
+            write_back_sizes.insert(guest_path, *bytes);
+        }
+        let mut write_back = WriteBack::default();
+        let mut host_fds = HostFds::default();
+        #[cfg(target_os = "linux")]
+        let mut preopen_dirs = Vec::new();
+        for (fd, preopen) in (FIRST_PREOPEN_FD..).zip(self.preopens.drain(..)) {
+            let Preopen {
+                guest_path,
+                dir,
+                rights,
+            } = preopen;
+            let guest_path =
+                normalize_guest_path(&guest_path).map_err(InstantiationError::Resource)?;
+            let f = dir.open_dir().map_err(|err| {
+                InstantiationError::Resource(format!(
+                    "couldn't open pre-opened dir {}: {}",
+                    guest_path, err
+                ))
+            })?;
+            let dup = |f: &File| {
+                f.try_clone().map_err(|err| {
+                    InstantiationError::Resource(format!(
+                        "couldn't duplicate pre-opened dir {}: {}",
+                        guest_path, err
+                    ))
+                })
+            };
+            host_fds.preopened(fd, dup(&f)?);
+            #[cfg(target_os = "linux")]
+            preopen_dirs.push((dup(&f)?, rights));
+            if let Some(bytes) = write_back_sizes.remove(&guest_path) {
+                write_back.enable(fd, bytes);
+            }
+            wasi_ctx_builder = wasi_ctx_builder.preopened_dir(f, guest_path);
+            if rights != PreopenRights::full() {
+                restricted.push((fd, rights));
+            }
+        }
+        if let Some(guest_path) = write_back_sizes.keys().next() {
+            return Err(InstantiationError::Resource(format!(
+                "can't buffer writes beneath {}: there's no such preopen",
+                guest_path
+            )));
+        }
+        if has_secret_files {
+            // Any directory holds the fd, as the `WasiCtx` is left no rights
+            // on it, and `SecretFiles` does everything with it instead.
+            let dir = File::open("/").map_err(|err| {
+                InstantiationError::Resource(format!(
+                    "couldn't open a directory for {}: {}",
+                    SECRETS_GUEST_PATH, err
+                ))
+            })?;
+            wasi_ctx_builder = wasi_ctx_builder.preopened_dir(dir, SECRETS_GUEST_PATH);
+            restricted.push((secrets_fd, PreopenRights::new(0, 0)));
+        }
+
+        let mut wasi_ctx = wasi_ctx_builder.build().map_err(|err| {
+            InstantiationError::Resource(format!("couldn't assemble WASI context object: {}", err))
+        })?;
+        for (fd, rights) in restricted {
+            fs.restrict(&mut wasi_ctx, fd, rights)
+                .map_err(InstantiationError::Resource)?;
+        }
+
+        let mut state = WasiState::new(Context::new(wasi_ctx), &self.memory);
+        state.fs = fs;
+        state.write_back = write_back;
+        state.host_fds = host_fds;
+        #[cfg(target_os = "linux")]
+        {
+            state.preopen_dirs = preopen_dirs;
+        }
+        if has_secret_files {
+            let files = std::mem::replace(&mut self.secret_files, HashMap::new());
+            state.secret_files = SecretFiles::new(secrets_fd, files);
+        }
+        Ok(state)
+    }
+}
+
+/// An argument for the guest.
+enum Arg {
+    Literal(String),
+    /// An argument to expand the builder's variables in, see
+    /// `arg_template`.
+    Template(String),
+}
+
+/// Substitutes the `vars` for the `{name}`s in `template`.
+fn expand(template: &str, vars: &HashMap<String, String>) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| format!("unterminated variable in argument {:?}", template))?;
+                let name = &rest[..end];
+                let value = vars.get(name).ok_or_else(|| {
+                    format!("undefined variable {:?} in argument {:?}", name, template)
+                })?;
+                out.push_str(value);
+                chars = rest[end + 1..].chars();
+            }
+            '}' => return Err(format!("unmatched '}}' in argument {:?}", template)),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+/// A module to instantiate before the main one, see `link_module`.
+struct Linked {
+    name: String,
+    wasm: Vec<u8>,
+    /// Configuration of the module's own WASI instances, if it doesn't
+    /// share the main module's.
+    wasi: Option<WasiInstanceBuilder>,
+}
+
+/// Makes the syscalls of the instances sharing `state` operate on the
+/// memory of `instance`, backed as configured.
+///
+/// Every linked module may define a memory of the same name, which makes
+/// it ambiguous in the exports the syscalls would otherwise resolve it
+/// from.
+fn bind_memory(state: &SharedState, instance: &mut InstanceHandle) -> Result<(), RunError> {
+    let name = state.borrow().memory.clone();
+    if let Some(Export::Memory {
+        definition, memory, ..
+    }) = instance.lookup(&name)
+    {
+        let mut state = state.borrow_mut();
+        state.memory_definition = Some(definition);
+        let (base, len) = unsafe { ((*definition).base, (*definition).current_length) };
+        unsafe { state.backing.reserve(base, len, reservation(&memory)) }.map_err(|err| {
+            RunError::Instantiation(InstantiationError::Resource(format!(
+                "couldn't back memory {} as configured: {}",
+                name, err
+            )))
+        })?;
+    }
+    Ok(())
+}
+
+/// Makes `instance` trap when `interrupt` is raised, if it's instrumented.
+fn register_interrupt(interrupt: &Interrupt, instance: &mut InstanceHandle) {
+    if let Some(Export::Global { definition, .. }) = instance.lookup(INTERRUPT_EXPORT) {
+        interrupt.register(definition);
+    }
+}
+
+/// Confines the calling thread to the host directories preopened for the
+/// WASI instances with `states`, and the one `core_dumps` go to, if
+/// `landlock` is set, and lets go of them.
+#[cfg(target_os = "linux")]
+fn confine_to_preopens(
+    states: &[SharedState],
+    landlock: bool,
+    core_dumps: Option<&PathBuf>,
+) -> Result<(), RunError> {
+    let mut dirs = Vec::new();
+    for state in states {
+        dirs.append(&mut state.borrow_mut().preopen_dirs);
+    }
+    if !landlock {
+        return Ok(());
+    }
+    if let Some(dir) = core_dumps {
+        let file = std::fs::File::open(dir).map_err(|err| {
+            RunError::Instantiation(InstantiationError::Resource(format!(
+                "couldn't open core dump directory {}: {}",
+                dir.display(),
+                err
+            )))
+        })?;
+        dirs.push((file, PreopenRights::create_only()));
+    }
+    let confined = super::landlock::restrict(&dirs).map_err(|err| {
+        RunError::Instantiation(InstantiationError::Resource(format!(
+            "couldn't confine the host filesystem to the preopens: {}",
+            err
+        )))
+    })?;
+    if !confined {
+        log::warn!("no Landlock in this kernel, the host filesystem is left unconfined");
+    }
+    Ok(())
+}
+
+impl Default for WasiInstanceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instantiate::MAX_HOST_CLOSURES;
+    use std::thread;
+    use wasmtime_runtime::VMContext;
+
+    /// The smallest module there is, importing and exporting nothing.
+    const EMPTY_MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    /// (func (export "_start") (call $proc_exit (i32.const 3)) (loop (br 0)))
+    const EXIT_THEN_SPIN: &[u8] = b"\0asm\x01\0\0\0\x01\x08\x02\x60\x01\x7f\0\x60\0\0\
+        \x02\x1b\x01\x0dwasi_unstable\x09proc_exit\0\0\x03\x02\x01\x01\x05\x03\x01\0\x01\
+        \x07\x13\x02\x06memory\x02\0\x06_start\0\x01\
+        \x0a\x0d\x01\x0b\0\x41\x03\x10\0\x03\x40\x0c\0\x0b\x0b";
+
+    /// (func (export "_start") (loop (br 0)))
+    const SPIN: &[u8] = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0\
+        \x07\x0a\x01\x06_start\0\0\x0a\x09\x01\x07\0\x03\x40\x0c\0\x0b\x0b";
+
+    /// Imports `f3` and `f17` from `wasi_unstable`, and calls `f17` then
+    /// `f3` from `_start`.
+    const CALL_F17_F3: &[u8] = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\
+        \x02\x28\x02\x0dwasi_unstable\x02f3\0\0\x0dwasi_unstable\x03f17\0\0\
+        \x03\x02\x01\0\x07\x0a\x01\x06_start\0\x02\x0a\x08\x01\x06\0\x10\x01\x10\0\x0b";
+
+    #[test]
+    fn host_closures_of_one_type() {
+        fn logger(calls: &Rc<RefCell<Vec<usize>>>, i: usize) -> impl Fn(&mut VMContext) {
+            let calls = calls.clone();
+            move |_: &mut VMContext| calls.borrow_mut().push(i)
+        }
+
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        // more than fit in an instance, but only the imported ones count
+        let mut builder = WasiInstanceBuilder::new().only_imported(true);
+        for i in 0..MAX_HOST_CLOSURES + 4 {
+            let name = format!("f{}", i);
+            builder = builder.host_function(HostFunction::from_closure(&name, logger(&calls, i)));
+        }
+        builder.run(CALL_F17_F3).unwrap();
+        assert_eq!(*calls.borrow(), vec![17, 3]);
+    }
+
+    #[test]
+    fn control_stops_a_spinning_guest() {
+        let control = Control::new();
+        let mut module = WasiInstanceBuilder::new()
+            .control(control.clone())
+            .instantiate_module(SPIN)
+            .unwrap();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            control.stop();
+        });
+        match module.run() {
+            Err(RunError::Stopped) => {}
+            other => panic!("expected the guest to be stopped, got {:?}", other.err()),
+        }
+        stopper.join().unwrap();
+    }
+
+    #[test]
+    fn proc_exit_stops_the_guest() {
+        let mut module = WasiInstanceBuilder::new()
+            .instantiate_module(EXIT_THEN_SPIN)
+            .unwrap();
+        match module.run() {
+            Err(RunError::Exit(3)) => {}
+            other => panic!("expected exit code 3, got {:?}", other.err()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn drops_privileges_once_per_thread() {
+        // run apart, so the test harness's thread keeps its privileges
+        thread::spawn(|| {
+            // entering a namespace takes root, the rest doesn't
+            let drop = if unsafe { libc::geteuid() } == 0 {
+                PrivilegeDrop::new().unshare_network()
+            } else {
+                PrivilegeDrop::new()
+            };
+            for _ in 0..2 {
+                WasiInstanceBuilder::new()
+                    .privilege_drop(drop.clone())
+                    .instantiate_module(EMPTY_MODULE)
+                    .unwrap();
+            }
+            // asking for more than was given up can't be granted any more
+            let err = WasiInstanceBuilder::new()
+                .privilege_drop(PrivilegeDrop::new().unshare_ipc())
+                .instantiate_module(EMPTY_MODULE)
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("couldn't drop privileges"));
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,49 @@
+/// Authority over something sensitive, which a guest only has if the
+/// embedder grants it, and which the guest can give up for good.
+///
+/// Hostcalls exercising it fail with `__WASI_ENOTCAPABLE` without it. The
+/// guest sees the capabilities it holds as a `u32` mask of the bits
+/// documented on each, through `enarx_capabilities`, and drops them with
+/// `enarx_capability_drop`. Only the host's record counts, so there's
+/// nothing for the guest to forge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Bit 0: using sockets, with `sock_recv`, `sock_send` and
+    /// `sock_shutdown`.
+    Network,
+    /// Bit 3: reading secrets with `enarx_secret_get`.
+    Secrets,
+}
+
+impl Capability {
+    fn bit(self) -> u32 {
+        match self {
+            Capability::Network => 1 << 0,
+            Capability::Secrets => 1 << 3,
+        }
+    }
+}
+
+/// The capabilities a guest holds.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Capabilities(u32);
+
+impl Capabilities {
+    pub(crate) fn grant(&mut self, capability: Capability) {
+        self.0 |= capability.bit();
+    }
+
+    pub(crate) fn has(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    /// Drops the capabilities whose bits are set in `mask`, for good.
+    pub(crate) fn drop_mask(&mut self, mask: u32) {
+        self.0 &= !mask;
+    }
+
+    /// The mask the guest sees.
+    pub(crate) fn mask(self) -> u32 {
+        self.0
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A cgroup (v2) to confine the host threads servicing a guest to, so the
+/// host-side work done on its behalf is bounded too.
+///
+/// The instantiating thread joins the cgroup when the instance is created,
+/// and so do the threads it spawns afterwards. The watchdog's thread is
+/// shared by the whole process, and only joins if spawned afterwards.
+///
+/// For a thread to join, the cgroup must be part of a threaded subtree, so
+/// only threaded controllers, like `cpu`, can limit it. The `memory`
+/// controller only works on whole processes: the guest's memory is bounded
+/// by `WasiInstanceBuilder::max_memory_pages` instead, and the host's by a
+/// cgroup of the whole process.
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Use the existing cgroup at `path`, e.g.
+    /// `/sys/fs/cgroup/enarx/keep-1`.
+    pub fn open(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Create a cgroup at `path`, as a threaded cgroup so threads can
+    /// join it individually.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let cgroup = Self::open(path);
+        fs::create_dir(&cgroup.path)?;
+        cgroup.write("cgroup.type", "threaded")?;
+        Ok(cgroup)
+    }
+
+    /// Allow the cgroup `quota` of CPU time every `period`.
+    pub fn cpu_max(&self, quota: Duration, period: Duration) -> io::Result<()> {
+        self.write(
+            "cpu.max",
+            &format!("{} {}", quota.as_micros(), period.as_micros()),
+        )
+    }
+
+    /// Number of CPUs worth of time the cgroup's `cpu.max` allows, rounded
+    /// up, or `None` if it's unlimited or can't be read.
+    pub(crate) fn cpu_limit(&self) -> Option<u32> {
+        let max = fs::read_to_string(self.path.join("cpu.max")).ok()?;
+        let mut fields = max.split_whitespace();
+        let quota: u64 = fields.next()?.parse().ok()?;
+        let period: u64 = fields.next()?.parse().ok()?;
+        if period == 0 {
+            return None;
+        }
+        Some(((quota + period - 1) / period) as u32)
+    }
+
+    /// Move the calling thread into the cgroup.
+    pub(crate) fn join(&self) -> io::Result<()> {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+        self.write("cgroup.threads", &tid.to_string())
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        fs::write(self.path.join(file), value).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{}: {}", self.path.join(file).display(), err),
+            )
+        })
+    }
+}
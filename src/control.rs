@@ -0,0 +1,181 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use wasi_common::wasm32;
+
+use super::debugger::{Breakpoint, BreakpointHit, Step};
+use super::interrupt::Interrupt;
+
+/// Handle for changing the configuration of a running instance from any
+/// thread, without restarting it.
+///
+/// Handed to `WasiInstanceBuilder::control`, or obtained from a
+/// `KeepManager`. Changes take effect at the guest's next syscall.
+#[derive(Clone, Default)]
+pub struct Control {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    stop: AtomicBool,
+    /// Bumped on every change, so syscalls can tell cheaply whether there's
+    /// anything new to apply.
+    version: AtomicU64,
+    settings: Mutex<Settings>,
+    pause: Mutex<Pause>,
+    /// Notified when the guest pauses at a breakpoint, and when it's to
+    /// resume or stop.
+    paused: Condvar,
+    /// Make the instances controlled trap, see `stop`.
+    interrupts: Mutex<Vec<Interrupt>>,
+}
+
+/// The breakpoint the guest is paused at, and what to do about it.
+#[derive(Default)]
+struct Pause {
+    at: Option<BreakpointHit>,
+    resume: Option<Step>,
+}
+
+/// The latest value of every setting changed through a `Control`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Settings {
+    pub(crate) hostcall_timeout: Option<Option<Duration>>,
+    pub(crate) max_fds: Option<Option<u32>>,
+    pub(crate) breakpoints: Option<Vec<Breakpoint>>,
+    pub(crate) log_level: Option<log::LevelFilter>,
+}
+
+impl Control {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bound the time spent in each hostcall by `timeout`, or stop bounding
+    /// it, like `WasiInstanceBuilder::hostcall_timeout`.
+    pub fn set_hostcall_timeout(&self, timeout: Option<Duration>) {
+        self.change(|settings| settings.hostcall_timeout = Some(timeout));
+    }
+
+    /// Allow the guest to hold at most `max` fds, or any number of them,
+    /// like `WasiInstanceBuilder::max_fds`. Fds the guest already holds
+    /// beyond a lowered limit stay open.
+    pub fn set_max_fds(&self, max: Option<u32>) {
+        self.change(|settings| settings.max_fds = Some(max));
+    }
+
+    /// Pause the guest when it makes a syscall meeting any of
+    /// `breakpoints`, replacing those set before, until it's resumed with
+    /// `resume`. Refused, with a warning, in keeps other than `Nil`, as it
+    /// exposes the guest's syscalls.
+    pub fn set_breakpoints(&self, breakpoints: Vec<Breakpoint>) {
+        self.change(|settings| settings.breakpoints = Some(breakpoints));
+    }
+
+    /// Waits up to `timeout` for the guest to be paused at a breakpoint,
+    /// and returns which, if it is.
+    pub fn wait_for_break(&self, timeout: Duration) -> Option<BreakpointHit> {
+        let deadline = Instant::now() + timeout;
+        let mut pause = self.shared.pause.lock().unwrap();
+        loop {
+            if let (Some(ref at), None) = (&pause.at, &pause.resume) {
+                return Some(at.clone());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let paused = &self.shared.paused;
+            pause = paused.wait_timeout(pause, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Resume the guest paused at a breakpoint, making the syscall or
+    /// failing it as `step` says. Does nothing if it isn't paused.
+    pub fn resume(&self, step: Step) {
+        let mut pause = self.shared.pause.lock().unwrap();
+        if pause.at.is_some() {
+            pause.resume = Some(step);
+            self.shared.paused.notify_all();
+        }
+    }
+
+    /// Log the syscalls of the instances controlled at most at `level`,
+    /// leaving the other instances' logging as it is.
+    ///
+    /// NB: this only filters what the logger's own level, which is
+    /// process-wide, lets through: it can't raise that.
+    pub fn set_log_level(&self, level: log::LevelFilter) {
+        self.change(|settings| settings.log_level = Some(level));
+    }
+
+    /// Stop the guest: it traps at its next function call, loop iteration
+    /// or return from a syscall, and the call into it ends with
+    /// `RunError::Stopped`. A guest paused at a breakpoint fails the
+    /// syscall it's paused at.
+    ///
+    /// A module compiled with debug info isn't instrumented to trap, so it
+    /// only sees every syscall it makes from then on fail with
+    /// `__WASI_EFAULT`, and keeps running if it ignores that or makes no
+    /// syscalls.
+    pub fn stop(&self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        for interrupt in self.shared.interrupts.lock().unwrap().iter() {
+            interrupt.raise();
+        }
+        let _pause = self.shared.pause.lock().unwrap();
+        self.shared.paused.notify_all();
+    }
+
+    pub(crate) fn stopped(&self) -> bool {
+        self.shared.stop.load(Ordering::Relaxed)
+    }
+
+    /// Makes `stop` raise `interrupt`, right away if it was already called.
+    pub(crate) fn attach(&self, interrupt: &Interrupt) {
+        let mut interrupts = self.shared.interrupts.lock().unwrap();
+        // those of the instances dropped since
+        interrupts.retain(Interrupt::armed);
+        if self.stopped() {
+            interrupt.raise();
+        }
+        interrupts.push(interrupt.clone());
+    }
+
+    /// Pauses the guest at `hit` until it's resumed or stopped, and returns
+    /// what to do with the syscall.
+    pub(crate) fn pause(&self, hit: BreakpointHit) -> Step {
+        let mut pause = self.shared.pause.lock().unwrap();
+        pause.at = Some(hit);
+        pause.resume = None;
+        self.shared.paused.notify_all();
+        let step = loop {
+            if self.stopped() {
+                break Step::Fail(wasm32::__WASI_EFAULT);
+            }
+            if let Some(step) = pause.resume.take() {
+                break step;
+            }
+            pause = self.shared.paused.wait(pause).unwrap();
+        };
+        pause.at = None;
+        step
+    }
+
+    /// Returns the settings if they changed since version `seen`, which is
+    /// then updated.
+    pub(crate) fn changes(&self, seen: &mut u64) -> Option<Settings> {
+        let version = self.shared.version.load(Ordering::Acquire);
+        if version == *seen {
+            return None;
+        }
+        *seen = version;
+        Some(self.shared.settings.lock().unwrap().clone())
+    }
+
+    fn change(&self, f: impl FnOnce(&mut Settings)) {
+        f(&mut self.shared.settings.lock().unwrap());
+        self.shared.version.fetch_add(1, Ordering::Release);
+    }
+}
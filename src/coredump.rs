@@ -0,0 +1,285 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use wasi_common::{hostcalls, wasm32};
+
+use super::fs::fdstat;
+use super::secret::zeroize_vec;
+use super::snapshot::{Reader, Snapshot};
+use super::state::WasiState;
+
+const MAGIC: &[u8; 4] = b"EWCD";
+const VERSION: u32 = 1;
+
+const SECTION_TRAP: u32 = 1;
+const SECTION_FUNCTION: u32 = 2;
+const SECTION_SNAPSHOT: u32 = 3;
+const SECTION_FDS: u32 = 4;
+
+/// Gives up looking for more fds after this many in a row aren't open.
+const MAX_FD_GAP: u32 = 1024;
+
+/// What a module looked like when it trapped, written out for post-mortem
+/// analysis if `WasiInstanceBuilder::core_dumps` asks for it.
+///
+/// A core dump is serialized as the magic `EWCD` and a `u32` version, 1,
+/// followed by sections, each a `u32` tag and the `u64` length of what
+/// follows. Integers are little-endian, and strings are UTF-8. Readers skip
+/// the sections they don't know.
+///
+/// - 1, the trap: the message the JIT reported it with, which says where
+///   in the module it hit. The pinned JIT doesn't unwind the guest stack,
+///   so there's no backtrace beyond that.
+/// - 2, the function: the name of the export that was called.
+/// - 3, the snapshot: the WASI memory and exported globals, as serialized
+///   by `Snapshot::to_bytes`.
+/// - 4, the fds: a `u32` count, and for each fd, the fd as a `u32`, its
+///   filetype as a `u8`, its base and inheriting rights as `u64`s, and
+///   the guest path it was preopened as, prefixed with its `u32` length
+///   and empty if it wasn't.
+///
+/// The snapshot holds whatever secrets the guest had in memory, so core
+/// dumps are written readable by their owner only.
+#[derive(Debug)]
+pub struct CoreDump {
+    trap: String,
+    function: String,
+    snapshot: Snapshot,
+    fds: Vec<FdSummary>,
+}
+
+/// An fd the guest held, as recorded in a `CoreDump`.
+#[derive(Debug, Clone)]
+pub struct FdSummary {
+    pub fd: u32,
+    /// One of the `__WASI_FILETYPE_*` constants.
+    pub filetype: u8,
+    pub rights_base: u64,
+    pub rights_inheriting: u64,
+    /// The guest path of a preopened directory.
+    pub preopen: Option<String>,
+}
+
+impl CoreDump {
+    pub(crate) fn new(
+        function: &str,
+        trap: &str,
+        snapshot: Snapshot,
+        state: Option<&mut WasiState>,
+    ) -> Self {
+        Self {
+            trap: trap.to_owned(),
+            function: function.to_owned(),
+            snapshot,
+            fds: state.map(fd_table).unwrap_or_default(),
+        }
+    }
+
+    /// The message the trap was reported with.
+    pub fn trap(&self) -> &str {
+        &self.trap
+    }
+
+    /// The export which was called when the module trapped.
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+
+    /// The WASI memory and exported globals, e.g. to `restore` into a new
+    /// instance of the module for a closer look.
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.snapshot.memory
+    }
+
+    /// The value of the exported global `name`.
+    pub fn global(&self, name: &str) -> Option<i64> {
+        self.snapshot
+            .globals
+            .iter()
+            .find(|(global, _)| global == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// The fds the guest held, as far as they could be found.
+    pub fn fds(&self) -> &[FdSummary] {
+        &self.fds
+    }
+
+    /// `len` bytes of memory at the guest pointer `ptr`, if they're all
+    /// within it.
+    pub fn read(&self, ptr: u32, len: usize) -> Option<&[u8]> {
+        let start = ptr as usize;
+        self.memory().get(start..start.checked_add(len)?)
+    }
+
+    /// Serialize the core dump.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.memory().len() + 4096);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        section(&mut out, SECTION_TRAP, self.trap.as_bytes());
+        section(&mut out, SECTION_FUNCTION, self.function.as_bytes());
+        let mut snapshot = self.snapshot.to_bytes();
+        section(&mut out, SECTION_SNAPSHOT, &snapshot);
+        zeroize_vec(&mut snapshot);
+        let mut fds = Vec::new();
+        fds.extend_from_slice(&(self.fds.len() as u32).to_le_bytes());
+        for fd in &self.fds {
+            let preopen = fd.preopen.as_ref().map_or("", String::as_str);
+            fds.extend_from_slice(&fd.fd.to_le_bytes());
+            fds.push(fd.filetype);
+            fds.extend_from_slice(&fd.rights_base.to_le_bytes());
+            fds.extend_from_slice(&fd.rights_inheriting.to_le_bytes());
+            fds.extend_from_slice(&(preopen.len() as u32).to_le_bytes());
+            fds.extend_from_slice(preopen.as_bytes());
+        }
+        section(&mut out, SECTION_FDS, &fds);
+        out
+    }
+
+    /// Deserialize a core dump serialized with `to_bytes`, e.g. one read
+    /// back from a file written on a trap.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes, "core dump");
+        if reader.take(4)? != MAGIC {
+            return Err("not a core dump".to_owned());
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(format!("unsupported core dump version {}", version));
+        }
+        let (mut trap, mut function, mut snapshot, mut fds) = (None, None, None, Vec::new());
+        while !reader.is_empty() {
+            let tag = reader.u32()?;
+            let len = reader.u64()? as usize;
+            let payload = reader.take(len)?;
+            let string = || {
+                String::from_utf8(payload.to_vec())
+                    .map_err(|_| format!("core dump section {} isn't UTF-8", tag))
+            };
+            match tag {
+                SECTION_TRAP => trap = Some(string()?),
+                SECTION_FUNCTION => function = Some(string()?),
+                SECTION_SNAPSHOT => snapshot = Some(Snapshot::from_bytes(payload)?),
+                SECTION_FDS => fds = read_fds(payload)?,
+                _ => {}
+            }
+        }
+        Ok(Self {
+            trap: trap.ok_or("core dump has no trap section")?,
+            function: function.ok_or("core dump has no function section")?,
+            snapshot: snapshot.ok_or("core dump has no snapshot section")?,
+            fds,
+        })
+    }
+
+    /// Writes the core dump into a new file in `dir`, readable by its owner
+    /// only, and returns the file's path.
+    pub(crate) fn write_to(&self, dir: &Path) -> io::Result<PathBuf> {
+        let mut bytes = self.to_bytes();
+        let result = write_new(dir, &bytes);
+        zeroize_vec(&mut bytes);
+        result
+    }
+}
+
+/// Writes `bytes` into a new file in `dir`, named after the process, and
+/// returns its path.
+fn write_new(dir: &Path, bytes: &[u8]) -> io::Result<PathBuf> {
+    let mut n = 0;
+    loop {
+        let path = dir.join(format!("{}.{}.core", std::process::id(), n));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path);
+        match file {
+            Ok(mut file) => {
+                file.write_all(bytes)?;
+                return Ok(path);
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn section(out: &mut Vec<u8>, tag: u32, payload: &[u8]) {
+    out.extend_from_slice(&tag.to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+fn read_fds(payload: &[u8]) -> Result<Vec<FdSummary>, String> {
+    let mut reader = Reader::new(payload, "core dump fd table");
+    let count = reader.u32()?;
+    let mut fds = Vec::new();
+    for _ in 0..count {
+        let fd = reader.u32()?;
+        let filetype = reader.u8()?;
+        let rights_base = reader.u64()?;
+        let rights_inheriting = reader.u64()?;
+        let preopen = reader.string()?;
+        fds.push(FdSummary {
+            fd,
+            filetype,
+            rights_base,
+            rights_inheriting,
+            preopen: if preopen.is_empty() {
+                None
+            } else {
+                Some(preopen)
+            },
+        });
+    }
+    Ok(fds)
+}
+
+/// Lists the fds the guest holds. The `WasiCtx` keeps its fd table
+/// private, so they're found by asking about one fd after the other.
+fn fd_table(state: &mut WasiState) -> Vec<FdSummary> {
+    let open = state.fs.open_fds() as usize;
+    let ctx = state.ctx.wasi_ctx();
+    let mut fds = Vec::new();
+    let mut gap = 0;
+    let mut fd = 0;
+    while fds.len() < open && gap < MAX_FD_GAP {
+        match fdstat(ctx, fd) {
+            Ok((filetype, rights_base, rights_inheriting)) => {
+                gap = 0;
+                fds.push(FdSummary {
+                    fd,
+                    filetype,
+                    rights_base,
+                    rights_inheriting,
+                    preopen: preopen(ctx, fd),
+                });
+            }
+            Err(_) => gap += 1,
+        }
+        fd += 1;
+    }
+    fds
+}
+
+/// The guest path `fd` was preopened as, if it was.
+fn preopen(ctx: &mut wasi_common::WasiCtx, fd: wasm32::__wasi_fd_t) -> Option<String> {
+    let mut prestat = [0; 8];
+    if hostcalls::fd_prestat_get(ctx, &mut prestat, fd, 0) != wasm32::__WASI_ESUCCESS {
+        return None;
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&prestat[4..8]);
+    let mut path = vec![0; u32::from_le_bytes(len) as usize];
+    let len = path.len() as wasm32::size_t;
+    if hostcalls::fd_prestat_dir_name(ctx, &mut path, fd, 0, len) != wasm32::__WASI_ESUCCESS {
+        return None;
+    }
+    String::from_utf8(path).ok()
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use wasi_common::wasm32;
+
+use super::guest;
+
+/// A syscall the guest is about to make.
+#[derive(Debug)]
+pub struct Syscall<'a> {
+    pub name: &'static str,
+    /// The raw arguments, by name: integers, sign-extended to `i64`, and
+    /// pointers into guest memory.
+    pub args: &'a [(&'static str, i64)],
+}
+
+impl Syscall<'_> {
+    /// The paths the syscall is passed, e.g. the `path` of `path_open`, as
+    /// far as they're within `memory`.
+    pub fn paths<'m>(&self, memory: &'m [u8]) -> Vec<&'m [u8]> {
+        self.args
+            .iter()
+            .filter_map(|&(name, ptr)| self.path(name, ptr, memory))
+            .collect()
+    }
+
+    /// What the argument `name`, of value `ptr`, points to if it's a path.
+    pub(crate) fn path<'m>(&self, name: &str, ptr: i64, memory: &'m [u8]) -> Option<&'m [u8]> {
+        if !name.starts_with("path") || name.starts_with("path_len") {
+            return None;
+        }
+        // `path` goes with `path_len`, `path0` with `path_len0`...
+        let len_name = format!("path_len{}", &name["path".len()..]);
+        let &(_, len) = self.args.iter().find(|(name, _)| *name == len_name)?;
+        let range = guest::range(memory, ptr as u32, len as u32 as usize).ok()?;
+        Some(&memory[range])
+    }
+}
+
+impl fmt::Display for Syscall<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        for (i, (name, value)) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={:#x}", name, value)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// What to do with a syscall the guest was stopped at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Make it.
+    Continue,
+    /// Don't make it, and fail it with this errno instead.
+    Fail(wasm32::__wasi_errno_t),
+}
+
+/// Stops the guest at its syscalls, for `WasiInstanceBuilder::debugger`.
+///
+/// The guest is paused for as long as `stop` takes to return.
+pub trait Debugger {
+    /// Decides what to do with `syscall`. `memory` is the WASI memory, for
+    /// looking at what the arguments point to.
+    fn stop(&mut self, syscall: &Syscall, memory: &[u8]) -> Step;
+}
+
+/// A `Debugger` driven by an operator, a line at a time, over stdin and
+/// stderr or e.g. a control socket.
+///
+/// At each syscall, it writes the syscall with its arguments, and reads
+/// commands until one resumes the guest:
+///
+/// - `c`: make the syscall;
+/// - `f ERRNO`: fail it with `ERRNO` instead;
+/// - `x PTR LEN`: dump `LEN` bytes of memory at `PTR`;
+/// - `d`: make this and every later syscall without stopping.
+///
+/// The end of input detaches it too.
+pub struct Console<R, W> {
+    input: R,
+    output: W,
+    detached: bool,
+}
+
+impl Console<BufReader<io::Stdin>, io::Stderr> {
+    /// A console on the host's stdin and stderr, which the guest had then
+    /// better not read from.
+    pub fn stdio() -> Self {
+        Self::new(BufReader::new(io::stdin()), io::stderr())
+    }
+}
+
+impl<R: BufRead, W: Write> Console<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            detached: false,
+        }
+    }
+
+    fn prompt(&mut self, syscall: &Syscall, memory: &[u8]) -> io::Result<Step> {
+        writeln!(self.output, "{}", syscall)?;
+        loop {
+            write!(self.output, "> ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                self.detached = true;
+                return Ok(Step::Continue);
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["c"] => return Ok(Step::Continue),
+                ["d"] => {
+                    self.detached = true;
+                    return Ok(Step::Continue);
+                }
+                ["f", errno] => match errno.parse() {
+                    Ok(errno) => return Ok(Step::Fail(errno)),
+                    Err(_) => writeln!(self.output, "not an errno: {}", errno)?,
+                },
+                ["x", ptr, len] => match (parse_int(ptr), parse_int(len)) {
+                    (Some(ptr), Some(len)) => self.dump(memory, ptr, len)?,
+                    _ => writeln!(self.output, "usage: x PTR LEN")?,
+                },
+                _ => writeln!(
+                    self.output,
+                    "c: continue, f ERRNO: fail, x PTR LEN: dump memory, d: detach"
+                )?,
+            }
+        }
+    }
+
+    fn dump(&mut self, memory: &[u8], ptr: usize, len: usize) -> io::Result<()> {
+        let bytes = match ptr.checked_add(len).and_then(|end| memory.get(ptr..end)) {
+            Some(bytes) => bytes,
+            None => return writeln!(self.output, "outside of memory"),
+        };
+        for (i, line) in bytes.chunks(16).enumerate() {
+            write!(self.output, "{:08x} ", ptr + i * 16)?;
+            for byte in line {
+                write!(self.output, " {:02x}", byte)?;
+            }
+            let text: String = line
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                .collect();
+            let pad = (16 - line.len()) * 3;
+            writeln!(self.output, "{:pad$}  {}", "", text, pad = pad)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead, W: Write> Debugger for Console<R, W> {
+    fn stop(&mut self, syscall: &Syscall, memory: &[u8]) -> Step {
+        if self.detached {
+            return Step::Continue;
+        }
+        self.prompt(syscall, memory).unwrap_or_else(|err| {
+            log::error!("debugger console failed, detaching: {}", err);
+            self.detached = true;
+            Step::Continue
+        })
+    }
+}
+
+/// A condition on the guest's syscalls which pauses it when met, until the
+/// embedder resumes it through its `Control`.
+///
+/// ```ignore
+/// let control = Control::new();
+/// control.set_breakpoints(vec![
+///     Breakpoint::syscall("path_open").path_matching("*.key"),
+///     Breakpoint::syscall("fd_write").nth(3),
+/// ]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    syscall: String,
+    nth: Option<u64>,
+    path: Option<String>,
+}
+
+impl Breakpoint {
+    /// Break on every call to the syscall `name`.
+    pub fn syscall(name: &str) -> Self {
+        Self {
+            syscall: name.to_owned(),
+            nth: None,
+            path: None,
+        }
+    }
+
+    /// Only break on the `n`th call, counting from 1, among those meeting
+    /// the other conditions.
+    pub fn nth(mut self, n: u64) -> Self {
+        self.nth = Some(n);
+        self
+    }
+
+    /// Only break on calls passed a path matching `pattern`, in which `*`
+    /// matches any number of bytes and `?` any one byte.
+    pub fn path_matching(mut self, pattern: &str) -> Self {
+        self.path = Some(pattern.to_owned());
+        self
+    }
+
+    fn matches(&self, syscall: &Syscall, memory: &[u8]) -> bool {
+        if syscall.name != self.syscall {
+            return false;
+        }
+        match self.path {
+            Some(ref pattern) => syscall
+                .paths(memory)
+                .iter()
+                .any(|path| glob(pattern.as_bytes(), path)),
+            None => true,
+        }
+    }
+}
+
+/// A breakpoint the guest is paused at.
+#[derive(Debug, Clone)]
+pub struct BreakpointHit {
+    /// Index of the breakpoint, among those last set.
+    pub breakpoint: usize,
+    /// The syscall, with its arguments.
+    pub syscall: String,
+    /// The paths the syscall is passed.
+    pub paths: Vec<String>,
+}
+
+/// The breakpoints set for an instance, with how often each was met.
+#[derive(Default)]
+pub(crate) struct Breakpoints {
+    breakpoints: Vec<(Breakpoint, u64)>,
+}
+
+impl Breakpoints {
+    pub(crate) fn new(breakpoints: Vec<Breakpoint>) -> Self {
+        Self {
+            breakpoints: breakpoints.into_iter().map(|bp| (bp, 0)).collect(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    /// Returns the first breakpoint `syscall` hits, if any, counting it
+    /// towards those it meets the conditions of.
+    pub(crate) fn check(&mut self, syscall: &Syscall, memory: &[u8]) -> Option<BreakpointHit> {
+        let mut hit = None;
+        for (i, (breakpoint, met)) in self.breakpoints.iter_mut().enumerate() {
+            if !breakpoint.matches(syscall, memory) {
+                continue;
+            }
+            *met += 1;
+            if hit.is_none() && breakpoint.nth.map_or(true, |n| n == *met) {
+                hit = Some(i);
+            }
+        }
+        hit.map(|breakpoint| BreakpointHit {
+            breakpoint,
+            syscall: syscall.to_string(),
+            paths: syscall
+                .paths(memory)
+                .iter()
+                .map(|path| String::from_utf8_lossy(path).into_owned())
+                .collect(),
+        })
+    }
+}
+
+/// Whether `text` matches the glob `pattern`.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    // where to resume after the last `*`, if the match fails past it
+    let mut star = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal integer.
+fn parse_int(s: &str) -> Option<usize> {
+    if s.starts_with("0x") {
+        usize::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
@@ -0,0 +1,426 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io;
+
+use super::secret::zeroize;
+
+/// Reseeds at most this many requests apart, the limit SP 800-90A sets for
+/// HMAC_DRBG.
+pub(crate) const MAX_RESEED_INTERVAL: u64 = 1 << 48;
+
+/// The most one request may generate, in bytes.
+const MAX_REQUEST: usize = 1 << 16;
+
+/// Bytes of entropy taken to seed and reseed, for a security strength of
+/// 256 bits.
+const SEED_LEN: usize = 32;
+
+/// Bytes taken for the nonce, half the security strength.
+const NONCE_LEN: usize = 16;
+
+const PERSONALIZATION: &[u8] = b"enarx-wasi random_get";
+
+/// The health tests of SP 800-90B take each byte the entropy source gives
+/// for a sample, with 8 bits of min-entropy, and fail once per 2^40
+/// samples of a healthy source: the repetition count test when a sample
+/// repeats this many times in a row...
+const REPETITION_CUTOFF: u32 = 6;
+
+/// ...and the adaptive proportion test when the first sample of a window
+/// of this many...
+const PROPORTION_WINDOW: u32 = 512;
+
+/// ...comes up this many times in it.
+const PROPORTION_CUTOFF: u32 = 19;
+
+/// Samples the startup tests run the health tests on before the source is
+/// used.
+const STARTUP_SAMPLES: usize = 1024;
+
+/// NIST SP 800-90A HMAC_DRBG, with SHA-256, serving `random_get`.
+///
+/// It's seeded and reseeded straight from the hardware entropy source, with
+/// prediction resistance left to the reseed interval. Once a health test
+/// fails, it stays in the error state and generates nothing more.
+pub(crate) struct Drbg {
+    k: [u8; 32],
+    v: [u8; 32],
+    /// Requests served since the last reseed, plus one.
+    reseed_counter: u64,
+    reseed_interval: u64,
+    source: EntropySource,
+    failed: bool,
+}
+
+impl Drbg {
+    /// Instantiates the DRBG, after testing HMAC-SHA-256 against a known
+    /// answer and running the startup tests of the entropy source. It's
+    /// reseeded every `reseed_interval` requests.
+    pub(crate) fn new(reseed_interval: u64) -> Result<Self, String> {
+        if reseed_interval == 0 || reseed_interval > MAX_RESEED_INTERVAL {
+            return Err(format!(
+                "DRBG reseed interval {} isn't between 1 and 2^48",
+                reseed_interval
+            ));
+        }
+        known_answer_test()?;
+        let mut source = EntropySource::default();
+        source
+            .startup()
+            .map_err(|err| format!("couldn't start the entropy source: {}", err))?;
+        let mut seed = [0; SEED_LEN + NONCE_LEN + PERSONALIZATION.len()];
+        let result = source
+            .fill(&mut seed[..SEED_LEN + NONCE_LEN])
+            .map_err(|err| format!("couldn't seed the DRBG: {}", err));
+        let drbg = result.map(|()| {
+            seed[SEED_LEN + NONCE_LEN..].copy_from_slice(PERSONALIZATION);
+            Self::instantiate(&seed, reseed_interval, source)
+        });
+        zeroize(&mut seed);
+        drbg
+    }
+
+    /// HMAC_DRBG_Instantiate, with `seed` the entropy input, nonce and
+    /// personalization string, concatenated.
+    fn instantiate(seed: &[u8], reseed_interval: u64, source: EntropySource) -> Self {
+        let mut drbg = Self {
+            k: [0; 32],
+            v: [1; 32],
+            reseed_counter: 1,
+            reseed_interval,
+            source,
+            failed: false,
+        };
+        drbg.update(&[seed]);
+        drbg
+    }
+
+    /// Fills `buf` with output, reseeding first whenever it's due. Fails
+    /// for good once a health test of the entropy source has.
+    pub(crate) fn generate(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        for chunk in buf.chunks_mut(MAX_REQUEST) {
+            if self.failed {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "the DRBG is in the error state",
+                ));
+            }
+            if self.reseed_counter > self.reseed_interval {
+                if let Err(err) = self.reseed() {
+                    self.failed = true;
+                    return Err(err);
+                }
+            }
+            for block in chunk.chunks_mut(32) {
+                self.v = hmac(&self.k, &[&self.v]);
+                block.copy_from_slice(&self.v[..block.len()]);
+            }
+            self.update(&[]);
+            self.reseed_counter += 1;
+        }
+        Ok(())
+    }
+
+    fn reseed(&mut self) -> io::Result<()> {
+        let mut entropy = [0; SEED_LEN];
+        let result = self.source.fill(&mut entropy);
+        if result.is_ok() {
+            self.update(&[&entropy]);
+            self.reseed_counter = 1;
+        }
+        zeroize(&mut entropy);
+        result
+    }
+
+    /// HMAC_DRBG_Update, with the concatenation of `data` as the provided
+    /// data.
+    fn update(&mut self, data: &[&[u8]]) {
+        for round in 0..2u8 {
+            let round = [round];
+            let mut input = vec![&self.v[..], &round[..]];
+            input.extend_from_slice(data);
+            self.k = hmac(&self.k, &input);
+            self.v = hmac(&self.k, &[&self.v]);
+            if data.iter().all(|data| data.is_empty()) {
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for Drbg {
+    fn drop(&mut self) {
+        zeroize(&mut self.k);
+        zeroize(&mut self.v);
+    }
+}
+
+/// The hardware entropy source: `RDSEED` where the CPU has it, or else the
+/// kernel's, which is fed by the hardware's, checked by the continuous
+/// health tests of SP 800-90B.
+#[derive(Default)]
+struct EntropySource {
+    /// The last sample, and how many times in a row it came, for the
+    /// repetition count test.
+    repeated: Option<(u8, u32)>,
+    /// The first sample of the adaptive proportion test's window, how many
+    /// times it came in it, and how many samples the window has so far.
+    window: Option<(u8, u32, u32)>,
+}
+
+impl EntropySource {
+    /// Runs the health tests on `STARTUP_SAMPLES` samples, which are
+    /// thrown away.
+    fn startup(&mut self) -> io::Result<()> {
+        let mut samples = [0; STARTUP_SAMPLES];
+        let result = self.fill(&mut samples);
+        zeroize(&mut samples);
+        result
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        for chunk in buf.chunks_mut(8) {
+            let mut word = read_word()?.to_le_bytes();
+            let result = word.iter().try_for_each(|&sample| self.test(sample));
+            chunk.copy_from_slice(&word[..chunk.len()]);
+            zeroize(&mut word);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Runs the continuous health tests on the next `sample`.
+    fn test(&mut self, sample: u8) -> io::Result<()> {
+        let repeated = match self.repeated {
+            Some((last, count)) if last == sample => count + 1,
+            _ => 1,
+        };
+        self.repeated = Some((sample, repeated));
+        if repeated >= REPETITION_CUTOFF {
+            return Err(health_test_failure("repetition count"));
+        }
+
+        let window = match self.window {
+            Some((first, count, len)) if len < PROPORTION_WINDOW => {
+                let count = if sample == first { count + 1 } else { count };
+                (first, count, len + 1)
+            }
+            _ => (sample, 1, 1),
+        };
+        self.window = Some(window);
+        if window.1 >= PROPORTION_CUTOFF {
+            return Err(health_test_failure("adaptive proportion"));
+        }
+        Ok(())
+    }
+}
+
+fn health_test_failure(test: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("the entropy source failed its {} test", test),
+    )
+}
+
+#[cfg(target_arch = "x86_64")]
+fn read_word() -> io::Result<u64> {
+    if is_x86_feature_detected!("rdseed") {
+        #[target_feature(enable = "rdseed")]
+        unsafe fn rdseed() -> Option<u64> {
+            let mut word = 0;
+            // it fails while the conditioner is drained, so retry a while
+            for _ in 0..1024 {
+                if std::arch::x86_64::_rdseed64_step(&mut word) == 1 {
+                    return Some(word);
+                }
+                std::arch::x86_64::_mm_pause();
+            }
+            None
+        }
+        return unsafe { rdseed() }
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "RDSEED kept failing"));
+    }
+    getrandom_word()
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn read_word() -> io::Result<u64> {
+    getrandom_word()
+}
+
+#[cfg(target_os = "linux")]
+fn getrandom_word() -> io::Result<u64> {
+    let mut word = [0u8; 8];
+    let mut read = 0;
+    while read < word.len() {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_getrandom,
+                word[read..].as_mut_ptr(),
+                word.len() - read,
+                0,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        } else {
+            read += ret as usize;
+        }
+    }
+    Ok(u64::from_le_bytes(word))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn getrandom_word() -> io::Result<u64> {
+    use std::io::Read;
+    let mut word = [0u8; 8];
+    std::fs::File::open("/dev/random")?.read_exact(&mut word)?;
+    Ok(u64::from_le_bytes(word))
+}
+
+/// Tests HMAC-SHA-256, which the DRBG is built on, against test case 2 of
+/// RFC 4231.
+fn known_answer_test() -> Result<(), String> {
+    const EXPECTED: [u8; 32] = [
+        0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75,
+        0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec,
+        0x38, 0x43,
+    ];
+    if hmac(b"Jefe", &[b"what do ya want ", b"for nothing?"]) != EXPECTED {
+        return Err("HMAC-SHA-256 failed its known answer test".to_owned());
+    }
+    Ok(())
+}
+
+/// HMAC-SHA-256 of the concatenation of `data`.
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    for data in data {
+        mac.input(data);
+    }
+    let mut code = [0; 32];
+    code.copy_from_slice(&mac.result().code());
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// The test cases of RFC 4231 with keys of at most a block, which is
+    /// all the DRBG uses: this tests how the HMAC is put together.
+    #[test]
+    fn hmac_known_answers() {
+        known_answer_test().unwrap();
+        let cases: &[(&[u8], &[u8], &str)] = &[
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                &hex("0102030405060708090a0b0c0d0e0f10111213141516171819"),
+                &[0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+        ];
+        for (key, data, mac) in cases {
+            assert_eq!(hmac(key, &[data]).to_vec(), hex(mac));
+        }
+        // test case 5, truncated to 128 bits
+        assert_eq!(
+            hmac(&[0x0c; 20], &[b"Test With Truncation"])[..16].to_vec(),
+            hex("a3b6167473100ee06e0c796c2955552b")
+        );
+    }
+
+    /// The first HMAC_DRBG SHA-256 vector of NIST's CAVP, without
+    /// prediction resistance, reseeding, personalization or additional
+    /// input: the second of two 1024 bit requests is compared.
+    #[test]
+    fn drbg_known_answer() {
+        let entropy = hex("ca851911349384bffe89de1cbdc46e6831e44d34a4fb935ee285dd14b71a7488");
+        let nonce = hex("659ba96c601dc69fc902940805ec0ca8");
+        let seed = [&entropy[..], &nonce[..]].concat();
+        let mut drbg = Drbg::instantiate(&seed, MAX_RESEED_INTERVAL, EntropySource::default());
+        let mut bits = [0; 128];
+        drbg.generate(&mut bits).unwrap();
+        drbg.generate(&mut bits).unwrap();
+        assert_eq!(
+            bits.to_vec(),
+            hex(concat!(
+                "e528e9abf2dece54d47c7e75e5fe302149f817ea9fb4bee6f4199697d04d5b89",
+                "d54fbb978a15b5c443c9ec21036d2460b6f73ebad0dc2aba6e624abf07745bc1",
+                "07694bb7547bb0995f70de25d6b29e2d3011bb19d27676c07162c8b5ccde0668",
+                "961df86803482cb37ed6d5c0bb8d50cf1f50d476aa0458bdaba806f48be9dcb8",
+            ))
+        );
+    }
+
+    #[test]
+    fn repetition_count_test() {
+        let mut source = EntropySource::default();
+        for &sample in &[1, 2, 2, 2, 2, 2, 3] {
+            source.test(sample).unwrap();
+        }
+        for _ in 2..REPETITION_CUTOFF {
+            source.test(3).unwrap();
+        }
+        assert!(source.test(3).is_err());
+    }
+
+    #[test]
+    fn adaptive_proportion_test() {
+        let mut source = EntropySource::default();
+        // a window with the first sample one time too few...
+        for i in 0..PROPORTION_WINDOW {
+            let sample = if i % 16 == 0 && i / 16 < PROPORTION_CUTOFF - 1 {
+                0
+            } else {
+                i as u8 | 1
+            };
+            source.test(sample).unwrap();
+        }
+        // ...and one with it often enough, though never repeated
+        for i in 0..PROPORTION_WINDOW {
+            let sample = if i % 2 == 0 { 7 } else { i as u8 | 0x80 };
+            if i / 2 + 1 >= PROPORTION_CUTOFF {
+                assert!(source.test(sample).is_err());
+                return;
+            }
+            source.test(sample).unwrap();
+        }
+        panic!("the adaptive proportion test didn't fail");
+    }
+
+    #[test]
+    fn startup_tests_pass() {
+        EntropySource::default().startup().unwrap();
+    }
+
+    #[test]
+    fn reseed_interval_bounds() {
+        assert!(Drbg::new(0).is_err());
+        assert!(Drbg::new(MAX_RESEED_INTERVAL + 1).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+/// Which host environment variables a guest gets, and under what names.
+///
+/// Variables are assembled in order from the host variables the policy
+/// inherits, the ones it renames, those set with `WasiInstanceBuilder::env`,
+/// and the policy's own overrides, later ones replacing earlier ones of the
+/// same name.
+///
+/// ```ignore
+/// let policy = EnvPolicy::new()
+///     .inherit("LANG")
+///     .inherit("LC_*")
+///     .rename("KEEP_TOKEN", "TOKEN")
+///     .set("HOME", "/")
+///     .strict(true);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EnvPolicy {
+    inherit: Vec<String>,
+    renames: Vec<(String, String)>,
+    overrides: Vec<(String, String)>,
+    strict: bool,
+}
+
+impl EnvPolicy {
+    /// A policy passing nothing from the host.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass the host variables matching `pattern` through, either a name,
+    /// or a prefix followed by `*`.
+    pub fn inherit(mut self, pattern: &str) -> Self {
+        self.inherit.push(pattern.to_owned());
+        self
+    }
+
+    /// Pass the host variable `host` through as `guest`.
+    pub fn rename(mut self, host: &str, guest: &str) -> Self {
+        self.renames.push((host.to_owned(), guest.to_owned()));
+        self
+    }
+
+    /// Set `key` to `value`, whatever the host or builder set it to.
+    pub fn set(mut self, key: &str, value: &str) -> Self {
+        self.overrides.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Whether variables set with `WasiInstanceBuilder::env` which the
+    /// policy doesn't declare, by inheriting, renaming or setting them,
+    /// make instantiation fail instead of reaching the guest.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// The guest's environment, given the `host` one and the variables
+    /// `explicit`ly set on the builder.
+    pub(crate) fn apply(
+        &self,
+        host: impl IntoIterator<Item = (String, String)>,
+        explicit: &[(String, String)],
+    ) -> Result<Vec<(String, String)>, String> {
+        let host: Vec<_> = host.into_iter().collect();
+        let mut env = BTreeMap::new();
+        for (key, value) in &host {
+            if self.inherit.iter().any(|pattern| matches(pattern, key)) {
+                env.insert(key.clone(), value.clone());
+            }
+        }
+        for (from, to) in &self.renames {
+            if let Some((_, value)) = host.iter().find(|(key, _)| key == from) {
+                env.insert(to.clone(), value.clone());
+            }
+        }
+        for (key, value) in explicit {
+            if self.strict && !self.declares(key) {
+                return Err(format!(
+                    "environment variable {} isn't declared by the policy",
+                    key
+                ));
+            }
+            env.insert(key.clone(), value.clone());
+        }
+        for (key, value) in &self.overrides {
+            env.insert(key.clone(), value.clone());
+        }
+        Ok(env.into_iter().collect())
+    }
+
+    fn declares(&self, key: &str) -> bool {
+        self.inherit.iter().any(|pattern| matches(pattern, key))
+            || self.renames.iter().any(|(_, to)| to == key)
+            || self.overrides.iter().any(|(k, _)| k == key)
+    }
+}
+
+fn matches(pattern: &str, key: &str) -> bool {
+    if pattern.ends_with('*') {
+        key.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        pattern == key
+    }
+}
@@ -0,0 +1,292 @@
+//! C API, declared in `include/enarx_wasi.h`.
+//!
+//! Every entry point catches panics, which mustn't unwind into C, and
+//! fails with an error instead.
+
+use super::builder::WasiInstanceBuilder;
+use super::module::{RunError, WasiModule};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::io::FromRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_error(msg: impl ToString) {
+    let msg = CString::new(msg.to_string().replace('\0', "\\0")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+}
+
+/// Runs `f`, returning `failed` with the panic as the last error if it
+/// panics.
+fn catch<T>(failed: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|s| *s)
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("<non-string panic payload>");
+        // setting the error can't panic again: NULs are escaped
+        set_error(format!("panicked: {}", msg));
+        failed
+    })
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> Option<&'a str> {
+    if s.is_null() {
+        set_error(format!("{} is NULL", what));
+        return None;
+    }
+    match CStr::from_ptr(s).to_str() {
+        Ok(s) => Some(s),
+        Err(_) => {
+            set_error(format!("{} isn't UTF-8", what));
+            None
+        }
+    }
+}
+
+unsafe fn builder_arg<'a>(builder: *mut Builder) -> Option<&'a mut Builder> {
+    if builder.is_null() {
+        set_error("builder is NULL");
+    }
+    builder.as_mut()
+}
+
+/// A duplicate of the caller's `fd`, which the caller keeps.
+fn dup_fd(fd: c_int) -> Option<File> {
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup < 0 {
+        set_error(format!(
+            "couldn't duplicate fd {}: {}",
+            fd,
+            std::io::Error::last_os_error()
+        ));
+        return None;
+    }
+    Some(unsafe { File::from_raw_fd(dup) })
+}
+
+/// Builder behind an `enarx_wasi_builder *`. The builder's methods take it
+/// by value, so it's moved out and back in around each of them.
+pub struct Builder(Option<WasiInstanceBuilder>);
+
+impl Builder {
+    fn update(&mut self, f: impl FnOnce(WasiInstanceBuilder) -> WasiInstanceBuilder) {
+        self.0 = self.0.take().map(f);
+    }
+}
+
+/// Returns the message of the last error on this thread, or NULL. The
+/// string is valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn enarx_wasi_last_error() -> *const c_char {
+    catch(ptr::null(), || {
+        LAST_ERROR.with(|last| {
+            last.borrow()
+                .as_ref()
+                .map_or(ptr::null(), |msg| msg.as_ptr())
+        })
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn enarx_wasi_builder_new() -> *mut Builder {
+    catch(ptr::null_mut(), || {
+        Box::into_raw(Box::new(Builder(Some(WasiInstanceBuilder::new()))))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_builder_free(builder: *mut Builder) {
+    catch((), || {
+        if !builder.is_null() {
+            drop(Box::from_raw(builder));
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_builder_arg(
+    builder: *mut Builder,
+    arg: *const c_char,
+) -> c_int {
+    catch(-1, || match (builder_arg(builder), str_arg(arg, "arg")) {
+        (Some(builder), Some(arg)) => {
+            builder.update(|b| b.arg(arg));
+            0
+        }
+        _ => -1,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_builder_env(
+    builder: *mut Builder,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    catch(-1, || {
+        match (
+            builder_arg(builder),
+            str_arg(key, "key"),
+            str_arg(value, "value"),
+        ) {
+            (Some(builder), Some(key), Some(value)) => {
+                builder.update(|b| b.env(key, value));
+                0
+            }
+            _ => -1,
+        }
+    })
+}
+
+/// Make the host directory `host_path` visible to the guest as
+/// `guest_path`. This lets the guest use the filesystem syscalls, which
+/// it couldn't otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_builder_map_dir(
+    builder: *mut Builder,
+    guest_path: *const c_char,
+    host_path: *const c_char,
+    readonly: bool,
+) -> c_int {
+    catch(-1, || {
+        match (
+            builder_arg(builder),
+            str_arg(guest_path, "guest_path"),
+            str_arg(host_path, "host_path"),
+        ) {
+            (Some(builder), Some(guest_path), Some(host_path)) => {
+                let host_path = std::path::PathBuf::from(host_path);
+                builder.update(|b| {
+                    let b = b.host_filesystem(true);
+                    if readonly {
+                        b.preopen_readonly(guest_path, host_path)
+                    } else {
+                        b.preopen(guest_path, host_path)
+                    }
+                });
+                0
+            }
+            _ => -1,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_builder_inherit_stdio(
+    builder: *mut Builder,
+    inherit: bool,
+) -> c_int {
+    catch(-1, || match builder_arg(builder) {
+        Some(builder) => {
+            builder.update(|b| b.inherit_stdio(inherit));
+            0
+        }
+        None => -1,
+    })
+}
+
+/// Write the guest's stdout to a duplicate of `fd`, e.g. a pipe's.
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_builder_capture_stdout(
+    builder: *mut Builder,
+    fd: c_int,
+) -> c_int {
+    catch(-1, || match (builder_arg(builder), dup_fd(fd)) {
+        (Some(builder), Some(file)) => {
+            builder.update(|b| b.capture_stdout(file));
+            0
+        }
+        _ => -1,
+    })
+}
+
+/// Write the guest's stderr to a duplicate of `fd`.
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_builder_capture_stderr(
+    builder: *mut Builder,
+    fd: c_int,
+) -> c_int {
+    catch(-1, || match (builder_arg(builder), dup_fd(fd)) {
+        (Some(builder), Some(file)) => {
+            builder.update(|b| b.capture_stderr(file));
+            0
+        }
+        _ => -1,
+    })
+}
+
+/// Compile and instantiate the module `wasm[..len]`. Consumes `builder`,
+/// which must not be used or freed afterwards, even on failure.
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_instantiate(
+    builder: *mut Builder,
+    wasm: *const u8,
+    len: usize,
+) -> *mut WasiModule {
+    catch(ptr::null_mut(), || {
+        if builder.is_null() {
+            set_error("builder is NULL");
+            return ptr::null_mut();
+        }
+        let builder = Box::from_raw(builder);
+        if wasm.is_null() {
+            set_error("wasm is NULL");
+            return ptr::null_mut();
+        }
+        let result = match builder.0 {
+            Some(builder) => builder.instantiate_module(slice::from_raw_parts(wasm, len)),
+            None => {
+                set_error("builder was already used");
+                return ptr::null_mut();
+            }
+        };
+        match result {
+            Ok(module) => Box::into_raw(Box::new(module)),
+            Err(err) => {
+                set_error(err);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Run the module's `_start` function. Returns 0 on success, the code the
+/// module passed to `proc_exit` if nonzero, or -1 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_module_run(module: *mut WasiModule) -> c_int {
+    catch(-1, || {
+        let module = match module.as_mut() {
+            Some(module) => module,
+            None => {
+                set_error("module is NULL");
+                return -1;
+            }
+        };
+        match module.run() {
+            Ok(()) => 0,
+            Err(RunError::Exit(code)) if code <= c_int::max_value() as u32 => code as c_int,
+            Err(err) => {
+                set_error(err);
+                -1
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn enarx_wasi_module_free(module: *mut WasiModule) {
+    catch((), || {
+        if !module.is_null() {
+            drop(Box::from_raw(module));
+        }
+    })
+}
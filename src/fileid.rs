@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use wasi_common::{hostcalls, wasm32, WasiCtx};
+
+use super::guest;
+
+/// Offsets of `st_dev` and `st_ino` in a `__wasi_filestat_t`, the same in
+/// both ABIs.
+const FILESTAT_DEV: usize = 0;
+const FILESTAT_INO: usize = 8;
+const FILESTAT_SIZE: usize = 56;
+
+/// Size of a `__wasi_dirent_t` header, and offsets of its `d_ino` and
+/// `d_namlen`.
+const DIRENT_SIZE: usize = 24;
+const DIRENT_INO: usize = 8;
+const DIRENT_NAMLEN: usize = 16;
+
+/// Device standing in for the one of a directory whose own can't be told,
+/// for the inodes of its entries.
+const UNKNOWN_DEV: u64 = !0;
+
+/// Replaces the host's device and inode numbers in what the guest sees
+/// with synthetic ones, numbered from 1 in the order the guest first comes
+/// across them, so they neither leak the host's layout nor change from one
+/// host to another. WASI has no uids or gids to hide.
+///
+/// The same host file always gets the same numbers within an instance, so
+/// the guest can still tell whether two paths are the same file.
+#[derive(Default)]
+pub(crate) struct FileIds {
+    devs: HashMap<u64, u64>,
+    inos: HashMap<(u64, u64), u64>,
+}
+
+impl FileIds {
+    fn dev(&mut self, dev: u64) -> u64 {
+        let next = self.devs.len() as u64 + 1;
+        *self.devs.entry(dev).or_insert(next)
+    }
+
+    fn ino(&mut self, dev: u64, ino: u64) -> u64 {
+        let next = self.inos.len() as u64 + 1;
+        *self.inos.entry((dev, ino)).or_insert(next)
+    }
+
+    /// Rewrites the filestat the `WasiCtx` wrote at `buf`.
+    pub(crate) fn filestat(&mut self, memory: &mut [u8], buf: wasm32::uintptr_t) {
+        let filestat = match guest::range(memory, buf, FILESTAT_SIZE) {
+            Ok(range) => &mut memory[range],
+            Err(_) => return,
+        };
+        let dev = read_u64(filestat, FILESTAT_DEV);
+        let ino = read_u64(filestat, FILESTAT_INO);
+        let (synthetic_dev, synthetic_ino) = (self.dev(dev), self.ino(dev, ino));
+        write_u64(filestat, FILESTAT_DEV, synthetic_dev);
+        write_u64(filestat, FILESTAT_INO, synthetic_ino);
+    }
+
+    /// Rewrites the inodes of the entries `fd_readdir` wrote to `dirents`,
+    /// as far as their headers made it in, for the directory `fd`.
+    pub(crate) fn dirents(
+        &mut self,
+        ctx: &mut WasiCtx,
+        fd: wasm32::__wasi_fd_t,
+        dirents: &mut [u8],
+    ) {
+        let dev = dir_dev(ctx, fd).unwrap_or(UNKNOWN_DEV);
+        let mut offset = 0;
+        while offset + DIRENT_SIZE <= dirents.len() {
+            let ino = read_u64(dirents, offset + DIRENT_INO);
+            let synthetic = self.ino(dev, ino);
+            write_u64(dirents, offset + DIRENT_INO, synthetic);
+            let namlen = read_u32(dirents, offset + DIRENT_NAMLEN) as usize;
+            offset += DIRENT_SIZE + namlen;
+        }
+    }
+}
+
+/// The host device the directory `fd` is on, which its entries are too,
+/// unless they're mount points.
+fn dir_dev(ctx: &mut WasiCtx, fd: wasm32::__wasi_fd_t) -> Option<u64> {
+    let mut filestat = [0; FILESTAT_SIZE];
+    if hostcalls::fd_filestat_get(ctx, &mut filestat, fd, 0) != wasm32::__WASI_ESUCCESS {
+        return None;
+    }
+    Some(read_u64(&filestat, FILESTAT_DEV))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn write_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
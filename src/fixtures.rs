@@ -0,0 +1,129 @@
+//! Filesystem fixtures shared by the tests of several modules.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use wasi_common::{hostcalls, wasm32, WasiCtx, WasiCtxBuilder};
+
+use super::guest::{read_u32, read_u64};
+
+pub(crate) const GIB: u64 = 1 << 30;
+
+/// A directory of a test's own, removed with what's in it on drop.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates a directory named after `name` which no other test, run at
+    /// the same time or by another process, shares.
+    pub(crate) fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "enarx-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub(crate) fn open(&self) -> File {
+        File::open(&self.0).unwrap()
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The fd the `WasiCtx` a `Sparse` opens has the directory preopened at.
+pub(crate) const DIR: wasm32::__wasi_fd_t = 3;
+
+/// Where `Sparse` tests keep things in their guest memory.
+pub(crate) const FD: wasm32::uintptr_t = 0;
+pub(crate) const IOV: wasm32::uintptr_t = 8;
+pub(crate) const NREAD: wasm32::uintptr_t = 16;
+pub(crate) const OFFSET: wasm32::uintptr_t = 24;
+pub(crate) const FILESTAT: wasm32::uintptr_t = 32;
+pub(crate) const PATH: wasm32::uintptr_t = 96;
+pub(crate) const DATA: wasm32::uintptr_t = 128;
+
+/// A directory holding `sparse`, a 6 GiB file with nothing in it but
+/// `tail`, 5 GiB in.
+pub(crate) struct Sparse(TempDir);
+
+impl Sparse {
+    pub(crate) fn new() -> Self {
+        let dir = TempDir::new("sparse");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(dir.join("sparse"))
+            .unwrap();
+        file.set_len(6 * GIB).unwrap();
+        file.seek(SeekFrom::Start(5 * GIB)).unwrap();
+        file.write_all(b"tail").unwrap();
+        Sparse(dir)
+    }
+
+    /// A `WasiCtx` with the directory preopened, and `sparse` opened in it,
+    /// with the fd it's at.
+    pub(crate) fn open(&self, memory: &mut [u8]) -> (WasiCtx, wasm32::__wasi_fd_t) {
+        let mut ctx = WasiCtxBuilder::new()
+            .unwrap()
+            .preopened_dir(self.0.open(), "/")
+            .build()
+            .unwrap();
+        let path = PATH as usize;
+        memory[path..path + 6].copy_from_slice(b"sparse");
+        let rights = wasm32::__WASI_RIGHT_FD_READ
+            | wasm32::__WASI_RIGHT_FD_SEEK
+            | wasm32::__WASI_RIGHT_FD_TELL
+            | wasm32::__WASI_RIGHT_FD_FILESTAT_GET;
+        let errno = hostcalls::path_open(&mut ctx, memory, DIR, 0, PATH, 6, 0, rights, 0, 0, FD);
+        assert_eq!(errno, wasm32::__WASI_ESUCCESS);
+        let fd = read_u32(memory, FD).unwrap();
+        (ctx, fd)
+    }
+}
+
+pub(crate) fn seek(
+    ctx: &mut WasiCtx,
+    memory: &mut [u8],
+    fd: wasm32::__wasi_fd_t,
+    delta: wasm32::__wasi_filedelta_t,
+    whence: wasm32::__wasi_whence_t,
+) -> u64 {
+    let errno = hostcalls::fd_seek(ctx, memory, fd, delta, whence, OFFSET);
+    assert_eq!(errno, wasm32::__WASI_ESUCCESS);
+    read_u64(memory, OFFSET).unwrap()
+}
+
+pub(crate) fn tell(ctx: &mut WasiCtx, memory: &mut [u8], fd: wasm32::__wasi_fd_t) -> u64 {
+    let errno = hostcalls::fd_tell(ctx, memory, fd, OFFSET);
+    assert_eq!(errno, wasm32::__WASI_ESUCCESS);
+    read_u64(memory, OFFSET).unwrap()
+}
+
+/// Points the iovec at `IOV` at `len` bytes at `DATA`.
+pub(crate) fn iovec(memory: &mut [u8], len: u32) {
+    let iov = IOV as usize;
+    memory[iov..iov + 4].copy_from_slice(&DATA.to_le_bytes());
+    memory[iov + 4..iov + 8].copy_from_slice(&len.to_le_bytes());
+}
+
+pub(crate) fn data(memory: &[u8], len: usize) -> &[u8] {
+    &memory[DATA as usize..DATA as usize + len]
+}
@@ -0,0 +1,477 @@
+use std::collections::{HashMap, HashSet};
+use wasi_common::{hostcalls, wasm32, WasiCtx};
+
+use super::guest;
+use super::preopen::PreopenRights;
+
+/// Rights allowing an fd, or anything opened through it, to modify the
+/// filesystem.
+pub(crate) const WRITE_RIGHTS: wasm32::__wasi_rights_t = wasm32::__WASI_RIGHT_FD_DATASYNC
+    | wasm32::__WASI_RIGHT_FD_WRITE
+    | wasm32::__WASI_RIGHT_FD_ALLOCATE
+    | wasm32::__WASI_RIGHT_FD_FILESTAT_SET_SIZE
+    | wasm32::__WASI_RIGHT_FD_FILESTAT_SET_TIMES
+    | wasm32::__WASI_RIGHT_PATH_CREATE_DIRECTORY
+    | wasm32::__WASI_RIGHT_PATH_CREATE_FILE
+    | wasm32::__WASI_RIGHT_PATH_LINK_SOURCE
+    | wasm32::__WASI_RIGHT_PATH_LINK_TARGET
+    | wasm32::__WASI_RIGHT_PATH_RENAME_SOURCE
+    | wasm32::__WASI_RIGHT_PATH_RENAME_TARGET
+    | wasm32::__WASI_RIGHT_PATH_FILESTAT_SET_SIZE
+    | wasm32::__WASI_RIGHT_PATH_FILESTAT_SET_TIMES
+    | wasm32::__WASI_RIGHT_PATH_SYMLINK
+    | wasm32::__WASI_RIGHT_PATH_REMOVE_DIRECTORY
+    | wasm32::__WASI_RIGHT_PATH_UNLINK_FILE;
+
+/// Open flags which may create or modify a file.
+const WRITE_OFLAGS: wasm32::__wasi_oflags_t =
+    wasm32::__WASI_O_CREAT | wasm32::__WASI_O_EXCL | wasm32::__WASI_O_TRUNC;
+
+/// Filesystem restrictions layered on top of the rights the `WasiCtx`
+/// enforces itself.
+///
+/// The `WasiCtx` hands every preopen the full set of rights, so restricting
+/// a preopen has to happen here: the rights requested by `path_open` are
+/// masked before they reach the `WasiCtx`, which then enforces the reduced
+/// rights on the new fd for the rest of its life.
+#[derive(Default)]
+pub(crate) struct FsPolicy {
+    /// Fds through which the filesystem must not be modified: read-only
+    /// preopens and everything opened beneath them.
+    readonly: HashSet<wasm32::__wasi_fd_t>,
+    /// Fds beneath which the guest may only create new files: create-only
+    /// preopens and the directories it creates in them.
+    create_only: HashSet<wasm32::__wasi_fd_t>,
+    /// The umask files and directories are created with beneath each fd
+    /// which has one: preopens configured with one, and everything opened
+    /// beneath them.
+    umasks: HashMap<wasm32::__wasi_fd_t, u32>,
+    /// Number of fds in the `WasiCtx`'s table.
+    open_fds: u32,
+    /// How many fds the guest may hold at once, if limited.
+    max_fds: Option<u32>,
+    /// How large the guest may make a file, if limited.
+    max_file_size: Option<u64>,
+    /// Fds below this, stdio and the preopens, can't be closed or
+    /// renumbered, if set.
+    protected: Option<wasm32::__wasi_fd_t>,
+    /// Whether the guest may use the filesystem syscalls at all.
+    host_fs: bool,
+    /// Whether the guest may only read and write stdio, in minimal mode.
+    stdio_only: bool,
+}
+
+impl FsPolicy {
+    /// Restricts the preopen `fd` of `ctx` to `rights`.
+    pub(crate) fn restrict(
+        &mut self,
+        ctx: &mut WasiCtx,
+        fd: wasm32::__wasi_fd_t,
+        rights: PreopenRights,
+    ) -> Result<(), String> {
+        // `fd_fdstat_set_rights` only drops rights, so intersect with the
+        // ones the preopen has.
+        let (_, base, inheriting) = fdstat(ctx, fd)
+            .map_err(|errno| format!("couldn't get rights of fd {}: errno {}", fd, errno))?;
+        let errno = hostcalls::fd_fdstat_set_rights(
+            ctx,
+            fd,
+            base & rights.base,
+            inheriting & rights.inheriting,
+        );
+        if errno != wasm32::__WASI_ESUCCESS {
+            return Err(format!(
+                "couldn't restrict rights of fd {}: errno {}",
+                fd, errno
+            ));
+        }
+        if rights.is_readonly() {
+            self.readonly.insert(fd);
+        }
+        if rights.create_only {
+            self.create_only.insert(fd);
+        }
+        if let Some(umask) = rights.umask {
+            self.umasks.insert(fd, umask);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn allow_host_fs(&mut self, allow: bool) {
+        self.host_fs = allow;
+    }
+
+    /// Fails with `__WASI_ENOSYS` unless the guest may use the filesystem
+    /// syscalls, which are stubbed out otherwise.
+    pub(crate) fn check_host_fs(&self) -> Result<(), wasm32::__wasi_errno_t> {
+        if self.host_fs {
+            Ok(())
+        } else {
+            Err(wasm32::__WASI_ENOSYS)
+        }
+    }
+
+    pub(crate) fn allow_only_stdio(&mut self, only: bool) {
+        self.stdio_only = only;
+    }
+
+    /// Fails with `__WASI_EBADF` if the guest may only read and write
+    /// stdio, and `fd` isn't one of stdin, stdout and stderr.
+    pub(crate) fn check_stdio(
+        &self,
+        fd: wasm32::__wasi_fd_t,
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        if self.stdio_only && fd > 2 {
+            Err(wasm32::__WASI_EBADF)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Records that the `WasiCtx` starts out with `count` fds.
+    pub(crate) fn set_fd_count(&mut self, count: u32) {
+        self.open_fds = count;
+    }
+
+    pub(crate) fn open_fds(&self) -> u32 {
+        self.open_fds
+    }
+
+    pub(crate) fn limit_fds(&mut self, max: Option<u32>) {
+        self.max_fds = max;
+    }
+
+    pub(crate) fn limit_file_size(&mut self, max: Option<u64>) {
+        self.max_file_size = max;
+    }
+
+    pub(crate) fn limits_file_size(&self) -> bool {
+        self.max_file_size.is_some()
+    }
+
+    /// Fails with `__WASI_EFBIG` if a file may not grow to `size` bytes.
+    pub(crate) fn check_file_size(&self, size: u64) -> Result<(), wasm32::__wasi_errno_t> {
+        match self.max_file_size {
+            Some(max) if size > max => Err(wasm32::__WASI_EFBIG),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `__WASI_EFBIG` if writing the iovecs at `iovs` at
+    /// `offset` in a file takes it past its size limit.
+    ///
+    /// The write is refused whole, rather than cut short at the limit, as
+    /// the `WasiCtx` writes all the iovecs it's handed.
+    pub(crate) fn check_write(
+        &self,
+        memory: &[u8],
+        offset: u64,
+        iovs: wasm32::uintptr_t,
+        iovs_len: wasm32::size_t,
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        let len = guest::iovecs_len(memory, iovs, iovs_len);
+        self.check_file_size(offset.saturating_add(len))
+    }
+
+    /// Protects the fds the `WasiCtx` starts out with from `fd_close` and
+    /// `fd_renumber`: closing stdio can wedge the instance, and the guest
+    /// finds the preopens by looking at fds from 3 up until one isn't.
+    pub(crate) fn protect_initial_fds(&mut self) {
+        self.protected = Some(self.open_fds);
+    }
+
+    /// Fails with `__WASI_ENOTSUP` if `fd` is protected from being closed
+    /// or replaced.
+    pub(crate) fn check_unprotected(
+        &self,
+        fd: wasm32::__wasi_fd_t,
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        match self.protected {
+            Some(protected) if fd < protected => Err(wasm32::__WASI_ENOTSUP),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `__WASI_EMFILE` if the guest may not open another fd.
+    pub(crate) fn check_fd_available(&self) -> Result<(), wasm32::__wasi_errno_t> {
+        match self.max_fds {
+            Some(max) if self.open_fds >= max => Err(wasm32::__WASI_EMFILE),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `__WASI_EROFS` if the filesystem mustn't be modified
+    /// through `fd`.
+    pub(crate) fn check_writable(
+        &self,
+        fd: wasm32::__wasi_fd_t,
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        if self.readonly.contains(&fd) {
+            Err(wasm32::__WASI_EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The umask to create files and directories beneath `dirfd` with, if
+    /// it has one.
+    pub(crate) fn umask(&self, dirfd: wasm32::__wasi_fd_t) -> Option<u32> {
+        self.umasks.get(&dirfd).cloned()
+    }
+
+    /// Restricts the rights requested by a `path_open` relative to `dirfd`
+    /// to what the policy allows, returning the new base and inheriting
+    /// rights.
+    ///
+    /// Both are first intersected with the inheriting rights of `dirfd`, so
+    /// the new fd gets the rights it asked for that the directory can pass
+    /// on. The `WasiCtx` would fail the whole `path_open` instead, even
+    /// for guests asking for every right just in case, as libc does.
+    pub(crate) fn open_rights(
+        &self,
+        ctx: &mut WasiCtx,
+        dirfd: wasm32::__wasi_fd_t,
+        oflags: wasm32::__wasi_oflags_t,
+        rights_base: wasm32::__wasi_rights_t,
+        rights_inheriting: wasm32::__wasi_rights_t,
+    ) -> Result<(wasm32::__wasi_rights_t, wasm32::__wasi_rights_t), wasm32::__wasi_errno_t> {
+        let (_, _, inheritable) = fdstat(ctx, dirfd)?;
+        self.restrict_open(dirfd, oflags, inheritable, rights_base, rights_inheriting)
+    }
+
+    /// `open_rights`, given the inheriting rights of `dirfd`.
+    fn restrict_open(
+        &self,
+        dirfd: wasm32::__wasi_fd_t,
+        oflags: wasm32::__wasi_oflags_t,
+        inheritable: wasm32::__wasi_rights_t,
+        rights_base: wasm32::__wasi_rights_t,
+        rights_inheriting: wasm32::__wasi_rights_t,
+    ) -> Result<(wasm32::__wasi_rights_t, wasm32::__wasi_rights_t), wasm32::__wasi_errno_t> {
+        let rights_base = rights_base & inheritable;
+        let rights_inheriting = rights_inheriting & inheritable;
+        if self.create_only.contains(&dirfd) {
+            // anything not created by this very call may already exist
+            let create = wasm32::__WASI_O_CREAT | wasm32::__WASI_O_EXCL;
+            let writes = (rights_base | rights_inheriting) & WRITE_RIGHTS != 0;
+            if writes && oflags & create != create {
+                return Err(wasm32::__WASI_ENOTCAPABLE);
+            }
+        }
+        if !self.readonly.contains(&dirfd) {
+            return Ok((rights_base, rights_inheriting));
+        }
+        if oflags & WRITE_OFLAGS != 0 {
+            return Err(wasm32::__WASI_EROFS);
+        }
+        Ok((rights_base & !WRITE_RIGHTS, rights_inheriting & !WRITE_RIGHTS))
+    }
+
+    /// Records that `fd` was opened relative to `dirfd`.
+    pub(crate) fn opened(&mut self, dirfd: wasm32::__wasi_fd_t, fd: wasm32::__wasi_fd_t) {
+        self.open_fds += 1;
+        inherit(&mut self.readonly, dirfd, fd);
+        inherit(&mut self.create_only, dirfd, fd);
+        match self.umasks.get(&dirfd).cloned() {
+            Some(umask) => self.umasks.insert(fd, umask),
+            None => self.umasks.remove(&fd),
+        };
+    }
+
+    pub(crate) fn closed(&mut self, fd: wasm32::__wasi_fd_t) {
+        self.open_fds = self.open_fds.saturating_sub(1);
+        self.readonly.remove(&fd);
+        self.create_only.remove(&fd);
+        self.umasks.remove(&fd);
+    }
+
+    pub(crate) fn renumbered(&mut self, from: wasm32::__wasi_fd_t, to: wasm32::__wasi_fd_t) {
+        // `to` was closed to make room for `from`
+        if from != to {
+            self.open_fds = self.open_fds.saturating_sub(1);
+        }
+        for fds in &mut [&mut self.readonly, &mut self.create_only] {
+            fds.remove(&to);
+            if fds.remove(&from) {
+                fds.insert(to);
+            }
+        }
+        match self.umasks.remove(&from) {
+            Some(umask) => self.umasks.insert(to, umask),
+            None => self.umasks.remove(&to),
+        };
+    }
+}
+
+/// Returns the type of `fd` and its base and inheriting rights.
+pub(crate) fn fdstat(
+    ctx: &mut WasiCtx,
+    fd: wasm32::__wasi_fd_t,
+) -> Result<
+    (
+        wasm32::__wasi_filetype_t,
+        wasm32::__wasi_rights_t,
+        wasm32::__wasi_rights_t,
+    ),
+    wasm32::__wasi_errno_t,
+> {
+    let mut fdstat = [0; 24];
+    let errno = hostcalls::fd_fdstat_get(ctx, &mut fdstat, fd, 0);
+    if errno != wasm32::__WASI_ESUCCESS {
+        return Err(errno);
+    }
+    let rights = |offset: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&fdstat[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    Ok((fdstat[0], rights(8), rights(16)))
+}
+
+/// Marks `fd` as restricted in `fds` if `dirfd`, which it was opened
+/// relative to, is.
+fn inherit(
+    fds: &mut HashSet<wasm32::__wasi_fd_t>,
+    dirfd: wasm32::__wasi_fd_t,
+    fd: wasm32::__wasi_fd_t,
+) {
+    if fds.contains(&dirfd) {
+        fds.insert(fd);
+    } else {
+        fds.remove(&fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIR: wasm32::__wasi_fd_t = 3;
+    const CREATE: wasm32::__wasi_oflags_t = wasm32::__WASI_O_CREAT | wasm32::__WASI_O_EXCL;
+
+    /// Every right WASI defines, from `__WASI_RIGHT_FD_DATASYNC` (bit 0) to
+    /// `__WASI_RIGHT_SOCK_SHUTDOWN` (bit 28), one at a time.
+    fn rights() -> impl Iterator<Item = wasm32::__wasi_rights_t> {
+        (0..29).map(|bit| 1 << bit)
+    }
+
+    fn policy(readonly: bool, create_only: bool) -> FsPolicy {
+        let mut policy = FsPolicy::default();
+        if readonly {
+            policy.readonly.insert(DIR);
+        }
+        if create_only {
+            policy.create_only.insert(DIR);
+        }
+        policy
+    }
+
+    #[test]
+    fn intersects_with_inheritable_rights() {
+        let policy = policy(false, false);
+        for right in rights() {
+            // asking for everything gets just what the directory passes on
+            assert_eq!(
+                policy.restrict_open(DIR, 0, right, !0, !0),
+                Ok((right, right))
+            );
+            // and asking for something it doesn't pass on gets nothing
+            assert_eq!(
+                policy.restrict_open(DIR, 0, !right, right, right),
+                Ok((0, 0))
+            );
+        }
+    }
+
+    #[test]
+    fn read_only_drops_write_rights() {
+        let policy = policy(true, false);
+        for right in rights() {
+            let kept = if right & WRITE_RIGHTS != 0 { 0 } else { right };
+            assert_eq!(
+                policy.restrict_open(DIR, 0, !0, right, right),
+                Ok((kept, kept)),
+                "right {:#x}",
+                right
+            );
+        }
+        for &oflags in &[
+            wasm32::__WASI_O_CREAT,
+            wasm32::__WASI_O_EXCL,
+            wasm32::__WASI_O_TRUNC,
+        ] {
+            assert_eq!(
+                policy.restrict_open(DIR, oflags, !0, wasm32::__WASI_RIGHT_FD_READ, 0),
+                Err(wasm32::__WASI_EROFS)
+            );
+        }
+        // other directories are left alone
+        assert_eq!(
+            policy.restrict_open(DIR + 1, 0, !0, WRITE_RIGHTS, 0),
+            Ok((WRITE_RIGHTS, 0))
+        );
+    }
+
+    #[test]
+    fn create_only_writes_only_new_files() {
+        let policy = policy(false, true);
+        for right in rights() {
+            let existing = policy.restrict_open(DIR, wasm32::__WASI_O_CREAT, !0, right, 0);
+            let inherited = policy.restrict_open(DIR, 0, !0, 0, right);
+            if right & WRITE_RIGHTS != 0 {
+                assert_eq!(
+                    existing,
+                    Err(wasm32::__WASI_ENOTCAPABLE),
+                    "right {:#x}",
+                    right
+                );
+                assert_eq!(
+                    inherited,
+                    Err(wasm32::__WASI_ENOTCAPABLE),
+                    "right {:#x}",
+                    right
+                );
+            } else {
+                assert_eq!(existing, Ok((right, 0)), "right {:#x}", right);
+                assert_eq!(inherited, Ok((0, right)), "right {:#x}", right);
+            }
+            assert_eq!(
+                policy.restrict_open(DIR, CREATE, !0, right, right),
+                Ok((right, right))
+            );
+        }
+        // a write right the directory can't pass on doesn't count
+        assert_eq!(
+            policy.restrict_open(DIR, 0, !WRITE_RIGHTS, !0, 0),
+            Ok((!WRITE_RIGHTS, 0))
+        );
+    }
+
+    #[test]
+    fn stdio_only() {
+        let mut policy = FsPolicy::default();
+        assert_eq!(policy.check_stdio(DIR), Ok(()));
+        policy.allow_only_stdio(true);
+        for fd in 0..3 {
+            assert_eq!(policy.check_stdio(fd), Ok(()));
+        }
+        assert_eq!(policy.check_stdio(DIR), Err(wasm32::__WASI_EBADF));
+    }
+
+    #[test]
+    fn read_only_and_create_only() {
+        let policy = policy(true, true);
+        assert_eq!(
+            policy.restrict_open(DIR, CREATE, !0, WRITE_RIGHTS, 0),
+            Err(wasm32::__WASI_EROFS)
+        );
+        assert_eq!(
+            policy.restrict_open(DIR, 0, !0, wasm32::__WASI_RIGHT_FD_WRITE, 0),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            policy.restrict_open(DIR, 0, !0, wasm32::__WASI_RIGHT_FD_READ, 0),
+            Ok((wasm32::__WASI_RIGHT_FD_READ, 0))
+        );
+    }
+}
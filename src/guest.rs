@@ -0,0 +1,59 @@
+use wasi_common::wasm32;
+
+/// Returns the range of guest memory covering `len` bytes at `ptr`, or
+/// `__WASI_EFAULT` if that isn't entirely within `memory`.
+pub(crate) fn range(
+    memory: &[u8],
+    ptr: wasm32::uintptr_t,
+    len: usize,
+) -> Result<std::ops::Range<usize>, wasm32::__wasi_errno_t> {
+    let start = ptr as usize;
+    match start.checked_add(len) {
+        Some(end) if end <= memory.len() => Ok(start..end),
+        _ => Err(wasm32::__WASI_EFAULT),
+    }
+}
+
+/// Reads the `u32` the guest has at `ptr`, e.g. an fd written back by a
+/// hostcall.
+pub(crate) fn read_u32(memory: &[u8], ptr: wasm32::uintptr_t) -> Option<u32> {
+    let range = range(memory, ptr, 4).ok()?;
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&memory[range]);
+    Some(u32::from_le_bytes(buf))
+}
+
+/// Reads the `u64` the guest has at `ptr`, e.g. an offset written back by
+/// a hostcall.
+pub(crate) fn read_u64(memory: &[u8], ptr: wasm32::uintptr_t) -> Option<u64> {
+    let range = range(memory, ptr, 8).ok()?;
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&memory[range]);
+    Some(u64::from_le_bytes(buf))
+}
+
+/// Size of an iovec in guest memory: a `u32` pointer and a `u32` length.
+pub(crate) const IOVEC_SIZE: u32 = 8;
+
+/// Returns the range of guest memory the `i`th of the iovecs at `iovs`
+/// covers, or `None` if either the iovec or what it points to isn't
+/// entirely within `memory`.
+pub(crate) fn iovec(
+    memory: &[u8],
+    iovs: wasm32::uintptr_t,
+    i: u32,
+) -> Option<std::ops::Range<usize>> {
+    let iov = iovs.checked_add(i.checked_mul(IOVEC_SIZE)?)?;
+    let buf = read_u32(memory, iov)?;
+    let len = read_u32(memory, iov.checked_add(4)?)?;
+    range(memory, buf, len as usize).ok()
+}
+
+/// Returns the total length of the `iovs_len` iovecs at `iovs`, skipping
+/// those not entirely within `memory`, which the hostcall fails on.
+pub(crate) fn iovecs_len(memory: &[u8], iovs: wasm32::uintptr_t, iovs_len: wasm32::size_t) -> u64 {
+    (0..iovs_len)
+        .filter_map(|i| iovec(memory, iovs, i))
+        .map(|range| range.len() as u64)
+        .sum()
+}
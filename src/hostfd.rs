@@ -0,0 +1,474 @@
+use log::debug;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::FromRawFd;
+use wasi_common::wasm32;
+
+use super::resolve;
+
+/// The runtime's own host handles on the guest's fds, next to the
+/// `WasiCtx`'s, which the pinned version keeps private.
+///
+/// Only fds opened beneath a preopen have one: the preopens themselves,
+/// and what `path_open` opens through them, resolved again beneath the
+/// directory. They're opened without any access to the file's contents, to
+/// resolve paths from and look the file up with.
+///
+/// Next to each handle is the guest's position in the file, as the guest
+/// sees it: it's kept here, as the `WasiCtx`'s own is only to be had with
+/// the rights to `fd_tell`, and is ahead of the guest's while something is
+/// read ahead on the fd.
+#[derive(Default)]
+pub(crate) struct HostFds {
+    files: HashMap<wasm32::__wasi_fd_t, Handle>,
+}
+
+struct Handle {
+    file: File,
+    /// Where the guest's next `fd_read` or `fd_write` starts, or `None` if
+    /// that's the end of the file, after a write in append mode.
+    offset: Option<u64>,
+    append: bool,
+}
+
+impl Handle {
+    fn new(file: File) -> Self {
+        Self {
+            file,
+            offset: Some(0),
+            append: false,
+        }
+    }
+}
+
+impl HostFds {
+    /// Records `dir` as the handle on the preopen `fd`.
+    pub(crate) fn preopened(&mut self, fd: wasm32::__wasi_fd_t, dir: File) {
+        self.files.insert(fd, Handle::new(dir));
+    }
+
+    /// Fails with `__WASI_ENOTCAPABLE` if `path`, relative to `dirfd`,
+    /// resolves outside of it, see `resolve::check_beneath`. Paths relative
+    /// to fds without a handle are left to the `WasiCtx`.
+    pub(crate) fn check_beneath(
+        &self,
+        dirfd: wasm32::__wasi_fd_t,
+        path: &[u8],
+        follow: bool,
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        match self.files.get(&dirfd) {
+            Some(dir) => resolve::check_beneath(&dir.file, path, follow),
+            None => Ok(()),
+        }
+    }
+
+    /// Records that `fd` was opened at `path` relative to `dirfd`, opening
+    /// a handle on it if `dirfd` has one. Returns whether it got one.
+    pub(crate) fn opened(
+        &mut self,
+        dirfd: wasm32::__wasi_fd_t,
+        path: &[u8],
+        follow: bool,
+        fd: wasm32::__wasi_fd_t,
+    ) -> bool {
+        let file = match self.files.get(&dirfd) {
+            Some(dir) => match resolve::open_beneath(&dir.file, path, follow) {
+                Ok(file) => Some(file),
+                Err(err) => {
+                    debug!("couldn't open a handle on fd {}: {}", fd, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        let opened = file.is_some();
+        match file {
+            Some(file) => self.files.insert(fd, Handle::new(file)),
+            None => self.files.remove(&fd),
+        };
+        opened
+    }
+
+    /// Whether `path`, relative to `dirfd`, is there, if `dirfd` has a
+    /// handle to tell with.
+    pub(crate) fn exists(
+        &self,
+        dirfd: wasm32::__wasi_fd_t,
+        path: &[u8],
+        follow: bool,
+    ) -> Option<bool> {
+        let dir = self.files.get(&dirfd)?;
+        Some(resolve::open_beneath(&dir.file, path, follow).is_ok())
+    }
+
+    /// Sets the permissions of the file open as `fd` to `mode`.
+    pub(crate) fn chmod(
+        &self,
+        fd: wasm32::__wasi_fd_t,
+        mode: u32,
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        let handle = self.files.get(&fd).ok_or(wasm32::__WASI_EBADF)?;
+        set_mode(&handle.file, mode).map_err(|err| errno(&err))
+    }
+
+    /// Sets the permissions of what's at `path`, relative to `dirfd`, to
+    /// `mode`, without following a symlink at its end.
+    pub(crate) fn chmod_at(
+        &self,
+        dirfd: wasm32::__wasi_fd_t,
+        path: &[u8],
+        mode: u32,
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        let dir = self.files.get(&dirfd).ok_or(wasm32::__WASI_EBADF)?;
+        resolve::open_beneath(&dir.file, path, false)
+            .and_then(|file| set_mode(&file, mode))
+            .map_err(|err| errno(&err))
+    }
+
+    /// Creates an anonymous file in the directory `dirfd`, open for reading
+    /// and writing, which is gone once closed unless `link` names it. Its
+    /// permissions are `0o666` less `umask`, or else the process's.
+    ///
+    /// Fails with `__WASI_ENOTSUP` if `dirfd` has no handle, or its
+    /// filesystem can't create such files.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn tmpfile(
+        &self,
+        dirfd: wasm32::__wasi_fd_t,
+        umask: Option<u32>,
+    ) -> Result<File, wasm32::__wasi_errno_t> {
+        let dir = self.files.get(&dirfd).ok_or(wasm32::__WASI_ENOTSUP)?;
+        let mode = 0o666 & !umask.unwrap_or(0);
+        let flags = libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC;
+        let fd = unsafe {
+            libc::openat(
+                dir.file.as_raw_fd(),
+                b".\0".as_ptr() as *const libc::c_char,
+                flags,
+                mode as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                // older kernels take `O_TMPFILE` for `O_DIRECTORY`
+                Some(libc::EOPNOTSUPP) | Some(libc::EISDIR) => wasm32::__WASI_ENOTSUP,
+                _ => errno(&err),
+            });
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        if umask.is_some() {
+            // rather than the process's umask on top
+            set_mode(&file, mode).map_err(|err| errno(&err))?;
+        }
+        Ok(file)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn tmpfile(
+        &self,
+        _dirfd: wasm32::__wasi_fd_t,
+        _umask: Option<u32>,
+    ) -> Result<File, wasm32::__wasi_errno_t> {
+        Err(wasm32::__WASI_ENOTSUP)
+    }
+
+    /// Names `file`, from `tmpfile`, `name` in the directory `dirfd`.
+    /// Fails with `__WASI_EEXIST`, rather than replace it, if there's
+    /// already something by that name.
+    pub(crate) fn link(
+        &self,
+        file: &File,
+        dirfd: wasm32::__wasi_fd_t,
+        name: &[u8],
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        let dir = self.files.get(&dirfd).ok_or(wasm32::__WASI_EBADF)?;
+        // linking the fd itself, with `AT_EMPTY_PATH`, takes a capability
+        let from = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap();
+        let to = CString::new(name).map_err(|_| wasm32::__WASI_EILSEQ)?;
+        let linked = unsafe {
+            libc::linkat(
+                libc::AT_FDCWD,
+                from.as_ptr(),
+                dir.file.as_raw_fd(),
+                to.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            )
+        };
+        if linked != 0 {
+            return Err(errno(&io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Removes the name `name`, which `link` gave `file`, from the
+    /// directory `dirfd`, unless it's been given to another file since.
+    pub(crate) fn unlink(
+        &self,
+        file: &File,
+        dirfd: wasm32::__wasi_fd_t,
+        name: &[u8],
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        let dir = self.files.get(&dirfd).ok_or(wasm32::__WASI_EBADF)?;
+        let name = CString::new(name).map_err(|_| wasm32::__WASI_EILSEQ)?;
+        let ours = file.metadata().map_err(|err| errno(&err))?;
+        let mut named: libc::stat = unsafe { std::mem::zeroed() };
+        let found = unsafe {
+            libc::fstatat(
+                dir.file.as_raw_fd(),
+                name.as_ptr(),
+                &mut named,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if found != 0 {
+            return Err(errno(&io::Error::last_os_error()));
+        }
+        if named.st_dev as u64 != ours.dev() || named.st_ino as u64 != ours.ino() {
+            return Err(wasm32::__WASI_EIO);
+        }
+        if unsafe { libc::unlinkat(dir.file.as_raw_fd(), name.as_ptr(), 0) } != 0 {
+            return Err(errno(&io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Returns where an `fd_write` to `fd` starts, if it's a regular file
+    /// with a handle: at its end if it's open for appending, or else at the
+    /// guest's position. What's buffered for `fd` has to be written first.
+    pub(crate) fn write_offset(
+        &self,
+        fd: wasm32::__wasi_fd_t,
+    ) -> Result<Option<u64>, wasm32::__wasi_errno_t> {
+        let handle = match self.files.get(&fd) {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+        let metadata = handle.file.metadata().map_err(|err| errno(&err))?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        match handle.offset {
+            Some(offset) if !handle.append => Ok(Some(offset)),
+            _ => Ok(Some(metadata.len())),
+        }
+    }
+
+    /// Records that the guest read `len` bytes from `fd`.
+    pub(crate) fn read(&mut self, fd: wasm32::__wasi_fd_t, len: u64) {
+        if let Some(handle) = self.files.get_mut(&fd) {
+            handle.offset = handle.offset.map(|offset| offset.saturating_add(len));
+        }
+    }
+
+    /// Records that the guest wrote `len` bytes to `fd`.
+    pub(crate) fn written(&mut self, fd: wasm32::__wasi_fd_t, len: u64) {
+        if let Some(handle) = self.files.get_mut(&fd) {
+            handle.offset = match handle.offset {
+                Some(offset) if !handle.append => Some(offset.saturating_add(len)),
+                _ => None,
+            };
+        }
+    }
+
+    /// Records that the guest moved its position in `fd` to `offset`.
+    pub(crate) fn seeked(&mut self, fd: wasm32::__wasi_fd_t, offset: u64) {
+        if let Some(handle) = self.files.get_mut(&fd) {
+            handle.offset = Some(offset);
+        }
+    }
+
+    /// Records that `fd` now has the fd flags `flags`. What's buffered for
+    /// `fd` has to be written first.
+    pub(crate) fn flags_changed(
+        &mut self,
+        fd: wasm32::__wasi_fd_t,
+        flags: wasm32::__wasi_fdflags_t,
+    ) {
+        if let Some(handle) = self.files.get_mut(&fd) {
+            handle.append = flags & wasm32::__WASI_FDFLAG_APPEND != 0;
+            if handle.offset.is_none() && !handle.append {
+                // the position it was left at, at the end of the file
+                match handle.file.metadata() {
+                    Ok(metadata) => handle.offset = Some(metadata.len()),
+                    Err(err) => debug!("couldn't stat fd {}: {}", fd, err),
+                }
+            }
+        }
+    }
+
+    pub(crate) fn closed(&mut self, fd: wasm32::__wasi_fd_t) {
+        self.files.remove(&fd);
+    }
+
+    pub(crate) fn renumbered(&mut self, from: wasm32::__wasi_fd_t, to: wasm32::__wasi_fd_t) {
+        match self.files.remove(&from) {
+            Some(handle) => self.files.insert(to, handle),
+            None => self.files.remove(&to),
+        };
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_mode(file: &File, mode: u32) -> io::Result<()> {
+    // `fchmod` refuses `O_PATH` handles, but not their link in /proc
+    let link = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap();
+    if unsafe { libc::fchmodat(libc::AT_FDCWD, link.as_ptr(), mode as libc::mode_t, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mode(file: &File, mode: u32) -> io::Result<()> {
+    if unsafe { libc::fchmod(file.as_raw_fd(), mode as libc::mode_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The errno to fail a syscall with for `err`.
+fn errno(err: &io::Error) -> wasm32::__wasi_errno_t {
+    match err.raw_os_error() {
+        Some(libc::EACCES) => wasm32::__WASI_EACCES,
+        Some(libc::EPERM) => wasm32::__WASI_EPERM,
+        Some(libc::ENOENT) => wasm32::__WASI_ENOENT,
+        Some(libc::EROFS) => wasm32::__WASI_EROFS,
+        Some(libc::EEXIST) => wasm32::__WASI_EEXIST,
+        Some(libc::ENOSPC) => wasm32::__WASI_ENOSPC,
+        _ => wasm32::__WASI_EIO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    const DIR: wasm32::__wasi_fd_t = 3;
+    const FILE: wasm32::__wasi_fd_t = 4;
+
+    struct Dir(TempDir);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            let dir = TempDir::new(&format!("hostfd-{}", name));
+            std::fs::create_dir(dir.join("sub")).unwrap();
+            std::fs::write(dir.join("file"), b"0123456789").unwrap();
+            Self(dir)
+        }
+
+        fn host_fds(&self) -> HostFds {
+            let mut fds = HostFds::default();
+            fds.preopened(DIR, self.0.open());
+            assert!(fds.opened(DIR, b"file", false, FILE));
+            fds
+        }
+
+        fn grow(&self, len: usize) {
+            let mut contents = std::fs::read(self.0.join("file")).unwrap();
+            contents.resize(contents.len() + len, 0);
+            std::fs::write(self.0.join("file"), contents).unwrap();
+        }
+
+        fn mode(&self, path: &str) -> u32 {
+            let metadata = std::fs::metadata(self.0.join(path)).unwrap();
+            metadata.permissions().mode() & 0o777
+        }
+    }
+
+    #[test]
+    fn tracks_the_position() {
+        let dir = Dir::new("position");
+        let mut fds = dir.host_fds();
+        assert_eq!(fds.write_offset(FILE), Ok(Some(0)));
+        fds.read(FILE, 4);
+        assert_eq!(fds.write_offset(FILE), Ok(Some(4)));
+        fds.written(FILE, 3);
+        assert_eq!(fds.write_offset(FILE), Ok(Some(7)));
+        fds.seeked(FILE, 2);
+        assert_eq!(fds.write_offset(FILE), Ok(Some(2)));
+        fds.renumbered(FILE, 5);
+        assert_eq!(fds.write_offset(FILE), Ok(None));
+        assert_eq!(fds.write_offset(5), Ok(Some(2)));
+        fds.closed(5);
+        assert_eq!(fds.write_offset(5), Ok(None));
+    }
+
+    #[test]
+    fn appends_at_the_end() {
+        let dir = Dir::new("append");
+        let mut fds = dir.host_fds();
+        fds.flags_changed(FILE, wasm32::__WASI_FDFLAG_APPEND);
+        assert_eq!(fds.write_offset(FILE), Ok(Some(10)));
+        fds.written(FILE, 5);
+        dir.grow(5);
+        assert_eq!(fds.write_offset(FILE), Ok(Some(15)));
+        // the position is left at the end
+        fds.flags_changed(FILE, 0);
+        assert_eq!(fds.write_offset(FILE), Ok(Some(15)));
+        fds.written(FILE, 1);
+        assert_eq!(fds.write_offset(FILE), Ok(Some(16)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn names_a_tmpfile_only_while_asked() {
+        let dir = Dir::new("tmpfile");
+        let fds = dir.host_fds();
+        let tmpfile = || match fds.tmpfile(DIR, Some(0o077)) {
+            Err(wasm32::__WASI_ENOTSUP) => None,
+            file => Some(file.unwrap()),
+        };
+        let file = match tmpfile() {
+            Some(file) => file,
+            None => return,
+        };
+        assert_eq!(fds.link(&file, DIR, b"file"), Err(wasm32::__WASI_EEXIST));
+        fds.link(&file, DIR, b"tmp").unwrap();
+        assert_eq!(dir.mode("tmp"), 0o600);
+        fds.unlink(&file, DIR, b"tmp").unwrap();
+        assert!(!dir.0.join("tmp").exists());
+
+        // the name is left alone once it's another file's
+        let file = tmpfile().unwrap();
+        fds.link(&file, DIR, b"tmp").unwrap();
+        std::fs::remove_file(dir.0.join("tmp")).unwrap();
+        std::fs::write(dir.0.join("tmp"), b"theirs").unwrap();
+        assert_eq!(fds.unlink(&file, DIR, b"tmp"), Err(wasm32::__WASI_EIO));
+        assert_eq!(std::fs::read(dir.0.join("tmp")).unwrap(), b"theirs");
+    }
+
+    #[test]
+    fn only_limits_regular_files() {
+        let dir = Dir::new("types");
+        let mut fds = dir.host_fds();
+        assert_eq!(fds.write_offset(DIR), Ok(None));
+        assert!(fds.opened(DIR, b"sub", false, 5));
+        assert_eq!(fds.write_offset(5), Ok(None));
+        // stdio has no handle
+        assert_eq!(fds.write_offset(1), Ok(None));
+        assert!(!fds.opened(DIR, b"missing", false, 6));
+        assert!(!fds.opened(1, b"file", false, 6));
+    }
+
+    #[test]
+    fn sets_modes() {
+        let dir = Dir::new("modes");
+        let fds = dir.host_fds();
+        assert_eq!(fds.exists(DIR, b"file", false), Some(true));
+        assert_eq!(fds.exists(DIR, b"missing", false), Some(false));
+        assert_eq!(fds.exists(1, b"file", false), None);
+        fds.chmod(FILE, 0o600).unwrap();
+        assert_eq!(dir.mode("file"), 0o600);
+        fds.chmod_at(DIR, b"sub", 0o700).unwrap();
+        assert_eq!(dir.mode("sub"), 0o700);
+        assert_eq!(fds.chmod(5, 0o644), Err(wasm32::__WASI_EBADF));
+    }
+}
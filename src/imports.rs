@@ -0,0 +1,296 @@
+use super::instantiate::{WasiAbi, ENARX_NAMESPACE};
+use super::syscalls;
+use cranelift_codegen::ir::types::{self, Type};
+use std::fmt;
+use wasmparser::{ImportSectionEntryType, ModuleReader, SectionCode};
+
+/// A function imported by a wasm module.
+#[derive(Debug, Clone)]
+pub struct FunctionImport {
+    pub module: String,
+    pub field: String,
+    pub params: Vec<Type>,
+    pub results: Vec<Type>,
+}
+
+/// Returns every function import of the module encoded in `wasm`.
+pub fn function_imports(wasm: &[u8]) -> Result<Vec<FunctionImport>, ImportError> {
+    let mut signatures = Vec::new();
+    let mut imports = Vec::new();
+    let mut reader = ModuleReader::new(wasm).map_err(ImportError::parse)?;
+    while !reader.eof() {
+        let section = reader.read().map_err(ImportError::parse)?;
+        match section.code {
+            SectionCode::Type => {
+                for ty in section
+                    .get_type_section_reader()
+                    .map_err(ImportError::parse)?
+                {
+                    let ty = ty.map_err(ImportError::parse)?;
+                    let params = value_types(&ty.params)?;
+                    let results = value_types(&ty.returns)?;
+                    signatures.push((params, results));
+                }
+            }
+            SectionCode::Import => {
+                for import in section
+                    .get_import_section_reader()
+                    .map_err(ImportError::parse)?
+                {
+                    let import = import.map_err(ImportError::parse)?;
+                    if let ImportSectionEntryType::Function(index) = import.ty {
+                        let (params, results) = signatures
+                            .get(index as usize)
+                            .cloned()
+                            .ok_or_else(|| {
+                                ImportError::Parse(format!(
+                                    "import {}::{} refers to unknown type {}",
+                                    import.module, import.field, index
+                                ))
+                            })?;
+                        imports.push(FunctionImport {
+                            module: import.module.to_owned(),
+                            field: import.field.to_owned(),
+                            params,
+                            results,
+                        });
+                    }
+                }
+                // Imports always precede the code, nothing left for us.
+                break;
+            }
+            _ => {}
+        }
+    }
+    Ok(imports)
+}
+
+fn value_types(tys: &[wasmparser::Type]) -> Result<Vec<Type>, ImportError> {
+    tys.iter()
+        .map(|ty| match ty {
+            wasmparser::Type::I32 => Ok(types::I32),
+            wasmparser::Type::I64 => Ok(types::I64),
+            wasmparser::Type::F32 => Ok(types::F32),
+            wasmparser::Type::F64 => Ok(types::F64),
+            ty => Err(ImportError::Parse(format!(
+                "unsupported value type {:?} in function signature",
+                ty
+            ))),
+        })
+        .collect()
+}
+
+/// Returns the WASI ABIs `wasm` imports functions from, along with the
+/// names of the functions it imports under each.
+pub fn wasi_imports(wasm: &[u8]) -> Result<Vec<(WasiAbi, Vec<String>)>, ImportError> {
+    let mut abis: Vec<(WasiAbi, Vec<String>)> = Vec::new();
+    for import in function_imports(wasm)? {
+        let abi = match WasiAbi::from_namespace(&import.module) {
+            Some(abi) => abi,
+            None => continue,
+        };
+        match abis.iter_mut().find(|(a, _)| *a == abi) {
+            Some((_, names)) => names.push(import.field),
+            None => abis.push((abi, vec![import.field])),
+        }
+    }
+    Ok(abis)
+}
+
+/// Checks that every function `wasm` imports from `namespace` is provided by
+/// an instance created with the given `prefix`, and with the same signature.
+///
+/// Modules built against a different WASI snapshot otherwise only fail deep
+/// inside wasmtime's linking, without saying which import is at fault.
+pub fn validate_imports(wasm: &[u8], namespace: &str, prefix: &str) -> Result<(), ImportError> {
+    let mismatches: Vec<_> = function_imports(wasm)?
+        .into_iter()
+        .filter(|import| import.module == namespace)
+        .filter_map(|import| check_import(import, prefix, &[]))
+        .collect();
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(ImportError::Mismatch(mismatches))
+    }
+}
+
+/// Checks that every function `wasm` imports can be resolved against the
+/// WASI instances created with the given `prefix`, exporting the syscalls
+/// and `host_functions`, and reports every one that can't, whatever module
+/// it's imported from.
+pub fn check_imports(
+    wasm: &[u8],
+    prefix: &str,
+    host_functions: &[&str],
+) -> Result<(), ImportError> {
+    let mismatches: Vec<_> = function_imports(wasm)?
+        .into_iter()
+        .filter_map(|import| check_import(import, prefix, host_functions))
+        .collect();
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(ImportError::Mismatch(mismatches))
+    }
+}
+
+fn check_import(
+    import: FunctionImport,
+    prefix: &str,
+    host_functions: &[&str],
+) -> Option<ImportMismatch> {
+    let name = if import.field.starts_with(prefix) {
+        Some(&import.field[prefix.len()..])
+    } else {
+        None
+    };
+    let expected = name
+        .and_then(syscalls::signature)
+        .map(|(params, results)| (params.to_vec(), results.to_vec()));
+
+    if WasiAbi::from_namespace(&import.module).is_some() {
+        if name.map_or(false, |name| host_functions.contains(&name)) {
+            return None;
+        }
+        let suggestion = match expected {
+            Some((ref params, ref results))
+                if *params == import.params && *results == import.results =>
+            {
+                return None;
+            }
+            Some(_) => "the module may have been built against another WASI snapshot".to_owned(),
+            None if name.is_none() && syscalls::signature(&import.field).is_some() => {
+                format!("syscalls are exported with the prefix {:?}", prefix)
+            }
+            None => "this runtime doesn't implement it".to_owned(),
+        };
+        return Some(ImportMismatch {
+            import,
+            expected,
+            suggestion: Some(suggestion),
+        });
+    }
+
+    if import.module == ENARX_NAMESPACE {
+        let suggestion = match syscalls::enarx::signature(&import.field) {
+            Some((params, results)) if params == import.params && results == import.results => {
+                return None;
+            }
+            Some((params, results)) => {
+                return Some(ImportMismatch {
+                    import,
+                    expected: Some((params.to_vec(), results.to_vec())),
+                    suggestion: None,
+                })
+            }
+            None => "this runtime doesn't implement it".to_owned(),
+        };
+        return Some(ImportMismatch {
+            import,
+            expected: None,
+            suggestion: Some(suggestion),
+        });
+    }
+
+    let namespaces = WasiAbi::ALL
+        .iter()
+        .map(|abi| abi.namespace())
+        .collect::<Vec<_>>()
+        .join(" or ");
+    let suggestion = if expected.is_some() {
+        format!("it's a WASI syscall, import it from {}", namespaces)
+    } else if import.module.starts_with("wasi") {
+        format!("unknown WASI namespace, expected {}", namespaces)
+    } else {
+        format!(
+            "only WASI and the {} hostcalls are provided, other imports must be provided as \
+             host functions",
+            ENARX_NAMESPACE
+        )
+    };
+    Some(ImportMismatch {
+        import,
+        expected: None,
+        suggestion: Some(suggestion),
+    })
+}
+
+/// An import which doesn't line up with what this crate provides.
+#[derive(Debug, Clone)]
+pub struct ImportMismatch {
+    pub import: FunctionImport,
+    /// Signature of our syscall by that name, or `None` if there's none.
+    pub expected: Option<(Vec<Type>, Vec<Type>)>,
+    /// What's likely to be wrong.
+    pub suggestion: Option<String>,
+}
+
+impl fmt::Display for ImportMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}::{}: ", self.import.module, self.import.field)?;
+        match self.expected {
+            Some((ref params, ref results)) => write!(
+                f,
+                "expected {}, but the module imports it as {}",
+                DisplaySignature(params, results),
+                DisplaySignature(&self.import.params, &self.import.results),
+            )?,
+            None => write!(
+                f,
+                "unresolved import {}",
+                DisplaySignature(&self.import.params, &self.import.results),
+            )?,
+        }
+        if let Some(ref suggestion) = self.suggestion {
+            write!(f, " ({})", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+struct DisplaySignature<'a>(&'a [Type], &'a [Type]);
+
+impl<'a> fmt::Display for DisplaySignature<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |tys: &[Type]| {
+            tys.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        write!(f, "({}) -> ({})", list(self.0), list(self.1))
+    }
+}
+
+/// An error returned while inspecting a module's imports.
+#[derive(Debug)]
+pub enum ImportError {
+    /// The module couldn't be decoded.
+    Parse(String),
+    /// Some imports don't match the syscalls and host functions provided.
+    Mismatch(Vec<ImportMismatch>),
+}
+
+impl ImportError {
+    fn parse(err: wasmparser::BinaryReaderError) -> Self {
+        ImportError::Parse(format!("{} (at offset {})", err.message, err.offset))
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Parse(msg) => write!(f, "couldn't parse module: {}", msg),
+            ImportError::Mismatch(mismatches) => {
+                write!(f, "module imports can't be resolved:")?;
+                for mismatch in mismatches {
+                    write!(f, "\n  {}", mismatch)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
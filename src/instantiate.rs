@@ -1,148 +1,416 @@
-use super::syscalls;
+use super::builder::WasiInstanceBuilder;
+use super::scope::GlobalExports;
+use super::state::{state_of, SharedState, DEFAULT_MEMORY_EXPORT};
+use super::syscalls::{self, AbiParam, AbiRet};
 use cranelift_codegen::ir::types;
 use cranelift_codegen::{ir, isa};
 use cranelift_entity::PrimaryMap;
 use cranelift_wasm::DefinedFuncIndex;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use log::error;
+use std::any::Any;
+use std::collections::HashSet;
 use std::fs::File;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
 use std::rc::Rc;
 use target_lexicon::HOST;
-use wasi_common::WasiCtxBuilder;
 use wasmtime_environ::{translate_signature, Export, Module};
-use wasmtime_runtime::{Imports, InstanceHandle, InstantiationError, VMFunctionBody};
+use wasmtime_runtime::{Imports, InstanceHandle, InstantiationError, VMContext, VMFunctionBody};
 
 /// Return an instance implementing the "wasi" interface.
 pub fn instantiate_wasi(
     prefix: &str,
-    global_exports: Rc<RefCell<HashMap<String, Option<wasmtime_runtime::Export>>>>,
+    global_exports: GlobalExports,
     preopened_dirs: &[(String, File)],
     argv: &[String],
     environ: &[(String, String)],
+) -> Result<InstanceHandle, InstantiationError> {
+    instantiate_wasi_with_memory(
+        prefix,
+        global_exports,
+        DEFAULT_MEMORY_EXPORT,
+        preopened_dirs,
+        argv,
+        environ,
+    )
+}
+
+/// Return an instance implementing the "wasi" interface, resolving guest
+/// pointers against the linear memory exported as `memory`.
+///
+/// This is needed for modules using the multi-memory proposal, where the
+/// memory the WASI syscalls should operate on isn't necessarily the one
+/// exported as `"memory"`.
+pub fn instantiate_wasi_with_memory(
+    prefix: &str,
+    global_exports: GlobalExports,
+    memory: &str,
+    preopened_dirs: &[(String, File)],
+    argv: &[String],
+    environ: &[(String, String)],
+) -> Result<InstanceHandle, InstantiationError> {
+    // the preopens are handed over to be used, as they always were
+    let mut builder = WasiInstanceBuilder::new()
+        .prefix(prefix)
+        .memory(memory)
+        .args(argv)
+        .envs(environ.iter().cloned())
+        .host_filesystem(true);
+
+    for (dir, f) in preopened_dirs {
+        builder = builder.preopen(
+            dir,
+            f.try_clone().map_err(|err| {
+                InstantiationError::Resource(format!(
+                    "couldn't clone an instance handle to pre-opened dir: {}",
+                    err
+                ))
+            })?,
+        );
+    }
+
+    builder.instantiate(global_exports)
+}
+
+/// A function provided by the embedder, exported next to the WASI syscalls.
+pub struct HostFunction {
+    name: String,
+    params: Vec<ir::Type>,
+    results: Vec<ir::Type>,
+    body: *const VMFunctionBody,
+    /// The closure to call instead of `body`, if any, and the trampolines
+    /// calling it from the slot of the host state it's put in.
+    closure: Option<(Rc<dyn Any>, fn(usize) -> Option<*const VMFunctionBody>)>,
+}
+
+impl HostFunction {
+    /// Describe the host function `body`, to be exported as `name`.
+    ///
+    /// The function is called by JIT code directly, so `body` must point to
+    /// an `unsafe extern "C" fn` taking a `*mut VMContext` followed by
+    /// arguments of the codegen types `params`, and returning `results`. It
+    /// can reach the instance's state through `vmctx_wasi_context`.
+    pub unsafe fn new(
+        name: &str,
+        params: Vec<ir::Type>,
+        results: Vec<ir::Type>,
+        body: *const VMFunctionBody,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            params,
+            results,
+            body,
+            closure: None,
+        }
+    }
+
+    /// Describe a host function calling the closure `f`, to be exported as
+    /// `name`, with the signature of `f`: it takes the instance's
+    /// `VMContext`, to reach its state through `vmctx_wasi_context`, then
+    /// up to four integer arguments, and returns an integer or nothing.
+    ///
+    /// `f` is kept in the instance's host state, in a slot of its own, and
+    /// called from a trampoline generated for its type and slot, so an
+    /// instance can have at most `MAX_HOST_CLOSURES` of them; instantiating
+    /// fails if it has more. A panic in `f` poisons the instance, like one
+    /// in a syscall.
+    pub fn from_closure<Args, F: HostClosure<Args>>(name: &str, f: F) -> Self {
+        let (params, results) = F::signature();
+        Self {
+            name: name.to_owned(),
+            params,
+            results,
+            body: ptr::null(),
+            closure: Some((Rc::new(f), slot_trampoline::<Args, F>)),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Closures which can back a `HostFunction`, taking the `VMContext` and
+/// the arguments `Args`, see `HostFunction::from_closure`.
+pub trait HostClosure<Args>: 'static {
+    #[doc(hidden)]
+    fn signature() -> (Vec<ir::Type>, Vec<ir::Type>);
+    #[doc(hidden)]
+    fn trampoline<S: Slot>() -> *const VMFunctionBody;
+}
+
+/// The number of closure-backed host functions an instance can have.
+pub const MAX_HOST_CLOSURES: usize = 16;
+
+/// Slot of the host state a host closure is put in, known to its
+/// trampoline at compile time.
+#[doc(hidden)]
+pub trait Slot: 'static {
+    const INDEX: usize;
+}
+
+macro_rules! slots {
+    ($($slot:ident = $index:literal),*) => {
+        $(
+            struct $slot;
+
+            impl Slot for $slot {
+                const INDEX: usize = $index;
+            }
+        )*
+
+        /// The trampoline calling a closure of type `F` put in `slot`, if
+        /// there's such a slot.
+        fn slot_trampoline<Args, F>(slot: usize) -> Option<*const VMFunctionBody>
+        where
+            F: HostClosure<Args>,
+        {
+            match slot {
+                $($index => Some(F::trampoline::<$slot>()),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+slots! {
+    S0 = 0, S1 = 1, S2 = 2, S3 = 3, S4 = 4, S5 = 5, S6 = 6, S7 = 7,
+    S8 = 8, S9 = 9, S10 = 10, S11 = 11, S12 = 12, S13 = 13, S14 = 14, S15 = 15
+}
+
+/// The closure the instance of `vmctx` has in slot `S`.
+unsafe fn closure_of<S: Slot>(vmctx: &mut VMContext) -> Option<Rc<dyn Any>> {
+    state_of(vmctx.host_state())?
+        .host_closures
+        .get(S::INDEX)
+        .cloned()
+}
+
+macro_rules! host_closures {
+    ($($arg:ident: $ty:ident),*) => {
+        impl<F, R, $($ty),*> HostClosure<($($ty,)*)> for F
+        where
+            F: Fn(&mut VMContext, $($ty),*) -> R + 'static,
+            R: AbiRet,
+            $($ty: AbiParam,)*
+        {
+            fn signature() -> (Vec<ir::Type>, Vec<ir::Type>) {
+                (
+                    vec![$(<$ty as AbiParam>::CODEGEN_TY),*],
+                    <R as AbiRet>::CODEGEN_TYS.to_vec(),
+                )
+            }
+
+            fn trampoline<S: Slot>() -> *const VMFunctionBody {
+                unsafe extern "C" fn trampoline<S, F, R, $($ty),*>(
+                    vmctx: *mut VMContext,
+                    $($arg: <$ty as AbiParam>::Abi,)*
+                ) -> <R as AbiRet>::Abi
+                where
+                    S: Slot,
+                    F: Fn(&mut VMContext, $($ty),*) -> R + 'static,
+                    R: AbiRet,
+                    $($ty: AbiParam,)*
+                {
+                    // like the syscall shims, don't unwind into JIT code
+                    let r = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let closure = closure_of::<S>(&mut *vmctx)?;
+                        let f = closure.downcast_ref::<F>()?;
+                        Some(f(&mut *vmctx, $(<$ty as AbiParam>::convert($arg)),*).convert())
+                    }));
+                    match r {
+                        Ok(Some(ret)) => ret,
+                        Ok(None) => {
+                            error!("host function called from an instance without its closure");
+                            <R as AbiRet>::fault()
+                        }
+                        Err(payload) => {
+                            syscalls::poison(&mut *vmctx, "host function", &*payload);
+                            <R as AbiRet>::fault()
+                        }
+                    }
+                }
+
+                trampoline::<S, F, R, $($ty),*> as *const VMFunctionBody
+            }
+        }
+    };
+}
+
+host_closures!();
+host_closures!(a: A);
+host_closures!(a: A, b: B);
+host_closures!(a: A, b: B, c: C);
+host_closures!(a: A, b: B, c: C, d: D);
+
+/// The WASI ABIs the syscalls can be exported with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WasiAbi {
+    /// The original ABI.
+    Unstable,
+    /// The first snapshot, which renumbered `__wasi_whence_t`, widened
+    /// `__wasi_filestat_t::st_nlink` and dropped the clock subscription's
+    /// identifier.
+    Preview1,
+}
+
+impl WasiAbi {
+    pub const ALL: [WasiAbi; 2] = [WasiAbi::Unstable, WasiAbi::Preview1];
+
+    /// The module name modules built against this ABI import WASI from.
+    pub fn namespace(self) -> &'static str {
+        match self {
+            WasiAbi::Unstable => "wasi_unstable",
+            WasiAbi::Preview1 => "wasi_snapshot_preview1",
+        }
+    }
+
+    /// The ABI modules importing WASI from `namespace` are built against.
+    pub fn from_namespace(namespace: &str) -> Option<Self> {
+        WasiAbi::ALL
+            .iter()
+            .cloned()
+            .find(|abi| abi.namespace() == namespace)
+    }
+}
+
+/// Module name the Enarx hostcalls, which tell the guest about the keep it
+/// runs in, are imported from.
+pub const ENARX_NAMESPACE: &str = "enarx";
+
+/// The syscalls exported in minimal mode: enough for a pure computation to
+/// read its input from stdin, write its output to stdout, tell the time,
+/// get random numbers and exit.
+pub(crate) const MINIMAL_SYSCALLS: &[&str] = &[
+    "clock_res_get",
+    "clock_time_get",
+    "random_get",
+    "fd_read",
+    "fd_write",
+    "proc_exit",
+];
+
+/// The sets of functions the instances created by this crate export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Interface {
+    /// The WASI syscalls, with the given ABI.
+    Wasi(WasiAbi),
+    /// The Enarx hostcalls.
+    Enarx,
+}
+
+impl Interface {
+    /// The module name modules import the interface from.
+    pub(crate) fn namespace(self) -> &'static str {
+        match self {
+            Interface::Wasi(abi) => abi.namespace(),
+            Interface::Enarx => ENARX_NAMESPACE,
+        }
+    }
+
+    /// The interface modules importing from `namespace` expect.
+    pub(crate) fn from_namespace(namespace: &str) -> Option<Self> {
+        match namespace {
+            ENARX_NAMESPACE => Some(Interface::Enarx),
+            namespace => WasiAbi::from_namespace(namespace).map(Interface::Wasi),
+        }
+    }
+}
+
+/// Create the instance exporting every function of `interface` with
+/// `state` as its host state. WASI instances also export every one of
+/// `host_functions`, and export it all under `prefix`.
+///
+/// If `only` is given, just the functions whose (prefixed) names are in it
+/// get exported, and the rest don't even have a signature generated.
+pub(crate) fn instantiate(
+    prefix: &str,
+    global_exports: GlobalExports,
+    interface: Interface,
+    state: SharedState,
+    host_functions: &[HostFunction],
+    only: Option<&HashSet<String>>,
 ) -> Result<InstanceHandle, InstantiationError> {
     let pointer_type = types::Type::triple_pointer_type(&HOST);
     let mut module = Module::new();
     let mut finished_functions: PrimaryMap<DefinedFuncIndex, *const VMFunctionBody> =
         PrimaryMap::new();
     let call_conv = isa::CallConv::triple_default(&HOST);
+    let exported = |name: &str| only.map_or(true, |only| only.contains(name));
 
-    macro_rules! signature {
-        ($name:ident) => {{
-            let sig = module.signatures.push(translate_signature(
-                ir::Signature {
-                    params: syscalls::$name::params()
-                        .into_iter()
-                        .map(ir::AbiParam::new)
-                        .collect(),
-                    returns: syscalls::$name::results()
-                        .into_iter()
-                        .map(ir::AbiParam::new)
-                        .collect(),
-                    call_conv,
-                },
-                pointer_type,
-            ));
-            let func = module.functions.push(sig);
-            module.exports.insert(
-                prefix.to_owned() + stringify!($name),
-                Export::Function(func),
-            );
-            finished_functions.push(syscalls::$name::SHIM as *const VMFunctionBody);
+    macro_rules! function {
+        ($name:expr, $params:expr, $results:expr, $body:expr) => {{
+            let name: String = $name;
+            if exported(&name) {
+                let sig = module.signatures.push(translate_signature(
+                    ir::Signature {
+                        params: $params.into_iter().map(ir::AbiParam::new).collect(),
+                        returns: $results.into_iter().map(ir::AbiParam::new).collect(),
+                        call_conv,
+                    },
+                    pointer_type,
+                ));
+                let func = module.functions.push(sig);
+                module.exports.insert(name, Export::Function(func));
+                finished_functions.push($body);
+            }
         }};
     }
 
-    // unknown
-    signature!(args_get);
-    signature!(args_sizes_get);
-    signature!(environ_get);
-    signature!(environ_sizes_get);
-    signature!(fd_allocate); // don't know what it does
-
-    // need
-    signature!(clock_res_get);
-    signature!(clock_time_get);
-    signature!(fd_close);
-    signature!(fd_read);
-    signature!(fd_renumber); // equivalent of dup2?
-    signature!(fd_sync); // probably needed for flushing
-    signature!(fd_write);
-    signature!(poll_oneoff);
-    signature!(proc_exit);
-    signature!(random_get);
-    signature!(sched_yield); // probably (related to frenetics?)
-    signature!(sock_recv);
-    signature!(sock_send);
-    signature!(sock_shutdown);
-
-    /// need equivalent of these but aren't standardized yet
-    ///
-    /// keeps must be able to establish connections from inside, as opposed to
-    /// getting pre-established connections as filedescriptors
-    /// * socket()
-    /// * connect()
-    /// * bind()
-    /// * listen()
-    /// * getsockopt()
-    /// * setsockopt()
-    /// * handshake() -- performs TLS handeshake, not POSIX
-
-    // when we implement FS support
-    signature!(fd_prestat_get); // used by the hello_world demo
-    signature!(fd_prestat_dir_name);
-    signature!(fd_datasync);
-    signature!(fd_pread); // offset
-    signature!(fd_pwrite); // offset
-    signature!(fd_seek);
-    signature!(fd_tell);
-    signature!(fd_fdstat_get);
-    signature!(fd_fdstat_set_flags);
-    signature!(fd_fdstat_set_rights);
-    signature!(fd_advise);
-    signature!(path_create_directory);
-    signature!(path_link);
-    signature!(path_open);
-    signature!(fd_readdir);
-    signature!(path_readlink);
-    signature!(path_rename);
-    signature!(fd_filestat_get);
-    signature!(fd_filestat_set_times);
-    signature!(fd_filestat_set_size);
-    signature!(path_filestat_get);
-    signature!(path_filestat_set_times);
-    signature!(path_symlink);
-    signature!(path_unlink_file);
-    signature!(path_remove_directory);
-
-    // want to remove from WASI
-    signature!(proc_raise); // related to signal handling
+    let (prefix, syscalls, host_functions) = match interface {
+        Interface::Wasi(abi) => {
+            let mut syscalls = syscalls::all();
+            if abi == WasiAbi::Preview1 {
+                // syscalls whose ABI changed between snapshots
+                for versioned in syscalls::preview1::all() {
+                    if let Some(syscall) = syscalls.iter_mut().find(|s| s.name == versioned.name) {
+                        *syscall = versioned;
+                    }
+                }
+            }
+            (prefix, syscalls, host_functions)
+        }
+        Interface::Enarx => ("", syscalls::enarx::all(), &[][..]),
+    };
 
-    let imports = Imports::none();
-    let data_initializers = Vec::new();
-    let signatures = PrimaryMap::new();
-
-    let mut wasi_ctx_builder = WasiCtxBuilder::new()
-        .and_then(|ctx| ctx.inherit_stdio())
-        .and_then(|ctx| ctx.args(argv.iter()))
-        .and_then(|ctx| ctx.envs(environ.iter()))
-        .map_err(|err| {
-            InstantiationError::Resource(format!("couldn't assemble WASI context object: {}", err))
-        })?;
+    for syscall in syscalls {
+        function!(
+            prefix.to_owned() + syscall.name,
+            syscall.params.iter().cloned(),
+            syscall.results.iter().cloned(),
+            syscall.body
+        );
+    }
 
-    for (dir, f) in preopened_dirs {
-        wasi_ctx_builder = wasi_ctx_builder.preopened_dir(
-            f.try_clone().map_err(|err| {
-                InstantiationError::Resource(format!(
-                    "couldn't clone an instance handle to pre-opened dir: {}",
-                    err
-                ))
-            })?,
-            dir,
+    for f in host_functions {
+        let body = match f.closure {
+            // Closures which aren't exported don't take up a slot.
+            Some((ref closure, trampoline)) if exported(&(prefix.to_owned() + &f.name)) => {
+                let mut state = state.borrow_mut();
+                let slot = state.host_closures.len();
+                let body = trampoline(slot).ok_or_else(|| {
+                    InstantiationError::Resource(format!(
+                        "host function {} is one closure more than the {} an instance can have",
+                        f.name, MAX_HOST_CLOSURES
+                    ))
+                })?;
+                state.host_closures.push(closure.clone());
+                body
+            }
+            _ => f.body,
+        };
+        function!(
+            prefix.to_owned() + &f.name,
+            f.params.iter().cloned(),
+            f.results.iter().cloned(),
+            body
         );
     }
 
-    let wasi_ctx = wasi_ctx_builder.build().map_err(|err| {
-        InstantiationError::Resource(format!("couldn't assemble WASI context object: {}", err))
-    })?;
+    let imports = Imports::none();
+    let data_initializers = Vec::new();
+    let signatures = PrimaryMap::new();
 
     InstanceHandle::new(
         Rc::new(module),
@@ -152,6 +420,6 @@ pub fn instantiate_wasi(
         &data_initializers,
         signatures.into_boxed_slice(),
         None,
-        Box::new(wasi_ctx),
+        Box::new(state),
     )
 }
@@ -0,0 +1,342 @@
+//! Stopping guest code from the host, for `proc_exit` and `Control::stop`.
+//!
+//! JIT code can't be preempted, so modules are instrumented before they're
+//! compiled: they get a global of their own, which they check at the start
+//! of every function, at the top of every loop and after every call, and
+//! trap on if it's set. The host sets it, and the guest traps before
+//! running much further, out of any loop, or right after the syscall it
+//! was in returns.
+
+use std::sync::{Arc, Mutex};
+use wasmparser::{BinaryReader, BinaryReaderError, Operator};
+use wasmtime_runtime::VMGlobalDefinition;
+
+use super::binary::{
+    read_bytes, read_name, read_u32, skip_limits, write_name, write_u32, IMPORT_SECTION,
+};
+
+/// Name the instrumentation exports its global under.
+pub(crate) const INTERRUPT_EXPORT: &str = "__enarx_interrupt";
+
+const GLOBAL_SECTION: u8 = 6;
+const EXPORT_SECTION: u8 = 7;
+const CODE_SECTION: u8 = 10;
+
+/// The globals of the instances of a module, and of the modules linked to
+/// it, which make them trap once set.
+///
+/// Shared by the `WasiModule`, its WASI states and its `Control`, if any.
+/// Once raised, it stays raised: instances registered afterwards trap
+/// right away.
+#[derive(Clone, Default)]
+pub(crate) struct Interrupt(Arc<Mutex<Globals>>);
+
+#[derive(Default)]
+struct Globals {
+    globals: Vec<*mut VMGlobalDefinition>,
+    raised: bool,
+}
+
+// The globals are only written through the lock, and released before the
+// instances owning them are dropped.
+unsafe impl Send for Globals {}
+
+impl Interrupt {
+    /// Makes the instance owning `global`, a module's `INTERRUPT_EXPORT`,
+    /// trap when this is raised.
+    pub(crate) fn register(&self, global: *mut VMGlobalDefinition) {
+        let mut globals = self.0.lock().unwrap();
+        if globals.raised {
+            unsafe { set(global) };
+        }
+        globals.globals.push(global);
+    }
+
+    /// Whether any instance would trap when this is raised, i.e. whether
+    /// raising it stops the guest at all.
+    pub(crate) fn armed(&self) -> bool {
+        !self.0.lock().unwrap().globals.is_empty()
+    }
+
+    /// Whether this was raised, i.e. whether a trap was caused by it.
+    pub(crate) fn raised(&self) -> bool {
+        self.0.lock().unwrap().raised
+    }
+
+    /// Makes every instance trap at its next check.
+    pub(crate) fn raise(&self) {
+        let mut globals = self.0.lock().unwrap();
+        globals.raised = true;
+        for &global in &globals.globals {
+            unsafe { set(global) };
+        }
+    }
+
+    /// Forgets the globals, whose instances are about to be dropped.
+    pub(crate) fn release(&self) {
+        self.0.lock().unwrap().globals.clear();
+    }
+}
+
+unsafe fn set(global: *mut VMGlobalDefinition) {
+    // The guest may be reading it on another thread: make sure the store
+    // isn't elided or torn.
+    std::ptr::write_volatile((*global).as_i32_mut(), 1);
+}
+
+/// Returns `wasm` with a mutable `i32` global added and exported as
+/// `INTERRUPT_EXPORT`, and a check trapping if it's nonzero inserted at the
+/// start of every function body, after every `loop` and after every call.
+pub(crate) fn instrument(wasm: &[u8]) -> Result<Vec<u8>, String> {
+    if wasm.len() < 8 {
+        return Err("module is truncated".to_owned());
+    }
+    let mut sections = Vec::new();
+    let mut pos = 8;
+    while pos < wasm.len() {
+        let id = wasm[pos];
+        pos += 1;
+        let size = read_u32(wasm, &mut pos)? as usize;
+        let payload =
+            read_bytes(wasm, &mut pos, size).map_err(|_| format!("section {} is truncated", id))?;
+        sections.push((id, payload.to_vec()));
+    }
+
+    let imported = match sections.iter().find(|(id, _)| *id == IMPORT_SECTION) {
+        Some((_, payload)) => imported_globals(payload)?,
+        None => 0,
+    };
+    let defined = match sections.iter().find(|(id, _)| *id == GLOBAL_SECTION) {
+        Some((_, payload)) => read_u32(payload, &mut 0)?,
+        None => 0,
+    };
+    let index = imported + defined;
+
+    for (id, payload) in &mut sections {
+        if *id == CODE_SECTION {
+            *payload = instrument_code(payload, index)?;
+        }
+    }
+
+    // global 0: (mut i32) (i32.const 0)
+    let entry = [0x7f, 0x01, 0x41, 0x00, 0x0b];
+    append_entry(&mut sections, GLOBAL_SECTION, &entry)?;
+    let mut entry = Vec::new();
+    write_name(&mut entry, INTERRUPT_EXPORT.as_bytes());
+    entry.push(0x03);
+    write_u32(&mut entry, index);
+    append_entry(&mut sections, EXPORT_SECTION, &entry)?;
+
+    let mut out = wasm[..8].to_vec();
+    for (id, payload) in sections {
+        out.push(id);
+        write_u32(&mut out, payload.len() as u32);
+        out.extend_from_slice(&payload);
+    }
+    Ok(out)
+}
+
+/// Returns the number of globals the import section `payload` imports.
+fn imported_globals(payload: &[u8]) -> Result<u32, String> {
+    let mut pos = 0;
+    let mut globals = 0;
+    for _ in 0..read_u32(payload, &mut pos)? {
+        read_name(payload, &mut pos)?;
+        read_name(payload, &mut pos)?;
+        match read_bytes(payload, &mut pos, 1)?[0] {
+            0 => {
+                read_u32(payload, &mut pos)?;
+            }
+            1 => {
+                read_bytes(payload, &mut pos, 1)?;
+                skip_limits(payload, &mut pos)?;
+            }
+            2 => skip_limits(payload, &mut pos)?,
+            3 => {
+                read_bytes(payload, &mut pos, 2)?;
+                globals += 1;
+            }
+            kind => return Err(format!("unknown import kind {}", kind)),
+        }
+    }
+    Ok(globals)
+}
+
+/// Adds `entry` to the vector section `id`, creating the section where it
+/// belongs if the module has none.
+fn append_entry(sections: &mut Vec<(u8, Vec<u8>)>, id: u8, entry: &[u8]) -> Result<(), String> {
+    if let Some((_, payload)) = sections.iter_mut().find(|(s, _)| *s == id) {
+        let mut pos = 0;
+        let count = read_u32(payload, &mut pos)?;
+        let mut out = Vec::with_capacity(payload.len() + entry.len() + 1);
+        write_u32(&mut out, count + 1);
+        out.extend_from_slice(&payload[pos..]);
+        out.extend_from_slice(entry);
+        *payload = out;
+        return Ok(());
+    }
+    // custom sections can go anywhere, the others have a fixed order, in
+    // which the data count section comes before the code
+    let order = |id: u8| match id {
+        12 => Some(95),
+        0 => None,
+        id => Some(u32::from(id) * 10),
+    };
+    let at = sections
+        .iter()
+        .position(|&(s, _)| order(s).map_or(false, |o| o > u32::from(id) * 10))
+        .unwrap_or_else(|| sections.len());
+    let mut payload = vec![1];
+    payload.extend_from_slice(entry);
+    sections.insert(at, (id, payload));
+    Ok(())
+}
+
+/// Inserts the checks of the global `index` into every body of the code
+/// section `payload`.
+fn instrument_code(payload: &[u8], index: u32) -> Result<Vec<u8>, String> {
+    let mut check = vec![0x23];
+    write_u32(&mut check, index);
+    // if (empty) unreachable end
+    check.extend_from_slice(&[0x04, 0x40, 0x00, 0x0b]);
+
+    let mut pos = 0;
+    let count = read_u32(payload, &mut pos)?;
+    let mut out = Vec::with_capacity(payload.len() * 2);
+    write_u32(&mut out, count);
+    for function in 0..count {
+        let size = read_u32(payload, &mut pos)? as usize;
+        let body = read_bytes(payload, &mut pos, size)
+            .map_err(|_| format!("function body {} is truncated", function))?;
+
+        let mut start = 0;
+        for _ in 0..read_u32(body, &mut start)? {
+            read_u32(body, &mut start)?;
+            read_bytes(body, &mut start, 1)?;
+        }
+        let code = &body[start..];
+        let mut checks = vec![0];
+        let mut reader = BinaryReader::new(code);
+        let parse = |err: BinaryReaderError| {
+            format!(
+                "function body {}: {} (at offset {})",
+                function, err.message, err.offset
+            )
+        };
+        while !reader.eof() {
+            match reader.read_operator().map_err(parse)? {
+                Operator::Loop { .. } | Operator::Call { .. } | Operator::CallIndirect { .. } => {
+                    checks.push(reader.current_position());
+                }
+                // Whatever the module refers to by that index, it's ours now.
+                Operator::GetGlobal { global_index } | Operator::SetGlobal { global_index }
+                    if global_index >= index =>
+                {
+                    return Err(format!(
+                        "function body {} refers to global {}, which doesn't exist",
+                        function, global_index
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        let mut instrumented = body[..start].to_vec();
+        let mut copied = 0;
+        for at in checks {
+            instrumented.extend_from_slice(&code[copied..at]);
+            instrumented.extend_from_slice(&check);
+            copied = at;
+        }
+        instrumented.extend_from_slice(&code[copied..]);
+        write_u32(&mut out, instrumented.len() as u32);
+        out.extend_from_slice(&instrumented);
+    }
+    if pos != payload.len() {
+        return Err("code section has trailing bytes".to_owned());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn module(sections: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut wasm = HEADER.to_vec();
+        for &(id, payload) in sections {
+            wasm.push(id);
+            write_u32(&mut wasm, payload.len() as u32);
+            wasm.extend_from_slice(payload);
+        }
+        wasm
+    }
+
+    #[test]
+    fn checks_at_entry_loops_and_calls() {
+        // (func $f (loop (call $f)))
+        let wasm = module(&[
+            (1, &[1, 0x60, 0, 0]),
+            (3, &[1, 0]),
+            (10, &[1, 7, 0, 0x03, 0x40, 0x10, 0x00, 0x0b, 0x0b]),
+        ]);
+        let check: &[u8] = &[0x23, 0, 0x04, 0x40, 0x00, 0x0b];
+        let mut code = vec![0];
+        code.extend_from_slice(check);
+        code.extend_from_slice(&[0x03, 0x40]);
+        code.extend_from_slice(check);
+        code.extend_from_slice(&[0x10, 0x00]);
+        code.extend_from_slice(check);
+        code.extend_from_slice(&[0x0b, 0x0b]);
+        let mut body = vec![1, code.len() as u8];
+        body.extend_from_slice(&code);
+
+        let mut export = vec![1];
+        write_name(&mut export, INTERRUPT_EXPORT.as_bytes());
+        export.extend_from_slice(&[0x03, 0]);
+        let expected = module(&[
+            (1, &[1, 0x60, 0, 0]),
+            (3, &[1, 0]),
+            (6, &[1, 0x7f, 0x01, 0x41, 0x00, 0x0b]),
+            (7, &export),
+            (10, &body),
+        ]);
+        assert_eq!(instrument(&wasm).unwrap(), expected);
+    }
+
+    #[test]
+    fn numbers_the_global_after_the_others() {
+        // an imported global and a defined one
+        let wasm = module(&[
+            (2, &[1, 1, b'a', 1, b'b', 0x03, 0x7f, 0x00]),
+            (6, &[1, 0x7f, 0x00, 0x41, 0x07, 0x0b]),
+        ]);
+        let mut export = vec![1];
+        write_name(&mut export, INTERRUPT_EXPORT.as_bytes());
+        export.extend_from_slice(&[0x03, 2]);
+        let expected = module(&[
+            (2, &[1, 1, b'a', 1, b'b', 0x03, 0x7f, 0x00]),
+            (
+                6,
+                &[
+                    2, 0x7f, 0x00, 0x41, 0x07, 0x0b, 0x7f, 0x01, 0x41, 0x00, 0x0b,
+                ],
+            ),
+            (7, &export),
+        ]);
+        assert_eq!(instrument(&wasm).unwrap(), expected);
+    }
+
+    #[test]
+    fn refuses_references_to_the_global() {
+        // (func (drop (global.get 0))) without any global
+        let wasm = module(&[
+            (1, &[1, 0x60, 0, 0]),
+            (3, &[1, 0]),
+            (10, &[1, 5, 0, 0x23, 0x00, 0x1a, 0x0b]),
+        ]);
+        assert!(instrument(&wasm).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+/// The kind of keep a guest runs in, as reported to it by `enarx_info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// A plain process, without hardware isolation.
+    Nil,
+    /// A KVM virtual machine.
+    Kvm,
+    /// An Intel SGX enclave.
+    Sgx,
+    /// An AMD SEV encrypted virtual machine.
+    Sev,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Nil
+    }
+}
+
+/// Blocking syscalls may fail with `__WASI_ETIMEDOUT`.
+pub(crate) const FEATURE_HOSTCALL_TIMEOUT: u32 = 1 << 0;
+/// Opening more fds than allowed fails with `__WASI_EMFILE`.
+pub(crate) const FEATURE_FD_LIMIT: u32 = 1 << 1;
+/// Some preopens are read-only.
+pub(crate) const FEATURE_READONLY_PREOPENS: u32 = 1 << 2;
+
+/// What the guest is told about its environment by `enarx_info`.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct KeepInfo {
+    pub(crate) backend: Backend,
+    /// Whether the keep can be attested.
+    pub(crate) attestation: bool,
+    /// The `FEATURE_*` flags of what the runtime enforces.
+    pub(crate) features: u32,
+    /// Number of logical CPUs the guest may use, as reported by
+    /// `enarx_cpu_count`.
+    pub(crate) cpus: u32,
+}
+
+impl KeepInfo {
+    /// Size of the encoded info. Later versions may append fields, so the
+    /// size comes first for the guest to tell which ones it got.
+    pub(crate) const SIZE: usize = 24;
+
+    /// Encodes the info as the guest sees it: little-endian `u32`s for the
+    /// size, backend, attestation and features, followed by `u16`s for the
+    /// runtime's major, minor and patch version, and 2 bytes of padding.
+    pub(crate) fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let backend: u32 = match self.backend {
+            Backend::Nil => 0,
+            Backend::Kvm => 1,
+            Backend::Sgx => 2,
+            Backend::Sev => 3,
+        };
+        let version = |part: &str| part.parse::<u16>().unwrap_or(0);
+
+        let mut out = [0; Self::SIZE];
+        out[0..4].copy_from_slice(&(Self::SIZE as u32).to_le_bytes());
+        out[4..8].copy_from_slice(&backend.to_le_bytes());
+        out[8..12].copy_from_slice(&u32::from(self.attestation).to_le_bytes());
+        out[12..16].copy_from_slice(&self.features.to_le_bytes());
+        out[16..18].copy_from_slice(&version(env!("CARGO_PKG_VERSION_MAJOR")).to_le_bytes());
+        out[18..20].copy_from_slice(&version(env!("CARGO_PKG_VERSION_MINOR")).to_le_bytes());
+        out[20..22].copy_from_slice(&version(env!("CARGO_PKG_VERSION_PATCH")).to_le_bytes());
+        out
+    }
+}
+
+/// Number of logical CPUs the calling thread may run on.
+pub(crate) fn available_cpus() -> u32 {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) == 0 {
+            return libc::CPU_COUNT(&set) as u32;
+        }
+    }
+    let online = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    online.max(1) as u32
+}
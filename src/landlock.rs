@@ -0,0 +1,168 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use super::preopen::PreopenRights;
+
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+
+thread_local! {
+    /// Whether the calling thread is confined already.
+    static CONFINED: Cell<bool> = Cell::new(false);
+}
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+/// Since ABI version 2: renaming and linking into another directory.
+const ACCESS_FS_REFER: u64 = 1 << 13;
+/// Since ABI version 3.
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// The filesystem accesses ABI version 1 knows of, all of which are denied
+/// outside of the preopens.
+const ACCESS_FS_V1: u64 = ACCESS_FS_EXECUTE
+    | ACCESS_FS_WRITE_FILE
+    | ACCESS_FS_READ_FILE
+    | ACCESS_FS_READ_DIR
+    | ACCESS_FS_REMOVE_DIR
+    | ACCESS_FS_REMOVE_FILE
+    | ACCESS_FS_MAKE_CHAR
+    | ACCESS_FS_MAKE_DIR
+    | ACCESS_FS_MAKE_REG
+    | ACCESS_FS_MAKE_SOCK
+    | ACCESS_FS_MAKE_FIFO
+    | ACCESS_FS_MAKE_BLOCK
+    | ACCESS_FS_MAKE_SYM;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The accesses beneath a preopen which the guest may need the runtime to
+/// make on its behalf, given the preopen's `rights`.
+fn access(rights: &PreopenRights) -> u64 {
+    let read = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    // opening the directories to create things in takes READ_DIR too
+    let create =
+        ACCESS_FS_READ_DIR | ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG | ACCESS_FS_MAKE_DIR;
+    if rights.create_only {
+        create
+    } else if rights.is_readonly() {
+        read
+    } else {
+        read | create
+            | ACCESS_FS_REMOVE_DIR
+            | ACCESS_FS_REMOVE_FILE
+            | ACCESS_FS_MAKE_SYM
+            | ACCESS_FS_REFER
+            | ACCESS_FS_TRUNCATE
+    }
+}
+
+/// Denies the calling thread, and the threads it spawns from then on, any
+/// access to the host filesystem other than beneath the `dirs`, as their
+/// rights allow. Returns `false` if the kernel doesn't support Landlock.
+///
+/// Fds opened before, like those of the preopens and stdio, are left
+/// alone. This can't be undone.
+///
+/// A thread is confined once: the kernel stacks at most 16 rulesets, and
+/// a later one could only narrow the first, which already denies the
+/// directories it doesn't allow. Later calls do nothing.
+pub(crate) fn restrict(dirs: &[(File, PreopenRights)]) -> io::Result<bool> {
+    if CONFINED.with(Cell::get) {
+        return Ok(true);
+    }
+    let abi = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Ok(false),
+            _ => Err(err),
+        };
+    }
+    let mut handled = ACCESS_FS_V1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let ruleset = unsafe {
+        let fd = libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        File::from_raw_fd(fd as i32)
+    };
+    for (dir, rights) in dirs {
+        let rule = PathBeneathAttr {
+            allowed_access: access(rights) & handled,
+            parent_fd: dir.as_raw_fd(),
+        };
+        let ret = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    unsafe {
+        // without this, only privileged processes may restrict themselves
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    CONFINED.with(|confined| confined.set(true));
+    Ok(true)
+}
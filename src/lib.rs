@@ -1,4 +1,87 @@
+mod backing;
+mod binary;
+mod builder;
+mod capability;
+#[cfg(target_os = "linux")]
+mod cgroup;
+mod control;
+mod coredump;
+mod debugger;
+mod drbg;
+mod env;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod fileid;
+#[cfg(test)]
+mod fixtures;
+mod fs;
+mod guest;
+mod hostfd;
+mod imports;
 mod instantiate;
+mod interrupt;
+mod keep;
+#[cfg(target_os = "linux")]
+mod landlock;
+mod limits;
+mod manager;
+mod module;
+#[cfg(target_os = "linux")]
+mod numa;
+mod pool;
+mod preopen;
+#[cfg(target_os = "linux")]
+mod privileges;
+mod profile;
+mod readahead;
+mod resolve;
+mod scope;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod seccomp;
+mod secret;
+mod snapshot;
+mod state;
+mod stdio;
+mod supervisor;
 mod syscalls;
+mod terminal;
+mod trace;
+mod typed;
+mod tz;
+mod usage;
+mod watchdog;
+mod writeback;
 
-pub use instantiate::instantiate_wasi;
+pub use builder::WasiInstanceBuilder;
+pub use capability::Capability;
+#[cfg(target_os = "linux")]
+pub use cgroup::Cgroup;
+pub use control::Control;
+pub use coredump::{CoreDump, FdSummary};
+pub use debugger::{Breakpoint, BreakpointHit, Console, Debugger, Step, Syscall};
+pub use env::EnvPolicy;
+pub use imports::{
+    check_imports, function_imports, validate_imports, wasi_imports, FunctionImport, ImportError,
+    ImportMismatch,
+};
+pub use instantiate::{
+    instantiate_wasi, instantiate_wasi_with_memory, HostClosure, HostFunction, WasiAbi,
+    ENARX_NAMESPACE, MAX_HOST_CLOSURES,
+};
+pub use keep::Backend;
+pub use manager::{KeepId, KeepManager, KeepStatus};
+pub use module::{OptLevel, RunError, WasiModule};
+pub use pool::{InstancePool, StandbyPool};
+pub use preopen::{parse_map_dir, PreopenDir, PreopenRights};
+#[cfg(target_os = "linux")]
+pub use privileges::PrivilegeDrop;
+pub use profile::Profile;
+pub use scope::{ExportScope, GlobalExports};
+pub use secret::Secret;
+pub use snapshot::Snapshot;
+pub use state::{vmctx_wasi_context, wasi_context, WasiContext, DEFAULT_MEMORY_EXPORT};
+pub use supervisor::{RestartPolicy, Supervisor, SupervisorEvent};
+pub use terminal::TerminalFilter;
+pub use typed::{WasmArgs, WasmResults, WasmTy};
+pub use usage::{resource_usage, ResourceUsage, SyscallUsage};
+pub use wasmtime_jit::{Features, RuntimeValue};
@@ -0,0 +1,78 @@
+use super::binary::{
+    read_bytes, read_u32, rewrite_sections, write_u32, MEMORY_SECTION, TABLE_SECTION,
+};
+use std::borrow::Cow;
+
+/// Upper bounds on the memories and tables a module may define.
+///
+/// They're applied by lowering the maximum the module declares for each of
+/// them, so instantiation fails if the initial size is already too large,
+/// and `memory.grow` past the limit fails inside the guest like it would
+/// against the module's own maximum.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Limits {
+    /// Maximum size of a linear memory, in 64KiB pages.
+    pub(crate) memory_pages: Option<u32>,
+    /// Maximum number of elements of a table.
+    pub(crate) table_elements: Option<u32>,
+}
+
+impl Limits {
+    /// Returns `wasm` with the limits applied to every memory and table it
+    /// defines. Imported memories and tables are bounded by whoever
+    /// defines them.
+    pub(crate) fn apply<'a>(&self, wasm: &'a [u8]) -> Result<Cow<'a, [u8]>, String> {
+        if self.memory_pages.is_none() && self.table_elements.is_none() {
+            return Ok(Cow::Borrowed(wasm));
+        }
+
+        rewrite_sections(wasm, |id, payload| match id {
+            MEMORY_SECTION => self
+                .memory_pages
+                .map(|max| limit_entries(payload, 0, max, "memory"))
+                .transpose(),
+            TABLE_SECTION => self
+                .table_elements
+                .map(|max| limit_entries(payload, 1, max, "table"))
+                .transpose(),
+            _ => Ok(None),
+        })
+        .map(Cow::Owned)
+    }
+}
+
+/// Rewrites the limits of every entry of a memory or table section, where
+/// each entry's limits are preceded by `prefix` bytes (the table's element
+/// type).
+fn limit_entries(payload: &[u8], prefix: usize, max: u32, what: &str) -> Result<Vec<u8>, String> {
+    let mut pos = 0;
+    let count = read_u32(payload, &mut pos)?;
+    let mut out = Vec::with_capacity(payload.len() + 5 * count as usize);
+    write_u32(&mut out, count);
+    for index in 0..count {
+        let entry = read_bytes(payload, &mut pos, prefix)
+            .map_err(|_| format!("{} {} is truncated", what, index))?;
+        out.extend_from_slice(entry);
+
+        let flags = read_u32(payload, &mut pos)?;
+        let initial = read_u32(payload, &mut pos)?;
+        let declared = if flags & 1 != 0 {
+            Some(read_u32(payload, &mut pos)?)
+        } else {
+            None
+        };
+        if initial > max {
+            return Err(format!(
+                "{} {} starts out at {}, more than the limit of {}",
+                what, index, initial, max
+            ));
+        }
+        write_u32(&mut out, flags | 1);
+        write_u32(&mut out, initial);
+        write_u32(&mut out, declared.map_or(max, |declared| declared.min(max)));
+    }
+    if pos != payload.len() {
+        return Err(format!("{} section has trailing bytes", what));
+    }
+    Ok(out)
+}
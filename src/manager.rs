@@ -0,0 +1,307 @@
+use super::builder::WasiInstanceBuilder;
+use super::control::Control;
+use super::keep::available_cpus;
+use super::module::RunError;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Identifies a keep spawned by a `KeepManager`.
+pub type KeepId = u64;
+
+/// Where a keep is in its lifecycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeepStatus {
+    /// The module is being compiled and instantiated.
+    Starting,
+    /// The module is running.
+    Running,
+    /// The module was asked to stop, and hasn't yet.
+    Stopping,
+    /// The module was stopped.
+    Stopped,
+    /// The module returned from `_start`, or called `proc_exit` with zero.
+    Exited,
+    /// The module called `proc_exit` with this nonzero code.
+    ExitedWith(u32),
+    /// The module trapped.
+    Trapped(String),
+    /// The module couldn't be started, or its thread panicked.
+    Failed(String),
+}
+
+impl KeepStatus {
+    fn finished(&self) -> bool {
+        match self {
+            KeepStatus::Starting | KeepStatus::Running | KeepStatus::Stopping => false,
+            _ => true,
+        }
+    }
+}
+
+struct Keep {
+    status: Arc<Status>,
+    control: Control,
+    thread: JoinHandle<()>,
+}
+
+/// The status of a keep, updated by its thread.
+struct Status {
+    status: Mutex<KeepStatus>,
+    /// Notified when the keep finishes.
+    finished: Condvar,
+}
+
+impl Status {
+    fn get(&self) -> KeepStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Moves to `to` if at `from`.
+    fn advance(&self, from: KeepStatus, to: KeepStatus) {
+        let mut status = self.status.lock().unwrap();
+        if *status == from {
+            *status = to;
+        }
+    }
+
+    fn finish(&self, status: KeepStatus) {
+        *self.status.lock().unwrap() = status;
+        self.finished.notify_all();
+    }
+
+    /// Waits up to `timeout` for the keep to finish, and returns whether
+    /// it did.
+    fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut status = self.status.lock().unwrap();
+        loop {
+            if status.finished() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            status = self
+                .finished
+                .wait_timeout(status, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+/// Marks the keep as failed if its thread panics, so it isn't waited for
+/// until the timeout.
+struct PanicGuard<'a>(&'a Status);
+
+impl Drop for PanicGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0
+                .finish(KeepStatus::Failed("keep thread panicked".to_owned()));
+        }
+    }
+}
+
+/// Limits how many keeps of a batch are compiled and instantiated at once.
+struct Gate {
+    permits: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Gate {
+    fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Runs `f` once a permit is free.
+    fn pass<T>(&self, f: impl FnOnce() -> T) -> T {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.freed.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        drop(permits);
+
+        let _permit = Permit(self);
+        f()
+    }
+}
+
+/// Hands a permit back to the gate, even if instantiation panicked.
+struct Permit<'a>(&'a Gate);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut permits) = self.0.permits.lock() {
+            *permits += 1;
+        }
+        self.0.freed.notify_one();
+    }
+}
+
+/// Runs command modules as keeps, each on a thread of its own, and keeps
+/// track of them.
+///
+/// Instances can't leave the thread they were created on, so a keep's
+/// builder is made by a factory called on the keep's thread, and given a
+/// `Control` of the manager's.
+///
+/// ```ignore
+/// let mut keeps = KeepManager::new();
+/// let id = keeps.spawn(wasm, || WasiInstanceBuilder::new().arg("service"));
+/// assert_eq!(keeps.wait(id), Some(KeepStatus::Exited));
+/// ```
+#[derive(Default)]
+pub struct KeepManager {
+    next_id: KeepId,
+    keeps: HashMap<KeepId, Keep>,
+}
+
+impl KeepManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a keep running the module `wasm`, configured by the builder
+    /// `factory` returns.
+    pub fn spawn<F>(&mut self, wasm: Vec<u8>, factory: F) -> KeepId
+    where
+        F: FnOnce() -> WasiInstanceBuilder + Send + 'static,
+    {
+        self.start(wasm.into(), factory, None)
+    }
+
+    /// Start `count` keeps running the module `wasm`, the `i`th configured
+    /// by the builder `factory(i)` returns.
+    ///
+    /// The keeps are compiled and instantiated concurrently, but no more
+    /// of them at once than there are CPUs to do it on, so a large fleet
+    /// doesn't thrash on startup. The module is shared between them rather
+    /// than copied for each.
+    pub fn spawn_batch<F>(&mut self, wasm: Vec<u8>, count: usize, factory: F) -> Vec<KeepId>
+    where
+        F: Fn(usize) -> WasiInstanceBuilder + Send + Sync + 'static,
+    {
+        let wasm: Arc<[u8]> = wasm.into();
+        let factory = Arc::new(factory);
+        let gate = Arc::new(Gate::new(available_cpus() as usize));
+        (0..count)
+            .map(|i| {
+                let factory = factory.clone();
+                self.start(wasm.clone(), move || factory(i), Some(gate.clone()))
+            })
+            .collect()
+    }
+
+    fn start<F>(&mut self, wasm: Arc<[u8]>, factory: F, gate: Option<Arc<Gate>>) -> KeepId
+    where
+        F: FnOnce() -> WasiInstanceBuilder + Send + 'static,
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let status = Arc::new(Status {
+            status: Mutex::new(KeepStatus::Starting),
+            finished: Condvar::new(),
+        });
+        let control = Control::new();
+        let thread = {
+            let status = status.clone();
+            let control = control.clone();
+            thread::spawn(move || {
+                let _guard = PanicGuard(&status);
+                let instantiate = || factory().control(control).instantiate_module(&wasm);
+                let module = match gate {
+                    Some(gate) => gate.pass(instantiate),
+                    None => instantiate(),
+                };
+                let result = module.and_then(|mut module| {
+                    status.advance(KeepStatus::Starting, KeepStatus::Running);
+                    module.run()
+                });
+                status.finish(match result {
+                    Ok(()) => KeepStatus::Exited,
+                    Err(RunError::Trap(message)) => KeepStatus::Trapped(message),
+                    Err(RunError::Exit(code)) => KeepStatus::ExitedWith(code),
+                    Err(RunError::Stopped) => KeepStatus::Stopped,
+                    Err(err) => KeepStatus::Failed(err.to_string()),
+                });
+            })
+        };
+        self.keeps.insert(
+            id,
+            Keep {
+                status,
+                control,
+                thread,
+            },
+        );
+        id
+    }
+
+    /// Every keep which hasn't been waited for, with its status, by id.
+    pub fn list(&self) -> Vec<(KeepId, KeepStatus)> {
+        let mut keeps: Vec<_> = self
+            .keeps
+            .iter()
+            .map(|(&id, keep)| (id, keep.status.get()))
+            .collect();
+        keeps.sort_by_key(|&(id, _)| id);
+        keeps
+    }
+
+    /// The status of the keep `id`, or `None` if there's no such keep.
+    pub fn status(&self, id: KeepId) -> Option<KeepStatus> {
+        self.keeps.get(&id).map(|keep| keep.status.get())
+    }
+
+    /// Stop the keep `id`, see `Control::stop`; it's `Stopping` until it
+    /// has. Returns whether there's such a keep.
+    pub fn stop(&self, id: KeepId) -> bool {
+        match self.keeps.get(&id) {
+            Some(keep) => {
+                keep.status
+                    .advance(KeepStatus::Starting, KeepStatus::Stopping);
+                keep.status
+                    .advance(KeepStatus::Running, KeepStatus::Stopping);
+                keep.control.stop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The handle to change the configuration of the keep `id` while it
+    /// runs, or `None` if there's no such keep.
+    pub fn control(&self, id: KeepId) -> Option<Control> {
+        self.keeps.get(&id).map(|keep| keep.control.clone())
+    }
+
+    /// Wait for the keep `id` to finish and forget about it, returning how
+    /// it ended, or `None` if there's no such keep.
+    pub fn wait(&mut self, id: KeepId) -> Option<KeepStatus> {
+        let keep = self.keeps.remove(&id)?;
+        if keep.thread.join().is_err() {
+            return Some(KeepStatus::Failed("keep thread panicked".to_owned()));
+        }
+        Some(keep.status.get())
+    }
+
+    /// Like `wait`, but gives up after `timeout`, returning the status the
+    /// keep is still in, e.g. `Stopping` for a keep stuck in a hostcall,
+    /// and keeping track of it.
+    pub fn wait_timeout(&mut self, id: KeepId, timeout: Duration) -> Option<KeepStatus> {
+        if self.keeps.get(&id)?.status.wait(timeout) {
+            self.wait(id)
+        } else {
+            self.status(id)
+        }
+    }
+}
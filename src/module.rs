@@ -0,0 +1,541 @@
+use super::coredump::CoreDump;
+use super::guest;
+use super::imports::ImportError;
+use super::interrupt::{Interrupt, INTERRUPT_EXPORT};
+use super::profile::Profile;
+use super::secret::{zeroize, zeroize_vec};
+use super::snapshot::Snapshot;
+use super::state::{SharedState, WasiState, DEFAULT_MEMORY_EXPORT};
+use super::typed::{WasmArgs, WasmResults};
+use super::usage::{memory_len, ResourceUsage};
+use cranelift_codegen::isa;
+use cranelift_codegen::settings::{self, Configurable};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Instant;
+use target_lexicon::{Triple, HOST};
+use wasmtime_jit::{ActionOutcome, Context, Features, RuntimeValue};
+use wasmtime_runtime::{Export, InstanceHandle, InstantiationError};
+
+/// A wasm module instantiated against the WASI syscalls, ready to run.
+///
+/// Created by `WasiInstanceBuilder::instantiate_module` straight from the
+/// module's bytes, so the module never has to exist as a host file.
+///
+/// The WASI memory, and the host-side buffers guest data passed through,
+/// are zeroed when the module is dropped, so no secrets linger in the host
+/// process. The buffers are also zeroed as soon as the module traps.
+pub struct WasiModule {
+    context: Context,
+    instance: InstanceHandle,
+    /// State of the WASI instances, if the module imports any.
+    state: Option<SharedState>,
+    initialized: bool,
+    /// Whether the module trapped, possibly leaving its internal globals
+    /// (e.g. the stack pointer) wherever the trap hit.
+    trapped: bool,
+    /// Contents of the WASI memory right after instantiation, or
+    /// initialization, if the module is to be reset, and whether it was
+    /// initialized by then.
+    pristine: Option<(Vec<u8>, bool)>,
+    /// Directory to write a `CoreDump` to if the module traps.
+    core_dumps: Option<PathBuf>,
+    /// Makes the module and the modules linked to it trap.
+    interrupt: Interrupt,
+}
+
+impl WasiModule {
+    pub(crate) fn new(
+        context: Context,
+        instance: InstanceHandle,
+        state: Option<SharedState>,
+        interrupt: Interrupt,
+    ) -> Self {
+        Self {
+            context,
+            instance,
+            state,
+            initialized: false,
+            trapped: false,
+            pristine: None,
+            core_dumps: None,
+            interrupt,
+        }
+    }
+
+    pub(crate) fn set_core_dumps(&mut self, dir: Option<PathBuf>) {
+        self.core_dumps = dir;
+    }
+
+    /// Remember the current contents of the WASI memory for `reset`.
+    pub(crate) fn capture(&mut self) {
+        let initialized = self.initialized;
+        self.pristine = self
+            .memory()
+            .ok()
+            .map(|memory| (memory.to_vec(), initialized));
+    }
+
+    /// Bring the module back to the state `capture` saw it in, with the
+    /// state `fresh` returns as its WASI state. Returns whether that was
+    /// possible, without calling `fresh` if it isn't.
+    ///
+    /// Globals the module doesn't export can't be reset, which is fine as
+    /// long as every call returned normally: then the ones a toolchain
+    /// keeps internally, like the stack pointer, are back where they were.
+    /// A memory which has grown can't shrink back either.
+    pub(crate) fn reset<F>(&mut self, fresh: F) -> Result<bool, InstantiationError>
+    where
+        F: FnOnce() -> Result<WasiState, InstantiationError>,
+    {
+        if self.trapped {
+            return Ok(false);
+        }
+        let len = match self.memory() {
+            Ok(memory) => memory.len(),
+            Err(_) => return Ok(false),
+        };
+        let (pristine, initialized) = match self.pristine.take() {
+            Some(pristine) if pristine.0.len() == len => pristine,
+            pristine => {
+                self.pristine = pristine;
+                return Ok(false);
+            }
+        };
+        let state = match fresh() {
+            Ok(state) => state,
+            Err(err) => {
+                self.pristine = Some((pristine, initialized));
+                return Err(err);
+            }
+        };
+        if let Ok(memory) = self.memory() {
+            memory.copy_from_slice(&pristine);
+        }
+        self.pristine = Some((pristine, initialized));
+        if let Some(ref shared) = self.state {
+            shared.borrow_mut().replace(state);
+        }
+        self.initialized = initialized;
+        Ok(true)
+    }
+
+    /// Initialize a reactor module by calling its `_initialize` function,
+    /// if it exports one. This only happens once, later calls do nothing.
+    ///
+    /// Reactors are kept alive after initialization, and the embedder then
+    /// calls their exports with `invoke` as often as it likes, all against
+    /// the same WASI context.
+    pub fn initialize(&mut self) -> Result<(), RunError> {
+        if self.initialized {
+            return Ok(());
+        }
+        self.initialized = true;
+        if self.instance.lookup("_initialize").is_some() {
+            self.invoke("_initialize", &[])?;
+        }
+        Ok(())
+    }
+
+    /// Run the module's `_start` function, for command modules.
+    ///
+    /// A module calling `proc_exit` with a nonzero code fails with
+    /// `RunError::Exit`; exiting with zero is a success.
+    pub fn run(&mut self) -> Result<(), RunError> {
+        match self.invoke("_start", &[]) {
+            Ok(_) | Err(RunError::Exit(0)) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Call the function the module exports as `name`, returning its
+    /// results.
+    pub fn invoke(
+        &mut self,
+        name: &str,
+        args: &[RuntimeValue],
+    ) -> Result<Vec<RuntimeValue>, RunError> {
+        self.profile_enter(name);
+        let start = Instant::now();
+        let outcome = self.context.invoke(&mut self.instance, name, args);
+        self.profile_leave(start);
+        let outcome = outcome.map_err(|err| RunError::Jit(err.to_string()))?;
+        // proc_exit makes the guest trap, or, if it isn't instrumented,
+        // returns to it, which then traps or runs on with its syscalls
+        // failing; either way, it's done. Its globals were left wherever
+        // it exited, so it can't be reset either.
+        if let Some(code) = self.exit_code() {
+            self.trapped = true;
+            self.scrub();
+            return Err(RunError::Exit(code));
+        }
+        match outcome {
+            ActionOutcome::Returned { values } => Ok(values),
+            // the trap `Control::stop` caused, which is no bug
+            ActionOutcome::Trapped { .. } if self.interrupt.raised() => {
+                self.trapped = true;
+                self.scrub();
+                Err(RunError::Stopped)
+            }
+            ActionOutcome::Trapped { message } => {
+                self.trapped = true;
+                self.dump_core(name, &message);
+                self.scrub();
+                Err(RunError::Trap(message))
+            }
+        }
+    }
+
+    /// Call the export `name` with Rust-typed arguments, a tuple of
+    /// `i32`, `i64`, `f32` and `f64`, returning Rust-typed results.
+    ///
+    /// ```ignore
+    /// let sum: i32 = module.call("add", (1, 2))?;
+    /// ```
+    pub fn call<A: WasmArgs, R: WasmResults>(
+        &mut self,
+        name: &str,
+        args: A,
+    ) -> Result<R, RunError> {
+        let values = self.invoke(name, &args.into_values())?;
+        R::from_values(values)
+    }
+
+    /// Copy `bytes` into the guest, into a buffer allocated by calling its
+    /// export `allocator` (e.g. `malloc`) with their length. Returns the
+    /// guest pointer to the buffer.
+    pub fn write_bytes(&mut self, allocator: &str, bytes: &[u8]) -> Result<u32, RunError> {
+        let ptr: i32 = self.call(allocator, (bytes.len() as i32,))?;
+        let memory = self.memory()?;
+        let range = guest::range(memory, ptr as u32, bytes.len()).map_err(|_| {
+            RunError::Type(format!(
+                "{} returned a buffer outside of memory: {:#x}",
+                allocator, ptr
+            ))
+        })?;
+        memory[range].copy_from_slice(bytes);
+        Ok(ptr as u32)
+    }
+
+    /// Copy `len` bytes at the guest pointer `ptr` out of the guest.
+    pub fn read_bytes(&mut self, ptr: u32, len: usize) -> Result<Vec<u8>, RunError> {
+        let memory = self.memory()?;
+        let range = guest::range(memory, ptr, len).map_err(|_| {
+            RunError::Type(format!("{} bytes at {:#x} are outside of memory", len, ptr))
+        })?;
+        Ok(memory[range].to_vec())
+    }
+
+    /// The code the module passed to `proc_exit`, if it has called it.
+    fn exit_code(&self) -> Option<u32> {
+        self.state
+            .as_ref()
+            .and_then(|state| state.borrow().exit_code)
+    }
+
+    /// The module's WASI memory.
+    fn memory(&mut self) -> Result<&mut [u8], RunError> {
+        let name = match self.state {
+            Some(ref state) => state.borrow().memory.clone(),
+            None => DEFAULT_MEMORY_EXPORT.to_owned(),
+        };
+        match self.instance.lookup(&name) {
+            Some(Export::Memory { definition, .. }) => unsafe {
+                Ok(std::slice::from_raw_parts_mut(
+                    (*definition).base,
+                    (*definition).current_length,
+                ))
+            },
+            _ => Err(RunError::Type(format!("module exports no memory {:?}", name))),
+        }
+    }
+
+    /// Capture the module's WASI memory and exported globals.
+    ///
+    /// The WASI context isn't part of the snapshot: the pinned wasi-common
+    /// keeps its fd table private, so there's no way to describe it.
+    pub fn snapshot(&mut self) -> Result<Snapshot, RunError> {
+        let memory = self.memory()?.to_vec();
+        let mut globals = Vec::new();
+        for name in self.global_names() {
+            if let Some(Export::Global { definition, .. }) = self.instance.lookup(&name) {
+                globals.push((name, unsafe { *(*definition).as_i64() }));
+            }
+        }
+        Ok(Snapshot { memory, globals })
+    }
+
+    /// Bring the module's WASI memory and exported globals back to what
+    /// they were in `snapshot`, taken from an instance of the same module.
+    ///
+    /// Memory can't be grown from the host, so the module's memory must be
+    /// at least as large as the snapshot's; anything beyond is zeroed.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), RunError> {
+        for (name, value) in &snapshot.globals {
+            match self.instance.lookup(name) {
+                Some(Export::Global { definition, .. }) => unsafe {
+                    *(*definition).as_i64_mut() = *value;
+                },
+                _ => return Err(RunError::Type(format!("module exports no global {:?}", name))),
+            }
+        }
+        let memory = self.memory()?;
+        if memory.len() < snapshot.memory.len() {
+            return Err(RunError::Type(format!(
+                "memory is {} bytes, but the snapshot's is {}",
+                memory.len(),
+                snapshot.memory.len()
+            )));
+        }
+        let (restored, rest) = memory.split_at_mut(snapshot.memory.len());
+        restored.copy_from_slice(&snapshot.memory);
+        for byte in rest {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    fn global_names(&self) -> Vec<String> {
+        self.instance
+            .exports()
+            .filter(|(name, export)| match export {
+                // the interrupt is the host's to set
+                wasmtime_environ::Export::Global(_) => name.as_str() != INTERRUPT_EXPORT,
+                _ => false,
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Returns the resources the module consumed so far, or `None` if it
+    /// doesn't import WASI.
+    pub fn resource_usage(&mut self) -> Option<ResourceUsage> {
+        let state = self.state.as_ref()?;
+        let mut state = state.borrow_mut();
+        let memory = memory_len(self.instance.lookup(&state.memory));
+        let open_fds = state.fs.open_fds();
+        Some(state.usage.report(memory, open_fds))
+    }
+
+    /// Where the module spent its time so far, if it's being profiled and
+    /// imports WASI.
+    pub fn profile(&self) -> Option<Profile> {
+        let state = self.state.as_ref()?.borrow();
+        state.profiler.as_ref().map(|profiler| profiler.profile())
+    }
+
+    fn profile_enter(&mut self, export: &str) {
+        if let Some(ref state) = self.state {
+            if let Some(ref mut profiler) = state.borrow_mut().profiler {
+                profiler.enter(export);
+            }
+        }
+    }
+
+    fn profile_leave(&mut self, start: Instant) {
+        if let Some(ref state) = self.state {
+            if let Some(ref mut profiler) = state.borrow_mut().profiler {
+                profiler.leave(start.elapsed());
+            }
+        }
+    }
+
+    /// The instance of the module itself.
+    pub fn instance(&mut self) -> &mut InstanceHandle {
+        &mut self.instance
+    }
+
+    /// Writes a core dump of the trap `message` in `function`, if the module
+    /// is to leave one.
+    fn dump_core(&mut self, function: &str, message: &str) {
+        let dir = match self.core_dumps {
+            Some(ref dir) => dir.clone(),
+            None => return,
+        };
+        let snapshot = match self.snapshot() {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                log::error!("couldn't capture a core dump: {}", err);
+                return;
+            }
+        };
+        let dump = match self.state {
+            Some(ref state) => {
+                CoreDump::new(function, message, snapshot, Some(&mut *state.borrow_mut()))
+            }
+            None => CoreDump::new(function, message, snapshot, None),
+        };
+        match dump.write_to(&dir) {
+            Ok(path) => log::warn!("module trapped, core dumped to {}", path.display()),
+            Err(err) => log::error!("couldn't write a core dump to {}: {}", dir.display(), err),
+        }
+    }
+
+    /// Zeroes the copies of guest data held outside the WASI memory. A
+    /// trapped module can't be reset, so its pristine memory is no use
+    /// anymore either.
+    fn scrub(&mut self) {
+        if let Some((mut pristine, _)) = self.pristine.take() {
+            zeroize_vec(&mut pristine);
+        }
+        if let Some(ref state) = self.state {
+            state.borrow_mut().scrub();
+        }
+    }
+}
+
+impl Drop for WasiModule {
+    fn drop(&mut self) {
+        // The states and `Control`s sharing the interrupt may outlive the
+        // instances, whose globals it points into.
+        self.interrupt.release();
+        self.scrub();
+        if let Ok(memory) = self.memory() {
+            zeroize(memory);
+        }
+    }
+}
+
+/// How hard Cranelift tries to optimize the generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    /// Compile as fast as possible, e.g. for short-lived keeps.
+    None,
+    /// Optimize for speed.
+    Speed,
+    /// Optimize for speed and code size, at the cost of compile time.
+    SpeedAndSize,
+}
+
+impl OptLevel {
+    fn setting(self) -> &'static str {
+        match self {
+            OptLevel::None => "fastest",
+            OptLevel::Speed => "default",
+            OptLevel::SpeedAndSize => "best",
+        }
+    }
+}
+
+/// Code generation settings modules are compiled with.
+#[derive(Clone)]
+pub(crate) struct CompileOptions {
+    pub(crate) opt_level: OptLevel,
+    pub(crate) canonicalize_nans: bool,
+    pub(crate) features: Features,
+    /// Target to generate code for, instead of the detected host CPU.
+    pub(crate) target: Option<Triple>,
+    pub(crate) isa_flags: Vec<(String, String)>,
+    /// Whether to emit DWARF for the generated code, and register it with
+    /// the GDB JIT interface.
+    pub(crate) debug_info: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            opt_level: OptLevel::Speed,
+            canonicalize_nans: false,
+            features: Features::default(),
+            target: None,
+            isa_flags: Vec::new(),
+            debug_info: false,
+        }
+    }
+}
+
+impl CompileOptions {
+    /// Returns a JIT context compiling with these settings.
+    pub(crate) fn context(&self) -> Result<Context, RunError> {
+        let mut isa_builder = match self.target {
+            // The code is run right here, so it must at least be for the
+            // host's architecture, but this spares us detecting the host
+            // CPU's features, so the generated code doesn't depend on the
+            // machine the keep happens to land on.
+            Some(ref target) if target.architecture != HOST.architecture => {
+                return Err(RunError::Isa(format!(
+                    "can't run code generated for {} on {}, and compiling ahead of time \
+                     for another architecture isn't supported",
+                    target, HOST
+                )));
+            }
+            Some(ref target) => isa::lookup(target.clone())
+                .map_err(|err| RunError::Isa(format!("{}: {}", target, err)))?,
+            None => cranelift_native::builder().map_err(|msg| RunError::Isa(msg.to_owned()))?,
+        };
+        for (name, value) in &self.isa_flags {
+            isa_builder.set(name, value).map_err(|err| {
+                RunError::Isa(format!("couldn't set ISA flag {} to {}: {}", name, value, err))
+            })?;
+        }
+        let mut flag_builder = settings::builder();
+        flag_builder
+            .set("opt_level", self.opt_level.setting())
+            .map_err(|err| RunError::Isa(format!("couldn't set opt level: {}", err)))?;
+        if self.canonicalize_nans {
+            flag_builder
+                .enable("enable_nan_canonicalization")
+                .map_err(|err| RunError::Isa(format!("couldn't canonicalize NaNs: {}", err)))?;
+        }
+        let isa = isa_builder.finish(settings::Flags::new(flag_builder));
+        let mut context = Context::with_isa(isa).with_features(self.features.clone());
+        context.set_debug_info(self.debug_info);
+        Ok(context)
+    }
+}
+
+/// An error returned while setting up or running a module.
+#[derive(Debug)]
+pub enum RunError {
+    /// Code can't be generated for the host, or the requested target.
+    Isa(String),
+    /// The module's imports are malformed or don't match the syscalls.
+    Imports(ImportError),
+    /// The WASI instances couldn't be created.
+    Instantiation(InstantiationError),
+    /// The module exceeds the configured limits.
+    Limits(String),
+    /// The module couldn't be compiled, linked or called.
+    Jit(String),
+    /// An export was called with, or returned, values of the wrong types,
+    /// or guest memory couldn't be accessed.
+    Type(String),
+    /// The module trapped.
+    Trap(String),
+    /// The module called `proc_exit` with this code.
+    Exit(u32),
+    /// The module was stopped through its `Control`.
+    Stopped,
+}
+
+impl From<ImportError> for RunError {
+    fn from(err: ImportError) -> Self {
+        RunError::Imports(err)
+    }
+}
+
+impl From<InstantiationError> for RunError {
+    fn from(err: InstantiationError) -> Self {
+        RunError::Instantiation(err)
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Isa(msg) => write!(f, "host machine is not supported: {}", msg),
+            RunError::Imports(err) => write!(f, "{}", err),
+            RunError::Instantiation(err) => {
+                write!(f, "couldn't instantiate the WASI syscalls: {}", err)
+            }
+            RunError::Limits(msg) => write!(f, "module exceeds its limits: {}", msg),
+            RunError::Jit(msg) => write!(f, "{}", msg),
+            RunError::Type(msg) => write!(f, "{}", msg),
+            RunError::Trap(msg) => write!(f, "module trapped: {}", msg),
+            RunError::Exit(code) => write!(f, "module exited with code {}", code),
+            RunError::Stopped => write!(f, "module was stopped"),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
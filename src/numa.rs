@@ -0,0 +1,67 @@
+use std::fs;
+use std::io;
+
+/// `mbind` policy restricting allocations to the given nodes.
+const MPOL_BIND: libc::c_int = 2;
+/// `mbind` flag moving pages already allocated elsewhere.
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+const BITS: usize = 8 * std::mem::size_of::<libc::c_ulong>();
+
+/// Pins the calling thread, and so the threads it spawns from then on, to
+/// the CPUs of NUMA node `node`.
+pub(crate) fn bind_thread(node: u32) -> io::Result<()> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let cpus = parse_cpu_list(fs::read_to_string(&path)?.trim()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("couldn't parse {}", path),
+        )
+    })?;
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Allocates the `len` bytes of memory at `addr` on NUMA node `node` only,
+/// moving any pages which already live elsewhere.
+pub(crate) unsafe fn bind_memory(node: u32, addr: *mut u8, len: usize) -> io::Result<()> {
+    let node = node as usize;
+    let mut mask = vec![0 as libc::c_ulong; node / BITS + 1];
+    mask[node / BITS] |= 1 << (node % BITS);
+    let ret = libc::syscall(
+        libc::SYS_mbind,
+        addr,
+        len,
+        MPOL_BIND,
+        mask.as_ptr(),
+        mask.len() * BITS + 1,
+        MPOL_MF_MOVE,
+    );
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Parses a list of CPUs like `0-3,8,10-11`, as found in sysfs.
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let first: usize = bounds.next()?.parse().ok()?;
+        let last = match bounds.next() {
+            Some(last) => last.parse().ok()?,
+            None => first,
+        };
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
@@ -0,0 +1,258 @@
+use super::builder::WasiInstanceBuilder;
+use super::module::{RunError, WasiModule};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// A pool of pre-instantiated copies of a module, for serving requests
+/// without paying for compilation and instantiation on each one.
+///
+/// Modules handed back to the pool are reset in place: their memory is
+/// restored to what it was right after instantiation, and their WASI
+/// context replaced with a fresh one from `factory`. Modules which can't
+/// be reset that way (because they trapped, or grew their memory) are
+/// replaced with a new instance instead.
+///
+/// ```ignore
+/// let mut pool = InstancePool::new(&wasm, 4, || WasiInstanceBuilder::new().arg("handler"))?;
+/// let mut module = pool.get()?;
+/// let response: i32 = module.call("handle", (request,))?;
+/// pool.put(module)?;
+/// ```
+pub struct InstancePool<F> {
+    wasm: Vec<u8>,
+    factory: F,
+    idle: Vec<WasiModule>,
+    /// Number of idle modules `replenish` tops the pool up to.
+    standby: usize,
+    /// Whether modules are initialized before they're pooled.
+    warm: bool,
+}
+
+impl<F: FnMut() -> WasiInstanceBuilder> InstancePool<F> {
+    /// Instantiate `size` copies of `wasm`, each configured by a builder
+    /// returned by `factory`.
+    pub fn new(wasm: &[u8], size: usize, factory: F) -> Result<Self, RunError> {
+        Self::with_standby(wasm, size, factory, false)
+    }
+
+    /// Like `new`, for reactor modules: every module is also initialized
+    /// before it's pooled, and reset back to its initialized state, so a
+    /// request can be handed to it right away.
+    ///
+    /// The WASI context a module gets on reset is a fresh one, so fds its
+    /// `_initialize` opened don't survive resets.
+    ///
+    /// The pool is only topped up again by `replenish`; `StandbyPool` does
+    /// that in the background.
+    pub fn warm(wasm: &[u8], standby: usize, factory: F) -> Result<Self, RunError> {
+        Self::with_standby(wasm, standby, factory, true)
+    }
+
+    fn with_standby(wasm: &[u8], standby: usize, factory: F, warm: bool) -> Result<Self, RunError> {
+        let mut pool = Self {
+            wasm: wasm.to_vec(),
+            factory,
+            idle: Vec::with_capacity(standby),
+            standby,
+            warm,
+        };
+        pool.replenish()?;
+        Ok(pool)
+    }
+
+    /// Take a pristine module out of the pool, instantiating a new one if
+    /// none is idle.
+    pub fn get(&mut self) -> Result<WasiModule, RunError> {
+        match self.idle.pop() {
+            Some(module) => Ok(module),
+            None => self.instantiate(),
+        }
+    }
+
+    /// Hand `module` back to the pool once it's done serving a request.
+    pub fn put(&mut self, mut module: WasiModule) -> Result<(), RunError> {
+        let factory = &mut self.factory;
+        if !module.reset(|| factory().into_state())? {
+            module = self.instantiate()?;
+        }
+        self.idle.push(module);
+        Ok(())
+    }
+
+    /// Number of idle modules in the pool.
+    pub fn idle(&self) -> usize {
+        self.idle.len()
+    }
+
+    /// Instantiate modules until the pool holds as many idle ones as it was
+    /// created with again, returning how many that took.
+    ///
+    /// Modules can't be sent to another thread, so this can't happen in the
+    /// background here: call it whenever the thread owning the pool has
+    /// nothing better to do, e.g. between requests, so `get` doesn't have
+    /// to instantiate while a request waits. `StandbyPool` keeps each
+    /// module on a thread of its own instead, which can.
+    pub fn replenish(&mut self) -> Result<usize, RunError> {
+        let missing = self.standby.saturating_sub(self.idle.len());
+        for _ in 0..missing {
+            let module = self.instantiate()?;
+            self.idle.push(module);
+        }
+        Ok(missing)
+    }
+
+    fn instantiate(&mut self) -> Result<WasiModule, RunError> {
+        let mut module = (self.factory)().instantiate_module(&self.wasm)?;
+        if self.warm {
+            module.initialize()?;
+        }
+        module.capture();
+        Ok(module)
+    }
+}
+
+/// A request handed to a standby keep.
+type Job = Box<dyn FnOnce(&mut WasiModule) + Send>;
+
+/// Warm standby keeps of a module, each on a thread of its own with its
+/// module instantiated and initialized, waiting to be handed a request.
+///
+/// Each keep serves a single request, and is replaced by a new one in the
+/// background as it's handed one, so requests don't wait for
+/// instantiation as long as they don't come faster than keeps start.
+/// Modules can't leave the thread they were created on, so the builder is
+/// made by `factory` on the keep's thread, and the request runs there too.
+///
+/// ```ignore
+/// let pool = StandbyPool::new(wasm, 4, || WasiInstanceBuilder::new().arg("handler"));
+/// let response: i32 = pool.run(move |module| module.call("handle", (request,)))?;
+/// ```
+pub struct StandbyPool<F> {
+    shared: Arc<Standby<F>>,
+}
+
+struct Standby<F> {
+    wasm: Arc<[u8]>,
+    factory: F,
+    ready: Mutex<Ready>,
+    /// Notified when a keep is ready, or failed to start.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Ready {
+    /// Where to send each ready keep its request, or why it failed to
+    /// start.
+    keeps: Vec<Result<Sender<Job>, RunError>>,
+    /// Whether the pool was dropped, so keeps still starting are to exit.
+    closed: bool,
+}
+
+impl<F> StandbyPool<F>
+where
+    F: Fn() -> WasiInstanceBuilder + Send + Sync + 'static,
+{
+    /// Start `standby` keeps of `wasm` in the background, each configured
+    /// by a builder returned by `factory`.
+    pub fn new(wasm: Vec<u8>, standby: usize, factory: F) -> Self {
+        let shared = Arc::new(Standby {
+            wasm: wasm.into(),
+            factory,
+            ready: Mutex::new(Ready::default()),
+            changed: Condvar::new(),
+        });
+        for _ in 0..standby {
+            Standby::start(&shared);
+        }
+        Self { shared }
+    }
+
+    /// Hand `job` to a ready keep, waiting for one if none is, and return
+    /// what it returns. The keep's module is dropped after, and another
+    /// keep started in its place.
+    pub fn run<R, J>(&self, job: J) -> Result<R, RunError>
+    where
+        R: Send + 'static,
+        J: FnOnce(&mut WasiModule) -> Result<R, RunError> + Send + 'static,
+    {
+        // the keep's replacement, started first so there's one to wait
+        // for even in a pool of none
+        Standby::start(&self.shared);
+        let keep = self.shared.take();
+        let (tx, rx) = mpsc::channel();
+        let job: Job = Box::new(move |module| {
+            let _ = tx.send(job(module));
+        });
+        keep?
+            .send(job)
+            .map_err(|_| RunError::Jit("the standby keep is gone".to_owned()))?;
+        rx.recv()
+            .unwrap_or_else(|_| Err(RunError::Jit("the standby keep panicked".to_owned())))
+    }
+
+    /// Number of keeps ready for a request.
+    pub fn ready(&self) -> usize {
+        let ready = self.shared.ready.lock().unwrap();
+        ready.keeps.iter().filter(|keep| keep.is_ok()).count()
+    }
+}
+
+impl<F> Drop for StandbyPool<F> {
+    fn drop(&mut self) {
+        let mut ready = self.shared.ready.lock().unwrap();
+        ready.closed = true;
+        // the keeps waiting for a request exit when their sender is dropped
+        ready.keeps.clear();
+    }
+}
+
+impl<F> Standby<F>
+where
+    F: Fn() -> WasiInstanceBuilder + Send + Sync + 'static,
+{
+    /// Starts a keep on a thread of its own, which waits for a request once
+    /// its module is initialized.
+    fn start(standby: &Arc<Self>) {
+        let standby = standby.clone();
+        thread::spawn(move || {
+            let module = (standby.factory)()
+                .instantiate_module(&standby.wasm)
+                .and_then(|mut module| {
+                    module.initialize()?;
+                    Ok(module)
+                });
+            let (tx, rx) = mpsc::channel::<Job>();
+            let mut module = match module {
+                Ok(module) => {
+                    standby.put(Ok(tx));
+                    module
+                }
+                Err(err) => return standby.put(Err(err)),
+            };
+            if let Ok(job) = rx.recv() {
+                job(&mut module);
+            }
+        });
+    }
+
+    fn put(&self, keep: Result<Sender<Job>, RunError>) {
+        let mut ready = self.ready.lock().unwrap();
+        if !ready.closed {
+            ready.keeps.push(keep);
+            self.changed.notify_one();
+        }
+    }
+
+    /// Takes a ready keep, or the error a keep failed to start with,
+    /// waiting for either.
+    fn take(&self) -> Result<Sender<Job>, RunError> {
+        let mut ready = self.ready.lock().unwrap();
+        loop {
+            if let Some(keep) = ready.keeps.pop() {
+                return keep;
+            }
+            ready = self.changed.wait(ready).unwrap();
+        }
+    }
+}
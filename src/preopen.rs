@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use wasi_common::wasm32;
+
+use super::fs::WRITE_RIGHTS;
+
+/// Fd of the first preopen. The `WasiCtx` numbers preopens consecutively
+/// after stdio, in the order they were added.
+pub(crate) const FIRST_PREOPEN_FD: wasm32::__wasi_fd_t = 3;
+
+/// A directory to preopen, along with the restrictions applying to it.
+pub(crate) struct Preopen {
+    pub(crate) guest_path: String,
+    pub(crate) dir: Box<dyn PreopenDir>,
+    pub(crate) rights: PreopenRights,
+}
+
+impl Preopen {
+    pub(crate) fn new(guest_path: &str, dir: impl PreopenDir + 'static) -> Self {
+        Self {
+            guest_path: guest_path.to_owned(),
+            dir: Box::new(dir),
+            rights: PreopenRights::full(),
+        }
+    }
+}
+
+/// Rights a preopen grants the guest: the `base` rights on the directory
+/// itself, and the `inheriting` rights on anything opened beneath it.
+///
+/// The guest sees them through `fd_fdstat_get`, and the `WasiCtx` enforces
+/// them like rights the guest dropped itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreopenRights {
+    pub(crate) base: wasm32::__wasi_rights_t,
+    pub(crate) inheriting: wasm32::__wasi_rights_t,
+    pub(crate) create_only: bool,
+    pub(crate) umask: Option<u32>,
+}
+
+/// Rights to create files and directories, and write to files once
+/// created.
+const CREATE_RIGHTS: wasm32::__wasi_rights_t = wasm32::__WASI_RIGHT_PATH_OPEN
+    | wasm32::__WASI_RIGHT_PATH_CREATE_FILE
+    | wasm32::__WASI_RIGHT_PATH_CREATE_DIRECTORY
+    | wasm32::__WASI_RIGHT_FD_WRITE
+    | wasm32::__WASI_RIGHT_FD_DATASYNC
+    | wasm32::__WASI_RIGHT_FD_SYNC
+    | wasm32::__WASI_RIGHT_FD_ALLOCATE
+    | wasm32::__WASI_RIGHT_FD_FILESTAT_GET
+    | wasm32::__WASI_RIGHT_FD_FILESTAT_SET_SIZE
+    | wasm32::__WASI_RIGHT_FD_FDSTAT_SET_FLAGS;
+
+impl PreopenRights {
+    /// Every right the `WasiCtx` grants a directory.
+    pub fn full() -> Self {
+        Self::new(!0, !0)
+    }
+
+    /// Rights to look, but not to create, modify or remove anything.
+    pub fn read_only() -> Self {
+        Self::new(!WRITE_RIGHTS, !WRITE_RIGHTS)
+    }
+
+    /// Rights to create new files and directories and write to them, but
+    /// not to read, list, modify or remove anything already there, as for
+    /// a drop box.
+    pub fn create_only() -> Self {
+        Self {
+            create_only: true,
+            ..Self::new(CREATE_RIGHTS, CREATE_RIGHTS)
+        }
+    }
+
+    /// The given `base` and `inheriting` rights, limited to those the
+    /// `WasiCtx` grants a directory.
+    pub fn new(base: wasm32::__wasi_rights_t, inheriting: wasm32::__wasi_rights_t) -> Self {
+        Self {
+            base,
+            inheriting,
+            create_only: false,
+            umask: None,
+        }
+    }
+
+    /// Create files and directories beneath the preopen with `umask` in
+    /// place of the process's, e.g. `0o177` for files only their owner can
+    /// read or write, whatever the host's default is.
+    ///
+    /// Each gets its mode set right after it's created, so the process
+    /// umask, shared with the embedder's threads, is left alone.
+    pub fn umask(mut self, umask: u32) -> Self {
+        self.umask = Some(umask & 0o777);
+        self
+    }
+
+    /// Whether nothing may be modified through the preopen.
+    pub(crate) fn is_readonly(&self) -> bool {
+        (self.base | self.inheriting) & WRITE_RIGHTS == 0
+    }
+}
+
+/// Parses a `GUEST::HOST` directory mapping, as used by `--mapdir`-style
+/// options, into the guest path and the host directory backing it.
+pub fn parse_map_dir(spec: &str) -> Result<(String, PathBuf), String> {
+    let mut parts = spec.splitn(2, "::");
+    match (parts.next(), parts.next()) {
+        (Some(guest), Some(host)) if !guest.is_empty() && !host.is_empty() => {
+            Ok((guest.to_owned(), PathBuf::from(host)))
+        }
+        _ => Err(format!(
+            "invalid directory mapping {:?}, expected GUEST::HOST",
+            spec
+        )),
+    }
+}
+
+/// Brings a guest path into the canonical form the guest will see it in:
+/// absolute, without `.` components, repeated or trailing slashes.
+pub(crate) fn normalize_guest_path(path: &str) -> Result<String, String> {
+    let mut normalized = String::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return Err(format!("guest path {:?} contains \"..\"", path)),
+            component => {
+                normalized.push('/');
+                normalized.push_str(component);
+            }
+        }
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// A directory which can be preopened for the guest.
+///
+/// NB: the `WasiCtx` still performs all filesystem operations on host file
+/// descriptors, so for now every implementation has to end up as a host
+/// directory handle. The indirection lets callers hand over whatever they
+/// hold (an open `File`, or just a path) and gives non-host backends a
+/// place to slot in later.
+pub trait PreopenDir {
+    /// Opens (or hands over) the host directory handle backing the preopen.
+    fn open_dir(self: Box<Self>) -> io::Result<File>;
+}
+
+impl PreopenDir for File {
+    fn open_dir(self: Box<Self>) -> io::Result<File> {
+        Ok(*self)
+    }
+}
+
+impl PreopenDir for PathBuf {
+    fn open_dir(self: Box<Self>) -> io::Result<File> {
+        open_dir(&self)
+    }
+}
+
+impl PreopenDir for &'static Path {
+    fn open_dir(self: Box<Self>) -> io::Result<File> {
+        open_dir(*self)
+    }
+}
+
+impl PreopenDir for &'static str {
+    fn open_dir(self: Box<Self>) -> io::Result<File> {
+        open_dir(Path::new(*self))
+    }
+}
+
+fn open_dir(path: &Path) -> io::Result<File> {
+    let dir = File::open(path)?;
+    if !dir.metadata()?.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} is not a directory", path.display()),
+        ));
+    }
+    Ok(dir)
+}
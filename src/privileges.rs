@@ -0,0 +1,147 @@
+use std::cell::Cell;
+use std::fs;
+use std::io;
+
+const PR_CAP_AMBIENT: libc::c_int = 47;
+const PR_CAP_AMBIENT_CLEAR_ALL: libc::c_ulong = 4;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+thread_local! {
+    /// What the calling thread has given up already, if anything: it can
+    /// be done only once, since it takes the capabilities it gives up.
+    static DROPPED: Cell<Option<(Option<(libc::uid_t, libc::gid_t)>, libc::c_int)>> =
+        Cell::new(None);
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// The authority the runtime gives up before it runs guest code, for when
+/// it's started as root but keeps must not retain that.
+///
+/// Namespaces and capabilities are per thread: they're left on the
+/// instantiating thread, and the threads it spawns afterwards. The user
+/// and group are switched for the whole process.
+///
+/// The authority is given up on the first instantiation on a thread only;
+/// later ones, e.g. a `Supervisor`'s restarts, check that it's gone.
+///
+/// ```ignore
+/// let drop = PrivilegeDrop::new().user(65534, 65534).unshare_network();
+/// WasiInstanceBuilder::new().privilege_drop(drop).run(&wasm)?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PrivilegeDrop {
+    user: Option<(libc::uid_t, libc::gid_t)>,
+    /// `CLONE_NEW*` flags of the namespaces to enter.
+    namespaces: libc::c_int,
+}
+
+impl PrivilegeDrop {
+    /// Drop every capability, and keep setuid binaries from granting new
+    /// ones.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Switch to the user `uid` and the group `gid`, without supplementary
+    /// groups.
+    pub fn user(mut self, uid: u32, gid: u32) -> Self {
+        self.user = Some((uid, gid));
+        self
+    }
+
+    /// Enter a new network namespace, without any interfaces but a
+    /// loopback one which is down.
+    pub fn unshare_network(mut self) -> Self {
+        self.namespaces |= libc::CLONE_NEWNET;
+        self
+    }
+
+    /// Enter new IPC and UTS namespaces, away from the host's System V IPC
+    /// objects, POSIX message queues and hostname.
+    pub fn unshare_ipc(mut self) -> Self {
+        self.namespaces |= libc::CLONE_NEWIPC | libc::CLONE_NEWUTS;
+        self
+    }
+
+    /// Gives up the authority for the calling thread. Entering namespaces
+    /// and switching users takes capabilities, so it happens first.
+    ///
+    /// If the thread has given it up already, this only checks that it
+    /// gave up at least as much, since it couldn't do it again.
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if let Some((user, namespaces)) = DROPPED.with(Cell::get) {
+            if self.namespaces & !namespaces != 0 || self.user.map_or(false, |u| Some(u) != user) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "this thread already dropped its privileges differently",
+                ));
+            }
+            return Ok(());
+        }
+        unsafe {
+            if self.namespaces != 0 && libc::unshare(self.namespaces) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            drop_bounding_set()?;
+            if let Some((uid, gid)) = self.user {
+                if libc::setgroups(0, std::ptr::null()) != 0
+                    || libc::setresgid(gid, gid, gid) != 0
+                    || libc::setresuid(uid, uid, uid) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if libc::prctl(PR_CAP_AMBIENT, PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let header = CapHeader {
+                version: LINUX_CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+            let data = [CapData::default(), CapData::default()];
+            if libc::syscall(libc::SYS_capset, &header, data.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        DROPPED.with(|dropped| dropped.set(Some((self.user, self.namespaces))));
+        Ok(())
+    }
+}
+
+/// Drops every capability from the bounding set, so none can be regained.
+/// Without `CAP_SETPCAP` that's not allowed, but then there's nothing to
+/// regain them from anyway with `PR_SET_NO_NEW_PRIVS` set.
+unsafe fn drop_bounding_set() -> io::Result<()> {
+    let last: u32 = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|last| last.trim().parse().ok())
+        .unwrap_or(63);
+    for cap in 0..=last {
+        if libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) != 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // beyond the last capability the kernel knows of
+                Some(libc::EINVAL) => break,
+                Some(libc::EPERM) => return Ok(()),
+                _ => return Err(err),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use super::usage::SyscallUsage;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Frame standing in for the export, for syscalls made while none was
+/// called through `WasiModule`.
+const UNKNOWN_EXPORT: &str = "[unknown]";
+
+/// Where a guest spent its time, by the export the host called and the
+/// syscalls made from it, as collected with `WasiInstanceBuilder::profile`.
+///
+/// The pinned JIT doesn't unwind the guest stack, so the export is as deep
+/// as a call site goes.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Wall-clock time each export ran for, syscalls included.
+    exports: HashMap<String, Duration>,
+    /// Time spent in each syscall, by the export it was made from.
+    syscalls: HashMap<String, HashMap<&'static str, SyscallUsage>>,
+}
+
+impl Profile {
+    /// Time spent in each syscall, across exports.
+    pub fn syscalls(&self) -> HashMap<&'static str, SyscallUsage> {
+        let mut syscalls = HashMap::new();
+        for (&name, usage) in self.syscalls.values().flatten() {
+            let total: &mut SyscallUsage = syscalls.entry(name).or_default();
+            total.calls += usage.calls;
+            total.time += usage.time;
+            total.cpu_time += usage.cpu_time;
+        }
+        syscalls
+    }
+
+    /// A table of the syscalls by the wall-clock time spent in them, most
+    /// first.
+    pub fn report(&self) -> String {
+        let mut syscalls: Vec<_> = self.syscalls().into_iter().collect();
+        syscalls.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+        let total: Duration = syscalls.iter().map(|(_, usage)| usage.time).sum();
+        let mut out = format!(
+            "{:<24} {:>10} {:>12} {:>12} {:>7}\n",
+            "syscall", "calls", "time (us)", "cpu (us)", "share"
+        );
+        for (name, usage) in syscalls {
+            let share = if total.as_nanos() == 0 {
+                0.0
+            } else {
+                usage.time.as_nanos() as f64 * 100.0 / total.as_nanos() as f64
+            };
+            let _ = writeln!(
+                out,
+                "{:<24} {:>10} {:>12} {:>12} {:>6.1}%",
+                name,
+                usage.calls,
+                usage.time.as_micros(),
+                usage.cpu_time.as_micros(),
+                share
+            );
+        }
+        out
+    }
+
+    /// The profile as folded stacks, e.g. for `flamegraph.pl`: a line per
+    /// export and per syscall made from it, with the microseconds spent
+    /// there. An export's own line has the time it spent outside of
+    /// syscalls, in the guest.
+    pub fn folded(&self) -> String {
+        let mut lines = Vec::new();
+        let mut in_syscalls: HashMap<&str, Duration> = HashMap::new();
+        for (export, syscalls) in &self.syscalls {
+            for (name, usage) in syscalls {
+                *in_syscalls.entry(export).or_default() += usage.time;
+                lines.push((format!("{};{}", frame(export), name), usage.time));
+            }
+        }
+        for (export, time) in &self.exports {
+            let syscalls = in_syscalls.get(export.as_str()).cloned();
+            let guest = time
+                .checked_sub(syscalls.unwrap_or_default())
+                .unwrap_or_default();
+            lines.push((frame(export), guest));
+        }
+        lines.sort();
+        let mut out = String::new();
+        for (stack, time) in lines {
+            if time.as_micros() > 0 {
+                let _ = writeln!(out, "{} {}", stack, time.as_micros());
+            }
+        }
+        out
+    }
+}
+
+/// An export name as a frame of a folded stack, which can't hold `;` or
+/// spaces.
+fn frame(export: &str) -> String {
+    export.replace(|c: char| c == ';' || c.is_whitespace(), "_")
+}
+
+/// Profile collection kept in the host state.
+#[derive(Default)]
+pub(crate) struct Profiler {
+    /// The export being called, if any.
+    export: Option<String>,
+    profile: Profile,
+}
+
+impl Profiler {
+    pub(crate) fn enter(&mut self, export: &str) {
+        self.export = Some(export.to_owned());
+    }
+
+    /// Records that the export entered last returned, or trapped, after
+    /// `time`.
+    pub(crate) fn leave(&mut self, time: Duration) {
+        if let Some(export) = self.export.take() {
+            *self.profile.exports.entry(export).or_default() += time;
+        }
+    }
+
+    pub(crate) fn hostcall(&mut self, name: &'static str, time: Duration, cpu_time: Duration) {
+        let export = self.export.as_ref().map_or(UNKNOWN_EXPORT, String::as_str);
+        // not `entry`, which would allocate a key on every syscall
+        if !self.profile.syscalls.contains_key(export) {
+            self.profile
+                .syscalls
+                .insert(export.to_owned(), HashMap::new());
+        }
+        let syscalls = self.profile.syscalls.get_mut(export).unwrap();
+        let usage = syscalls.entry(name).or_default();
+        usage.calls += 1;
+        usage.time += time;
+        usage.cpu_time += cpu_time;
+    }
+
+    pub(crate) fn profile(&self) -> Profile {
+        self.profile.clone()
+    }
+}
@@ -0,0 +1,252 @@
+use log::error;
+use std::collections::HashMap;
+use wasi_common::{hostcalls, wasm32, WasiCtx};
+
+use super::fs::fdstat;
+use super::guest::{self, iovec, read_u32};
+use super::secret::zeroize_vec;
+
+/// Offset of the data in a read-ahead buffer, after the iovec and `nread`
+/// the `WasiCtx` fills it through.
+const HEADER: usize = 12;
+
+/// Reads ahead on regular files, so guests making many small sequential
+/// `fd_read`s don't pay for a host read each.
+///
+/// The host file position runs ahead of the guest's by what's buffered, so
+/// before anything else depends on an fd's position or contents, the
+/// buffer is handed back by seeking back over it. Writes made through
+/// other fds, or by the host, aren't seen in data already buffered.
+#[derive(Default)]
+pub(crate) struct ReadAhead {
+    /// Bytes to read ahead unless the guest advises otherwise, or 0 not to
+    /// read ahead at all.
+    size: usize,
+    fds: HashMap<wasm32::__wasi_fd_t, Buffer>,
+}
+
+struct Buffer {
+    /// Whether the fd is a regular file the position can be moved back on.
+    eligible: bool,
+    /// Bytes to read ahead on this fd, according to `fd_advise`.
+    size: usize,
+    /// `HEADER`, followed by the data read ahead.
+    buf: Vec<u8>,
+    /// The part of `buf` which hasn't been handed to the guest yet.
+    start: usize,
+    end: usize,
+}
+
+impl ReadAhead {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            size,
+            fds: HashMap::new(),
+        }
+    }
+
+    /// Serves an `fd_read` from, and into, the read-ahead buffer of `fd`.
+    /// Returns `None` if it's not one to read ahead on, for the read to
+    /// go straight to the `WasiCtx`.
+    pub(crate) fn read(
+        &mut self,
+        ctx: &mut WasiCtx,
+        memory: &mut [u8],
+        fd: wasm32::__wasi_fd_t,
+        iovs: wasm32::uintptr_t,
+        iovs_len: wasm32::size_t,
+        nread: wasm32::uintptr_t,
+    ) -> Option<wasm32::__wasi_errno_t> {
+        if self.size == 0 {
+            return None;
+        }
+        let size = self.size;
+        let buffer = self
+            .fds
+            .entry(fd)
+            .or_insert_with(|| Buffer::new(ctx, fd, size));
+        if !buffer.eligible || buffer.size == 0 {
+            return None;
+        }
+
+        let mut requested = 0;
+        for i in 0..iovs_len {
+            match iovec(memory, iovs, i) {
+                Some(range) => requested += range.len(),
+                None => return Some(wasm32::__WASI_EFAULT),
+            }
+        }
+        let nread = match guest::range(memory, nread, 4) {
+            Ok(range) => range,
+            Err(errno) => return Some(errno),
+        };
+        if buffer.start == buffer.end && requested >= buffer.size {
+            // reading ahead wouldn't save anything
+            return None;
+        }
+
+        let mut total = 0;
+        'iovs: for i in 0..iovs_len {
+            let range = match iovec(memory, iovs, i) {
+                Some(range) => range,
+                // the iovecs were overwritten by what was read into them
+                None => break,
+            };
+            let mut dst = range.start;
+            while dst < range.end {
+                if buffer.start == buffer.end {
+                    match buffer.refill(ctx, fd) {
+                        Ok(0) => break 'iovs,
+                        Ok(_) => {}
+                        Err(_) if total > 0 => break 'iovs,
+                        Err(errno) => return Some(errno),
+                    }
+                }
+                let n = (range.end - dst).min(buffer.end - buffer.start);
+                memory[dst..dst + n].copy_from_slice(&buffer.buf[buffer.start..buffer.start + n]);
+                buffer.start += n;
+                dst += n;
+                total += n;
+            }
+        }
+        memory[nread].copy_from_slice(&(total as u32).to_le_bytes());
+        Some(wasm32::__WASI_ESUCCESS)
+    }
+
+    /// Hands back what's buffered for `fd`, before its position or contents
+    /// are relied on by anything but `fd_read`.
+    pub(crate) fn release(&mut self, ctx: &mut WasiCtx, fd: wasm32::__wasi_fd_t) {
+        if let Some(buffer) = self.fds.get_mut(&fd) {
+            buffer.release(ctx, fd);
+        }
+    }
+
+    /// Forgets about `fd`, which was closed or replaced.
+    pub(crate) fn forget(&mut self, fd: wasm32::__wasi_fd_t) {
+        if let Some(mut buffer) = self.fds.remove(&fd) {
+            zeroize_vec(&mut buffer.buf);
+        }
+    }
+
+    /// Hands back and zeroes every buffer, so no file contents linger in
+    /// them.
+    pub(crate) fn scrub(&mut self, ctx: &mut WasiCtx) {
+        for (&fd, buffer) in &mut self.fds {
+            buffer.release(ctx, fd);
+            zeroize_vec(&mut buffer.buf);
+        }
+    }
+
+    /// Adjusts how far to read ahead on `fd` to the guest's `advice`.
+    pub(crate) fn advise(
+        &mut self,
+        ctx: &mut WasiCtx,
+        fd: wasm32::__wasi_fd_t,
+        advice: wasm32::__wasi_advice_t,
+    ) {
+        if self.size == 0 {
+            return;
+        }
+        let size = self.size;
+        let buffer = self
+            .fds
+            .entry(fd)
+            .or_insert_with(|| Buffer::new(ctx, fd, size));
+        buffer.release(ctx, fd);
+        buffer.size = match advice {
+            wasm32::__WASI_ADVICE_SEQUENTIAL | wasm32::__WASI_ADVICE_WILLNEED => 4 * size,
+            wasm32::__WASI_ADVICE_RANDOM
+            | wasm32::__WASI_ADVICE_DONTNEED
+            | wasm32::__WASI_ADVICE_NOREUSE => 0,
+            _ => size,
+        };
+    }
+}
+
+impl Buffer {
+    fn new(ctx: &mut WasiCtx, fd: wasm32::__wasi_fd_t, size: usize) -> Self {
+        let needed = wasm32::__WASI_RIGHT_FD_READ | wasm32::__WASI_RIGHT_FD_SEEK;
+        let eligible = match fdstat(ctx, fd) {
+            Ok((filetype, base, _)) => {
+                filetype == wasm32::__WASI_FILETYPE_REGULAR_FILE && base & needed == needed
+            }
+            Err(_) => false,
+        };
+        Self {
+            eligible,
+            size,
+            buf: Vec::new(),
+            start: HEADER,
+            end: HEADER,
+        }
+    }
+
+    /// Reads up to `size` bytes ahead, returning how many were read.
+    fn refill(
+        &mut self,
+        ctx: &mut WasiCtx,
+        fd: wasm32::__wasi_fd_t,
+    ) -> Result<usize, wasm32::__wasi_errno_t> {
+        self.buf.resize(HEADER + self.size, 0);
+        self.buf[0..4].copy_from_slice(&(HEADER as u32).to_le_bytes());
+        self.buf[4..8].copy_from_slice(&(self.size as u32).to_le_bytes());
+        let errno = hostcalls::fd_read(ctx, &mut self.buf, fd, 0, 1, 8);
+        if errno != wasm32::__WASI_ESUCCESS {
+            return Err(errno);
+        }
+        let n = read_u32(&self.buf, 8).unwrap_or(0) as usize;
+        self.start = HEADER;
+        self.end = HEADER + n;
+        Ok(n)
+    }
+
+    fn release(&mut self, ctx: &mut WasiCtx, fd: wasm32::__wasi_fd_t) {
+        let unread = self.end - self.start;
+        self.start = HEADER;
+        self.end = HEADER;
+        if unread == 0 {
+            return;
+        }
+        let mut newoffset = [0; 8];
+        let errno = hostcalls::fd_seek(
+            ctx,
+            &mut newoffset,
+            fd,
+            -(unread as i64),
+            wasm32::__WASI_WHENCE_CUR,
+            0,
+        );
+        if errno != wasm32::__WASI_ESUCCESS {
+            error!(
+                "couldn't hand back read-ahead of fd {}: errno {}",
+                fd, errno
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{data, iovec, seek, tell, Sparse, GIB, IOV, NREAD};
+
+    #[test]
+    fn hands_back_past_4_gib() {
+        let sparse = Sparse::new();
+        let mut memory = vec![0; 256];
+        let (mut ctx, fd) = sparse.open(&mut memory);
+        let delta = 5 * GIB as i64;
+        seek(&mut ctx, &mut memory, fd, delta, wasm32::__WASI_WHENCE_SET);
+        let mut read_ahead = ReadAhead::new(64);
+        for expected in &[b"ta", b"il"] {
+            iovec(&mut memory, 2);
+            let errno = read_ahead.read(&mut ctx, &mut memory, fd, IOV, 1, NREAD);
+            assert_eq!(errno, Some(wasm32::__WASI_ESUCCESS));
+            assert_eq!(data(&memory, 2), &expected[..]);
+        }
+        // the host's position is 64 bytes in, until the rest is handed back
+        assert_eq!(tell(&mut ctx, &mut memory, fd), 5 * GIB + 64);
+        read_ahead.release(&mut ctx, fd);
+        assert_eq!(tell(&mut ctx, &mut memory, fd), 5 * GIB + 4);
+    }
+}
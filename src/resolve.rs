@@ -0,0 +1,568 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use wasi_common::wasm32;
+
+use super::guest;
+
+#[cfg(target_os = "linux")]
+const SYS_OPENAT2: libc::c_long = 437;
+#[cfg(target_os = "linux")]
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+#[cfg(target_os = "linux")]
+const RESOLVE_BENEATH: u64 = 0x08;
+
+/// Flags the runtime opens its own handles on the guest's files with: just
+/// enough to resolve paths from, and to look at, without reading, writing,
+/// or blocking on a FIFO.
+#[cfg(target_os = "linux")]
+const HANDLE_FLAGS: libc::c_int = libc::O_PATH | libc::O_CLOEXEC;
+#[cfg(not(target_os = "linux"))]
+const HANDLE_FLAGS: libc::c_int = libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC;
+
+/// How many symlinks `open_beneath` follows before giving up with `ELOOP`,
+/// like Linux.
+const MAX_SYMLINKS: usize = 40;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// Bounds on the paths the guest may hand to the `WasiCtx`, so that it
+/// can't make the host do unbounded work resolving them.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PathLimits {
+    /// Maximum length of a path, in bytes.
+    pub(crate) max_len: Option<usize>,
+    /// Maximum number of directories deep beneath its fd a path may reach.
+    pub(crate) max_depth: Option<usize>,
+}
+
+/// Checks the path at `path[..path_len]`, which a `path_*` syscall is to
+/// resolve relative to a directory fd, before the `WasiCtx` sees it. Every
+/// path the guest hands to the `WasiCtx` goes through here first.
+///
+/// Only the path itself is looked at: a path which could only resolve
+/// outside the directory is refused with `__WASI_ENOTCAPABLE`, whatever
+/// the directory's contents. `check_beneath` then resolves it, symlinks
+/// and all, against the runtime's own handle on the directory.
+///
+/// A path longer or deeper than `limits` allow is refused with
+/// `__WASI_ENAMETOOLONG`. The `WasiCtx` bounds the symlinks it follows on
+/// the way itself, at 128 hops.
+pub(crate) fn check(
+    memory: &[u8],
+    path: wasm32::uintptr_t,
+    path_len: wasm32::size_t,
+    limits: PathLimits,
+) -> Result<(), wasm32::__wasi_errno_t> {
+    check_len(path_len, limits)?;
+    let range = guest::range(memory, path, path_len as usize)?;
+    check_bytes(&memory[range], limits)
+}
+
+/// Checks the target of a symlink the guest is about to create, which has
+/// to resolve beneath the directory it's created in for the link to be of
+/// any use. Its `..`s are checked once it's followed, against where the
+/// link lives. The link has to be within `limits` too, so that following
+/// it doesn't take more work than resolving a path the guest could pass.
+pub(crate) fn check_symlink_target(
+    memory: &[u8],
+    path: wasm32::uintptr_t,
+    path_len: wasm32::size_t,
+    limits: PathLimits,
+) -> Result<(), wasm32::__wasi_errno_t> {
+    check_len(path_len, limits)?;
+    let range = guest::range(memory, path, path_len as usize)?;
+    let target = &memory[range];
+    if target.contains(&0) {
+        return Err(wasm32::__WASI_EILSEQ);
+    }
+    if target.starts_with(b"/") {
+        return Err(wasm32::__WASI_ENOTCAPABLE);
+    }
+    let depth = target
+        .split(|&b| b == b'/')
+        .filter(|&component| !component.is_empty() && component != b"." && component != b"..")
+        .count();
+    check_depth(depth, limits)
+}
+
+fn check_len(len: wasm32::size_t, limits: PathLimits) -> Result<(), wasm32::__wasi_errno_t> {
+    match limits.max_len {
+        Some(max) if len as usize > max => Err(wasm32::__WASI_ENAMETOOLONG),
+        _ => Ok(()),
+    }
+}
+
+fn check_depth(depth: usize, limits: PathLimits) -> Result<(), wasm32::__wasi_errno_t> {
+    match limits.max_depth {
+        Some(max) if depth > max => Err(wasm32::__WASI_ENAMETOOLONG),
+        _ => Ok(()),
+    }
+}
+
+fn check_bytes(path: &[u8], limits: PathLimits) -> Result<(), wasm32::__wasi_errno_t> {
+    if path.is_empty() {
+        return Err(wasm32::__WASI_ENOENT);
+    }
+    if path.contains(&0) {
+        // the host would cut the path short there
+        return Err(wasm32::__WASI_EILSEQ);
+    }
+    if path.starts_with(b"/") {
+        return Err(wasm32::__WASI_ENOTCAPABLE);
+    }
+    // how many directories deep beneath the fd the path is at
+    let mut depth = 0usize;
+    for component in path.split(|&b| b == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => {
+                depth = depth.checked_sub(1).ok_or(wasm32::__WASI_ENOTCAPABLE)?;
+            }
+            _ => {
+                depth += 1;
+                check_depth(depth, limits)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails with `__WASI_ENOTCAPABLE` if `path`, already through `check`,
+/// resolves outside the directory `dir`, by way of symlinks; `follow` is
+/// whether a symlink at its end is followed.
+///
+/// The `WasiCtx` resolves paths beneath the directory too, but the pinned
+/// version does so by hand, in user space: this resolves them again with
+/// the kernel's `openat2(RESOLVE_BENEATH)` where there's one, so a bug in
+/// either can't let the guest out. A path to something yet to be created
+/// is checked up to the directory it's in. Any other error is left for the
+/// `WasiCtx` to report as it would.
+pub(crate) fn check_beneath(
+    dir: &File,
+    path: &[u8],
+    follow: bool,
+) -> Result<(), wasm32::__wasi_errno_t> {
+    let resolved = if follow {
+        match open_beneath(dir, path, true) {
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOENT) => resolve_parent(dir, path),
+            resolved => resolved.map(drop),
+        }
+    } else {
+        // a symlink at the end isn't followed, so only its way there counts
+        resolve_parent(dir, path)
+    };
+    match resolved.map_err(|err| err.raw_os_error()) {
+        Err(Some(libc::EXDEV)) => Err(wasm32::__WASI_ENOTCAPABLE),
+        Err(Some(libc::ELOOP)) => Err(wasm32::__WASI_ELOOP),
+        _ => Ok(()),
+    }
+}
+
+fn resolve_parent(dir: &File, path: &[u8]) -> io::Result<()> {
+    match parent(path) {
+        Some(parent) => open_beneath(dir, parent, true).map(drop),
+        None => Ok(()),
+    }
+}
+
+/// The directory `path` is in, if it's in another than the one it's
+/// relative to.
+fn parent(path: &[u8]) -> Option<&[u8]> {
+    let end = path.iter().rposition(|&b| b != b'/')?;
+    let slash = path[..end].iter().rposition(|&b| b == b'/')?;
+    Some(&path[..=slash])
+}
+
+/// Opens a handle on `path` for the runtime's own use, resolving it beneath
+/// the directory `dir`: a path leaving it, with `..` or an absolute symlink
+/// at any point, fails with `EXDEV`. `follow` is whether a symlink at the
+/// end of `path` is followed, rather than opened itself.
+pub(crate) fn open_beneath(dir: &File, path: &[u8], follow: bool) -> io::Result<File> {
+    #[cfg(target_os = "linux")]
+    {
+        match openat2_beneath(dir, path, follow) {
+            Err(ref err) if fallback(err) => {}
+            opened => return opened,
+        }
+    }
+    walk_beneath(dir, path, follow)
+}
+
+/// Whether `openat2` failed for lack of support, rather than because of the
+/// path, so resolving it by hand may still work.
+#[cfg(target_os = "linux")]
+fn fallback(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        // not before Linux 5.6, or filtered out; or a concurrent rename
+        // it refuses to race with
+        Some(libc::ENOSYS) | Some(libc::EPERM) | Some(libc::EAGAIN) => true,
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn openat2_beneath(dir: &File, path: &[u8], follow: bool) -> io::Result<File> {
+    let path = c_path(path)?;
+    let mut flags = HANDLE_FLAGS;
+    if !follow {
+        flags |= libc::O_NOFOLLOW;
+    }
+    let how = OpenHow {
+        flags: flags as u64,
+        mode: 0,
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            SYS_OPENAT2,
+            dir.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const OpenHow,
+            std::mem::size_of::<OpenHow>(),
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+/// Resolves `path` beneath `dir` like `openat2_beneath`, one component at a
+/// time, opening each without following symlinks and following those met
+/// by hand, for kernels without `openat2`.
+fn walk_beneath(dir: &File, path: &[u8], follow: bool) -> io::Result<File> {
+    let escape = || io::Error::from_raw_os_error(libc::EXDEV);
+    if path.starts_with(b"/") {
+        return Err(escape());
+    }
+    // the components still to resolve, the next one last
+    let mut pending: Vec<Vec<u8>> = components(path);
+    // the directories resolved so far, from `dir` down
+    let mut dirs = vec![dir.try_clone()?];
+    let mut symlinks = 0;
+    while let Some(component) = pending.pop() {
+        match &component[..] {
+            b"" | b"." => continue,
+            b".." => {
+                if dirs.len() == 1 {
+                    return Err(escape());
+                }
+                dirs.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let at = dirs.last().unwrap();
+        let name = c_path(&component)?;
+        let last = pending.is_empty();
+        if (follow || !last) && is_symlink(at, &name)? {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(io::Error::from_raw_os_error(libc::ELOOP));
+            }
+            let target = read_link(at, &name)?;
+            if target.is_empty() {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            if target.starts_with(b"/") {
+                return Err(escape());
+            }
+            pending.extend(components(&target));
+            continue;
+        }
+        let fd = unsafe {
+            libc::openat(
+                at.as_raw_fd(),
+                name.as_ptr(),
+                HANDLE_FLAGS | libc::O_NOFOLLOW,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        if last {
+            return Ok(file);
+        }
+        dirs.push(file);
+    }
+    Ok(dirs.pop().unwrap())
+}
+
+/// The components of `path`, last first, as `walk_beneath` pops them.
+fn components(path: &[u8]) -> Vec<Vec<u8>> {
+    path.split(|&b| b == b'/')
+        .rev()
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+fn c_path(path: &[u8]) -> io::Result<CString> {
+    CString::new(path).map_err(|_| io::Error::from_raw_os_error(libc::EILSEQ))
+}
+
+fn is_symlink(dir: &File, name: &CString) -> io::Result<bool> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::fstatat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            &mut stat,
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.st_mode & libc::S_IFMT == libc::S_IFLNK)
+}
+
+fn read_link(dir: &File, name: &CString) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; libc::PATH_MAX as usize];
+    let len = unsafe {
+        libc::readlinkat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(len as usize);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::TempDir;
+    use std::os::unix::fs::symlink;
+
+    const NO_LIMITS: PathLimits = PathLimits {
+        max_len: None,
+        max_depth: None,
+    };
+
+    #[test]
+    fn dotdot_above_the_directory() {
+        assert_eq!(
+            check_bytes(b"..", NO_LIMITS),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            check_bytes(b"../a", NO_LIMITS),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            check_bytes(b"a/../..", NO_LIMITS),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            check_bytes(b"a/b/../../../a", NO_LIMITS),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(check_bytes(b"a/..", NO_LIMITS), Ok(()));
+        assert_eq!(check_bytes(b"a/./b/../..", NO_LIMITS), Ok(()));
+    }
+
+    #[test]
+    fn absolute_paths() {
+        assert_eq!(
+            check_bytes(b"/", NO_LIMITS),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            check_bytes(b"/etc/passwd", NO_LIMITS),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(check_bytes(b"a//b", NO_LIMITS), Ok(()));
+    }
+
+    #[test]
+    fn nul_and_empty_paths() {
+        assert_eq!(check_bytes(b"a\0b", NO_LIMITS), Err(wasm32::__WASI_EILSEQ));
+        assert_eq!(check_bytes(b"\0", NO_LIMITS), Err(wasm32::__WASI_EILSEQ));
+        assert_eq!(check_bytes(b"", NO_LIMITS), Err(wasm32::__WASI_ENOENT));
+    }
+
+    #[test]
+    fn depth_limit() {
+        let limits = PathLimits {
+            max_len: None,
+            max_depth: Some(2),
+        };
+        assert_eq!(check_bytes(b"a/b", limits), Ok(()));
+        assert_eq!(
+            check_bytes(b"a/b/c", limits),
+            Err(wasm32::__WASI_ENAMETOOLONG)
+        );
+        // the depth reached counts, not the number of components
+        assert_eq!(check_bytes(b"a/../b/./c", limits), Ok(()));
+        assert_eq!(
+            check_bytes(b"a/b/../c/d", limits),
+            Err(wasm32::__WASI_ENAMETOOLONG)
+        );
+    }
+
+    #[test]
+    fn length_limit() {
+        let limits = PathLimits {
+            max_len: Some(3),
+            max_depth: None,
+        };
+        let memory = b"abcd";
+        assert_eq!(check(memory, 0, 3, limits), Ok(()));
+        assert_eq!(
+            check(memory, 0, 4, limits),
+            Err(wasm32::__WASI_ENAMETOOLONG)
+        );
+        assert_eq!(check(memory, 2, 3, NO_LIMITS), Err(wasm32::__WASI_EFAULT));
+    }
+
+    #[test]
+    fn symlink_targets() {
+        let target = |path: &[u8], limits| check_symlink_target(path, 0, path.len() as u32, limits);
+        assert_eq!(target(b"../sibling", NO_LIMITS), Ok(()));
+        assert_eq!(target(b"/etc", NO_LIMITS), Err(wasm32::__WASI_ENOTCAPABLE));
+        assert_eq!(target(b"a\0", NO_LIMITS), Err(wasm32::__WASI_EILSEQ));
+        let limits = PathLimits {
+            max_len: Some(8),
+            max_depth: Some(1),
+        };
+        assert_eq!(target(b"../a", limits), Ok(()));
+        assert_eq!(target(b"a/b", limits), Err(wasm32::__WASI_ENAMETOOLONG));
+        assert_eq!(
+            target(b"aaaaaaaaa", limits),
+            Err(wasm32::__WASI_ENAMETOOLONG)
+        );
+    }
+
+    /// A directory holding `sub/file` and symlinks in and out of it, removed
+    /// again on drop.
+    struct Tree(TempDir);
+
+    impl Tree {
+        fn new() -> Self {
+            let dir = TempDir::new("resolve");
+            std::fs::create_dir(dir.join("sub")).unwrap();
+            std::fs::write(dir.join("sub/file"), b"").unwrap();
+            symlink("sub", dir.join("in")).unwrap();
+            symlink("..", dir.join("up")).unwrap();
+            symlink("sub/../..", dir.join("sneaky")).unwrap();
+            symlink("/etc", dir.join("abs")).unwrap();
+            symlink("loop", dir.join("loop")).unwrap();
+            symlink("../up", dir.join("sub/chained")).unwrap();
+            Self(dir)
+        }
+
+        fn open(&self) -> File {
+            self.0.open()
+        }
+    }
+
+    fn errno<T>(result: io::Result<T>) -> Option<i32> {
+        result.err().and_then(|err| err.raw_os_error())
+    }
+
+    /// Checks `resolve` keeps to the tree, whichever resolver it is.
+    fn resolves_beneath(resolve: fn(&File, &[u8], bool) -> io::Result<File>) {
+        let tree = Tree::new();
+        let dir = tree.open();
+        for path in &[
+            &b"sub"[..],
+            b"sub/file",
+            b"in/file",
+            b"in/../sub/./file",
+            b".",
+        ] {
+            assert!(resolve(&dir, path, true).is_ok(), "{:?}", path);
+        }
+        for path in &[
+            &b".."[..],
+            b"sub/../..",
+            b"up",
+            b"up/x",
+            b"sneaky",
+            b"abs",
+            b"in/chained",
+        ] {
+            assert_eq!(
+                errno(resolve(&dir, path, true)),
+                Some(libc::EXDEV),
+                "{:?}",
+                path
+            );
+        }
+        assert_eq!(errno(resolve(&dir, b"loop", true)), Some(libc::ELOOP));
+        assert_eq!(
+            errno(resolve(&dir, b"sub/missing", true)),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(
+            errno(resolve(&dir, b"sub/file/x", true)),
+            Some(libc::ENOTDIR)
+        );
+    }
+
+    #[test]
+    fn walk_resolves_beneath() {
+        resolves_beneath(walk_beneath);
+    }
+
+    #[test]
+    fn open_resolves_beneath() {
+        resolves_beneath(open_beneath);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unfollowed_symlinks_are_opened() {
+        let tree = Tree::new();
+        let dir = tree.open();
+        for &resolve in &[walk_beneath as fn(&File, &[u8], bool) -> _, open_beneath] {
+            assert!(resolve(&dir, b"up", false).is_ok());
+            assert!(resolve(&dir, b"abs", false).is_ok());
+            // only the last one isn't followed
+            assert_eq!(errno(resolve(&dir, b"up/sub", false)), Some(libc::EXDEV));
+        }
+    }
+
+    #[test]
+    fn checks_beneath() {
+        let tree = Tree::new();
+        let dir = tree.open();
+        assert_eq!(check_beneath(&dir, b"sub/file", true), Ok(()));
+        // to be created, in a directory which is beneath or isn't
+        assert_eq!(check_beneath(&dir, b"sub/new", true), Ok(()));
+        assert_eq!(
+            check_beneath(&dir, b"up/new", true),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            check_beneath(&dir, b"in/chained/new", false),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        // a symlink out is fine as long as it isn't followed
+        assert_eq!(check_beneath(&dir, b"up", false), Ok(()));
+        assert_eq!(
+            check_beneath(&dir, b"up", true),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            check_beneath(&dir, b"loop", true),
+            Err(wasm32::__WASI_ELOOP)
+        );
+        // left for the `WasiCtx` to fail
+        assert_eq!(check_beneath(&dir, b"missing/new", true), Ok(()));
+    }
+}
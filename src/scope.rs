@@ -0,0 +1,56 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The map instances register their exports in, and resolve each other's
+/// from, the syscalls' WASI memory included.
+pub type GlobalExports = Rc<RefCell<HashMap<String, Option<wasmtime_runtime::Export>>>>;
+
+/// The exports visible to one logical keep.
+///
+/// Every instance created with the same `GlobalExports` can resolve all of
+/// the others' exports, so keeps sharing one could reach each other's
+/// memories. Giving each keep its own scope keeps them apart, and `share`
+/// lets individual exports cross over when that's explicitly wanted.
+///
+/// ```ignore
+/// let keep = ExportScope::new();
+/// let wasi = WasiInstanceBuilder::new().instantiate(keep.global_exports())?;
+/// ```
+pub struct ExportScope {
+    exports: GlobalExports,
+}
+
+impl ExportScope {
+    /// Create an empty scope.
+    pub fn new() -> Self {
+        Self {
+            exports: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+
+    /// The map to create the keep's instances with.
+    pub fn global_exports(&self) -> GlobalExports {
+        self.exports.clone()
+    }
+
+    /// Make the export `name` of this scope resolvable from `other` as
+    /// well. Returns whether there was an (unambiguous) export to share.
+    pub fn share(&self, name: &str, other: &ExportScope) -> bool {
+        let export = match self.exports.borrow().get(name) {
+            Some(Some(export)) => export.clone(),
+            _ => return false,
+        };
+        other
+            .exports
+            .borrow_mut()
+            .insert(name.to_owned(), Some(export));
+        true
+    }
+}
+
+impl Default for ExportScope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
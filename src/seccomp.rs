@@ -0,0 +1,204 @@
+use std::cell::Cell;
+use std::io;
+
+thread_local! {
+    /// Whether the calling thread has the filter installed already.
+    static INSTALLED: Cell<bool> = Cell::new(false);
+}
+
+const PR_SET_SECCOMP: libc::c_int = 22;
+const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+// x86_64 syscalls newer than the libc crate
+const SYS_STATX: libc::c_long = 332;
+const SYS_RSEQ: libc::c_long = 334;
+const SYS_CLONE3: libc::c_long = 435;
+const SYS_OPENAT2: libc::c_long = 437;
+
+/// `AUDIT_ARCH_X86_64`, the only architecture the JIT generates code for.
+const AUDIT_ARCH: u32 = 0xc000_003e;
+
+// classic BPF opcodes
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets of the syscall number and architecture in `struct seccomp_data`.
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+/// Host syscalls the runtime makes once the guest is instantiated: those
+/// the WASI backends use for files, clocks, randomness and polling, and
+/// those the JIT and the standard library need for memory, signals,
+/// threads and exiting. Any other fails with `ENOSYS`, which the standard
+/// library falls back from, e.g. from `statx` to `fstatat` on old kernels.
+const ALLOWED: &[libc::c_long] = &[
+    // files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_lseek,
+    libc::SYS_openat,
+    SYS_OPENAT2,
+    libc::SYS_close,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    SYS_STATX,
+    libc::SYS_faccessat,
+    libc::SYS_getdents64,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_linkat,
+    libc::SYS_symlinkat,
+    libc::SYS_readlinkat,
+    libc::SYS_utimensat,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_fallocate,
+    libc::SYS_ftruncate,
+    libc::SYS_fadvise64,
+    libc::SYS_ppoll,
+    // clocks and randomness
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    libc::SYS_sched_yield,
+    // memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_mlock,
+    libc::SYS_mbind,
+    // signals, threads and exiting
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_futex,
+    libc::SYS_clone,
+    SYS_CLONE3,
+    libc::SYS_set_robust_list,
+    SYS_RSEQ,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prlimit64,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // older variants the standard library and wasi-common still use
+    libc::SYS_poll,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_dup2,
+];
+
+/// Host syscalls that kill the process rather than fail: those only an
+/// exploit of the runtime would make, to run or inspect other programs.
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+];
+
+/// Fails the host syscalls not in `ALLOWED` made by the calling thread, or
+/// a thread it spawns from then on, with `ENOSYS`, and kills the process
+/// on those in `DENIED`.
+///
+/// This can't be undone. Threads spawned before, like the watchdog's, are
+/// left alone. Filters would pile up, so a thread only gets one: later
+/// calls do nothing.
+pub(crate) fn install() -> io::Result<()> {
+    if INSTALLED.with(Cell::get) {
+        return Ok(());
+    }
+    let mut filter = vec![
+        stmt(BPF_LD_W_ABS, ARCH_OFFSET),
+        jump(AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, NR_OFFSET),
+    ];
+    for &nr in DENIED {
+        filter.push(jump(nr as u32, 0, 1));
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+    }
+    for &nr in ALLOWED {
+        filter.push(jump(nr as u32, 0, 1));
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+
+    let prog = SockFprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr(),
+    };
+    unsafe {
+        // without this, only privileged processes may install a filter
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::prctl(
+            PR_SET_SECCOMP,
+            SECCOMP_MODE_FILTER,
+            &prog as *const SockFprog,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    INSTALLED.with(|installed| installed.set(true));
+    Ok(())
+}
+
+fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter {
+        code: BPF_JEQ_K,
+        jt,
+        jf,
+        k,
+    }
+}
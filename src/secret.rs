@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{compiler_fence, Ordering};
+use wasi_common::wasm32;
+
+use super::guest;
+
+/// A secret handed to the guest, e.g. one received over the keep's
+/// attested provisioning channel.
+///
+/// Secrets are only ever held in memory: they reach the guest through its
+/// environment, the `enarx_secret_get` hostcall, or files which only exist
+/// in the guest's view, never through a file on the host. Their bytes are
+/// zeroed when they're dropped, and kept out of `Debug` output.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Secret(bytes)
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Secret {
+    fn from(bytes: Vec<u8>) -> Self {
+        Secret(bytes)
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Secret(s.into_bytes())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(<{} bytes>)", self.0.len())
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        zeroize_vec(&mut self.0);
+    }
+}
+
+/// Overwrites `bytes` with zeros, in a way that isn't optimized away as a
+/// dead store when they're freed right after.
+pub(crate) fn zeroize(bytes: &mut [u8]) {
+    unsafe { std::ptr::write_bytes(bytes.as_mut_ptr(), 0, bytes.len()) };
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Zeroes all of `buf`'s allocation, including the capacity beyond its
+/// length which earlier contents may linger in, and empties it.
+pub(crate) fn zeroize_vec(buf: &mut Vec<u8>) {
+    unsafe {
+        std::ptr::write_bytes(buf.as_mut_ptr(), 0, buf.capacity());
+        buf.set_len(0);
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Like `zeroize_vec`, for a copy of a secret which had to be a `String`.
+pub(crate) fn zeroize_string(s: &mut String) {
+    // all zeros is valid UTF-8, and it's left empty anyway
+    zeroize_vec(unsafe { s.as_mut_vec() });
+}
+
+/// Where `WasiInstanceBuilder::secret_file` puts secrets in the guest's
+/// filesystem.
+pub(crate) const SECRETS_GUEST_PATH: &str = "/run/secrets";
+
+/// Fds of the secret files the guest opens count down from here, far
+/// above any the `WasiCtx` hands out, which counts up from 3.
+const FIRST_FILE_FD: wasm32::__wasi_fd_t = u32::max_value() - 1;
+
+/// How many secret files the guest may have open at once.
+const MAX_OPEN_FILES: usize = 1024;
+
+/// Rights the guest has on a secret file.
+const FILE_RIGHTS: wasm32::__wasi_rights_t = wasm32::__WASI_RIGHT_FD_READ
+    | wasm32::__WASI_RIGHT_FD_SEEK
+    | wasm32::__WASI_RIGHT_FD_TELL
+    | wasm32::__WASI_RIGHT_FD_FILESTAT_GET;
+
+/// Size of `__wasi_fdstat_t` and `__wasi_filestat_t`.
+const FDSTAT_SIZE: usize = 24;
+const FILESTAT_SIZE: usize = 56;
+
+/// Secrets the guest reads as files in `SECRETS_GUEST_PATH`, a preopen
+/// which only exists in the guest's view: the files are served from the
+/// `Secret`s, and never touch a filesystem.
+///
+/// The preopen's fd is held in the `WasiCtx` by a directory with no rights
+/// at all, so the `WasiCtx` hands it out to nothing else, and reports it
+/// to `fd_prestat_get`. Everything else done with it, and with the files,
+/// is done here instead: the guest can open a file by its name, read it,
+/// seek in it, stat it and close it.
+#[derive(Default)]
+pub(crate) struct SecretFiles {
+    dir: Option<wasm32::__wasi_fd_t>,
+    files: HashMap<String, Secret>,
+    /// The name of each open file, and its position.
+    open: HashMap<wasm32::__wasi_fd_t, (String, u64)>,
+}
+
+impl SecretFiles {
+    /// Serves `files` through the preopen `dir`.
+    pub(crate) fn new(dir: wasm32::__wasi_fd_t, files: HashMap<String, Secret>) -> Self {
+        Self {
+            dir: Some(dir),
+            files,
+            open: HashMap::new(),
+        }
+    }
+
+    /// Whether there's a preopen of secret files at all.
+    pub(crate) fn has_dir(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    pub(crate) fn is_dir(&self, fd: wasm32::__wasi_fd_t) -> bool {
+        self.dir == Some(fd)
+    }
+
+    /// Opens the file named by the `path_len` bytes at `path` like
+    /// `path_open`, writing its fd to `fd`. Only existing files can be
+    /// opened, and only for reading.
+    pub(crate) fn open(
+        &mut self,
+        memory: &mut [u8],
+        path: wasm32::uintptr_t,
+        path_len: wasm32::size_t,
+        oflags: wasm32::__wasi_oflags_t,
+        fs_rights_base: wasm32::__wasi_rights_t,
+        fd: wasm32::uintptr_t,
+    ) -> wasm32::__wasi_errno_t {
+        if oflags & wasm32::__WASI_O_DIRECTORY != 0 {
+            return wasm32::__WASI_ENOTDIR;
+        }
+        if oflags != 0 || fs_rights_base & !FILE_RIGHTS != 0 {
+            return wasm32::__WASI_ENOTCAPABLE;
+        }
+        let path = match guest::range(memory, path, path_len as usize) {
+            Ok(path) => path,
+            Err(errno) => return errno,
+        };
+        let name = match std::str::from_utf8(&memory[path]) {
+            Ok(name) if self.files.contains_key(name) => name.to_owned(),
+            Ok(_) => return wasm32::__WASI_ENOENT,
+            Err(_) => return wasm32::__WASI_EILSEQ,
+        };
+        let fd = match guest::range(memory, fd, 4) {
+            Ok(fd) => fd,
+            Err(errno) => return errno,
+        };
+        if self.open.len() >= MAX_OPEN_FILES {
+            return wasm32::__WASI_EMFILE;
+        }
+        let opened = (0..)
+            .map(|i| FIRST_FILE_FD - i)
+            .find(|fd| !self.open.contains_key(fd))
+            .unwrap();
+        self.open.insert(opened, (name, 0));
+        memory[fd].copy_from_slice(&opened.to_le_bytes());
+        wasm32::__WASI_ESUCCESS
+    }
+
+    /// Reads the secret file `fd` like `fd_read`, or returns `None` if `fd`
+    /// isn't one.
+    pub(crate) fn read(
+        &mut self,
+        memory: &mut [u8],
+        fd: wasm32::__wasi_fd_t,
+        iovs: wasm32::uintptr_t,
+        iovs_len: wasm32::size_t,
+        nread: wasm32::uintptr_t,
+    ) -> Option<wasm32::__wasi_errno_t> {
+        let (name, position) = self.open.get_mut(&fd)?;
+        let bytes = self.files[name.as_str()].bytes();
+        let nread = match guest::range(memory, nread, 4) {
+            Ok(range) => range,
+            Err(errno) => return Some(errno),
+        };
+        let mut read = 0;
+        for i in 0..iovs_len {
+            let range = match guest::iovec(memory, iovs, i) {
+                Some(range) => range,
+                None => return Some(wasm32::__WASI_EFAULT),
+            };
+            let start = (*position as usize).min(bytes.len());
+            let len = range.len().min(bytes.len() - start);
+            memory[range.start..range.start + len].copy_from_slice(&bytes[start..start + len]);
+            *position += len as u64;
+            read += len;
+        }
+        memory[nread].copy_from_slice(&(read as u32).to_le_bytes());
+        Some(wasm32::__WASI_ESUCCESS)
+    }
+
+    /// Moves the position of the secret file `fd` like `fd_seek`, or
+    /// returns `None` if `fd` isn't one.
+    pub(crate) fn seek(
+        &mut self,
+        memory: &mut [u8],
+        fd: wasm32::__wasi_fd_t,
+        offset: wasm32::__wasi_filedelta_t,
+        whence: wasm32::__wasi_whence_t,
+        newoffset: wasm32::uintptr_t,
+    ) -> Option<wasm32::__wasi_errno_t> {
+        let (name, position) = self.open.get_mut(&fd)?;
+        let from = match whence {
+            wasm32::__WASI_WHENCE_SET => 0,
+            wasm32::__WASI_WHENCE_CUR => *position as i64,
+            wasm32::__WASI_WHENCE_END => self.files[name.as_str()].bytes().len() as i64,
+            _ => return Some(wasm32::__WASI_EINVAL),
+        };
+        let to = match from.checked_add(offset) {
+            Some(to) if to >= 0 => to as u64,
+            _ => return Some(wasm32::__WASI_EINVAL),
+        };
+        let newoffset = match guest::range(memory, newoffset, 8) {
+            Ok(range) => range,
+            Err(errno) => return Some(errno),
+        };
+        *position = to;
+        memory[newoffset].copy_from_slice(&to.to_le_bytes());
+        Some(wasm32::__WASI_ESUCCESS)
+    }
+
+    /// Writes the position of the secret file `fd` like `fd_tell`, or
+    /// returns `None` if `fd` isn't one.
+    pub(crate) fn tell(
+        &self,
+        memory: &mut [u8],
+        fd: wasm32::__wasi_fd_t,
+        newoffset: wasm32::uintptr_t,
+    ) -> Option<wasm32::__wasi_errno_t> {
+        let (_, position) = self.open.get(&fd)?;
+        Some(match guest::range(memory, newoffset, 8) {
+            Ok(range) => {
+                memory[range].copy_from_slice(&position.to_le_bytes());
+                wasm32::__WASI_ESUCCESS
+            }
+            Err(errno) => errno,
+        })
+    }
+
+    /// Writes the `__wasi_fdstat_t` of the secret file `fd` to `buf`, or
+    /// returns `None` if `fd` isn't one.
+    pub(crate) fn fdstat(
+        &self,
+        memory: &mut [u8],
+        fd: wasm32::__wasi_fd_t,
+        buf: wasm32::uintptr_t,
+    ) -> Option<wasm32::__wasi_errno_t> {
+        self.open.get(&fd)?;
+        let buf = match guest::range(memory, buf, FDSTAT_SIZE) {
+            Ok(range) => range,
+            Err(errno) => return Some(errno),
+        };
+        let mut fdstat = [0; FDSTAT_SIZE];
+        fdstat[0] = wasm32::__WASI_FILETYPE_REGULAR_FILE;
+        fdstat[8..16].copy_from_slice(&FILE_RIGHTS.to_le_bytes());
+        memory[buf].copy_from_slice(&fdstat);
+        Some(wasm32::__WASI_ESUCCESS)
+    }
+
+    /// Writes the `__wasi_filestat_t` of the secret file `fd` to `buf`, or
+    /// returns `None` if `fd` isn't one.
+    pub(crate) fn filestat(
+        &self,
+        memory: &mut [u8],
+        fd: wasm32::__wasi_fd_t,
+        buf: wasm32::uintptr_t,
+    ) -> Option<wasm32::__wasi_errno_t> {
+        let (name, _) = self.open.get(&fd)?;
+        let buf = match guest::range(memory, buf, FILESTAT_SIZE) {
+            Ok(range) => range,
+            Err(errno) => return Some(errno),
+        };
+        let size = self.files[name.as_str()].bytes().len() as u64;
+        let mut filestat = [0; FILESTAT_SIZE];
+        filestat[16] = wasm32::__WASI_FILETYPE_REGULAR_FILE;
+        filestat[20..24].copy_from_slice(&1u32.to_le_bytes());
+        filestat[24..32].copy_from_slice(&size.to_le_bytes());
+        memory[buf].copy_from_slice(&filestat);
+        Some(wasm32::__WASI_ESUCCESS)
+    }
+
+    /// Closes the secret file `fd`, or returns `None` if `fd` isn't one.
+    pub(crate) fn close(&mut self, fd: wasm32::__wasi_fd_t) -> Option<wasm32::__wasi_errno_t> {
+        self.open.remove(&fd)?;
+        Some(wasm32::__WASI_ESUCCESS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{data, iovec, FD, FILESTAT, IOV, NREAD, OFFSET, PATH};
+    use crate::guest::{read_u32, read_u64};
+
+    const DIR: wasm32::__wasi_fd_t = 3;
+
+    fn secret_files() -> SecretFiles {
+        let mut files = HashMap::new();
+        files.insert("token".to_owned(), Secret::from("hunter2".to_owned()));
+        SecretFiles::new(DIR, files)
+    }
+
+    /// Opens `name` with `oflags` and `rights`, returning its fd.
+    fn open(
+        files: &mut SecretFiles,
+        memory: &mut [u8],
+        name: &[u8],
+        oflags: wasm32::__wasi_oflags_t,
+        rights: wasm32::__wasi_rights_t,
+    ) -> Result<wasm32::__wasi_fd_t, wasm32::__wasi_errno_t> {
+        let path = PATH as usize;
+        memory[path..path + name.len()].copy_from_slice(name);
+        match files.open(memory, PATH, name.len() as u32, oflags, rights, FD) {
+            wasm32::__WASI_ESUCCESS => Ok(read_u32(memory, FD).unwrap()),
+            errno => Err(errno),
+        }
+    }
+
+    #[test]
+    fn reads_seeks_and_stats() {
+        let mut files = secret_files();
+        let mut memory = vec![0; 256];
+        let fd = open(&mut files, &mut memory, b"token", 0, FILE_RIGHTS).unwrap();
+        assert!(!files.is_dir(fd));
+        for expected in &[&b"hunt"[..], b"er2", b""] {
+            iovec(&mut memory, 4);
+            assert_eq!(
+                files.read(&mut memory, fd, IOV, 1, NREAD),
+                Some(wasm32::__WASI_ESUCCESS)
+            );
+            assert_eq!(read_u32(&memory, NREAD), Some(expected.len() as u32));
+            assert_eq!(data(&memory, expected.len()), *expected);
+        }
+        let seek = |files: &mut SecretFiles, memory: &mut [u8], offset, whence| {
+            files.seek(memory, fd, offset, whence, OFFSET)
+        };
+        assert_eq!(
+            seek(&mut files, &mut memory, -3, wasm32::__WASI_WHENCE_END),
+            Some(wasm32::__WASI_ESUCCESS)
+        );
+        assert_eq!(read_u64(&memory, OFFSET), Some(4));
+        assert_eq!(
+            seek(&mut files, &mut memory, -5, wasm32::__WASI_WHENCE_CUR),
+            Some(wasm32::__WASI_EINVAL)
+        );
+        assert_eq!(
+            files.tell(&mut memory, fd, OFFSET),
+            Some(wasm32::__WASI_ESUCCESS)
+        );
+        assert_eq!(read_u64(&memory, OFFSET), Some(4));
+        assert_eq!(
+            files.filestat(&mut memory, fd, FILESTAT),
+            Some(wasm32::__WASI_ESUCCESS)
+        );
+        // `st_filetype` and `st_size`
+        assert_eq!(
+            memory[FILESTAT as usize + 16],
+            wasm32::__WASI_FILETYPE_REGULAR_FILE
+        );
+        assert_eq!(read_u64(&memory, FILESTAT + 24), Some(7));
+        assert_eq!(files.close(fd), Some(wasm32::__WASI_ESUCCESS));
+        assert_eq!(files.read(&mut memory, fd, IOV, 1, NREAD), None);
+        assert_eq!(files.close(fd), None);
+    }
+
+    #[test]
+    fn only_opens_existing_files_for_reading() {
+        let mut files = secret_files();
+        let mut memory = vec![0; 256];
+        assert!(files.is_dir(DIR));
+        assert_eq!(
+            open(&mut files, &mut memory, b"missing", 0, FILE_RIGHTS),
+            Err(wasm32::__WASI_ENOENT)
+        );
+        assert_eq!(
+            open(
+                &mut files,
+                &mut memory,
+                b"token",
+                wasm32::__WASI_O_CREAT,
+                FILE_RIGHTS
+            ),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            open(
+                &mut files,
+                &mut memory,
+                b"token",
+                0,
+                wasm32::__WASI_RIGHT_FD_WRITE
+            ),
+            Err(wasm32::__WASI_ENOTCAPABLE)
+        );
+        assert_eq!(
+            open(
+                &mut files,
+                &mut memory,
+                b"token",
+                wasm32::__WASI_O_DIRECTORY,
+                0
+            ),
+            Err(wasm32::__WASI_ENOTDIR)
+        );
+        // each open gets an fd of its own
+        let first = open(&mut files, &mut memory, b"token", 0, 0).unwrap();
+        let second = open(&mut files, &mut memory, b"token", 0, 0).unwrap();
+        assert_ne!(first, second);
+    }
+}
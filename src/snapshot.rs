@@ -0,0 +1,113 @@
+use super::secret::zeroize_vec;
+use std::convert::TryInto;
+
+const MAGIC: &[u8; 4] = b"EWSN";
+const VERSION: u32 = 1;
+
+/// The guest-visible state of a module at some point: its WASI memory and
+/// the values of its exported globals.
+///
+/// Taken with `WasiModule::snapshot`, and applied to another instance of
+/// the same module with `WasiModule::restore`, e.g. after moving the
+/// snapshot to another host with `to_bytes` and `from_bytes`.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub(crate) memory: Vec<u8>,
+    pub(crate) globals: Vec<(String, i64)>,
+}
+
+impl Snapshot {
+    /// Serialize the snapshot.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.memory.len() + 64);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.memory.len() as u64).to_le_bytes());
+        out.extend_from_slice(&self.memory);
+        out.extend_from_slice(&(self.globals.len() as u32).to_le_bytes());
+        for (name, value) in &self.globals {
+            out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out
+    }
+
+    /// Deserialize a snapshot serialized with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader::new(bytes, "snapshot");
+        if reader.take(4)? != MAGIC {
+            return Err("not a snapshot".to_owned());
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(format!("unsupported snapshot version {}", version));
+        }
+        let len = reader.u64()? as usize;
+        let memory = reader.take(len)?.to_vec();
+        let count = reader.u32()?;
+        let mut globals = Vec::new();
+        for _ in 0..count {
+            let len = reader.u32()? as usize;
+            let name = String::from_utf8(reader.take(len)?.to_vec())
+                .map_err(|_| "global name isn't UTF-8".to_owned())?;
+            let value = i64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+            globals.push((name, value));
+        }
+        if !reader.is_empty() {
+            return Err("trailing bytes after snapshot".to_owned());
+        }
+        Ok(Self { memory, globals })
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        // the memory may hold whatever secrets the guest had
+        zeroize_vec(&mut self.memory);
+    }
+}
+
+/// Reads the little-endian fields of a serialized `what`.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    what: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], what: &'static str) -> Self {
+        Self { bytes, what }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err(format!("{} is truncated", self.what));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A string prefixed with its `u32` length.
+    pub(crate) fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| format!("{} holds a string which isn't UTF-8", self.what))
+    }
+}
@@ -0,0 +1,259 @@
+use super::backing::Backing;
+use super::capability::Capabilities;
+use super::control::Control;
+use super::debugger::{Breakpoints, Debugger};
+use super::drbg::Drbg;
+use super::fileid::FileIds;
+use super::fs::FsPolicy;
+use super::hostfd::HostFds;
+use super::interrupt::Interrupt;
+use super::keep::KeepInfo;
+#[cfg(target_os = "linux")]
+use super::preopen::PreopenRights;
+use super::profile::Profiler;
+use super::readahead::ReadAhead;
+use super::resolve::PathLimits;
+use super::secret::{zeroize_vec, Secret, SecretFiles};
+use super::stdio::Stdio;
+use super::trace::Tracer;
+use super::usage::Usage;
+use super::watchdog::Watchdog;
+use super::writeback::WriteBack;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::fs::File;
+use std::rc::Rc;
+use wasi_common::WasiCtx;
+use wasmtime_runtime::{InstanceHandle, VMContext, VMMemoryDefinition};
+
+/// Name of the linear memory export guest pointers are resolved against
+/// unless another one is chosen at instantiation.
+pub const DEFAULT_MEMORY_EXPORT: &str = "memory";
+
+/// Host state the syscalls can get a `WasiCtx` out of.
+///
+/// Embedders which need to keep their own bookkeeping next to the WASI
+/// context (or substitute a test double around it) implement this for their
+/// type and hand it to `WasiInstanceBuilder::host_state`.
+pub trait WasiContext: Any {
+    /// The WASI context the syscalls operate on.
+    fn wasi_ctx(&mut self) -> &mut WasiCtx;
+}
+
+impl WasiContext for WasiCtx {
+    fn wasi_ctx(&mut self) -> &mut WasiCtx {
+        self
+    }
+}
+
+/// Returns the embedder-supplied host state of an instance created by this
+/// crate, if it's of type `T`.
+pub fn wasi_context<T: WasiContext>(instance: &mut InstanceHandle) -> Option<&mut T> {
+    unsafe { state_of(instance.host_state()) }.and_then(|state| state.ctx.inner_mut::<T>())
+}
+
+/// Like `wasi_context`, for use from within a host function called by the
+/// guest with the instance's `vmctx`.
+pub unsafe fn vmctx_wasi_context<T: WasiContext>(vmctx: &mut VMContext) -> Option<&mut T> {
+    state_of(vmctx.host_state()).and_then(|state| state.ctx.inner_mut::<T>())
+}
+
+/// Host state of the instances created by this crate. The instances
+/// exporting the syscalls under different WASI namespaces for the same
+/// guest all share one `WasiState`.
+pub(crate) type SharedState = Rc<RefCell<WasiState>>;
+
+/// Gets the `WasiState` out of an instance's host state.
+///
+/// NB: this bypasses the `RefCell`'s borrow tracking. Instances are bound to
+/// a single thread and syscalls never call each other through their shims,
+/// so the state only ever has one user at a time.
+pub(crate) unsafe fn state_of(host_state: &mut dyn Any) -> Option<&mut WasiState> {
+    host_state
+        .downcast_mut::<SharedState>()
+        .map(|state| &mut *state.as_ptr())
+}
+
+/// Returns the shared state behind an instance's host state.
+pub(crate) fn shared_state_of(host_state: &mut dyn Any) -> Option<SharedState> {
+    host_state.downcast_ref::<SharedState>().cloned()
+}
+
+/// Type-erased `WasiContext`.
+pub(crate) struct Context {
+    inner: Box<dyn Any>,
+    wasi_ctx: fn(&mut dyn Any) -> &mut WasiCtx,
+    /// The `WasiCtx` within `inner`, so syscalls don't have to look it up
+    /// every time. Forgotten whenever `inner` is handed out, as the
+    /// embedder may then move the `WasiCtx`.
+    cached: Option<*mut WasiCtx>,
+}
+
+impl Context {
+    pub(crate) fn new<T: WasiContext>(ctx: T) -> Self {
+        fn wasi_ctx<T: WasiContext>(ctx: &mut dyn Any) -> &mut WasiCtx {
+            ctx.downcast_mut::<T>()
+                .expect("context type doesn't change")
+                .wasi_ctx()
+        }
+
+        Self {
+            inner: Box::new(ctx),
+            wasi_ctx: wasi_ctx::<T>,
+            cached: None,
+        }
+    }
+
+    pub(crate) fn wasi_ctx(&mut self) -> &mut WasiCtx {
+        let ctx = match self.cached {
+            Some(ctx) => ctx,
+            None => {
+                let ctx: *mut WasiCtx = (self.wasi_ctx)(&mut *self.inner);
+                self.cached = Some(ctx);
+                ctx
+            }
+        };
+        unsafe { &mut *ctx }
+    }
+
+    pub(crate) fn inner_mut<T: WasiContext>(&mut self) -> Option<&mut T> {
+        self.cached = None;
+        self.inner.downcast_mut::<T>()
+    }
+}
+
+/// Host state attached to every instance created by this crate.
+pub(crate) struct WasiState {
+    pub(crate) ctx: Context,
+    /// Export name of the "WASI memory", i.e. the linear memory every
+    /// buffer pointer passed to a syscall refers to. Modules using the
+    /// multi-memory proposal export several memories, so this can't simply
+    /// be assumed to be `"memory"`.
+    pub(crate) memory: String,
+    /// The WASI memory itself, once it's known which instance's export it
+    /// is. Until then, it's looked up by name among the global exports,
+    /// and the first one found is kept.
+    pub(crate) memory_definition: Option<*mut VMMemoryDefinition>,
+    pub(crate) backing: Backing,
+    /// Set once a syscall has panicked; every later syscall then fails with
+    /// `__WASI_EFAULT` instead of touching possibly inconsistent state.
+    pub(crate) poisoned: bool,
+    /// The code the guest passed to `proc_exit`, once it has. Every later
+    /// syscall then fails with `__WASI_EFAULT`, and the call into the
+    /// guest ends with `RunError::Exit`.
+    pub(crate) exit_code: Option<u32>,
+    /// Makes the guest trap, so `proc_exit` doesn't return into it.
+    pub(crate) interrupt: Interrupt,
+    pub(crate) fs: FsPolicy,
+    pub(crate) path_limits: PathLimits,
+    pub(crate) host_fds: HostFds,
+    /// Hides the host's device and inode numbers from the guest, if set.
+    pub(crate) file_ids: Option<FileIds>,
+    /// The host directories preopened, with their rights, until the
+    /// runtime is confined to them with Landlock, or not.
+    #[cfg(target_os = "linux")]
+    pub(crate) preopen_dirs: Vec<(File, PreopenRights)>,
+    pub(crate) read_ahead: ReadAhead,
+    pub(crate) write_back: WriteBack,
+    /// Routes the guest's writes to stdio.
+    pub(crate) stdio: Stdio,
+    pub(crate) watchdog: Option<Watchdog>,
+    pub(crate) usage: Usage,
+    pub(crate) profiler: Option<Profiler>,
+    pub(crate) tracer: Option<Tracer>,
+    /// Stops the guest at its syscalls, if attached.
+    pub(crate) debugger: Option<Box<dyn Debugger>>,
+    /// Set through the `Control`, to pause the guest at.
+    pub(crate) breakpoints: Breakpoints,
+    pub(crate) info: KeepInfo,
+    /// Handle through which the configuration is changed while the guest
+    /// runs, and the version of the changes last applied.
+    pub(crate) control: Option<Control>,
+    pub(crate) control_seen: u64,
+    /// The most the guest's syscalls are logged at, see
+    /// `Control::set_log_level`.
+    pub(crate) log_level: log::LevelFilter,
+    pub(crate) capabilities: Capabilities,
+    /// Secrets the guest can read with `enarx_secret_get`, by name.
+    pub(crate) secrets: HashMap<String, Secret>,
+    /// Secrets the guest reads as files, see `SecretFiles`.
+    pub(crate) secret_files: SecretFiles,
+    /// Serves `random_get` instead of the `WasiCtx`, if set.
+    pub(crate) drbg: Option<Drbg>,
+    /// Buffer for syscalls to lay data out in, kept so they don't allocate
+    /// one on every call.
+    pub(crate) scratch: Vec<u8>,
+    /// The closures behind the embedder's host functions, by the slot
+    /// their trampolines look them up in, see `HostFunction::from_closure`.
+    pub(crate) host_closures: Vec<Rc<dyn Any>>,
+}
+
+impl WasiState {
+    pub(crate) fn new(ctx: Context, memory: &str) -> Self {
+        Self {
+            ctx,
+            memory: memory.to_owned(),
+            memory_definition: None,
+            backing: Backing::default(),
+            poisoned: false,
+            exit_code: None,
+            interrupt: Interrupt::default(),
+            fs: FsPolicy::default(),
+            path_limits: PathLimits::default(),
+            host_fds: HostFds::default(),
+            file_ids: None,
+            #[cfg(target_os = "linux")]
+            preopen_dirs: Vec::new(),
+            read_ahead: ReadAhead::default(),
+            write_back: WriteBack::default(),
+            stdio: Stdio::default(),
+            watchdog: None,
+            usage: Usage::default(),
+            profiler: None,
+            tracer: None,
+            debugger: None,
+            breakpoints: Breakpoints::default(),
+            info: KeepInfo::default(),
+            control: None,
+            control_seen: 0,
+            log_level: log::LevelFilter::Trace,
+            capabilities: Capabilities::default(),
+            secrets: HashMap::new(),
+            secret_files: SecretFiles::default(),
+            drbg: None,
+            scratch: Vec::new(),
+            host_closures: Vec::new(),
+        }
+    }
+
+    /// Replaces the state with `fresh`, e.g. on reset, keeping the host
+    /// resources set up once per instance: the tracer, watchdog and DRBG,
+    /// and the interrupt of the instances.
+    pub(crate) fn replace(&mut self, mut fresh: WasiState) {
+        fresh.interrupt = self.interrupt.clone();
+        fresh.tracer = self.tracer.take();
+        fresh.watchdog = self.watchdog.take();
+        fresh.drbg = self.drbg.take();
+        fresh.host_closures = std::mem::replace(&mut self.host_closures, Vec::new());
+        *self = fresh;
+    }
+
+    /// Zeroes the host-side buffers the guest's data passed through, except
+    /// for writes still to be flushed.
+    pub(crate) fn scrub(&mut self) {
+        zeroize_vec(&mut self.scratch);
+        self.read_ahead.scrub(self.ctx.wasi_ctx());
+    }
+}
+
+impl Drop for WasiState {
+    fn drop(&mut self) {
+        // Writes the guest buffered but never synced or closed would
+        // otherwise be lost with the `WasiCtx`.
+        self.write_back.flush_all(self.ctx.wasi_ctx());
+        self.write_back.scrub();
+        self.scrub();
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::io::Write;
+use wasi_common::wasm32;
+
+use super::guest::{self, iovec};
+use super::terminal::Terminal;
+
+/// Where the guest's writes to stdio end up: which fds are redirected to
+/// which, which are host terminals to filter writes to, and which the
+/// embedder captures.
+///
+/// Both follow the fds they were set up for when the guest renumbers them,
+/// and are dropped with them when they're closed or replaced, so that e.g.
+/// a file renumbered onto stderr is written to, not stdout, and a terminal
+/// renumbered away from stdout stays filtered.
+pub(crate) struct Stdio {
+    /// Writes to the key go to the value instead.
+    redirects: HashMap<wasm32::__wasi_fd_t, wasm32::__wasi_fd_t>,
+    terminals: HashMap<wasm32::__wasi_fd_t, Terminal>,
+    /// Writes to the key go to the embedder's writer instead, before any
+    /// redirect.
+    captures: HashMap<wasm32::__wasi_fd_t, Box<dyn Write>>,
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        let mut redirects = HashMap::new();
+        // stderr goes to stdout
+        redirects.insert(2, 1);
+        Self {
+            redirects,
+            terminals: HashMap::new(),
+            captures: HashMap::new(),
+        }
+    }
+}
+
+impl Stdio {
+    /// Filters the writes to `fd` through `terminal`.
+    pub(crate) fn set_terminal(&mut self, fd: wasm32::__wasi_fd_t, terminal: Terminal) {
+        self.terminals.insert(fd, terminal);
+    }
+
+    /// Writes the guest's writes to `fd` to `writer` instead.
+    pub(crate) fn capture(&mut self, fd: wasm32::__wasi_fd_t, writer: Box<dyn Write>) {
+        self.captures.insert(fd, writer);
+    }
+
+    /// Writes what the `iovs_len` iovecs at `iovs` hold to the writer `fd`
+    /// is captured by, and the number of bytes written to `nwritten`, like
+    /// `fd_write`. Returns `None` if `fd` isn't captured.
+    pub(crate) fn write_captured(
+        &mut self,
+        memory: &mut [u8],
+        fd: wasm32::__wasi_fd_t,
+        iovs: wasm32::uintptr_t,
+        iovs_len: wasm32::size_t,
+        nwritten: wasm32::uintptr_t,
+    ) -> Option<wasm32::__wasi_errno_t> {
+        let writer = self.captures.get_mut(&fd)?;
+        let nwritten = match guest::range(memory, nwritten, 4) {
+            Ok(range) => range,
+            Err(errno) => return Some(errno),
+        };
+        let mut written = 0;
+        for i in 0..iovs_len {
+            let range = match iovec(memory, iovs, i) {
+                Some(range) => range,
+                None => return Some(wasm32::__WASI_EFAULT),
+            };
+            written += range.len();
+            if writer.write_all(&memory[range]).is_err() {
+                return Some(wasm32::__WASI_EIO);
+            }
+        }
+        if writer.flush().is_err() {
+            return Some(wasm32::__WASI_EIO);
+        }
+        memory[nwritten].copy_from_slice(&(written as u32).to_le_bytes());
+        Some(wasm32::__WASI_ESUCCESS)
+    }
+
+    /// The fd writes to `fd` go to.
+    pub(crate) fn route(&self, fd: wasm32::__wasi_fd_t) -> wasm32::__wasi_fd_t {
+        self.redirects.get(&fd).cloned().unwrap_or(fd)
+    }
+
+    /// The terminal writes to `fd`, once routed, are filtered for, if any.
+    pub(crate) fn terminal(&mut self, fd: wasm32::__wasi_fd_t) -> Option<&mut Terminal> {
+        self.terminals.get_mut(&fd)
+    }
+
+    /// Records that `fd` was closed, so writes redirected to it go to the
+    /// fds they were meant for again.
+    pub(crate) fn closed(&mut self, fd: wasm32::__wasi_fd_t) {
+        self.redirects.remove(&fd);
+        self.redirects.retain(|_, to| *to != fd);
+        self.terminals.remove(&fd);
+        self.captures.remove(&fd);
+    }
+
+    /// Records that `from` was renumbered to `to`, which was closed first.
+    pub(crate) fn renumbered(&mut self, from: wasm32::__wasi_fd_t, to: wasm32::__wasi_fd_t) {
+        self.closed(to);
+        if let Some(redirect) = self.redirects.remove(&from) {
+            self.redirects.insert(to, redirect);
+        }
+        for redirect in self.redirects.values_mut() {
+            if *redirect == from {
+                *redirect = to;
+            }
+        }
+        if let Some(terminal) = self.terminals.remove(&from) {
+            self.terminals.insert(to, terminal);
+        }
+        if let Some(writer) = self.captures.remove(&from) {
+            self.captures.insert(to, writer);
+        }
+    }
+}
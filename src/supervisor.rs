@@ -0,0 +1,135 @@
+use super::builder::WasiInstanceBuilder;
+use super::module::RunError;
+use std::thread;
+use std::time::Duration;
+
+/// How a `Supervisor` restarts a module which failed.
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// Give up after this many restarts, or never if `None`.
+    pub max_restarts: Option<u32>,
+    /// Delay before the first restart, doubled for every one after it.
+    pub backoff: Duration,
+    /// Upper bound on the delay between restarts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: Some(5),
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Something that happened to a supervised module.
+#[derive(Debug, Clone)]
+pub enum SupervisorEvent {
+    /// A fresh instance of the module started running, after `restarts`
+    /// others failed.
+    Started { restarts: u32 },
+    /// The module returned from `_start`, or called `proc_exit` with zero.
+    Exited,
+    /// The module called `proc_exit` with this nonzero code.
+    ExitedWith(u32),
+    /// The module trapped.
+    Trapped(String),
+    /// The module will be restarted after `delay`.
+    Restarting { delay: Duration },
+    /// The module failed once more than the policy allows, and won't be
+    /// restarted.
+    GaveUp,
+}
+
+/// Runs a command module for a long-lived service keep, restarting it with
+/// a fresh instance whenever it traps or calls `proc_exit` with a nonzero
+/// code.
+///
+/// Every instance is configured by a builder returned by `factory`, so each
+/// restart gets a fresh WASI context too.
+///
+/// ```ignore
+/// Supervisor::new(&wasm, || WasiInstanceBuilder::new().arg("service"))
+///     .on_event(|event| eprintln!("service: {:?}", event))
+///     .run()?;
+/// ```
+pub struct Supervisor<F> {
+    wasm: Vec<u8>,
+    factory: F,
+    policy: RestartPolicy,
+    on_event: Option<Box<dyn FnMut(&SupervisorEvent)>>,
+}
+
+impl<F: FnMut() -> WasiInstanceBuilder> Supervisor<F> {
+    /// Supervise `wasm`, with the default restart policy.
+    pub fn new(wasm: &[u8], factory: F) -> Self {
+        Self {
+            wasm: wasm.to_vec(),
+            factory,
+            policy: RestartPolicy::default(),
+            on_event: None,
+        }
+    }
+
+    /// Restart the module according to `policy`.
+    pub fn policy(mut self, policy: RestartPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Call `f` with every lifecycle event of the module.
+    pub fn on_event(mut self, f: impl FnMut(&SupervisorEvent) + 'static) -> Self {
+        self.on_event = Some(Box::new(f));
+        self
+    }
+
+    /// Run the module until it returns from `_start` or exits with zero, or
+    /// fails once more than the policy allows, in which case its last trap
+    /// or `RunError::Exit` is returned.
+    ///
+    /// Other errors, like a module that fails to compile, would only happen
+    /// again, so they're returned without any restart.
+    pub fn run(&mut self) -> Result<(), RunError> {
+        let mut restarts = 0;
+        let mut delay = self.policy.backoff;
+        loop {
+            self.emit(SupervisorEvent::Started { restarts });
+            let err = match (self.factory)().run(&self.wasm) {
+                Ok(()) => {
+                    self.emit(SupervisorEvent::Exited);
+                    return Ok(());
+                }
+                Err(RunError::Trap(message)) => {
+                    self.emit(SupervisorEvent::Trapped(message.clone()));
+                    RunError::Trap(message)
+                }
+                Err(RunError::Exit(code)) => {
+                    self.emit(SupervisorEvent::ExitedWith(code));
+                    RunError::Exit(code)
+                }
+                Err(err) => return Err(err),
+            };
+
+            if self
+                .policy
+                .max_restarts
+                .map_or(false, |max| restarts >= max)
+            {
+                self.emit(SupervisorEvent::GaveUp);
+                return Err(err);
+            }
+            self.emit(SupervisorEvent::Restarting { delay });
+            thread::sleep(delay);
+            delay = (delay * 2).min(self.policy.max_backoff);
+            restarts += 1;
+        }
+    }
+
+    fn emit(&mut self, event: SupervisorEvent) {
+        if let Some(ref mut on_event) = self.on_event {
+            on_event(&event);
+        }
+    }
+}
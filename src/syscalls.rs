@@ -1,18 +1,80 @@
 use cranelift_codegen::ir::types::{Type, I32, I64};
-use log::{debug, trace};
+use log::{Level, LevelFilter};
+use std::any::Any;
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Instant;
 use wasi_common::{hostcalls, wasm32, WasiCtx};
-use wasmtime_runtime::{Export, VMContext};
+use wasmtime_runtime::{Export, VMContext, VMFunctionBody, VMMemoryDefinition};
+
+use super::capability::Capability;
+use super::debugger::{Breakpoints, Step, Syscall};
+use super::fs::fdstat;
+use super::guest::{self, read_u32};
+use super::keep::Backend;
+use super::resolve;
+use super::state::{state_of, WasiState};
+use super::usage::thread_cpu_time;
+use super::watchdog::{Watch, Watchdog};
+
+// The `log` macros, filtered by the level of the instance whose syscall
+// logs, see `LogLevel`, on top of the logger's own.
+macro_rules! instance_log {
+    ($level:ident, $($arg:tt)+) => {
+        if Level::$level <= LOG_LEVEL.with(Cell::get) {
+            log::log!(Level::$level, $($arg)+);
+        }
+    };
+}
+macro_rules! error { ($($arg:tt)+) => { instance_log!(Error, $($arg)+) }; }
+macro_rules! warn { ($($arg:tt)+) => { instance_log!(Warn, $($arg)+) }; }
+macro_rules! info { ($($arg:tt)+) => { instance_log!(Info, $($arg)+) }; }
+macro_rules! debug { ($($arg:tt)+) => { instance_log!(Debug, $($arg)+) }; }
+macro_rules! trace { ($($arg:tt)+) => { instance_log!(Trace, $($arg)+) }; }
+
+thread_local! {
+    /// The level of the instance whose syscall the thread is in.
+    static LOG_LEVEL: Cell<LevelFilter> = Cell::new(LevelFilter::Trace);
+}
+
+/// Has the thread log by the level of the instance at `vmctx`, until
+/// dropped.
+struct LogLevel(LevelFilter);
+
+impl LogLevel {
+    fn enter(vmctx: &mut VMContext) -> Self {
+        let level = get_state(vmctx).map_or(LevelFilter::Trace, |state| state.log_level);
+        Self(LOG_LEVEL.with(|current| current.replace(level)))
+    }
+}
+
+impl Drop for LogLevel {
+    fn drop(&mut self) {
+        LOG_LEVEL.with(|current| current.set(self.0));
+    }
+}
 
 pub trait AbiRet {
     type Abi;
     fn convert(self) -> Self::Abi;
-    fn codegen_tys() -> Vec<Type>;
+    const CODEGEN_TYS: &'static [Type];
+    /// Value handed back to the guest when the syscall couldn't run to
+    /// completion, e.g. because it panicked.
+    fn fault() -> Self::Abi;
+    /// Value handed back to the guest in place of `ret` when the watchdog
+    /// interrupted the syscall.
+    fn interrupted(ret: Self::Abi) -> Self::Abi;
+    /// Value handed back to the guest when a debugger failed the syscall
+    /// with `errno` instead of letting it run.
+    fn failed(errno: wasm32::__wasi_errno_t) -> Self::Abi;
+    /// `ret` as recorded in a syscall trace, if there's anything to record.
+    fn traced(ret: &Self::Abi) -> Option<i64>;
 }
 
 pub trait AbiParam {
     type Abi;
     fn convert(arg: Self::Abi) -> Self;
-    fn codegen_ty() -> Type;
+    const CODEGEN_TY: Type;
 }
 
 macro_rules! cast32 {
@@ -24,7 +86,21 @@ macro_rules! cast32 {
                 self as i32
             }
 
-            fn codegen_tys() -> Vec<Type> { vec![I32] }
+            const CODEGEN_TYS: &'static [Type] = &[I32];
+
+            fn fault() -> Self::Abi { wasm32::__WASI_EFAULT as i32 }
+
+            fn failed(errno: wasm32::__wasi_errno_t) -> Self::Abi { errno as i32 }
+
+            fn traced(ret: &Self::Abi) -> Option<i64> { Some(*ret as i64) }
+
+            fn interrupted(ret: Self::Abi) -> Self::Abi {
+                if ret == wasm32::__WASI_EINTR as i32 {
+                    wasm32::__WASI_ETIMEDOUT as i32
+                } else {
+                    ret
+                }
+            }
         }
 
         impl AbiParam for $i {
@@ -34,7 +110,7 @@ macro_rules! cast32 {
                 param as $i
             }
 
-            fn codegen_ty() -> Type { I32 }
+            const CODEGEN_TY: Type = I32;
         }
     )*)
 }
@@ -48,7 +124,21 @@ macro_rules! cast64 {
                 self as i64
             }
 
-            fn codegen_tys() -> Vec<Type> { vec![I64] }
+            const CODEGEN_TYS: &'static [Type] = &[I64];
+
+            fn fault() -> Self::Abi { wasm32::__WASI_EFAULT as i64 }
+
+            fn failed(errno: wasm32::__wasi_errno_t) -> Self::Abi { errno as i64 }
+
+            fn traced(ret: &Self::Abi) -> Option<i64> { Some(*ret) }
+
+            fn interrupted(ret: Self::Abi) -> Self::Abi {
+                if ret == wasm32::__WASI_EINTR as i64 {
+                    wasm32::__WASI_ETIMEDOUT as i64
+                } else {
+                    ret
+                }
+            }
         }
 
         impl AbiParam for $i {
@@ -58,7 +148,7 @@ macro_rules! cast64 {
                 param as $i
             }
 
-            fn codegen_ty() -> Type { I64 }
+            const CODEGEN_TY: Type = I64;
         }
     )*)
 }
@@ -68,36 +158,56 @@ cast64!(i64 u64);
 
 impl AbiRet for () {
     type Abi = ();
+    const CODEGEN_TYS: &'static [Type] = &[];
     fn convert(self) {}
-    fn codegen_tys() -> Vec<Type> {
-        Vec::new()
+    fn fault() {}
+    fn interrupted(_ret: ()) {}
+    fn failed(_errno: wasm32::__wasi_errno_t) {}
+    fn traced(_ret: &()) -> Option<i64> {
+        None
     }
 }
 
-fn get_wasi_ctx(vmctx: &mut VMContext) -> Result<&mut WasiCtx, wasm32::__wasi_errno_t> {
+fn get_state(vmctx: &mut VMContext) -> Result<&mut WasiState, wasm32::__wasi_errno_t> {
     unsafe {
-        vmctx.host_state().downcast_mut::<WasiCtx>().ok_or_else(|| {
-            println!("!!! no host state named WasiCtx available");
+        state_of(vmctx.host_state()).ok_or_else(|| {
+            println!("!!! no host state named WasiState available");
             wasm32::__WASI_EINVAL
         })
     }
 }
 
+fn get_wasi_ctx(vmctx: &mut VMContext) -> Result<&mut WasiCtx, wasm32::__wasi_errno_t> {
+    get_state(vmctx).map(|state| state.ctx.wasi_ctx())
+}
+
 fn get_memory(vmctx: &mut VMContext) -> Result<&mut [u8], wasm32::__wasi_errno_t> {
+    let state = get_state(vmctx)?;
+    if let Some(definition) = state.memory_definition {
+        return unsafe { memory_of(state, definition) };
+    }
+
+    // The export name lives in the host state, which is itself borrowed from
+    // `vmctx`; detach it so the lookup below can borrow `vmctx` again.
+    let name: *const str = get_state(vmctx)?.memory.as_str();
     unsafe {
-        match vmctx.lookup_global_export("memory") {
+        match vmctx.lookup_global_export(&*name) {
             Some(Export::Memory {
                 definition,
                 vmctx: _,
                 memory: _,
-            }) => Ok(std::slice::from_raw_parts_mut(
-                (*definition).base,
-                (*definition).current_length,
-            )),
+            }) => {
+                // The definition stays put when the memory grows, only its
+                // base and length change, so it's safe to keep for the
+                // next syscalls.
+                let state = get_state(vmctx)?;
+                state.memory_definition = Some(definition);
+                memory_of(state, definition)
+            }
             x => {
                 println!(
-                    "!!! no export named \"memory\", or the export isn't a mem: {:?}",
-                    x
+                    "!!! no export named {:?}, or the export isn't a mem: {:?}",
+                    &*name, x
                 );
                 Err(wasm32::__WASI_EINVAL)
             }
@@ -105,6 +215,288 @@ fn get_memory(vmctx: &mut VMContext) -> Result<&mut [u8], wasm32::__wasi_errno_t
     }
 }
 
+/// The memory `definition` describes, as it is now.
+unsafe fn memory_of<'a>(
+    state: &mut WasiState,
+    definition: *mut VMMemoryDefinition,
+) -> Result<&'a mut [u8], wasm32::__wasi_errno_t> {
+    let base = (*definition).base;
+    let len = (*definition).current_length;
+    state.usage.memory_seen(len);
+    if let Err(err) = state.backing.apply(base, len) {
+        error!("couldn't back grown memory as configured: {}", err);
+        return Err(wasm32::__WASI_ENOMEM);
+    }
+    Ok(std::slice::from_raw_parts_mut(base, len))
+}
+
+/// Reads from `fd` like `fd_read`, through its read-ahead buffer if it has
+/// one, and accounts for what was read.
+fn read(
+    state: &mut WasiState,
+    memory: &mut [u8],
+    fd: wasm32::__wasi_fd_t,
+    iovs: wasm32::uintptr_t,
+    iovs_len: wasm32::size_t,
+    nread: wasm32::uintptr_t,
+) -> wasm32::__wasi_errno_t {
+    ok_or_errno!(state.fs.check_stdio(fd));
+    if let Some(ret) = state.secret_files.read(memory, fd, iovs, iovs_len, nread) {
+        return ret;
+    }
+    let ctx = state.ctx.wasi_ctx();
+    ok_or_errno!(state.write_back.flush(ctx, fd));
+    let read_ahead = state
+        .read_ahead
+        .read(ctx, memory, fd, iovs, iovs_len, nread);
+    let ret = match read_ahead {
+        Some(ret) => ret,
+        None => hostcalls::fd_read(ctx, memory, fd, iovs, iovs_len, nread),
+    };
+    if ret == wasm32::__WASI_ESUCCESS {
+        let nread = read_u32(memory, nread).unwrap_or(0);
+        state.usage.read(nread);
+        state.host_fds.read(fd, u64::from(nread));
+    }
+    ret
+}
+
+/// Writes to `fd` like `fd_write`, or to where it's redirected, through its
+/// write-back buffer if it has one, and accounts for what was written.
+fn write(
+    state: &mut WasiState,
+    memory: &mut [u8],
+    fd: wasm32::__wasi_fd_t,
+    iovs: wasm32::uintptr_t,
+    iovs_len: wasm32::size_t,
+    nwritten: wasm32::uintptr_t,
+) -> wasm32::__wasi_errno_t {
+    ok_or_errno!(state.fs.check_stdio(fd));
+    if let Some(ret) = state
+        .stdio
+        .write_captured(memory, fd, iovs, iovs_len, nwritten)
+    {
+        if ret == wasm32::__WASI_ESUCCESS {
+            state.usage.written(read_u32(memory, nwritten).unwrap_or(0));
+        }
+        return ret;
+    }
+    let fd = state.stdio.route(fd);
+    ok_or_errno!(state.fs.check_writable(fd));
+    if state.fs.limits_file_size() {
+        // Only files opened beneath a preopen are limited, so stdio can be
+        // written to endlessly. Their size has to count what's still
+        // buffered.
+        ok_or_errno!(settle(state, fd));
+        if let Some(offset) = ok_or_errno!(state.host_fds.write_offset(fd)) {
+            ok_or_errno!(state.fs.check_write(memory, offset, iovs, iovs_len));
+        }
+    }
+    let ctx = state.ctx.wasi_ctx();
+    state.read_ahead.release(ctx, fd);
+    let write_back = state
+        .write_back
+        .write(ctx, memory, fd, iovs, iovs_len, nwritten);
+    let ret = match (write_back, state.stdio.terminal(fd)) {
+        (Some(ret), _) => ret,
+        (None, Some(terminal)) => terminal.write(ctx, memory, fd, iovs, iovs_len, nwritten),
+        (None, None) => hostcalls::fd_write(ctx, memory, fd, iovs, iovs_len, nwritten),
+    };
+    if ret == wasm32::__WASI_ESUCCESS {
+        let nwritten = read_u32(memory, nwritten).unwrap_or(0);
+        state.usage.written(nwritten);
+        state.host_fds.written(fd, u64::from(nwritten));
+    }
+    ret
+}
+
+/// Replaces the host's device and inode numbers in the filestat at `buf`
+/// with synthetic ones, if they're to be hidden.
+fn virtualize_filestat(state: &mut WasiState, memory: &mut [u8], buf: wasm32::uintptr_t) {
+    if let Some(ref mut file_ids) = state.file_ids {
+        file_ids.filestat(memory, buf);
+    }
+}
+
+/// Fails with `__WASI_ENOSYS` unless the embedder let the guest use the
+/// filesystem syscalls, with `WasiInstanceBuilder::host_filesystem`.
+fn check_host_fs(vmctx: &mut VMContext) -> Result<(), wasm32::__wasi_errno_t> {
+    get_state(vmctx)?.fs.check_host_fs()
+}
+
+/// Like `check_host_fs`, for the syscalls the guest finds its preopens
+/// with: the preopen of the secret files is there even without the host
+/// filesystem, and then it's the only one.
+fn check_preopens(vmctx: &mut VMContext) -> Result<(), wasm32::__wasi_errno_t> {
+    let state = get_state(vmctx)?;
+    if state.secret_files.has_dir() {
+        Ok(())
+    } else {
+        state.fs.check_host_fs()
+    }
+}
+
+/// Fails with `__WASI_ENOTCAPABLE` unless the guest holds `capability`.
+fn check_capability(
+    vmctx: &mut VMContext,
+    capability: Capability,
+) -> Result<(), wasm32::__wasi_errno_t> {
+    if get_state(vmctx)?.capabilities.has(capability) {
+        Ok(())
+    } else {
+        Err(wasm32::__WASI_ENOTCAPABLE)
+    }
+}
+
+/// Checks the path at `path`, relative to `fd`, before the `WasiCtx`
+/// resolves it: see `resolve::check`, then `HostFds::check_beneath`, which
+/// `follow`s a symlink at its end or not as the syscall will.
+fn check_path(
+    state: &WasiState,
+    memory: &[u8],
+    fd: wasm32::__wasi_fd_t,
+    path: wasm32::uintptr_t,
+    path_len: wasm32::size_t,
+    follow: bool,
+) -> Result<(), wasm32::__wasi_errno_t> {
+    resolve::check(memory, path, path_len, state.path_limits)?;
+    let range = guest::range(memory, path, path_len as usize)?;
+    state.host_fds.check_beneath(fd, &memory[range], follow)
+}
+
+/// Whether `flags` have a symlink at the end of a path followed.
+fn follows(flags: wasm32::__wasi_lookupflags_t) -> bool {
+    flags & wasm32::__WASI_LOOKUP_SYMLINK_FOLLOW != 0
+}
+
+/// Brings the host's view of `fd` in line with the guest's, by handing
+/// back what was read ahead on it and writing what was buffered, before
+/// something relies on its position or contents.
+fn settle(state: &mut WasiState, fd: wasm32::__wasi_fd_t) -> Result<(), wasm32::__wasi_errno_t> {
+    let ctx = state.ctx.wasi_ctx();
+    state.read_ahead.release(ctx, fd);
+    state.write_back.flush(ctx, fd)
+}
+
+/// Starts timing a syscall, if the instance bounds their duration.
+fn watch(vmctx: &mut VMContext) -> Option<Watch> {
+    get_state(vmctx)
+        .ok()
+        .and_then(|state| state.watchdog.as_ref())
+        .map(|watchdog| watchdog.arm())
+}
+
+fn is_stopped(vmctx: &mut VMContext) -> bool {
+    get_state(vmctx)
+        .ok()
+        .and_then(|state| state.control.as_ref())
+        .map_or(false, |control| control.stopped())
+}
+
+/// Applies the configuration changes made through the instance's `Control`
+/// since the last syscall.
+fn apply_control(vmctx: &mut VMContext) {
+    let state = match get_state(vmctx) {
+        Ok(state) => state,
+        Err(_) => return,
+    };
+    let settings = match state.control {
+        Some(ref control) => control.changes(&mut state.control_seen),
+        None => None,
+    };
+    if let Some(settings) = settings {
+        debug!("applying configuration changes: {:?}", settings);
+        if let Some(timeout) = settings.hostcall_timeout {
+            state.watchdog = timeout.map(Watchdog::new);
+        }
+        if let Some(max) = settings.max_fds {
+            state.fs.limit_fds(max);
+        }
+        if let Some(level) = settings.log_level {
+            state.log_level = level;
+        }
+        if let Some(breakpoints) = settings.breakpoints {
+            if state.info.backend == Backend::Nil {
+                state.breakpoints = Breakpoints::new(breakpoints);
+            } else {
+                warn!(
+                    "ignoring breakpoints, which can't be set in a {:?} keep",
+                    state.info.backend
+                );
+            }
+        }
+    }
+}
+
+/// Pauses at the syscall `name` if it hits a breakpoint, or else stops at
+/// it if a debugger is attached, and returns the errno the syscall is to
+/// fail with instead, if any.
+unsafe fn debug_stop(
+    vmctx: *mut VMContext,
+    name: &'static str,
+    args: &[(&'static str, i64)],
+) -> Option<wasm32::__wasi_errno_t> {
+    let state = get_state(&mut *vmctx).ok()?;
+    if state.breakpoints.is_empty() && state.debugger.is_none() {
+        return None;
+    }
+    let memory = get_memory(&mut *vmctx).unwrap_or(&mut []);
+    let syscall = Syscall { name, args };
+    let step = match (state.breakpoints.check(&syscall, memory), &state.control) {
+        (Some(hit), Some(control)) => {
+            info!("paused at breakpoint {}: {}", hit.breakpoint, syscall);
+            control.pause(hit)
+        }
+        _ => state.debugger.as_mut()?.stop(&syscall, memory),
+    };
+    match step {
+        Step::Continue => None,
+        Step::Fail(errno) => Some(errno),
+    }
+}
+
+/// Records the syscall `name` in the trace, if the guest is traced.
+unsafe fn trace_call(vmctx: *mut VMContext, name: &'static str, args: &[(&'static str, i64)]) {
+    let state = match get_state(&mut *vmctx) {
+        Ok(state) => state,
+        Err(_) => return,
+    };
+    if let Some(ref mut tracer) = state.tracer {
+        let memory = get_memory(&mut *vmctx).unwrap_or(&mut []);
+        tracer.call(&Syscall { name, args }, memory);
+    }
+}
+
+fn trace_ret(vmctx: &mut VMContext, ret: Option<i64>) {
+    if let Ok(state) = get_state(vmctx) {
+        if let Some(ref mut tracer) = state.tracer {
+            tracer.ret(ret);
+        }
+    }
+}
+
+fn is_poisoned(vmctx: &mut VMContext) -> bool {
+    get_state(vmctx).map(|state| state.poisoned).unwrap_or(false)
+}
+
+fn has_exited(vmctx: &mut VMContext) -> bool {
+    get_state(vmctx).map_or(false, |state| state.exit_code.is_some())
+}
+
+/// Records a panic which escaped from the syscall `name` and poisons the
+/// instance, since its `WasiCtx` may have been left half-updated.
+pub(crate) fn poison(vmctx: &mut VMContext, name: &str, payload: &(dyn Any + Send)) {
+    let msg = payload
+        .downcast_ref::<&str>()
+        .map(|s| *s)
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<non-string panic payload>");
+    error!("{} panicked: {}; poisoning the instance", name, msg);
+    if let Ok(state) = get_state(vmctx) {
+        state.poisoned = true;
+    }
+}
+
 macro_rules! ok_or_errno {
     ($expr:expr) => {
         match $expr {
@@ -117,24 +509,27 @@ macro_rules! ok_or_errno {
     };
 }
 
+/// A syscall shim, along with what's needed to export it from an instance.
+pub struct Syscall {
+    pub name: &'static str,
+    pub params: &'static [Type],
+    pub results: &'static [Type],
+    pub body: *const VMFunctionBody,
+}
+
 macro_rules! syscalls {
-    ($(pub unsafe extern "C" fn $name:ident($ctx:ident: *mut VMContext $(, $arg:ident: $ty:ty)*,) -> $ret:ty {
+    ($($(#[$attr:meta])* pub unsafe extern "C" fn $name:ident($ctx:ident: *mut VMContext $(, $arg:ident: $ty:ty)*,) -> $ret:ty {
         $($body:tt)*
-    })*) => ($(
+    })*) => {$(
         pub mod $name {
             use super::*;
 
-            /// Returns the codegen types of all the parameters to the shim
+            /// The codegen types of all the parameters to the shim
             /// generated
-            pub fn params() -> Vec<Type> {
-                vec![$(<$ty as AbiParam>::codegen_ty()),*]
-            }
+            pub const PARAMS: &[Type] = &[$(<$ty as AbiParam>::CODEGEN_TY),*];
 
-            /// Returns the codegen types of all the results of the shim
-            /// generated
-            pub fn results() -> Vec<Type> {
-                <$ret as AbiRet>::codegen_tys()
-            }
+            /// The codegen types of all the results of the shim generated
+            pub const RESULTS: &[Type] = <$ret as AbiRet>::CODEGEN_TYS;
 
             /// The actual function pointer to the shim for a syscall.
             ///
@@ -149,15 +544,94 @@ macro_rules! syscalls {
                 $ctx: *mut VMContext,
                 $($arg: <$ty as AbiParam>::Abi,)*
             ) -> <$ret as AbiRet>::Abi {
-                let r = super::$name($ctx, $(<$ty as AbiParam>::convert($arg),)*);
-                <$ret as AbiRet>::convert(r)
+                // Unwinding out of this function would cross into JIT code,
+                // which is undefined behavior, so stop any panic right here:
+                // from the syscall, or from the bookkeeping around it, like
+                // the debugger's I/O.
+                let r = panic::catch_unwind(AssertUnwindSafe(|| {
+                    if is_poisoned(&mut *$ctx) {
+                        trace!("{}: instance is poisoned", stringify!($name));
+                        return <$ret as AbiRet>::fault();
+                    }
+                    if is_stopped(&mut *$ctx) {
+                        trace!("{}: instance is being stopped", stringify!($name));
+                        return <$ret as AbiRet>::fault();
+                    }
+                    if has_exited(&mut *$ctx) {
+                        trace!("{}: instance has exited", stringify!($name));
+                        return <$ret as AbiRet>::fault();
+                    }
+                    apply_control(&mut *$ctx);
+                    let _log_level = LogLevel::enter(&mut *$ctx);
+                    let args = [$((stringify!($arg), $arg as i64)),*];
+                    trace_call($ctx, stringify!($name), &args);
+                    if let Some(errno) = debug_stop($ctx, stringify!($name), &args) {
+                        let ret = <$ret as AbiRet>::failed(errno);
+                        trace_ret(&mut *$ctx, <$ret as AbiRet>::traced(&ret));
+                        return ret;
+                    }
+                    let start = Instant::now();
+                    let cpu_start = thread_cpu_time();
+                    let watch = watch(&mut *$ctx);
+                    // the syscall's own panics are still accounted and traced
+                    let r = panic::catch_unwind(AssertUnwindSafe(|| {
+                        super::$name($ctx, $(<$ty as AbiParam>::convert($arg),)*)
+                    }));
+                    let interrupted = watch.map_or(false, Watch::finish);
+                    if let Ok(state) = get_state(&mut *$ctx) {
+                        let time = start.elapsed();
+                        let cpu_time = thread_cpu_time() - cpu_start;
+                        state.usage.hostcall(stringify!($name), time, cpu_time);
+                        if let Some(ref mut profiler) = state.profiler {
+                            profiler.hostcall(stringify!($name), time, cpu_time);
+                        }
+                    }
+                    let ret = match r {
+                        Ok(r) if interrupted => {
+                            <$ret as AbiRet>::interrupted(<$ret as AbiRet>::convert(r))
+                        }
+                        Ok(r) => <$ret as AbiRet>::convert(r),
+                        Err(payload) => {
+                            poison(&mut *$ctx, stringify!($name), &*payload);
+                            <$ret as AbiRet>::fault()
+                        }
+                    };
+                    trace_ret(&mut *$ctx, <$ret as AbiRet>::traced(&ret));
+                    ret
+                }));
+                r.unwrap_or_else(|payload| {
+                    poison(&mut *$ctx, stringify!($name), &*payload);
+                    <$ret as AbiRet>::fault()
+                })
             }
         }
 
+        $(#[$attr])*
         pub unsafe extern "C" fn $name($ctx: *mut VMContext, $($arg: $ty,)*) -> $ret {
             $($body)*
         }
-    )*)
+    )*
+
+        /// Returns the codegen types of the parameters and results of the
+        /// syscall called `name`, or `None` if there's no such syscall.
+        pub fn signature(name: &str) -> Option<(&'static [Type], &'static [Type])> {
+            match name {
+                $(stringify!($name) => Some(($name::PARAMS, $name::RESULTS)),)*
+                _ => None,
+            }
+        }
+
+        /// Returns every syscall defined alongside this function, in the
+        /// order they're defined in.
+        pub fn all() -> Vec<Syscall> {
+            vec![$(Syscall {
+                name: stringify!($name),
+                params: $name::PARAMS,
+                results: $name::RESULTS,
+                body: $name::SHIM as *const VMFunctionBody,
+            },)*]
+        }
+    }
 }
 
 syscalls! {
@@ -252,22 +726,28 @@ syscalls! {
     }
 
     pub unsafe extern "C" fn fd_prestat_get(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         buf: wasm32::uintptr_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_prestat_get(fd={:?}, buf={:#x?})", fd, buf);
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_preopens(&mut *vmctx));
+        let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        hostcalls::fd_prestat_get(wasi_ctx, memory, fd, buf)
     }
 
     pub unsafe extern "C" fn fd_prestat_dir_name(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         path: wasm32::uintptr_t,
         path_len: wasm32::size_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_prestat_dir_name(fd={:?}, path={:#x?}, path_len={})", fd, path, path_len);
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_preopens(&mut *vmctx));
+        let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        hostcalls::fd_prestat_dir_name(wasi_ctx, memory, fd, path, path_len)
     }
 
     pub unsafe extern "C" fn fd_close(
@@ -275,20 +755,38 @@ syscalls! {
         fd: wasm32::__wasi_fd_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_close(fd={:?})", fd);
-        let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
-        hostcalls::fd_close(wasi_ctx, fd)
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        if let Some(ret) = state.secret_files.close(fd) {
+            return ret;
+        }
+        ok_or_errno!(state.fs.check_unprotected(fd));
+        // close even if the buffered writes can't be written, but report it
+        let settled = settle(state, fd);
+        let ret = hostcalls::fd_close(state.ctx.wasi_ctx(), fd);
+        if ret == wasm32::__WASI_ESUCCESS {
+            state.fs.closed(fd);
+            state.host_fds.closed(fd);
+            state.stdio.closed(fd);
+            state.read_ahead.forget(fd);
+            state.write_back.forget(fd);
+            ok_or_errno!(settled);
+        }
+        ret
     }
 
     pub unsafe extern "C" fn fd_datasync(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_datasync(fd={:?})", fd);
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(settle(state, fd));
+        hostcalls::fd_datasync(state.ctx.wasi_ctx(), fd)
     }
 
     pub unsafe extern "C" fn fd_pread(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         iovs: wasm32::uintptr_t,
         iovs_len: wasm32::size_t,
@@ -303,11 +801,27 @@ syscalls! {
             offset,
             nread
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.write_back.flush(state.ctx.wasi_ctx(), fd));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        let ret = hostcalls::fd_pread(
+            state.ctx.wasi_ctx(),
+            memory,
+            fd,
+            iovs,
+            iovs_len,
+            offset,
+            nread
+        );
+        if ret == wasm32::__WASI_ESUCCESS {
+            state.usage.read(read_u32(memory, nread).unwrap_or(0));
+        }
+        ret
     }
 
     pub unsafe extern "C" fn fd_pwrite(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         iovs: wasm32::uintptr_t,
         iovs_len: wasm32::size_t,
@@ -322,7 +836,26 @@ syscalls! {
             offset,
             nwritten
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd));
+        ok_or_errno!(settle(state, fd));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        let len = guest::iovecs_len(memory, iovs, iovs_len);
+        ok_or_errno!(state.fs.check_file_size(offset.saturating_add(len)));
+        let ret = hostcalls::fd_pwrite(
+            state.ctx.wasi_ctx(),
+            memory,
+            fd,
+            iovs,
+            iovs_len,
+            offset,
+            nwritten
+        );
+        if ret == wasm32::__WASI_ESUCCESS {
+            state.usage.written(read_u32(memory, nwritten).unwrap_or(0));
+        }
+        ret
     }
 
     pub unsafe extern "C" fn fd_read(
@@ -339,23 +872,47 @@ syscalls! {
             iovs_len,
             nread
         );
-        let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
         let memory = ok_or_errno!(get_memory(&mut *vmctx));
-        hostcalls::fd_read(wasi_ctx, memory, fd, iovs, iovs_len, nread)
+        read(state, memory, fd, iovs, iovs_len, nread)
     }
 
+    // Like `dup2`, but `to` has to be open already: it's closed, after its
+    // buffered writes are flushed, and `from` takes its place, rights,
+    // preopen restrictions, stdio redirections and terminal filtering
+    // included. Preopens can't be renumbered, nor replaced.
     pub unsafe extern "C" fn fd_renumber(
         vmctx: *mut VMContext,
         from: wasm32::__wasi_fd_t,
         to: wasm32::__wasi_fd_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_renumber(from={:?}, to={:?})", from, to);
-        let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
-        hostcalls::fd_renumber(wasi_ctx, from, to)
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        if from == to {
+            // like `dup2`, which leaves the fd be if it's open
+            return match fdstat(state.ctx.wasi_ctx(), from) {
+                Ok(_) => wasm32::__WASI_ESUCCESS,
+                Err(errno) => errno,
+            };
+        }
+        ok_or_errno!(state.fs.check_unprotected(from));
+        ok_or_errno!(state.fs.check_unprotected(to));
+        ok_or_errno!(settle(state, from));
+        ok_or_errno!(settle(state, to));
+        let ret = hostcalls::fd_renumber(state.ctx.wasi_ctx(), from, to);
+        if ret == wasm32::__WASI_ESUCCESS {
+            state.fs.renumbered(from, to);
+            state.host_fds.renumbered(from, to);
+            state.stdio.renumbered(from, to);
+            state.read_ahead.forget(from);
+            state.read_ahead.forget(to);
+            state.write_back.renumbered(from, to);
+        }
+        ret
     }
 
     pub unsafe extern "C" fn fd_seek(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         offset: wasm32::__wasi_filedelta_t,
         whence: wasm32::__wasi_whence_t,
@@ -368,29 +925,55 @@ syscalls! {
             wasm32::whence_to_str(whence),
             newoffset
         );
-        wasm32::__WASI_ENOSYS
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        if let Some(ret) = state.secret_files.seek(memory, fd, offset, whence, newoffset) {
+            return ret;
+        }
+        ok_or_errno!(state.fs.check_host_fs());
+        ok_or_errno!(settle(state, fd));
+        let ret = hostcalls::fd_seek(state.ctx.wasi_ctx(), memory, fd, offset, whence, newoffset);
+        if ret == wasm32::__WASI_ESUCCESS {
+            if let Some(offset) = guest::read_u64(memory, newoffset) {
+                state.host_fds.seeked(fd, offset);
+            }
+        }
+        ret
     }
 
     pub unsafe extern "C" fn fd_tell(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         newoffset: wasm32::uintptr_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_tell(fd={:?}, newoffset={:#x?})", fd, newoffset);
-        wasm32::__WASI_ENOSYS
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        if let Some(ret) = state.secret_files.tell(memory, fd, newoffset) {
+            return ret;
+        }
+        ok_or_errno!(state.fs.check_host_fs());
+        ok_or_errno!(settle(state, fd));
+        hostcalls::fd_tell(state.ctx.wasi_ctx(), memory, fd, newoffset)
     }
 
     pub unsafe extern "C" fn fd_fdstat_get(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         buf: wasm32::uintptr_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_fdstat_get(fd={:?}, buf={:#x?})", fd, buf);
-        wasm32::__WASI_ENOSYS
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        if let Some(ret) = state.secret_files.fdstat(memory, fd, buf) {
+            return ret;
+        }
+        ok_or_errno!(state.fs.check_host_fs());
+        hostcalls::fd_fdstat_get(state.ctx.wasi_ctx(), memory, fd, buf)
     }
 
     pub unsafe extern "C" fn fd_fdstat_set_flags(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         flags: wasm32::__wasi_fdflags_t,
     ) -> wasm32::__wasi_errno_t {
@@ -399,11 +982,18 @@ syscalls! {
             fd,
             flags
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(settle(state, fd));
+        let ret = hostcalls::fd_fdstat_set_flags(state.ctx.wasi_ctx(), fd, flags);
+        if ret == wasm32::__WASI_ESUCCESS {
+            state.host_fds.flags_changed(fd, flags);
+        }
+        ret
     }
 
     pub unsafe extern "C" fn fd_fdstat_set_rights(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         fs_rights_base: wasm32::__wasi_rights_t,
         fs_rights_inheriting: wasm32::__wasi_rights_t,
@@ -414,7 +1004,16 @@ syscalls! {
             fs_rights_base,
             fs_rights_inheriting
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(settle(state, fd));
+        state.write_back.rights_changed(fd);
+        hostcalls::fd_fdstat_set_rights(
+            state.ctx.wasi_ctx(),
+            fd,
+            fs_rights_base,
+            fs_rights_inheriting,
+        )
     }
 
     pub unsafe extern "C" fn fd_sync(
@@ -422,8 +1021,9 @@ syscalls! {
         fd: wasm32::__wasi_fd_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_sync(fd={:?})", fd);
-        let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
-        hostcalls::fd_sync(wasi_ctx, fd)
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(settle(state, fd));
+        hostcalls::fd_sync(state.ctx.wasi_ctx(), fd)
     }
 
     pub unsafe extern "C" fn fd_write(
@@ -441,20 +1041,13 @@ syscalls! {
             nwritten
         );
 
-        let fd = if fd == 2 {
-            debug!("redirecting stderr to stdout");
-            1
-        } else {
-            fd
-        };
-
-        let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
         let memory = ok_or_errno!(get_memory(&mut *vmctx));
-        hostcalls::fd_write(wasi_ctx, memory, fd, iovs, iovs_len, nwritten)
+        write(state, memory, fd, iovs, iovs_len, nwritten)
     }
 
     pub unsafe extern "C" fn fd_advise(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         offset: wasm32::__wasi_filesize_t,
         len: wasm32::__wasi_filesize_t,
@@ -467,7 +1060,10 @@ syscalls! {
             len,
             advice
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        state.read_ahead.advise(state.ctx.wasi_ctx(), fd, advice);
+        hostcalls::fd_advise(state.ctx.wasi_ctx(), fd, offset, len, advice)
     }
 
     pub unsafe extern "C" fn fd_allocate(
@@ -477,12 +1073,15 @@ syscalls! {
         len: wasm32::__wasi_filesize_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_allocate(fd={:?}, offset={}, len={})", fd, offset, len);
-        let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
-        hostcalls::fd_allocate(wasi_ctx, fd, offset, len)
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd));
+        ok_or_errno!(state.fs.check_file_size(offset.saturating_add(len)));
+        ok_or_errno!(settle(state, fd));
+        hostcalls::fd_allocate(state.ctx.wasi_ctx(), fd, offset, len)
     }
 
     pub unsafe extern "C" fn path_create_directory(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         path: wasm32::uintptr_t,
         path_len: wasm32::size_t,
@@ -493,11 +1092,26 @@ syscalls! {
             path,
             path_len,
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        ok_or_errno!(check_path(state, memory, fd, path, path_len, false));
+        let ctx = state.ctx.wasi_ctx();
+        let ret = hostcalls::path_create_directory(ctx, memory, fd, path, path_len);
+        if let (wasm32::__WASI_ESUCCESS, Some(umask)) = (ret, state.fs.umask(fd)) {
+            let name = &memory[path as usize..(path + path_len) as usize];
+            if let Err(errno) = state.host_fds.chmod_at(fd, name, 0o777 & !umask) {
+                // rather than leave it more accessible than asked for
+                hostcalls::path_remove_directory(ctx, memory, fd, path, path_len);
+                return errno;
+            }
+        }
+        ret
     }
 
     pub unsafe extern "C" fn path_link(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd0: wasm32::__wasi_fd_t,
         flags0: wasm32::__wasi_lookupflags_t,
         path0: wasm32::uintptr_t,
@@ -516,13 +1130,30 @@ syscalls! {
             path1,
             path_len1
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd0));
+        ok_or_errno!(state.fs.check_writable(fd1));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        ok_or_errno!(check_path(state, memory, fd0, path0, path_len0, follows(flags0)));
+        ok_or_errno!(check_path(state, memory, fd1, path1, path_len1, false));
+        hostcalls::path_link(
+            state.ctx.wasi_ctx(),
+            memory,
+            fd0,
+            flags0,
+            path0,
+            path_len0,
+            fd1,
+            path1,
+            path_len1
+        )
     }
 
     // TODO: When multi-value happens, switch to that instead of passing
     // the `fd` by reference?
     pub unsafe extern "C" fn path_open(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         dirfd: wasm32::__wasi_fd_t,
         dirflags: wasm32::__wasi_lookupflags_t,
         path: wasm32::uintptr_t,
@@ -545,11 +1176,79 @@ syscalls! {
             fs_flags,
             fd
         );
-        wasm32::__WASI_ENOSYS
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        if state.secret_files.is_dir(dirfd) {
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            return state
+                .secret_files
+                .open(memory, path, path_len, oflags, fs_rights_base, fd);
+        }
+        ok_or_errno!(state.fs.check_host_fs());
+        ok_or_errno!(state.fs.check_fd_available());
+        let (fs_rights_base, fs_rights_inheriting) = ok_or_errno!(state.fs.open_rights(
+            state.ctx.wasi_ctx(),
+            dirfd,
+            oflags,
+            fs_rights_base,
+            fs_rights_inheriting
+        ));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        let follow = follows(dirflags);
+        ok_or_errno!(check_path(state, memory, dirfd, path, path_len, follow));
+        // `check_path` made sure the path is within memory
+        let name = memory[path as usize..(path + path_len) as usize].to_vec();
+        let umask = state.fs.umask(dirfd).filter(|_| oflags & wasm32::__WASI_O_CREAT != 0);
+        // only a file this very call creates gets the umask
+        let created = match umask {
+            Some(_) if oflags & wasm32::__WASI_O_EXCL != 0 => true,
+            Some(_) => match state.host_fds.exists(dirfd, &name, follow) {
+                Some(exists) => !exists,
+                None => return wasm32::__WASI_ENOTCAPABLE,
+            },
+            None => false,
+        };
+        let ret = hostcalls::path_open(
+            state.ctx.wasi_ctx(),
+            memory,
+            dirfd,
+            dirflags,
+            path,
+            path_len,
+            oflags,
+            fs_rights_base,
+            fs_rights_inheriting,
+            fs_flags,
+            fd,
+        );
+        if ret == wasm32::__WASI_ESUCCESS {
+            if let Some(fd) = read_u32(memory, fd) {
+                if !state.host_fds.opened(dirfd, &name, follow, fd)
+                    && state.fs.limits_file_size()
+                {
+                    // its writes couldn't be held to the limit
+                    hostcalls::fd_close(state.ctx.wasi_ctx(), fd);
+                    return wasm32::__WASI_EIO;
+                }
+                state.host_fds.flags_changed(fd, fs_flags);
+                if let (Some(umask), true) = (umask, created) {
+                    if let Err(errno) = state.host_fds.chmod(fd, 0o666 & !umask) {
+                        // rather than leave it more accessible than asked for
+                        let ctx = state.ctx.wasi_ctx();
+                        hostcalls::fd_close(ctx, fd);
+                        hostcalls::path_unlink_file(ctx, memory, dirfd, path, path_len);
+                        state.host_fds.closed(fd);
+                        return errno;
+                    }
+                }
+                state.fs.opened(dirfd, fd);
+                state.write_back.opened(dirfd, fd);
+            }
+        }
+        ret
     }
 
     pub unsafe extern "C" fn fd_readdir(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         buf: wasm32::uintptr_t,
         buf_len: wasm32::size_t,
@@ -564,11 +1263,24 @@ syscalls! {
             cookie,
             buf_used,
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        let wasi_ctx = state.ctx.wasi_ctx();
+        let ret = hostcalls::fd_readdir(wasi_ctx, memory, fd, buf, buf_len, cookie, buf_used);
+        if ret == wasm32::__WASI_ESUCCESS {
+            if let Some(ref mut file_ids) = state.file_ids {
+                let used = read_u32(memory, buf_used).unwrap_or(0) as usize;
+                if let Ok(range) = guest::range(memory, buf, used) {
+                    file_ids.dirents(state.ctx.wasi_ctx(), fd, &mut memory[range]);
+                }
+            }
+        }
+        ret
     }
 
     pub unsafe extern "C" fn path_readlink(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         path: wasm32::uintptr_t,
         path_len: wasm32::size_t,
@@ -585,11 +1297,24 @@ syscalls! {
             buf_len,
             buf_used,
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        ok_or_errno!(check_path(state, memory, fd, path, path_len, false));
+        hostcalls::path_readlink(
+            state.ctx.wasi_ctx(),
+            memory,
+            fd,
+            path,
+            path_len,
+            buf,
+            buf_len,
+            buf_used
+        )
     }
 
     pub unsafe extern "C" fn path_rename(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd0: wasm32::__wasi_fd_t,
         path0: wasm32::uintptr_t,
         path_len0: wasm32::size_t,
@@ -606,20 +1331,47 @@ syscalls! {
             path1,
             path_len1,
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd0));
+        ok_or_errno!(state.fs.check_writable(fd1));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        ok_or_errno!(check_path(state, memory, fd0, path0, path_len0, false));
+        ok_or_errno!(check_path(state, memory, fd1, path1, path_len1, false));
+        hostcalls::path_rename(
+            state.ctx.wasi_ctx(),
+            memory,
+            fd0,
+            path0,
+            path_len0,
+            fd1,
+            path1,
+            path_len1
+        )
     }
 
     pub unsafe extern "C" fn fd_filestat_get(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         buf: wasm32::uintptr_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("fd_filestat_get(fd={:?}, buf={:#x?})", fd, buf);
-        wasm32::__WASI_ENOSYS
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        if let Some(ret) = state.secret_files.filestat(memory, fd, buf) {
+            return ret;
+        }
+        ok_or_errno!(state.fs.check_host_fs());
+        ok_or_errno!(state.write_back.flush(state.ctx.wasi_ctx(), fd));
+        let ret = hostcalls::fd_filestat_get(state.ctx.wasi_ctx(), memory, fd, buf);
+        if ret == wasm32::__WASI_ESUCCESS {
+            virtualize_filestat(state, memory, buf);
+        }
+        ret
     }
 
     pub unsafe extern "C" fn fd_filestat_set_times(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         st_atim: wasm32::__wasi_timestamp_t,
         st_mtim: wasm32::__wasi_timestamp_t,
@@ -631,11 +1383,14 @@ syscalls! {
             st_atim, st_mtim,
             fstflags
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd));
+        hostcalls::fd_filestat_set_times(state.ctx.wasi_ctx(), fd, st_atim, st_mtim, fstflags)
     }
 
     pub unsafe extern "C" fn fd_filestat_set_size(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         size: wasm32::__wasi_filesize_t,
     ) -> wasm32::__wasi_errno_t {
@@ -644,11 +1399,16 @@ syscalls! {
             fd,
             size
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd));
+        ok_or_errno!(state.fs.check_file_size(size));
+        ok_or_errno!(settle(state, fd));
+        hostcalls::fd_filestat_set_size(state.ctx.wasi_ctx(), fd, size)
     }
 
     pub unsafe extern "C" fn path_filestat_get(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         flags: wasm32::__wasi_lookupflags_t,
         path: wasm32::uintptr_t,
@@ -663,11 +1423,20 @@ syscalls! {
             path_len,
             buf
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        ok_or_errno!(check_path(state, memory, fd, path, path_len, follows(flags)));
+        let wasi_ctx = state.ctx.wasi_ctx();
+        let ret = hostcalls::path_filestat_get(wasi_ctx, memory, fd, flags, path, path_len, buf);
+        if ret == wasm32::__WASI_ESUCCESS {
+            virtualize_filestat(state, memory, buf);
+        }
+        ret
     }
 
     pub unsafe extern "C" fn path_filestat_set_times(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         flags: wasm32::__wasi_lookupflags_t,
         path: wasm32::uintptr_t,
@@ -685,11 +1454,26 @@ syscalls! {
             st_atim, st_mtim,
             fstflags
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        ok_or_errno!(check_path(state, memory, fd, path, path_len, follows(flags)));
+        hostcalls::path_filestat_set_times(
+            state.ctx.wasi_ctx(),
+            memory,
+            fd,
+            flags,
+            path,
+            path_len,
+            st_atim,
+            st_mtim,
+            fstflags
+        )
     }
 
     pub unsafe extern "C" fn path_symlink(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         path0: wasm32::uintptr_t,
         path_len0: wasm32::size_t,
         fd: wasm32::__wasi_fd_t,
@@ -704,11 +1488,25 @@ syscalls! {
             path1,
             path_len1
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        ok_or_errno!(resolve::check_symlink_target(memory, path0, path_len0, state.path_limits));
+        ok_or_errno!(check_path(state, memory, fd, path1, path_len1, false));
+        hostcalls::path_symlink(
+            state.ctx.wasi_ctx(),
+            memory,
+            path0,
+            path_len0,
+            fd,
+            path1,
+            path_len1
+        )
     }
 
     pub unsafe extern "C" fn path_unlink_file(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         path: wasm32::uintptr_t,
         path_len: wasm32::size_t,
@@ -719,11 +1517,16 @@ syscalls! {
             path,
             path_len
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        ok_or_errno!(check_path(state, memory, fd, path, path_len, false));
+        hostcalls::path_unlink_file(state.ctx.wasi_ctx(), memory, fd, path, path_len)
     }
 
     pub unsafe extern "C" fn path_remove_directory(
-        _vmctx: *mut VMContext,
+        vmctx: *mut VMContext,
         fd: wasm32::__wasi_fd_t,
         path: wasm32::uintptr_t,
         path_len: wasm32::size_t,
@@ -734,7 +1537,12 @@ syscalls! {
             path,
             path_len
         );
-        wasm32::__WASI_ENOSYS
+        ok_or_errno!(check_host_fs(&mut *vmctx));
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        ok_or_errno!(state.fs.check_writable(fd));
+        let memory = ok_or_errno!(get_memory(&mut *vmctx));
+        ok_or_errno!(check_path(state, memory, fd, path, path_len, false));
+        hostcalls::path_remove_directory(state.ctx.wasi_ctx(), memory, fd, path, path_len)
     }
 
     pub unsafe extern "C" fn poll_oneoff(
@@ -755,11 +1563,28 @@ syscalls! {
         hostcalls::poll_oneoff(memory, in_, out, nsubscriptions, nevents)
     }
 
-    pub unsafe extern "C" fn proc_exit(_vmctx: *mut VMContext, rval: u32,) -> () {
+    pub unsafe extern "C" fn proc_exit(vmctx: *mut VMContext, rval: u32,) -> () {
         trace!("proc_exit(rval={:?})", rval);
-        hostcalls::proc_exit(rval)
+        // Record the code instead of exiting the host process, so it gets
+        // back to `WasiModule::invoke`, and raise the interrupt so the
+        // guest traps as soon as this returns, at the check after the call.
+        // Without instrumentation, e.g. for modules compiled with debug
+        // info, it's up to the `unreachable` toolchains put after the call,
+        // and to the later syscalls failing.
+        match get_state(&mut *vmctx) {
+            Ok(state) => {
+                state.write_back.flush_all(state.ctx.wasi_ctx());
+                if let Some(ref mut tracer) = state.tracer {
+                    tracer.flush();
+                }
+                state.exit_code = Some(rval);
+                state.interrupt.raise();
+            }
+            Err(_) => hostcalls::proc_exit(rval),
+        }
     }
 
+    // want to remove from WASI, related to signal handling
     pub unsafe extern "C" fn proc_raise(
         _vmctx: *mut VMContext,
         sig: wasm32::__wasi_signal_t,
@@ -775,7 +1600,20 @@ syscalls! {
     ) -> wasm32::__wasi_errno_t {
         trace!("random_get(buf={:#x?}, buf_len={:?})", buf, buf_len);
         let memory = ok_or_errno!(get_memory(&mut *vmctx));
-        hostcalls::random_get(memory, buf, buf_len)
+        let state = ok_or_errno!(get_state(&mut *vmctx));
+        match state.drbg {
+            Some(ref mut drbg) => {
+                let range = ok_or_errno!(guest::range(memory, buf, buf_len as usize));
+                match drbg.generate(&mut memory[range]) {
+                    Ok(()) => wasm32::__WASI_ESUCCESS,
+                    Err(err) => {
+                        error!("random_get: {}", err);
+                        wasm32::__WASI_EIO
+                    }
+                }
+            }
+            None => hostcalls::random_get(memory, buf, buf_len),
+        }
     }
 
     pub unsafe extern "C" fn sched_yield(_vmctx: *mut VMContext,) -> wasm32::__wasi_errno_t {
@@ -783,6 +1621,17 @@ syscalls! {
         hostcalls::sched_yield()
     }
 
+    // need equivalent of these but aren't standardized yet
+    //
+    // keeps must be able to establish connections from inside, as opposed to
+    // getting pre-established connections as filedescriptors
+    // * socket()
+    // * connect()
+    // * bind()
+    // * listen()
+    // * getsockopt()
+    // * setsockopt()
+    // * handshake() -- performs TLS handeshake, not POSIX
     pub unsafe extern "C" fn sock_recv(
         vmctx: *mut VMContext,
         sock: wasm32::__wasi_fd_t,
@@ -798,6 +1647,7 @@ syscalls! {
             ri_data, ri_data_len, ri_flags,
             ro_datalen, ro_flags
         );
+        ok_or_errno!(check_capability(&mut *vmctx, Capability::Network));
         let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
         let memory = ok_or_errno!(get_memory(&mut *vmctx));
         hostcalls::sock_recv(
@@ -825,6 +1675,7 @@ syscalls! {
             sock,
             si_data, si_data_len, si_flags, so_datalen,
         );
+        ok_or_errno!(check_capability(&mut *vmctx, Capability::Network));
         let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
         let memory = ok_or_errno!(get_memory(&mut *vmctx));
         hostcalls::sock_send(
@@ -844,8 +1695,581 @@ syscalls! {
         how: wasm32::__wasi_sdflags_t,
     ) -> wasm32::__wasi_errno_t {
         trace!("sock_shutdown(sock={:?}, how={:?})", sock, how);
+        ok_or_errno!(check_capability(&mut *vmctx, Capability::Network));
         let wasi_ctx = ok_or_errno!(get_wasi_ctx(&mut *vmctx));
         let memory = ok_or_errno!(get_memory(&mut *vmctx));
         hostcalls::sock_shutdown(wasi_ctx, memory, sock, how)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{data, iovec, seek, tell, Sparse, FILESTAT, GIB, IOV, NREAD};
+
+    // The shims hand 64-bit offsets and sizes to these hostcalls as they are.
+
+    #[test]
+    fn seeks_past_4_gib() {
+        let sparse = Sparse::new();
+        let mut memory = vec![0; 256];
+        let (mut ctx, fd) = sparse.open(&mut memory);
+        let delta = 5 * GIB as i64;
+        assert_eq!(
+            seek(&mut ctx, &mut memory, fd, delta, wasm32::__WASI_WHENCE_SET),
+            5 * GIB
+        );
+        let delta = -2 * GIB as i64;
+        assert_eq!(
+            seek(&mut ctx, &mut memory, fd, delta, wasm32::__WASI_WHENCE_CUR),
+            3 * GIB
+        );
+        assert_eq!(
+            seek(&mut ctx, &mut memory, fd, -1, wasm32::__WASI_WHENCE_END),
+            6 * GIB - 1
+        );
+        assert_eq!(tell(&mut ctx, &mut memory, fd), 6 * GIB - 1);
+    }
+
+    #[test]
+    fn preads_and_stats_past_4_gib() {
+        let sparse = Sparse::new();
+        let mut memory = vec![0; 256];
+        let (mut ctx, fd) = sparse.open(&mut memory);
+        iovec(&mut memory, 4);
+        let errno = hostcalls::fd_pread(&mut ctx, &mut memory, fd, IOV, 1, 5 * GIB, NREAD);
+        assert_eq!(errno, wasm32::__WASI_ESUCCESS);
+        assert_eq!(read_u32(&memory, NREAD), Some(4));
+        assert_eq!(data(&memory, 4), b"tail");
+        // the position is left alone
+        assert_eq!(tell(&mut ctx, &mut memory, fd), 0);
+        let errno = hostcalls::fd_filestat_get(&mut ctx, &mut memory, fd, FILESTAT);
+        assert_eq!(errno, wasm32::__WASI_ESUCCESS);
+        // `st_size`
+        assert_eq!(guest::read_u64(&memory, FILESTAT + 24), Some(6 * GIB));
+    }
+}
+
+/// Shims for the syscalls whose ABI changed in `wasi_snapshot_preview1`.
+///
+/// The `WasiCtx` only speaks the original ABI, so these translate the
+/// arguments and results around the `wasi_unstable` implementations.
+pub mod preview1 {
+    use super::*;
+
+    /// Layout of `__wasi_subscription_t` in each ABI: size, and offsets of
+    /// the clock subscription's fields.
+    const SUBSCRIPTION_SIZE: usize = 48;
+    const UNSTABLE_SUBSCRIPTION_SIZE: usize = 56;
+    const EVENT_SIZE: usize = 32;
+
+    /// `__wasi_filestat_t::st_nlink` grew from 32 to 64 bits, moving every
+    /// field after it up by 8 bytes.
+    const FILESTAT_SIZE: usize = 64;
+    const UNSTABLE_FILESTAT_SIZE: usize = 56;
+
+    fn filestat_from_unstable(memory: &mut [u8], buf: usize) {
+        let mut nlink = [0; 4];
+        nlink.copy_from_slice(&memory[buf + 20..buf + 24]);
+        let nlink = u64::from(u32::from_le_bytes(nlink));
+        memory.copy_within(buf + 24..buf + UNSTABLE_FILESTAT_SIZE, buf + 32);
+        memory[buf + 20..buf + 24].copy_from_slice(&[0; 4]);
+        memory[buf + 24..buf + 32].copy_from_slice(&nlink.to_le_bytes());
+    }
+
+    syscalls! {
+        pub unsafe extern "C" fn fd_seek(
+            vmctx: *mut VMContext,
+            fd: wasm32::__wasi_fd_t,
+            offset: wasm32::__wasi_filedelta_t,
+            whence: wasm32::__wasi_whence_t,
+            newoffset: wasm32::uintptr_t,
+        ) -> wasm32::__wasi_errno_t {
+            // preview1 orders these SET, CUR, END
+            let whence = match whence {
+                0 => wasm32::__WASI_WHENCE_SET,
+                1 => wasm32::__WASI_WHENCE_CUR,
+                2 => wasm32::__WASI_WHENCE_END,
+                _ => return wasm32::__WASI_EINVAL,
+            };
+            super::fd_seek(vmctx, fd, offset, whence, newoffset)
+        }
+
+        pub unsafe extern "C" fn fd_filestat_get(
+            vmctx: *mut VMContext,
+            fd: wasm32::__wasi_fd_t,
+            buf: wasm32::uintptr_t,
+        ) -> wasm32::__wasi_errno_t {
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            let range = ok_or_errno!(guest::range(memory, buf, FILESTAT_SIZE));
+            let ret = super::fd_filestat_get(vmctx, fd, buf);
+            if ret == wasm32::__WASI_ESUCCESS {
+                filestat_from_unstable(memory, range.start);
+            }
+            ret
+        }
+
+        pub unsafe extern "C" fn path_filestat_get(
+            vmctx: *mut VMContext,
+            fd: wasm32::__wasi_fd_t,
+            flags: wasm32::__wasi_lookupflags_t,
+            path: wasm32::uintptr_t,
+            path_len: wasm32::size_t,
+            buf: wasm32::uintptr_t,
+        ) -> wasm32::__wasi_errno_t {
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            let range = ok_or_errno!(guest::range(memory, buf, FILESTAT_SIZE));
+            let ret = super::path_filestat_get(vmctx, fd, flags, path, path_len, buf);
+            if ret == wasm32::__WASI_ESUCCESS {
+                filestat_from_unstable(memory, range.start);
+            }
+            ret
+        }
+
+        pub unsafe extern "C" fn poll_oneoff(
+            vmctx: *mut VMContext,
+            in_: wasm32::uintptr_t,
+            out: wasm32::uintptr_t,
+            nsubscriptions: wasm32::size_t,
+            nevents: wasm32::uintptr_t,
+        ) -> wasm32::__wasi_errno_t {
+            trace!(
+                "preview1::poll_oneoff(in={:#x?}, out={:#x?}, nsubscriptions={}, nevents={:#x?})",
+                in_,
+                out,
+                nsubscriptions,
+                nevents,
+            );
+            let state = ok_or_errno!(get_state(&mut *vmctx));
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            let n = nsubscriptions as usize;
+            let in_range = ok_or_errno!(guest::range(memory, in_, n * SUBSCRIPTION_SIZE));
+            let out_range = ok_or_errno!(guest::range(memory, out, n * EVENT_SIZE));
+            let nevents_range = ok_or_errno!(guest::range(memory, nevents, 4));
+
+            // Lay the subscriptions out the way the `WasiCtx` expects them in
+            // a scratch buffer, followed by room for the events and their
+            // count, and poll on that instead of guest memory.
+            let events = n * UNSTABLE_SUBSCRIPTION_SIZE;
+            let count = events + n * EVENT_SIZE;
+            let mut scratch = std::mem::replace(&mut state.scratch, Vec::new());
+            scratch.clear();
+            scratch.resize(count + 4, 0);
+            let subscriptions = memory[in_range].chunks(SUBSCRIPTION_SIZE);
+            let unstable = scratch[..events].chunks_mut(UNSTABLE_SUBSCRIPTION_SIZE);
+            for (src, dst) in subscriptions.zip(unstable) {
+                // userdata and type
+                dst[..16].copy_from_slice(&src[..16]);
+                if src[8] == wasm32::__WASI_EVENTTYPE_CLOCK {
+                    // clock_id, timeout, precision and flags, after the
+                    // identifier preview1 dropped
+                    dst[24..28].copy_from_slice(&src[16..20]);
+                    dst[32..50].copy_from_slice(&src[24..42]);
+                } else {
+                    dst[16..20].copy_from_slice(&src[16..20]);
+                }
+            }
+
+            let ret = hostcalls::poll_oneoff(
+                &mut scratch,
+                0,
+                events as wasm32::uintptr_t,
+                nsubscriptions,
+                count as wasm32::uintptr_t,
+            );
+            if ret == wasm32::__WASI_ESUCCESS {
+                memory[out_range].copy_from_slice(&scratch[events..count]);
+                memory[nevents_range].copy_from_slice(&scratch[count..]);
+            }
+            state.scratch = scratch;
+            ret
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn filestat_keeps_sizes_past_4_gib() {
+            let size = 6u64 << 30;
+            let mut memory = vec![0xff; 8 + FILESTAT_SIZE];
+            let buf = 8;
+            memory[buf + 20..buf + 24].copy_from_slice(&3u32.to_le_bytes());
+            memory[buf + 24..buf + 32].copy_from_slice(&size.to_le_bytes());
+            memory[buf + 48..buf + 56].copy_from_slice(&size.to_le_bytes());
+            filestat_from_unstable(&mut memory, buf);
+            // `st_nlink`, `st_size`, and `st_ctim`, each 8 bytes up
+            assert_eq!(guest::read_u64(&memory, buf as u32 + 24), Some(3));
+            assert_eq!(guest::read_u64(&memory, buf as u32 + 32), Some(size));
+            assert_eq!(guest::read_u64(&memory, buf as u32 + 56), Some(size));
+            assert_eq!(
+                &memory[buf + 16..buf + 24],
+                &[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]
+            );
+        }
+    }
+}
+
+/// Hostcalls exported under `ENARX_NAMESPACE`, for guests to learn about the
+/// keep they run in.
+pub mod enarx {
+    use super::*;
+    use crate::keep::KeepInfo;
+    use crate::usage::Usage;
+    use std::time::Duration;
+
+    /// Size of an operation passed to `enarx_batch`, laid out as:
+    ///
+    /// - 0: `u32` opcode, `BATCH_READ`, `BATCH_WRITE` or `BATCH_SLEEP`
+    /// - 4: `u32` fd to read or write
+    /// - 8: `u32` pointer to the iovecs to read into or write from
+    /// - 12: `u32` number of iovecs
+    /// - 16: `u64` nanoseconds to sleep
+    /// - 24: `u32` errno, written by the host
+    /// - 28: `u32` bytes transferred, written by the host
+    pub(crate) const BATCH_OP_SIZE: usize = 32;
+
+    pub(crate) const BATCH_READ: u32 = 0;
+    pub(crate) const BATCH_WRITE: u32 = 1;
+    pub(crate) const BATCH_SLEEP: u32 = 2;
+
+    /// How long a `BATCH_SLEEP` sleeps at a time, between checks on
+    /// whether the instance is being stopped.
+    const SLEEP_SLICE: Duration = Duration::from_millis(10);
+
+    /// Performs the operation at `op`, returning its errno. Sleeps end by
+    /// `deadline`, if there is one, with `__WASI_ETIMEDOUT` if cut short.
+    unsafe fn batch_op(
+        vmctx: &mut VMContext,
+        op: wasm32::uintptr_t,
+        deadline: Option<Instant>,
+    ) -> wasm32::__wasi_errno_t {
+        let state = ok_or_errno!(get_state(vmctx));
+        let memory = ok_or_errno!(get_memory(vmctx));
+        let field = |offset: u32| op + offset;
+        let opcode = ok_or_errno!(read_u32(memory, field(0)).ok_or(wasm32::__WASI_EFAULT));
+        let fd = ok_or_errno!(read_u32(memory, field(4)).ok_or(wasm32::__WASI_EFAULT));
+        let iovs = ok_or_errno!(read_u32(memory, field(8)).ok_or(wasm32::__WASI_EFAULT));
+        let iovs_len = ok_or_errno!(read_u32(memory, field(12)).ok_or(wasm32::__WASI_EFAULT));
+        let nbytes = field(28);
+        match opcode {
+            BATCH_READ => read(state, memory, fd, iovs, iovs_len, nbytes),
+            BATCH_WRITE => write(state, memory, fd, iovs, iovs_len, nbytes),
+            BATCH_SLEEP => {
+                let range = ok_or_errno!(guest::range(memory, field(16), 8));
+                let mut nanos = [0; 8];
+                nanos.copy_from_slice(&memory[range]);
+                let requested = Duration::from_nanos(u64::from_le_bytes(nanos));
+                let start = Instant::now();
+                let allowed = deadline.map_or(requested, |deadline| {
+                    if deadline > start {
+                        requested.min(deadline - start)
+                    } else {
+                        Duration::from_secs(0)
+                    }
+                });
+                sleep(state, allowed);
+                if start.elapsed() < requested {
+                    wasm32::__WASI_ETIMEDOUT
+                } else {
+                    wasm32::__WASI_ESUCCESS
+                }
+            }
+            _ => wasm32::__WASI_EINVAL,
+        }
+    }
+
+    /// Sleeps for `duration`, or until the instance is being stopped.
+    fn sleep(state: &WasiState, duration: Duration) {
+        let start = Instant::now();
+        loop {
+            if state
+                .control
+                .as_ref()
+                .map_or(false, |control| control.stopped())
+            {
+                return;
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= duration {
+                return;
+            }
+            std::thread::sleep((duration - elapsed).min(SLEEP_SLICE));
+        }
+    }
+
+    /// Tries to name the anonymous file this many times, each time with a
+    /// fresh random name, before giving up.
+    const TMPFILE_ATTEMPTS: usize = 8;
+
+    /// Creates an anonymous file with `O_TMPFILE` in `dirfd`, and returns
+    /// the guest fd it's open on.
+    ///
+    /// The `WasiCtx` only hands out fds for files it opens by name. So the
+    /// file gets a random name for as long as the `WasiCtx` needs to open
+    /// it, once it's fully set up. Only that name is ever removed, and only
+    /// if it still refers to the file.
+    fn tmpfile(
+        state: &mut WasiState,
+        dirfd: wasm32::__wasi_fd_t,
+    ) -> Result<wasm32::__wasi_fd_t, wasm32::__wasi_errno_t> {
+        state.fs.check_host_fs()?;
+        state.fs.check_fd_available()?;
+        state.fs.check_writable(dirfd)?;
+        // whatever files created in the directory may have
+        let oflags = wasm32::__WASI_O_CREAT | wasm32::__WASI_O_EXCL;
+        let (rights_base, rights_inheriting) =
+            state
+                .fs
+                .open_rights(state.ctx.wasi_ctx(), dirfd, oflags, !0, 0)?;
+        let file = state.host_fds.tmpfile(dirfd, state.fs.umask(dirfd))?;
+        // The `WasiCtx` reads the name from, and writes the fd to, the
+        // memory it's handed, so lay them out in one of our own: the fd
+        // first, where it's aligned as the `WasiCtx` insists, then the name.
+        let mut buf = b"\0\0\0\0.enarx-tmp-0123456789abcdef".to_vec();
+        let (fd_ptr, path) = (0, 4);
+        let path_len = buf.len() as wasm32::size_t - path;
+        let suffix = buf.len() - 16;
+        for _ in 0..TMPFILE_ATTEMPTS {
+            let mut random = [0; 8];
+            let errno = hostcalls::random_get(&mut random, 0, 8);
+            if errno != wasm32::__WASI_ESUCCESS {
+                return Err(errno);
+            }
+            for (i, byte) in random.iter().enumerate() {
+                let hex = format!("{:02x}", byte);
+                buf[suffix + 2 * i..suffix + 2 * i + 2].copy_from_slice(hex.as_bytes());
+            }
+            let name = buf[path as usize..].to_vec();
+            match state.host_fds.link(&file, dirfd, &name) {
+                Ok(()) => {}
+                Err(wasm32::__WASI_EEXIST) => continue,
+                Err(errno) => return Err(errno),
+            }
+
+            // From here on, the name has to go again, whatever happens.
+            let ctx = state.ctx.wasi_ctx();
+            let mut errno = hostcalls::path_open(
+                ctx,
+                &mut buf,
+                dirfd,
+                0,
+                path,
+                path_len,
+                0,
+                rights_base,
+                rights_inheriting,
+                0,
+                fd_ptr,
+            );
+            let fd = match errno {
+                wasm32::__WASI_ESUCCESS => read_u32(&buf, fd_ptr),
+                _ => None,
+            };
+            if let Some(fd) = fd {
+                // while it can still be looked up by name
+                if !state.host_fds.opened(dirfd, &name, false, fd) && state.fs.limits_file_size() {
+                    errno = wasm32::__WASI_EIO;
+                }
+            }
+            // Which fails if the name was taken over, in which case the
+            // guest's fd may be on something else: it's closed below.
+            let unlinked = state.host_fds.unlink(&file, dirfd, &name);
+            if errno == wasm32::__WASI_ESUCCESS {
+                errno = unlinked.err().unwrap_or(wasm32::__WASI_ESUCCESS);
+            }
+            let fd = match fd {
+                Some(fd) => fd,
+                None if errno == wasm32::__WASI_ESUCCESS => return Err(wasm32::__WASI_EFAULT),
+                None => return Err(errno),
+            };
+            if errno != wasm32::__WASI_ESUCCESS {
+                hostcalls::fd_close(ctx, fd);
+                state.host_fds.closed(fd);
+                return Err(errno);
+            }
+            state.fs.opened(dirfd, fd);
+            state.write_back.opened(dirfd, fd);
+            return Ok(fd);
+        }
+        Err(wasm32::__WASI_EEXIST)
+    }
+
+    syscalls! {
+        /// Writes the first `buf_len` bytes of the keep's description, as
+        /// encoded by `KeepInfo::to_bytes`, at `buf`.
+        pub unsafe extern "C" fn enarx_info(
+            vmctx: *mut VMContext,
+            buf: wasm32::uintptr_t,
+            buf_len: wasm32::size_t,
+        ) -> wasm32::__wasi_errno_t {
+            trace!("enarx_info(buf={:#x?}, buf_len={})", buf, buf_len);
+            let info = ok_or_errno!(get_state(&mut *vmctx)).info.to_bytes();
+            let len = (buf_len as usize).min(KeepInfo::SIZE);
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            let range = ok_or_errno!(guest::range(memory, buf, len));
+            memory[range].copy_from_slice(&info[..len]);
+            wasm32::__WASI_ESUCCESS
+        }
+
+        /// Writes the number of logical CPUs the guest may use, as a `u32`
+        /// at `count`.
+        pub unsafe extern "C" fn enarx_cpu_count(
+            vmctx: *mut VMContext,
+            count: wasm32::uintptr_t,
+        ) -> wasm32::__wasi_errno_t {
+            trace!("enarx_cpu_count(count={:#x?})", count);
+            let cpus = ok_or_errno!(get_state(&mut *vmctx)).info.cpus;
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            let range = ok_or_errno!(guest::range(memory, count, 4));
+            memory[range].copy_from_slice(&cpus.to_le_bytes());
+            wasm32::__WASI_ESUCCESS
+        }
+
+        /// Writes the first `buf_len` bytes of the resources the guest has
+        /// consumed so far, as encoded by `Usage::to_guest`, at `buf`.
+        pub unsafe extern "C" fn enarx_usage(
+            vmctx: *mut VMContext,
+            buf: wasm32::uintptr_t,
+            buf_len: wasm32::size_t,
+        ) -> wasm32::__wasi_errno_t {
+            trace!("enarx_usage(buf={:#x?}, buf_len={})", buf, buf_len);
+            let state = ok_or_errno!(get_state(&mut *vmctx));
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            let open_fds = state.fs.open_fds();
+            let usage = state.usage.to_guest(memory.len(), open_fds);
+            let len = (buf_len as usize).min(Usage::GUEST_SIZE);
+            let range = ok_or_errno!(guest::range(memory, buf, len));
+            memory[range].copy_from_slice(&usage[..len]);
+            wasm32::__WASI_ESUCCESS
+        }
+
+        /// Copies the first `buf_len` bytes of the secret called
+        /// `name[..name_len]` to `buf`, and writes its full length as a
+        /// `u32` at `len`. Fails with `__WASI_ENOENT` if there's no such
+        /// secret.
+        pub unsafe extern "C" fn enarx_secret_get(
+            vmctx: *mut VMContext,
+            name: wasm32::uintptr_t,
+            name_len: wasm32::size_t,
+            buf: wasm32::uintptr_t,
+            buf_len: wasm32::size_t,
+            len: wasm32::uintptr_t,
+        ) -> wasm32::__wasi_errno_t {
+            trace!(
+                "enarx_secret_get(name={:#x?}, name_len={}, buf={:#x?}, buf_len={}, len={:#x?})",
+                name,
+                name_len,
+                buf,
+                buf_len,
+                len,
+            );
+            ok_or_errno!(check_capability(&mut *vmctx, Capability::Secrets));
+            let state = ok_or_errno!(get_state(&mut *vmctx));
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            let name = ok_or_errno!(guest::range(memory, name, name_len as usize));
+            let name = ok_or_errno!(
+                std::str::from_utf8(&memory[name]).map_err(|_| wasm32::__WASI_EILSEQ)
+            );
+            let secret = match state.secrets.get(name) {
+                Some(secret) => secret.bytes(),
+                None => return wasm32::__WASI_ENOENT,
+            };
+            let copied = (buf_len as usize).min(secret.len());
+            let buf = ok_or_errno!(guest::range(memory, buf, copied));
+            let len = ok_or_errno!(guest::range(memory, len, 4));
+            memory[buf].copy_from_slice(&secret[..copied]);
+            memory[len].copy_from_slice(&(secret.len() as u32).to_le_bytes());
+            wasm32::__WASI_ESUCCESS
+        }
+
+        /// Writes the mask of the capabilities the guest holds, as described
+        /// for `Capability`, as a `u32` at `mask`.
+        pub unsafe extern "C" fn enarx_capabilities(
+            vmctx: *mut VMContext,
+            mask: wasm32::uintptr_t,
+        ) -> wasm32::__wasi_errno_t {
+            trace!("enarx_capabilities(mask={:#x?})", mask);
+            let held = ok_or_errno!(get_state(&mut *vmctx)).capabilities.mask();
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            let range = ok_or_errno!(guest::range(memory, mask, 4));
+            memory[range].copy_from_slice(&held.to_le_bytes());
+            wasm32::__WASI_ESUCCESS
+        }
+
+        /// Drops the capabilities whose bits are set in `mask`. There's no
+        /// getting them back.
+        pub unsafe extern "C" fn enarx_capability_drop(
+            vmctx: *mut VMContext,
+            mask: u32,
+        ) -> wasm32::__wasi_errno_t {
+            trace!("enarx_capability_drop(mask={:#x})", mask);
+            ok_or_errno!(get_state(&mut *vmctx)).capabilities.drop_mask(mask);
+            wasm32::__WASI_ESUCCESS
+        }
+
+        /// Creates an anonymous file in the directory `dirfd`, like
+        /// `O_TMPFILE`, and writes the fd it's open on as a `u32` at `fd`.
+        /// The file has the rights files created in the directory may have.
+        ///
+        /// It's created with `O_TMPFILE`, so it's gone with its last fd,
+        /// even if the guest is killed. The `WasiCtx` only opens files by
+        /// name, so the file has one, at random, for as long as that takes.
+        /// Fails with `__WASI_ENOTSUP` where `O_TMPFILE` isn't supported.
+        ///
+        /// Fails with `__WASI_ENOSYS` like the filesystem syscalls unless
+        /// the guest may use them.
+        pub unsafe extern "C" fn enarx_tmpfile(
+            vmctx: *mut VMContext,
+            dirfd: wasm32::__wasi_fd_t,
+            fd: wasm32::uintptr_t,
+        ) -> wasm32::__wasi_errno_t {
+            trace!("enarx_tmpfile(dirfd={:?}, fd={:#x?})", dirfd, fd);
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            let range = ok_or_errno!(guest::range(memory, fd, 4));
+            let state = ok_or_errno!(get_state(&mut *vmctx));
+            let tmpfile = ok_or_errno!(tmpfile(state, dirfd));
+            memory[range].copy_from_slice(&tmpfile.to_le_bytes());
+            wasm32::__WASI_ESUCCESS
+        }
+
+        /// Experimental: performs the `ops_len` operations at `ops`, laid
+        /// out as described for `BATCH_OP_SIZE`, in order, writing each
+        /// one's errno and bytes transferred back into it. A failing
+        /// operation doesn't stop the later ones.
+        ///
+        /// Lets I/O-heavy guests do in one transition to the host what
+        /// would otherwise take one per operation.
+        ///
+        /// Sleeps are cut short, failing with `__WASI_ETIMEDOUT`, once the
+        /// batch has taken the time a hostcall is allowed, or once the
+        /// instance is being stopped.
+        pub unsafe extern "C" fn enarx_batch(
+            vmctx: *mut VMContext,
+            ops: wasm32::uintptr_t,
+            ops_len: wasm32::size_t,
+        ) -> wasm32::__wasi_errno_t {
+            trace!("enarx_batch(ops={:#x?}, ops_len={})", ops, ops_len);
+            let size = ok_or_errno!((ops_len as usize)
+                .checked_mul(BATCH_OP_SIZE)
+                .ok_or(wasm32::__WASI_EFAULT));
+            let memory = ok_or_errno!(get_memory(&mut *vmctx));
+            ok_or_errno!(guest::range(memory, ops, size));
+            // the whole batch is one hostcall, so its sleeps share its bound
+            let state = ok_or_errno!(get_state(&mut *vmctx));
+            let deadline = state
+                .watchdog
+                .as_ref()
+                .map(|watchdog| Instant::now() + watchdog.timeout());
+            for i in 0..ops_len {
+                let op = ops + i * BATCH_OP_SIZE as u32;
+                let errno = batch_op(&mut *vmctx, op, deadline);
+                let memory = ok_or_errno!(get_memory(&mut *vmctx));
+                let range = ok_or_errno!(guest::range(memory, op + 24, 4));
+                memory[range].copy_from_slice(&u32::from(errno).to_le_bytes());
+            }
+            wasm32::__WASI_ESUCCESS
+        }
+    }
+}
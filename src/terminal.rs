@@ -0,0 +1,230 @@
+use wasi_common::{wasm32, WasiCtx};
+
+use super::guest::{self, iovec};
+use super::secret::zeroize;
+use super::writeback::{write_all, HEADER};
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Most bytes a string is stripped as a part of: beyond that, it's taken
+/// to be unterminated, and what follows is output again rather than all
+/// of it swallowed.
+const MAX_STRING_LEN: usize = 4096;
+
+/// What to do with the control sequences a guest writes to a host
+/// terminal, which could otherwise move the cursor, rewrite what the
+/// operator sees, retitle the window or worse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalFilter {
+    /// Drop escape sequences, and control characters other than tab,
+    /// newline and carriage return.
+    Strip,
+    /// Write control characters, `ESC` included, out as `\xNN` instead, so
+    /// the operator sees what the guest tried.
+    Escape,
+}
+
+/// Where the filter is within an escape sequence or a UTF-8 encoded C1
+/// control, either of which may be split across writes. `Escape` only
+/// tracks the latter.
+#[derive(Clone, Copy)]
+enum Sequence {
+    None,
+    /// After `ESC`.
+    Escape,
+    /// In a control sequence, up to its final byte.
+    Csi,
+    /// In a string, like an operating system command, up to `BEL`,
+    /// `ESC \` or the C1 `ST`.
+    String,
+    /// After an `ESC` in a string.
+    StringEscape,
+    /// After a `0xc2` in a string.
+    StringC2,
+    /// After a `0xc2`, which starts the UTF-8 encoding of C1 controls.
+    C2,
+}
+
+/// Filters the guest's writes to stdout or stderr, when that's a host
+/// terminal.
+pub(crate) struct Terminal {
+    filter: TerminalFilter,
+    sequence: Sequence,
+    /// Bytes of the string the filter is in so far.
+    string_len: usize,
+    /// `HEADER`, followed by the filtered data.
+    buf: Vec<u8>,
+}
+
+impl Terminal {
+    pub(crate) fn new(filter: TerminalFilter) -> Self {
+        Self {
+            filter,
+            sequence: Sequence::None,
+            string_len: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Serves an `fd_write` to the terminal at `fd`, reporting everything
+    /// as written once the filtered data is.
+    pub(crate) fn write(
+        &mut self,
+        ctx: &mut WasiCtx,
+        memory: &mut [u8],
+        fd: wasm32::__wasi_fd_t,
+        iovs: wasm32::uintptr_t,
+        iovs_len: wasm32::size_t,
+        nwritten: wasm32::uintptr_t,
+    ) -> wasm32::__wasi_errno_t {
+        let nwritten = match guest::range(memory, nwritten, 4) {
+            Ok(range) => range,
+            Err(errno) => return errno,
+        };
+        self.buf.clear();
+        self.buf.resize(HEADER, 0);
+        let mut written = 0;
+        for i in 0..iovs_len {
+            let range = match iovec(memory, iovs, i) {
+                Some(range) => range,
+                None => return wasm32::__WASI_EFAULT,
+            };
+            written += range.len();
+            for &byte in &memory[range] {
+                self.filter(byte);
+            }
+        }
+        let result = write_all(ctx, &mut self.buf, fd);
+        zeroize(&mut self.buf);
+        if let Err(errno) = result {
+            return errno;
+        }
+        memory[nwritten].copy_from_slice(&(written as u32).to_le_bytes());
+        wasm32::__WASI_ESUCCESS
+    }
+
+    fn filter(&mut self, byte: u8) {
+        if self.filter == TerminalFilter::Escape {
+            self.sequence = match (self.sequence, byte) {
+                (Sequence::C2, 0x80..=0x9f) => {
+                    self.escape(0xc2);
+                    self.escape(byte);
+                    Sequence::None
+                }
+                (Sequence::C2, _) => {
+                    self.buf.push(0xc2);
+                    self.filter(byte);
+                    return;
+                }
+                (_, 0xc2) => Sequence::C2,
+                (_, b'\t') | (_, b'\n') | (_, b'\r') => {
+                    self.buf.push(byte);
+                    Sequence::None
+                }
+                (_, 0..=0x1f) | (_, 0x7f) => {
+                    self.escape(byte);
+                    Sequence::None
+                }
+                (_, _) => {
+                    self.buf.push(byte);
+                    Sequence::None
+                }
+            };
+            return;
+        }
+        match self.sequence {
+            Sequence::String | Sequence::StringEscape | Sequence::StringC2 => {
+                self.string_len += 1;
+                if self.string_len > MAX_STRING_LEN {
+                    self.sequence = Sequence::None;
+                }
+            }
+            _ => self.string_len = 0,
+        }
+        self.sequence = match (self.sequence, byte) {
+            (Sequence::None, ESC) => Sequence::Escape,
+            (Sequence::None, 0xc2) => Sequence::C2,
+            (Sequence::None, b'\t') | (Sequence::None, b'\n') | (Sequence::None, b'\r') => {
+                self.buf.push(byte);
+                Sequence::None
+            }
+            (Sequence::None, 0..=0x1f) | (Sequence::None, 0x7f) => Sequence::None,
+            (Sequence::None, _) => {
+                self.buf.push(byte);
+                Sequence::None
+            }
+            (Sequence::Escape, b'[') => Sequence::Csi,
+            (Sequence::Escape, b'P') | (Sequence::Escape, b'X') => Sequence::String,
+            (Sequence::Escape, b']') | (Sequence::Escape, b'^') | (Sequence::Escape, b'_') => {
+                Sequence::String
+            }
+            // intermediate bytes, before the final one
+            (Sequence::Escape, 0x20..=0x2f) => Sequence::Escape,
+            (Sequence::Escape, _) => Sequence::None,
+            (Sequence::Csi, 0x40..=0x7e) => Sequence::None,
+            (Sequence::Csi, _) => Sequence::Csi,
+            (Sequence::String, BEL) => Sequence::None,
+            (Sequence::String, ESC) => Sequence::StringEscape,
+            (Sequence::String, 0xc2) => Sequence::StringC2,
+            (Sequence::String, _) => Sequence::String,
+            (Sequence::StringEscape, b'\\') => Sequence::None,
+            (Sequence::StringEscape, _) => Sequence::String,
+            (Sequence::StringC2, 0x9c) => Sequence::None,
+            (Sequence::StringC2, _) => {
+                self.sequence = Sequence::String;
+                self.filter(byte);
+                return;
+            }
+            // the C1 forms of the same
+            (Sequence::C2, 0x9b) => Sequence::Csi,
+            (Sequence::C2, 0x90) | (Sequence::C2, 0x98) => Sequence::String,
+            (Sequence::C2, 0x9d..=0x9f) => Sequence::String,
+            (Sequence::C2, 0x80..=0x9f) => Sequence::None,
+            (Sequence::C2, _) => {
+                self.buf.push(0xc2);
+                self.sequence = Sequence::None;
+                self.filter(byte);
+                return;
+            }
+        };
+    }
+
+    fn escape(&mut self, byte: u8) {
+        self.buf
+            .extend_from_slice(format!("\\x{:02x}", byte).as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(bytes: &[u8]) -> Vec<u8> {
+        let mut terminal = Terminal::new(TerminalFilter::Strip);
+        for &byte in bytes {
+            terminal.filter(byte);
+        }
+        terminal.buf
+    }
+
+    #[test]
+    fn strips_strings_up_to_any_terminator() {
+        assert_eq!(strip(b"a\x1b]0;title\x07b"), b"ab");
+        assert_eq!(strip(b"a\x1b]0;title\x1b\\b"), b"ab");
+        assert_eq!(strip(b"a\x1b]0;title\xc2\x9cb"), b"ab");
+        assert_eq!(strip(b"a\xc2\x9d0;title\xc2\x9cb"), b"ab");
+        // a `0xc2` which doesn't start `ST` is still part of the string
+        assert_eq!(strip(b"a\x1b]0;\xc2\xa9\x1b\\b"), b"ab");
+    }
+
+    #[test]
+    fn gives_up_on_unterminated_strings() {
+        let mut bytes = b"\x1b]0;".to_vec();
+        bytes.resize(bytes.len() + MAX_STRING_LEN, b'x');
+        bytes.extend_from_slice(b"\nafter\n");
+        let out = strip(&bytes);
+        assert!(out.ends_with(b"\nafter\n"));
+        assert!(out.len() < MAX_STRING_LEN);
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::debugger::Syscall;
+
+/// Writes the guest's syscalls to a file in a canonical form, a line each,
+/// for `WasiInstanceBuilder::trace`:
+///
+/// ```text
+/// path_open(dirfd=3, dirflags=1, path="data/in.txt", oflags=0, ...) = 0
+/// proc_exit(rval=0)
+/// ```
+///
+/// Integers are decimal, paths are replaced by what they point to, and the
+/// result is the bare errno, so that a trace only changes when what the
+/// guest sees does. Pointers are kept: they're the guest's own, so the
+/// same for every run of the same module.
+pub(crate) struct Tracer {
+    out: BufWriter<File>,
+    /// Whether the line of a syscall still lacks its result.
+    pending: bool,
+}
+
+impl Tracer {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+            pending: false,
+        })
+    }
+
+    /// Records that the guest makes `syscall`.
+    pub(crate) fn call(&mut self, syscall: &Syscall, memory: &[u8]) {
+        self.end_line();
+        let line = canonical(syscall, memory);
+        self.write(line.as_bytes());
+        self.pending = true;
+    }
+
+    /// Records what the syscall last made returned, if anything.
+    pub(crate) fn ret(&mut self, ret: Option<i64>) {
+        if let Some(ret) = ret {
+            self.write(format!(" = {}", ret).as_bytes());
+        }
+        self.end_line();
+    }
+
+    /// Writes out what's buffered, e.g. before the process exits without
+    /// dropping the instance.
+    pub(crate) fn flush(&mut self) {
+        self.end_line();
+        if let Err(err) = self.out.flush() {
+            log::error!("couldn't write the syscall trace: {}", err);
+        }
+    }
+
+    fn end_line(&mut self) {
+        if self.pending {
+            self.pending = false;
+            self.write(b"\n");
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if let Err(err) = self.out.write_all(bytes) {
+            log::error!("couldn't write the syscall trace: {}", err);
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// `syscall` in the canonical form of a trace, without its result.
+fn canonical(syscall: &Syscall, memory: &[u8]) -> String {
+    let mut line = format!("{}(", syscall.name);
+    let mut first = true;
+    for &(name, value) in syscall.args {
+        if name.starts_with("path_len") {
+            continue;
+        }
+        if !first {
+            line.push_str(", ");
+        }
+        first = false;
+        match syscall.path(name, value, memory) {
+            Some(path) => {
+                let path = String::from_utf8_lossy(path);
+                let _ = write!(line, "{}={:?}", name, path);
+            }
+            None => {
+                let _ = write!(line, "{}={}", name, value);
+            }
+        }
+    }
+    line.push(')');
+    line
+}
@@ -0,0 +1,119 @@
+use super::module::RunError;
+use wasmtime_jit::RuntimeValue;
+
+/// A Rust type corresponding to a wasm value type.
+pub trait WasmTy: Sized {
+    fn into_value(self) -> RuntimeValue;
+    fn from_value(value: RuntimeValue) -> Option<Self>;
+}
+
+impl WasmTy for i32 {
+    fn into_value(self) -> RuntimeValue {
+        RuntimeValue::I32(self)
+    }
+
+    fn from_value(value: RuntimeValue) -> Option<Self> {
+        match value {
+            RuntimeValue::I32(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl WasmTy for i64 {
+    fn into_value(self) -> RuntimeValue {
+        RuntimeValue::I64(self)
+    }
+
+    fn from_value(value: RuntimeValue) -> Option<Self> {
+        match value {
+            RuntimeValue::I64(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl WasmTy for f32 {
+    fn into_value(self) -> RuntimeValue {
+        RuntimeValue::F32(self.to_bits())
+    }
+
+    fn from_value(value: RuntimeValue) -> Option<Self> {
+        match value {
+            RuntimeValue::F32(bits) => Some(f32::from_bits(bits)),
+            _ => None,
+        }
+    }
+}
+
+impl WasmTy for f64 {
+    fn into_value(self) -> RuntimeValue {
+        RuntimeValue::F64(self.to_bits())
+    }
+
+    fn from_value(value: RuntimeValue) -> Option<Self> {
+        match value {
+            RuntimeValue::F64(bits) => Some(f64::from_bits(bits)),
+            _ => None,
+        }
+    }
+}
+
+/// Arguments of an export, as a tuple of `WasmTy`s.
+pub trait WasmArgs {
+    fn into_values(self) -> Vec<RuntimeValue>;
+}
+
+/// Results of an export: `()`, a single `WasmTy`, or a tuple of them.
+pub trait WasmResults: Sized {
+    fn from_values(values: Vec<RuntimeValue>) -> Result<Self, RunError>;
+}
+
+fn mismatch(values: &[RuntimeValue]) -> RunError {
+    RunError::Type(format!("export returned unexpected results {:?}", values))
+}
+
+impl<T: WasmTy> WasmResults for T {
+    fn from_values(values: Vec<RuntimeValue>) -> Result<Self, RunError> {
+        if values.len() != 1 {
+            return Err(mismatch(&values));
+        }
+        T::from_value(values[0].clone()).ok_or_else(|| mismatch(&values))
+    }
+}
+
+macro_rules! tuples {
+    ($(($($t:ident)*))*) => ($(
+        #[allow(non_snake_case)]
+        impl<$($t: WasmTy,)*> WasmArgs for ($($t,)*) {
+            fn into_values(self) -> Vec<RuntimeValue> {
+                let ($($t,)*) = self;
+                vec![$($t.into_value(),)*]
+            }
+        }
+
+        impl<$($t: WasmTy,)*> WasmResults for ($($t,)*) {
+            #[allow(unused_mut, unused_variables)]
+            fn from_values(values: Vec<RuntimeValue>) -> Result<Self, RunError> {
+                let mut iter = values.iter().cloned();
+                let results = ($(
+                    iter.next().and_then($t::from_value).ok_or_else(|| mismatch(&values))?,
+                )*);
+                if iter.next().is_some() {
+                    return Err(mismatch(&values));
+                }
+                Ok(results)
+            }
+        }
+    )*)
+}
+
+tuples! {
+    ()
+    (A)
+    (A B)
+    (A B C)
+    (A B C D)
+    (A B C D E)
+    (A B C D E F)
+}
@@ -0,0 +1,24 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Where the guest finds the time zone database, as in most libcs.
+pub(crate) const ZONEINFO_GUEST_PATH: &str = "/usr/share/zoneinfo";
+
+/// Checks that the host time zone database at `zoneinfo` has a valid entry
+/// for the zone `tz`, e.g. `Europe/Berlin`, before the guest is pointed at
+/// it.
+pub(crate) fn verify_zone(zoneinfo: &Path, tz: &str) -> Result<(), String> {
+    if tz.is_empty() || tz.starts_with('/') || tz.split('/').any(|c| c == "." || c == "..") {
+        return Err(format!("invalid time zone {:?}", tz));
+    }
+    let path = zoneinfo.join(tz);
+    let mut magic = [0; 4];
+    File::open(&path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map_err(|err| format!("couldn't read time zone {}: {}", path.display(), err))?;
+    if &magic != b"TZif" {
+        return Err(format!("{} isn't a TZif file", path.display()));
+    }
+    Ok(())
+}
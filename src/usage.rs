@@ -0,0 +1,141 @@
+use super::state::state_of;
+use std::collections::HashMap;
+use std::time::Duration;
+use wasmtime_runtime::{Export, InstanceHandle};
+
+/// Host resources consumed by a guest, as reported by `resource_usage`.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceUsage {
+    /// Current size of the WASI memory, in bytes.
+    pub memory: usize,
+    /// Largest size the WASI memory was seen at, in bytes. Growth is only
+    /// observed when the guest makes a syscall or usage is queried.
+    pub peak_memory: usize,
+    /// Number of fds the guest holds.
+    pub open_fds: u32,
+    /// Bytes the guest read from its fds.
+    pub bytes_read: u64,
+    /// Bytes the guest wrote to its fds.
+    pub bytes_written: u64,
+    /// Number of syscalls the guest made.
+    pub hostcalls: u64,
+    /// Wall-clock time spent servicing them.
+    pub hostcall_time: Duration,
+    /// Host CPU time spent servicing them.
+    pub hostcall_cpu_time: Duration,
+    /// Breakdown of the above by syscall.
+    pub syscalls: HashMap<&'static str, SyscallUsage>,
+}
+
+/// Host resources consumed servicing one syscall.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyscallUsage {
+    /// Number of times the guest made the syscall.
+    pub calls: u64,
+    /// Wall-clock time spent servicing it.
+    pub time: Duration,
+    /// Host CPU time spent servicing it.
+    pub cpu_time: Duration,
+}
+
+/// Resource accounting kept in the host state.
+#[derive(Default)]
+pub(crate) struct Usage {
+    peak_memory: usize,
+    bytes_read: u64,
+    bytes_written: u64,
+    syscalls: HashMap<&'static str, SyscallUsage>,
+}
+
+impl Usage {
+    /// Size of the usage as encoded by `to_guest`.
+    pub(crate) const GUEST_SIZE: usize = 48;
+
+    pub(crate) fn memory_seen(&mut self, len: usize) {
+        self.peak_memory = self.peak_memory.max(len);
+    }
+
+    pub(crate) fn read(&mut self, bytes: u32) {
+        self.bytes_read += u64::from(bytes);
+    }
+
+    pub(crate) fn written(&mut self, bytes: u32) {
+        self.bytes_written += u64::from(bytes);
+    }
+
+    pub(crate) fn hostcall(&mut self, name: &'static str, time: Duration, cpu_time: Duration) {
+        let usage = self.syscalls.entry(name).or_default();
+        usage.calls += 1;
+        usage.time += time;
+        usage.cpu_time += cpu_time;
+    }
+
+    pub(crate) fn report(&mut self, memory: usize, open_fds: u32) -> ResourceUsage {
+        self.memory_seen(memory);
+        let mut usage = ResourceUsage {
+            memory,
+            peak_memory: self.peak_memory,
+            open_fds,
+            bytes_read: self.bytes_read,
+            bytes_written: self.bytes_written,
+            syscalls: self.syscalls.clone(),
+            ..ResourceUsage::default()
+        };
+        for syscall in self.syscalls.values() {
+            usage.hostcalls += syscall.calls;
+            usage.hostcall_time += syscall.time;
+            usage.hostcall_cpu_time += syscall.cpu_time;
+        }
+        usage
+    }
+
+    /// Encodes the usage as `enarx_usage` hands it to the guest: a
+    /// little-endian `u32` for the size, then one for the open fds,
+    /// followed by `u64`s for the current and peak memory size, the bytes
+    /// read and written, and the number of hostcalls made.
+    ///
+    /// Like `report`, but without copying the per-syscall breakdown the
+    /// guest doesn't get.
+    pub(crate) fn to_guest(&mut self, memory: usize, open_fds: u32) -> [u8; Self::GUEST_SIZE] {
+        self.memory_seen(memory);
+        let hostcalls: u64 = self.syscalls.values().map(|syscall| syscall.calls).sum();
+
+        let mut out = [0; Self::GUEST_SIZE];
+        out[0..4].copy_from_slice(&(Self::GUEST_SIZE as u32).to_le_bytes());
+        out[4..8].copy_from_slice(&open_fds.to_le_bytes());
+        out[8..16].copy_from_slice(&(memory as u64).to_le_bytes());
+        out[16..24].copy_from_slice(&(self.peak_memory as u64).to_le_bytes());
+        out[24..32].copy_from_slice(&self.bytes_read.to_le_bytes());
+        out[32..40].copy_from_slice(&self.bytes_written.to_le_bytes());
+        out[40..48].copy_from_slice(&hostcalls.to_le_bytes());
+        out
+    }
+}
+
+/// Returns the resources consumed so far by the guest of an instance
+/// created by this crate.
+pub fn resource_usage(instance: &mut InstanceHandle) -> Option<ResourceUsage> {
+    let name = unsafe { state_of(instance.host_state()) }?.memory.clone();
+    let memory = memory_len(unsafe { instance.vmctx_mut().lookup_global_export(&name) });
+    let state = unsafe { state_of(instance.host_state()) }?;
+    let open_fds = state.fs.open_fds();
+    Some(state.usage.report(memory, open_fds))
+}
+
+/// Current length of `export` if it's a memory, 0 otherwise.
+pub(crate) fn memory_len(export: Option<Export>) -> usize {
+    match export {
+        Some(Export::Memory { definition, .. }) => unsafe { (*definition).current_length },
+        _ => 0,
+    }
+}
+
+/// CPU time consumed by the calling thread so far.
+pub(crate) fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
@@ -0,0 +1,173 @@
+use log::warn;
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::{Condvar, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Signal used to knock a hostcall out of a blocking host syscall. It's
+/// ignored by default, so one arriving after the hostcall returned is
+/// harmless.
+const SIGNAL: libc::c_int = libc::SIGURG;
+
+/// How often the signal is sent again while an interrupted hostcall hasn't
+/// returned, in case it arrived just before the thread blocked.
+const RESEND_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Bounds the time spent inside any single hostcall.
+///
+/// While a hostcall runs, a background thread waits for its deadline, and
+/// if it passes interrupts the thread servicing the hostcall with a signal.
+/// The handler is installed without `SA_RESTART`, so a host syscall blocked
+/// on the guest's behalf (e.g. `read` on a dead socket) fails with `EINTR`,
+/// which the shim then reports to the guest as `__WASI_ETIMEDOUT`. The
+/// signal is sent again every `RESEND_INTERVAL` until the hostcall returns,
+/// as one landing between the hostcall's syscalls interrupts nothing.
+///
+/// The background thread is shared by all the watchdogs of the process, and
+/// spawned by the first of them, or by `start`: creating a watchdog after
+/// that, e.g. on each reset of a pooled instance, spawns nothing.
+pub(crate) struct Watchdog {
+    timeout: Duration,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    armed: HashMap<u64, Armed>,
+    next_id: u64,
+}
+
+struct Armed {
+    thread: libc::pthread_t,
+    /// When to signal the thread next: the deadline, until it passes.
+    deadline: Instant,
+    fired: bool,
+}
+
+/// Spawns the thread the watchdogs share, if it isn't running yet, so
+/// creating one later needn't spawn it, e.g. once seccomp denies `clone`.
+pub(crate) fn start() {
+    install_handler();
+    shared();
+}
+
+fn shared() -> &'static Shared {
+    static START: Once = Once::new();
+    static mut SHARED: *const Shared = ptr::null();
+
+    START.call_once(|| {
+        let shared: &'static Shared = Box::leak(Box::new(Shared {
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+        }));
+        thread::spawn(move || shared.watch());
+        unsafe { SHARED = shared };
+    });
+    unsafe { &*SHARED }
+}
+
+impl Watchdog {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        start();
+        Self { timeout }
+    }
+
+    /// The time a hostcall is allowed.
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Starts timing a hostcall made on the current thread.
+    pub(crate) fn arm(&self) -> Watch {
+        let shared = shared();
+        let mut state = shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.armed.insert(
+            id,
+            Armed {
+                thread: unsafe { libc::pthread_self() },
+                deadline: Instant::now() + self.timeout,
+                fired: false,
+            },
+        );
+        shared.cond.notify_one();
+        Watch { id }
+    }
+}
+
+impl Shared {
+    fn watch(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let mut wait: Option<Duration> = None;
+            for armed in state.armed.values_mut() {
+                if now >= armed.deadline {
+                    if !armed.fired {
+                        warn!("hostcall exceeded its time bound, interrupting it");
+                        armed.fired = true;
+                    }
+                    unsafe { libc::pthread_kill(armed.thread, SIGNAL) };
+                    armed.deadline = now + RESEND_INTERVAL;
+                }
+                let left = armed.deadline - now;
+                wait = Some(wait.map_or(left, |wait| wait.min(left)));
+            }
+            state = match wait {
+                Some(wait) => self.cond.wait_timeout(state, wait).unwrap().0,
+                None => self.cond.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+/// A hostcall being timed by a `Watchdog`.
+pub(crate) struct Watch {
+    id: u64,
+}
+
+impl Watch {
+    /// Stops timing the hostcall, returning whether it was interrupted.
+    pub(crate) fn finish(self) -> bool {
+        self.disarm()
+    }
+
+    fn disarm(&self) -> bool {
+        shared()
+            .state
+            .lock()
+            .unwrap()
+            .armed
+            .remove(&self.id)
+            .map_or(false, |armed| armed.fired)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.disarm();
+    }
+}
+
+fn install_handler() {
+    static INSTALL: Once = Once::new();
+
+    extern "C" fn interrupt(_signum: libc::c_int) {}
+
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = interrupt as libc::sighandler_t;
+        // NB: no SA_RESTART, interrupting blocking syscalls is the point.
+        action.sa_flags = 0;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(SIGNAL, &action, std::ptr::null_mut()) != 0 {
+            warn!("couldn't install the hostcall watchdog's signal handler");
+        }
+    });
+}
@@ -0,0 +1,214 @@
+use log::error;
+use std::collections::HashMap;
+use wasi_common::{hostcalls, wasm32, WasiCtx};
+
+use super::fs::fdstat;
+use super::guest::{self, iovec, read_u32};
+use super::secret::zeroize_vec;
+
+/// Offset of the data in a write-back buffer, after the iovec and
+/// `nwritten` the `WasiCtx` drains it through.
+pub(crate) const HEADER: usize = 12;
+
+/// Buffers the guest's writes to regular files beneath the preopens
+/// configured for it, trading durability for throughput.
+///
+/// Buffered writes reach the host file when the buffer fills up, and on
+/// `fd_sync`, `fd_datasync`, `fd_close`, `proc_exit` and teardown. They're
+/// also flushed before anything else depends on the fd's position or
+/// contents, so the guest itself can't tell. Other fds and the host only
+/// see them once they're flushed.
+#[derive(Default)]
+pub(crate) struct WriteBack {
+    /// Bytes to buffer on each fd writes are buffered beneath or on: the
+    /// configured preopens and everything opened through them.
+    sizes: HashMap<wasm32::__wasi_fd_t, usize>,
+    buffers: HashMap<wasm32::__wasi_fd_t, Buffer>,
+}
+
+struct Buffer {
+    /// Whether the fd is a regular file the guest may write to.
+    eligible: bool,
+    /// `HEADER`, followed by the data not written yet.
+    buf: Vec<u8>,
+}
+
+impl WriteBack {
+    /// Buffers up to `size` bytes of the writes to files opened beneath
+    /// the preopen `fd`.
+    pub(crate) fn enable(&mut self, fd: wasm32::__wasi_fd_t, size: usize) {
+        self.sizes.insert(fd, size);
+    }
+
+    /// Records that `fd` was opened relative to `dirfd`.
+    pub(crate) fn opened(&mut self, dirfd: wasm32::__wasi_fd_t, fd: wasm32::__wasi_fd_t) {
+        self.buffers.remove(&fd);
+        match self.sizes.get(&dirfd) {
+            Some(&size) => self.sizes.insert(fd, size),
+            None => self.sizes.remove(&fd),
+        };
+    }
+
+    /// Forgets about `fd`, which was flushed and then closed or replaced.
+    pub(crate) fn forget(&mut self, fd: wasm32::__wasi_fd_t) {
+        self.sizes.remove(&fd);
+        self.buffers.remove(&fd);
+    }
+
+    /// Records that `from` was renumbered to `to`, after both were flushed.
+    pub(crate) fn renumbered(&mut self, from: wasm32::__wasi_fd_t, to: wasm32::__wasi_fd_t) {
+        self.buffers.remove(&from);
+        self.buffers.remove(&to);
+        match self.sizes.remove(&from) {
+            Some(size) => self.sizes.insert(to, size),
+            None => self.sizes.remove(&to),
+        };
+    }
+
+    /// Records that the rights of `fd` changed, so whether its writes may
+    /// be buffered has to be decided anew.
+    pub(crate) fn rights_changed(&mut self, fd: wasm32::__wasi_fd_t) {
+        self.buffers.remove(&fd);
+    }
+
+    /// Serves an `fd_write` by buffering it. Returns `None` if `fd` isn't
+    /// one to buffer writes on, for the write to go straight to the
+    /// `WasiCtx`.
+    pub(crate) fn write(
+        &mut self,
+        ctx: &mut WasiCtx,
+        memory: &mut [u8],
+        fd: wasm32::__wasi_fd_t,
+        iovs: wasm32::uintptr_t,
+        iovs_len: wasm32::size_t,
+        nwritten: wasm32::uintptr_t,
+    ) -> Option<wasm32::__wasi_errno_t> {
+        let size = *self.sizes.get(&fd)?;
+        let buffer = self
+            .buffers
+            .entry(fd)
+            .or_insert_with(|| Buffer::new(ctx, fd));
+        if !buffer.eligible {
+            return None;
+        }
+
+        let mut requested = 0;
+        for i in 0..iovs_len {
+            match iovec(memory, iovs, i) {
+                Some(range) => requested += range.len(),
+                None => return Some(wasm32::__WASI_EFAULT),
+            }
+        }
+        let nwritten = match guest::range(memory, nwritten, 4) {
+            Ok(range) => range,
+            Err(errno) => return Some(errno),
+        };
+        let pending = buffer.buf.len().saturating_sub(HEADER);
+        if pending + requested > size {
+            if let Err(errno) = buffer.flush(ctx, fd) {
+                return Some(errno);
+            }
+        }
+        if requested >= size {
+            // too large to be worth buffering
+            return None;
+        }
+
+        if buffer.buf.is_empty() {
+            buffer.buf.resize(HEADER, 0);
+        }
+        for i in 0..iovs_len {
+            if let Some(range) = iovec(memory, iovs, i) {
+                buffer.buf.extend_from_slice(&memory[range]);
+            }
+        }
+        memory[nwritten].copy_from_slice(&(requested as u32).to_le_bytes());
+        Some(wasm32::__WASI_ESUCCESS)
+    }
+
+    /// Writes what's buffered for `fd` to the host file.
+    pub(crate) fn flush(
+        &mut self,
+        ctx: &mut WasiCtx,
+        fd: wasm32::__wasi_fd_t,
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        match self.buffers.get_mut(&fd) {
+            Some(buffer) => buffer.flush(ctx, fd),
+            None => Ok(()),
+        }
+    }
+
+    /// Zeroes every buffer, which must have been flushed, so no written
+    /// data lingers in them.
+    pub(crate) fn scrub(&mut self) {
+        for buffer in self.buffers.values_mut() {
+            zeroize_vec(&mut buffer.buf);
+        }
+    }
+
+    /// Writes everything buffered to the host files, e.g. before the
+    /// process exits.
+    pub(crate) fn flush_all(&mut self, ctx: &mut WasiCtx) {
+        for (&fd, buffer) in &mut self.buffers {
+            if let Err(errno) = buffer.flush(ctx, fd) {
+                error!(
+                    "couldn't write back buffered writes to fd {}: errno {}",
+                    fd, errno
+                );
+            }
+        }
+    }
+}
+
+impl Buffer {
+    fn new(ctx: &mut WasiCtx, fd: wasm32::__wasi_fd_t) -> Self {
+        let eligible = match fdstat(ctx, fd) {
+            Ok((filetype, base, _)) => {
+                filetype == wasm32::__WASI_FILETYPE_REGULAR_FILE
+                    && base & wasm32::__WASI_RIGHT_FD_WRITE != 0
+            }
+            Err(_) => false,
+        };
+        Self {
+            eligible,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Writes the buffer to the host file, dropping it whether that worked
+    /// or not, like the kernel does with a failed delayed write.
+    fn flush(
+        &mut self,
+        ctx: &mut WasiCtx,
+        fd: wasm32::__wasi_fd_t,
+    ) -> Result<(), wasm32::__wasi_errno_t> {
+        let result = write_all(ctx, &mut self.buf, fd);
+        self.buf.clear();
+        result
+    }
+}
+
+/// Writes all of `buf` after its first `HEADER` bytes, which are
+/// overwritten with the iovec and `nwritten` the `WasiCtx` writes it
+/// through, to `fd`.
+pub(crate) fn write_all(
+    ctx: &mut WasiCtx,
+    buf: &mut [u8],
+    fd: wasm32::__wasi_fd_t,
+) -> Result<(), wasm32::__wasi_errno_t> {
+    let mut written = HEADER;
+    while written < buf.len() {
+        let len = buf.len() - written;
+        buf[0..4].copy_from_slice(&(written as u32).to_le_bytes());
+        buf[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        let errno = hostcalls::fd_write(ctx, buf, fd, 0, 1, 8);
+        if errno != wasm32::__WASI_ESUCCESS {
+            return Err(errno);
+        }
+        match read_u32(buf, 8) {
+            Some(0) | None => return Err(wasm32::__WASI_EIO),
+            Some(n) => written += n as usize,
+        }
+    }
+    Ok(())
+}
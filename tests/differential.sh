@@ -0,0 +1,116 @@
+#!/bin/sh
+# Runs MODULE under this crate's `enarx-wasi` and under stock wasmtime's
+# WASI, with the same arguments and stdin, and reports where they diverge:
+# in exit status, in what the module writes to stdout, or in the sequence
+# of syscalls it makes and the errnos they return.
+#
+# Our syscalls come from `--trace`. wasmtime's come from wasi-common's
+# trace-level log, which names each hostcall as it's made and then logs
+# `| errno=` with what it returned, so that's turned on with RUST_LOG and
+# WASMTIME_FLAGS, `--debug` by default, which has it logged to stderr. The
+# errnos are compared by name, e.g. EBADF.
+#
+# usage: tests/differential.sh MODULE [ARGS...]
+# WASMTIME names the wasmtime binary, `wasmtime` by default, and STDIN a
+# file both runs read their stdin from, /dev/null by default.
+
+set -u
+
+if [ $# -lt 1 ]; then
+    echo "usage: $0 MODULE [ARGS...]" >&2
+    exit 2
+fi
+module=$1
+shift
+
+root=$(cd "$(dirname "$0")/.." && pwd)
+cargo build --manifest-path "$root/Cargo.toml" --bin enarx-wasi || exit 2
+ours="$root/target/debug/enarx-wasi"
+theirs=${WASMTIME:-wasmtime}
+stdin=${STDIN:-/dev/null}
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+"$ours" --trace "$tmp/ours.trace" "$module" "$@" <"$stdin" >"$tmp/ours.out" 2>"$tmp/ours.err"
+our_status=$?
+# shellcheck disable=SC2086
+RUST_LOG=${RUST_LOG:-wasi_common=trace} \
+    "$theirs" ${WASMTIME_FLAGS---debug} "$module" "$@" <"$stdin" >"$tmp/theirs.out" 2>"$tmp/theirs.err"
+their_status=$?
+
+# Our trace as a line per syscall, its name and then its errno's, if it
+# returned one: `fd_write(fd=1, ...) = 8` is `fd_write EBADF`.
+awk '
+BEGIN {
+    n = split("ESUCCESS E2BIG EACCES EADDRINUSE EADDRNOTAVAIL EAFNOSUPPORT EAGAIN " \
+        "EALREADY EBADF EBADMSG EBUSY ECANCELED ECHILD ECONNABORTED ECONNREFUSED " \
+        "ECONNRESET EDEADLK EDESTADDRREQ EDOM EDQUOT EEXIST EFAULT EFBIG " \
+        "EHOSTUNREACH EIDRM EILSEQ EINPROGRESS EINTR EINVAL EIO EISCONN EISDIR " \
+        "ELOOP EMFILE EMLINK EMSGSIZE EMULTIHOP ENAMETOOLONG ENETDOWN ENETRESET " \
+        "ENETUNREACH ENFILE ENOBUFS ENODEV ENOENT ENOEXEC ENOLCK ENOLINK ENOMEM " \
+        "ENOMSG ENOPROTOOPT ENOSPC ENOSYS ENOTCONN ENOTDIR ENOTEMPTY " \
+        "ENOTRECOVERABLE ENOTSOCK ENOTSUP ENOTTY ENXIO EOVERFLOW EOWNERDEAD EPERM " \
+        "EPIPE EPROTO EPROTONOSUPPORT EPROTOTYPE ERANGE EROFS ESPIPE ESRCH ESTALE " \
+        "ETIMEDOUT ETXTBSY EXDEV ENOTCAPABLE", names, " ")
+    for (i = 1; i <= n; i++) errno[i - 1] = names[i]
+}
+{
+    name = substr($0, 1, index($0, "(") - 1)
+    if (match($0, / = -?[0-9]+$/)) {
+        ret = substr($0, RSTART + 3)
+        print name, (ret in errno) ? errno[ret] : ret
+    } else {
+        print name
+    }
+}' "$tmp/ours.trace" >"$tmp/ours.errnos"
+
+# wasmtime's log likewise: a hostcall is the first `name(` of a line, and
+# the `errno=` after it, if any, is what it returned.
+awk '
+function flush() {
+    if (name != "") print name
+    name = ""
+}
+/errno=/ {
+    ret = substr($0, index($0, "errno=") + 6)
+    sub(/[^A-Z0-9_].*/, "", ret)
+    sub(/^__WASI_/, "", ret)
+    if (name != "") print name, ret
+    name = ""
+    traced = 1
+    next
+}
+match($0, /[a-z][a-z0-9_]*\(/) {
+    flush()
+    name = substr($0, RSTART, RLENGTH - 1)
+}
+END {
+    flush()
+    if (!traced) exit 1
+}' "$tmp/theirs.err" >"$tmp/theirs.errnos"
+their_trace=$?
+
+diverged=0
+if [ $our_status -ne $their_status ]; then
+    echo "exit status: $our_status here, $their_status under $theirs"
+    diverged=1
+fi
+if ! diff -u --label enarx-wasi --label "$theirs" "$tmp/ours.out" "$tmp/theirs.out"; then
+    diverged=1
+fi
+if [ $their_trace -ne 0 ]; then
+    echo "no errnos in $theirs's log, so they weren't compared;" \
+        "check RUST_LOG and WASMTIME_FLAGS" >&2
+    diverged=1
+elif ! diff -u --label "enarx-wasi errnos" --label "$theirs errnos" \
+    "$tmp/ours.errnos" "$tmp/theirs.errnos"; then
+    diverged=1
+fi
+if [ $diverged -ne 0 ]; then
+    echo "stderr here:"
+    cat "$tmp/ours.err"
+    echo "stderr under $theirs:"
+    cat "$tmp/theirs.err"
+    exit 1
+fi
+echo "no divergence"
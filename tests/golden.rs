@@ -0,0 +1,79 @@
+//! Runs each fixture module under `tests/golden` with its syscalls traced,
+//! and diffs the trace against the golden one next to it, so changes to the
+//! syscall layer which alter what guests see don't go unnoticed.
+//!
+//! A fixture is a `NAME.wat`, and its golden trace is `NAME.trace`. With
+//! `GOLDEN_BLESS=1` in the environment, the goldens are rewritten from this
+//! run instead, for changes which are meant to alter them, and for new
+//! fixtures, which have none yet; `tests/golden/run.sh --bless` does that.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use wasmtime_wasi::WasiInstanceBuilder;
+
+/// Runs the fixture `wat`, and returns its syscall trace.
+fn trace(name: &str, wat: &Path) -> String {
+    let wasm = wat::parse_file(wat).unwrap_or_else(|err| panic!("{}: {}", name, err));
+    let path = env::temp_dir().join(format!("enarx-golden-{}-{}.trace", process::id(), name));
+    let module = WasiInstanceBuilder::new()
+        .arg(name)
+        .capture_stdout(io::sink())
+        .capture_stderr(io::sink())
+        .trace(&path)
+        .instantiate_module(&wasm);
+    match module {
+        // the guest's exit status and output are up to the fixture, and
+        // the trace is written out when the module is dropped
+        Ok(mut module) => drop(module.run()),
+        Err(err) => panic!("{}: couldn't instantiate: {}", name, err),
+    }
+    let trace = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("{}: couldn't read the trace: {}", name, err));
+    let _ = fs::remove_file(&path);
+    trace
+}
+
+#[test]
+fn traces_match_the_goldens() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let bless = env::var_os("GOLDEN_BLESS").map_or(false, |bless| bless == "1");
+    let mut fixtures: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "wat"))
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty(), "no fixtures in {}", dir.display());
+
+    let mut failed = Vec::new();
+    for fixture in fixtures {
+        let name = fixture.file_stem().unwrap().to_string_lossy().into_owned();
+        let trace = trace(&name, &fixture);
+        let golden = fixture.with_extension("trace");
+        if bless {
+            fs::write(&golden, &trace).unwrap();
+            continue;
+        }
+        match fs::read_to_string(&golden) {
+            Ok(ref expected) if *expected == trace => {}
+            Ok(expected) => {
+                eprintln!(
+                    "{}: trace differs from the golden one\n--- expected\n{}+++ actual\n{}",
+                    name, expected, trace
+                );
+                failed.push(name);
+            }
+            Err(err) => {
+                eprintln!(
+                    "{}: no golden trace ({}), record one with GOLDEN_BLESS=1",
+                    name, err
+                );
+                failed.push(name);
+            }
+        }
+    }
+    assert!(failed.is_empty(), "traces differ: {}", failed.join(", "));
+}
@@ -0,0 +1,19 @@
+#!/bin/sh
+# Diffs the syscall trace of each fixture module in this directory against
+# the golden one next to it; `cargo test` runs the same check, from
+# tests/golden.rs.
+#
+# A fixture is a `NAME.wat`, and its golden trace is `NAME.trace`. With
+# `--bless`, the goldens are rewritten from this run instead, for changes
+# which are meant to alter them, and for new fixtures, which have none yet.
+#
+# usage: tests/golden/run.sh [--bless]
+
+set -eu
+
+root=$(cd "$(dirname "$0")/../.." && pwd)
+if [ "${1:-}" = "--bless" ]; then
+    GOLDEN_BLESS=1
+    export GOLDEN_BLESS
+fi
+exec cargo test --manifest-path "$root/Cargo.toml" --test golden
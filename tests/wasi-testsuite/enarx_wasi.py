@@ -0,0 +1,35 @@
+#!/usr/bin/env python3
+"""Runtime adapter for wasi-testsuite's test runner: translates the options
+it runs each test with into an `enarx-wasi` command line.
+
+ENARX_WASI names the binary, target/debug/enarx-wasi by default.
+"""
+
+import argparse
+import os
+import subprocess
+import sys
+
+ROOT = os.path.dirname(os.path.dirname(os.path.dirname(os.path.abspath(__file__))))
+ENARX_WASI = os.environ.get("ENARX_WASI", os.path.join(ROOT, "target", "debug", "enarx-wasi"))
+
+parser = argparse.ArgumentParser()
+parser.add_argument("--version", action="store_true")
+parser.add_argument("--test-file")
+parser.add_argument("--arg", action="append", default=[])
+parser.add_argument("--env", action="append", default=[])
+parser.add_argument("--dir", action="append", default=[])
+args = parser.parse_args()
+
+if args.version:
+    sys.exit(subprocess.run([ENARX_WASI, "--version"]).returncode)
+
+command = [ENARX_WASI]
+for env in args.env:
+    command += ["--env", env]
+for preopen in args.dir:
+    command += ["--dir", preopen]
+command.append(args.test_file)
+# after the module, so the runner's arguments are never taken for options
+command += args.arg
+sys.exit(subprocess.run(command).returncode)
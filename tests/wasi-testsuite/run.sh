@@ -0,0 +1,27 @@
+#!/bin/sh
+# Runs the WASI conformance suite, from a checkout of
+# https://github.com/WebAssembly/wasi-testsuite, against `enarx-wasi`.
+#
+# Tests listed in expectations.json are known not to pass yet, with the
+# reason why, and are skipped; the rest are expected to pass. Drop a test
+# from the list once the runtime passes it, so it can't regress unnoticed.
+#
+# usage: tests/wasi-testsuite/run.sh WASI_TESTSUITE
+
+set -eu
+
+if [ $# -ne 1 ]; then
+    echo "usage: $0 WASI_TESTSUITE" >&2
+    exit 2
+fi
+suite=$(cd "$1" && pwd)
+dir=$(cd "$(dirname "$0")" && pwd)
+root=$(cd "$dir/../.." && pwd)
+
+cargo build --manifest-path "$root/Cargo.toml" --bin enarx-wasi
+ENARX_WASI="$root/target/debug/enarx-wasi" python3 "$suite/test-runner/wasi_test_runner.py" \
+    --test-suite "$suite/tests/assemblyscript/testsuite" \
+        "$suite/tests/c/testsuite" \
+        "$suite/tests/rust/testsuite" \
+    --runtime-adapter "$dir/enarx_wasi.py" \
+    --exclude-filter "$dir/expectations.json"